@@ -0,0 +1,44 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use rustV::{
+    config::MachineConfig,
+    cpu::{builder::CpuBuilder, test_framework::run},
+};
+
+// iterative fibonacci, 2000 rounds of a 5 instruction loop
+const FIB: [u32; 8] = [
+    0x7d000293, // li t0, 2000
+    0x00000513, // li a0, 0
+    0x00100593, // li a1, 1
+    0x00b50333, // loop: add t1, a0, a1
+    0x00058513, // mv a0, a1
+    0x00030593, // mv a1, t1
+    0xfff28293, // addi t0, t0, -1
+    0xfe029ce3, // bnez t0, loop
+];
+
+fn fib(c: &mut Criterion) {
+    let code: Vec<u8> = FIB.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+    let mut config = MachineConfig::default();
+    config.uart_stdin = false;
+
+    let mut group = c.benchmark_group("fib");
+    for (name, cached) in [("uncached", false), ("cached", true)] {
+        let mut cpu = Some(
+            CpuBuilder::new(code.clone(), vec![0])
+                .config(config.clone())
+                .block_cache(cached)
+                .build(),
+        );
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let mut c = cpu.take().unwrap();
+                c.reset();
+                cpu = Some(run(c, -1).unwrap().0);
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, fib);
+criterion_main!(benches);
@@ -0,0 +1,202 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use rustV::{
+    config::MachineConfig,
+    cpu::{
+        builder::CpuBuilder,
+        cpu::{AccessType, Cpu},
+        test_framework::{run, rv_c_binary, toolchain_available},
+        timing::{SifiveTiming, SimpleTiming, TimingModel},
+    },
+    csr::SATP,
+    param::{DRAM_BASE, PAGE_SIZE},
+};
+
+// 10000 rounds of add, store, load and branch
+const MIX: [u32; 7] = [
+    0x000022b7, // lui t0, 0x2
+    0x71028293, // addi t0, t0, 1808
+    0xfff00393, // li t2, -1
+    0x007282b3, // loop: add t0, t0, t2
+    0xfe513c23, // sd t0, -8(sp)
+    0xff813503, // ld a0, -8(sp)
+    0xfe051ae3, // bnez a0, loop
+];
+
+// 2500 rounds of four cycle reads
+const CSR_READ: [u32; 8] = [
+    0x000012b7, // lui t0, 0x1
+    0x9c428293, // addi t0, t0, -1596
+    0xc00020f3, // loop: csrr ra, cycle
+    0xc00020f3, // csrr ra, cycle
+    0xc00020f3, // csrr ra, cycle
+    0xc00020f3, // csrr ra, cycle
+    0xfff28293, // addi t0, t0, -1
+    0xfe0296e3, // bnez t0, loop
+];
+
+const ROOT: u64 = DRAM_BASE + 0x10000;
+const L1: u64 = DRAM_BASE + 0x11000;
+const L0: u64 = DRAM_BASE + 0x12000;
+const PAGES: u64 = 32;
+const TRANSLATIONS: u64 = 1_000_000;
+
+fn config() -> MachineConfig {
+    let mut config = MachineConfig::default();
+    config.uart_stdin = false;
+    config
+}
+
+fn encode(code: &[u32]) -> Vec<u8> {
+    code.iter().flat_map(|inst| inst.to_le_bytes()).collect()
+}
+
+// runs code to the end on a fresh cpu every sample, throughput in retired instructions
+fn bench_program(c: &mut Criterion, name: &str, code: Vec<u8>) {
+    let build = || {
+        CpuBuilder::new(code.clone(), vec![0])
+            .config(config())
+            .build()
+    };
+    let (cpu, _) = run(build(), -1).unwrap();
+    let mut group = c.benchmark_group(name);
+    group.throughput(Throughput::Elements(cpu.instret));
+    group.bench_function("run", |b| {
+        b.iter_batched(build, |cpu| run(cpu, -1).unwrap(), BatchSize::SmallInput)
+    });
+    group.finish();
+}
+
+// the C programs need the riscv toolchain, see build.rs
+fn bench_c_program(c: &mut Criterion, name: &str, path: &str) {
+    if !toolchain_available() {
+        eprintln!("{}: skipped, no riscv toolchain", name);
+        return;
+    }
+    let code = rv_c_binary(path, name).unwrap();
+    bench_program(c, name, code);
+}
+
+fn bench_fibonacci(c: &mut Criterion) {
+    bench_c_program(c, "bench_fibonacci", "./m_tests/fib.c");
+}
+
+fn bench_sorting(c: &mut Criterion) {
+    bench_c_program(c, "bench_sorting", "./m_tests/sorting.c");
+}
+
+// weighted cycles and retired instructions of a run of code under model
+fn timed_run(code: &[u8], model: Box<dyn TimingModel>) -> (u64, u64) {
+    let cpu = CpuBuilder::new(code.to_vec(), vec![0])
+        .config(config())
+        .timing_model(model)
+        .build();
+    let (cpu, _) = run(cpu, -1).unwrap();
+    (cpu.cycles, cpu.instret)
+}
+
+// fib and sort's weighted cycles under SimpleTiming and SifiveTiming. Sort's
+// array accesses are loads and stores, so per instruction fib has to be faster.
+fn bench_timing_models(c: &mut Criterion) {
+    if !toolchain_available() {
+        eprintln!("bench_timing_models: skipped, no riscv toolchain");
+        return;
+    }
+    let fib = rv_c_binary("./m_tests/fib.c", "bench_timing_fib").unwrap();
+    let sort = rv_c_binary("./m_tests/sorting.c", "bench_timing_sort").unwrap();
+    let models: [(&str, fn() -> Box<dyn TimingModel>); 2] = [
+        ("simple", || Box::new(SimpleTiming)),
+        ("sifive", || Box::new(SifiveTiming)),
+    ];
+
+    let mut group = c.benchmark_group("bench_timing_models");
+    for (model_name, model) in models {
+        let (fib_cycles, fib_instret) = timed_run(&fib, model());
+        let (sort_cycles, sort_instret) = timed_run(&sort, model());
+        let fib_cpi = fib_cycles as f64 / fib_instret as f64;
+        let sort_cpi = sort_cycles as f64 / sort_instret as f64;
+        eprintln!(
+            "{}: fib {} cycles ({:.2} per instruction), sort {} cycles ({:.2} per instruction)",
+            model_name, fib_cycles, fib_cpi, sort_cycles, sort_cpi
+        );
+        if model_name == "sifive" {
+            assert!(
+                fib_cpi < sort_cpi,
+                "fib isn't faster than sort per instruction"
+            );
+        }
+        for (name, code) in [("fib", &fib), ("sort", &sort)] {
+            group.bench_function(format!("{}/{}", name, model_name), |b| {
+                b.iter_batched(
+                    || {
+                        CpuBuilder::new(code.clone(), vec![0])
+                            .config(config())
+                            .timing_model(model())
+                            .build()
+                    },
+                    |cpu| run(cpu, -1).unwrap(),
+                    BatchSize::SmallInput,
+                )
+            });
+        }
+    }
+    group.finish();
+}
+
+fn pte(pa: u64, flags: u64) -> u64 {
+    ((pa >> 12) << 10) | flags
+}
+
+// Sv39 with PAGES pages mapped at va 0
+fn paged_cpu() -> Cpu {
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).config(config()).build();
+    cpu.bus.store(ROOT, 64, pte(L1, 0b1)).unwrap();
+    cpu.bus.store(L1, 64, pte(L0, 0b1)).unwrap();
+    for i in 0..PAGES {
+        let frame = DRAM_BASE + 0x100000 + i * PAGE_SIZE;
+        cpu.bus.store(L0 + i * 8, 64, pte(frame, 0b11)).unwrap();
+    }
+    cpu.csr.store(SATP, (8 << 60) | (ROOT / PAGE_SIZE));
+    cpu.page_table = ROOT;
+    cpu.enable_paging = true;
+    cpu
+}
+
+// every translation is a full walk, the tlb is flushed before each one
+fn bench_page_walk(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bench_page_walk");
+    group.throughput(Throughput::Elements(TRANSLATIONS));
+    group.sample_size(10);
+    group.bench_function("walk", |b| {
+        b.iter_batched(
+            paged_cpu,
+            |mut cpu| {
+                for i in 0..TRANSLATIONS {
+                    cpu.flush_tlb(None, None);
+                    let va = (i % PAGES) * PAGE_SIZE;
+                    black_box(cpu.translate(va, AccessType::Load).unwrap());
+                }
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+fn bench_instruction_mix(c: &mut Criterion) {
+    bench_program(c, "bench_instruction_mix", encode(&MIX));
+}
+
+fn bench_csr_read(c: &mut Criterion) {
+    bench_program(c, "bench_csr_read", encode(&CSR_READ));
+}
+
+criterion_group!(
+    benches,
+    bench_fibonacci,
+    bench_sorting,
+    bench_page_walk,
+    bench_instruction_mix,
+    bench_csr_read,
+    bench_timing_models
+);
+criterion_main!(benches);
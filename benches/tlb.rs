@@ -0,0 +1,51 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use rustV::{
+    config::MachineConfig,
+    cpu::{builder::CpuBuilder, cpu::AccessType},
+    csr::SATP,
+    param::{DRAM_BASE, PAGE_SIZE},
+};
+
+const ROOT: u64 = DRAM_BASE + 0x10000;
+const L1: u64 = DRAM_BASE + 0x11000;
+const L0: u64 = DRAM_BASE + 0x12000;
+// 32 pages mapped at va 0
+const PAGES: u64 = 32;
+
+fn pte(pa: u64, flags: u64) -> u64 {
+    ((pa >> 12) << 10) | flags
+}
+
+// translate every mapped page, hits when the tlb is kept, full walks when it's flushed
+fn translate(c: &mut Criterion) {
+    let mut config = MachineConfig::default();
+    config.uart_stdin = false;
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).config(config).build();
+    cpu.bus.store(ROOT, 64, pte(L1, 0b1)).unwrap();
+    cpu.bus.store(L1, 64, pte(L0, 0b1)).unwrap();
+    for i in 0..PAGES {
+        let frame = DRAM_BASE + 0x100000 + i * PAGE_SIZE;
+        cpu.bus.store(L0 + i * 8, 64, pte(frame, 0b11)).unwrap();
+    }
+    cpu.csr.store(SATP, (8 << 60) | (ROOT / PAGE_SIZE));
+    cpu.page_table = ROOT;
+    cpu.enable_paging = true;
+
+    let mut group = c.benchmark_group("translate");
+    for (name, flush) in [("walk", true), ("tlb", false)] {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                for i in 0..PAGES {
+                    if flush {
+                        cpu.flush_tlb(None, None);
+                    }
+                    black_box(cpu.translate(i * PAGE_SIZE, AccessType::Load).unwrap());
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, translate);
+criterion_main!(benches);
@@ -0,0 +1,86 @@
+// Pre-compiles the C test programs and records whether a riscv64 clang / llvm-objcopy
+// toolchain is installed. Tests that need it fail when the marker is missing, unless
+// RUSTV_SKIP_COMPILE is set.
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+const CC: &str = "clang";
+const OBJCOPY: &str = "llvm-objcopy";
+
+// same flags as cpu::test_framework
+fn compile(args: &[&str], source: &Path, dest: &Path) -> bool {
+    Command::new(CC)
+        .args(args)
+        .args(["-nostdlib", "-march=rv64g", "-mabi=lp64"])
+        .args(["--target=riscv64", "-mno-relax", "-o"])
+        .arg(dest)
+        .arg(source)
+        .output()
+        .map(|out| out.status.success())
+        .unwrap_or(false)
+}
+
+fn objcopy(source: &Path, dest: &Path) -> bool {
+    Command::new(OBJCOPY)
+        .args(["-O", "binary"])
+        .arg(source)
+        .arg(dest)
+        .output()
+        .map(|out| out.status.success())
+        .unwrap_or(false)
+}
+
+// C -> assembly -> ELF -> raw binary
+fn build_c(source: &Path, work: &Path, dest: &Path) -> bool {
+    let asm = work.with_extension("s");
+    compile(&["-S"], source, &asm)
+        && compile(&["-Wl,-Ttext=0x0"], &asm, work)
+        && objcopy(work, dest)
+}
+
+fn main() {
+    let root = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let bin_dir = root.join("tests/target");
+    let marker = bin_dir.join("toolchain_available");
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=m_tests");
+    println!("cargo:rerun-if-env-changed=PATH");
+
+    if fs::create_dir_all(&bin_dir).is_err() {
+        return;
+    }
+
+    // assemble and link a single nop the way the asm tests do
+    let probe = out.join("probe.s");
+    fs::write(&probe, "nop\n").unwrap();
+    let available = compile(&["-Wl,-Ttext=0x0"], &probe, &out.join("probe"))
+        && objcopy(&out.join("probe"), &out.join("probe.bin"));
+    if !available {
+        let _ = fs::remove_file(&marker);
+        println!(
+            "cargo:warning=no riscv64 clang / llvm-objcopy, set RUSTV_SKIP_COMPILE to skip the assembly tests"
+        );
+        return;
+    }
+    fs::write(&marker, "").unwrap();
+
+    let Ok(entries) = fs::read_dir(root.join("m_tests")) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let source = entry.path();
+        if source.extension().is_none_or(|ext| ext != "c") {
+            continue;
+        }
+        let stem = source.file_stem().unwrap().to_string_lossy().into_owned();
+        let dest = bin_dir.join(format!("{}.bin", stem));
+        if !build_c(&source, &out.join(&stem), &dest) {
+            let _ = fs::remove_file(&dest);
+            println!("cargo:warning=failed to pre-compile {}", source.display());
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, BufWriter},
+};
+
+use rustV::{
+    config::MachineConfig,
+    cpu::{
+        builder::{CpuBuilder, DeterministicMode},
+        trace::record_trace,
+    },
+    elf::Elf,
+};
+
+// steps recorded when no limit is given
+const MAX_STEPS: u64 = 1_000_000;
+
+// rustv-trace <program> <trace> [max steps] - run the program with fixed inputs
+// and write a reference trace for CpuBuilder::reference_trace
+fn main() -> io::Result<()> {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 3 {
+        println!("usage: rustv-trace <program> <trace> [max steps]");
+        return Ok(());
+    }
+    let max_steps = match args.get(3) {
+        Some(n) => n
+            .parse()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "bad step count"))?,
+        None => MAX_STEPS,
+    };
+
+    let mut config = MachineConfig::default();
+    let mut code = fs::read(&args[1])?;
+    if Elf::is_elf(&code) {
+        let elf = Elf::parse(&code)?;
+        code = elf.image(config.dram_base);
+        config.boot_pc = elf.entry;
+    }
+
+    let mut cpu = CpuBuilder::new(code, vec![])
+        .config(config)
+        .deterministic(DeterministicMode::default())
+        .build();
+    let mut out = BufWriter::new(File::create(&args[2])?);
+    let records = record_trace(&mut cpu, &mut out, max_steps)?;
+    println!("{} instructions traced", records);
+    Ok(())
+}
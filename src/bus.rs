@@ -1,53 +1,373 @@
+use std::{
+    fmt,
+    ops::RangeInclusive,
+    sync::{atomic::Ordering, Arc, Mutex},
+};
+
 use crate::{
-    device::{uart::Uart, virtio::virtio::VirtioBlock},
-    dram::Dram,
+    config::MachineConfig,
+    debug_module::DebugModule,
+    device::{
+        rom::Rom,
+        uart::Uart,
+        virtio::{
+            virtio::VirtioBlock, virtio_console::VirtioConsole, virtio_net::VirtioNet,
+            virtio_rng::VirtioRng,
+        },
+    },
+    dram::{AmoOp, Dram},
     exept::Exception,
     interrupt::{clint::Clint, plic::Plic},
     param::*,
 };
 
+// Counts of guest memory traffic since the cpu was built or the stats were reset.
+// Instruction fetches and page table walks count as dram reads, an amo as a read and a write.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemStats {
+    pub dram_reads: u64,
+    pub dram_writes: u64,
+    pub uart_reads: u64,
+    pub uart_writes: u64,
+    // every other device, the rom included
+    pub mmio_reads: u64,
+    pub mmio_writes: u64,
+    pub page_faults: u64,
+    pub tlb_hits: u64,
+    pub tlb_misses: u64,
+}
+
+impl fmt::Display for MemStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "dram reads   {:>12}", self.dram_reads)?;
+        writeln!(f, "dram writes  {:>12}", self.dram_writes)?;
+        writeln!(f, "uart reads   {:>12}", self.uart_reads)?;
+        writeln!(f, "uart writes  {:>12}", self.uart_writes)?;
+        writeln!(f, "mmio reads   {:>12}", self.mmio_reads)?;
+        writeln!(f, "mmio writes  {:>12}", self.mmio_writes)?;
+        writeln!(f, "page faults  {:>12}", self.page_faults)?;
+        writeln!(f, "tlb hits     {:>12}", self.tlb_hits)?;
+        write!(f, "tlb misses   {:>12}", self.tlb_misses)
+    }
+}
+
+// What a protect_region range still allows, checked on top of PMP and paging
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegionPerms {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+// Passive observer of guest loads and stores, see Bus::add_sniffer. It gets
+// the completed transaction and can't change it.
+pub trait BusSniffer: Send {
+    fn on_load(&mut self, addr: u64, size: u64, value: u64);
+    fn on_store(&mut self, addr: u64, size: u64, value: u64);
+}
+
+// (is_store, addr, size, value)
+pub type BusAccess = (bool, u64, u64, u64);
+
+// Sniffer keeping every access it sees, clones share the record so one can be
+// handed to the bus and the other kept for checking it
+#[derive(Clone, Default)]
+pub struct RecordingSniffer {
+    accesses: Arc<Mutex<Vec<BusAccess>>>,
+}
+
+impl RecordingSniffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn accesses(&self) -> Vec<BusAccess> {
+        self.accesses.lock().unwrap().clone()
+    }
+
+    pub fn clear(&self) {
+        self.accesses.lock().unwrap().clear();
+    }
+}
+
+impl BusSniffer for RecordingSniffer {
+    fn on_load(&mut self, addr: u64, size: u64, value: u64) {
+        self.accesses
+            .lock()
+            .unwrap()
+            .push((false, addr, size, value));
+    }
+
+    fn on_store(&mut self, addr: u64, size: u64, value: u64) {
+        self.accesses
+            .lock()
+            .unwrap()
+            .push((true, addr, size, value));
+    }
+}
+
 pub struct Bus {
     dram: Dram,
-    clint: Clint,
-    plic: Plic,
+    pub clint: Clint,
+    pub plic: Plic,
+    pub rom: Rom,
+    pub debug_module: DebugModule,
     pub uart: Uart,
     pub virtio_blk: VirtioBlock,
+    pub virtio_rng: VirtioRng,
+    pub virtio_console: VirtioConsole,
+    // network card with its backend, see CpuBuilder::virtio_net
+    pub virtio_net: Option<VirtioNet>,
+    // (config base, size, default base) for every region, in routing order
+    regions: [(u64, u64, u64); 9],
+    // (start, size, perms) set by protect_region, for tests
+    protected: Vec<(u64, u64, RegionPerms)>,
+    // set by add_sniffer, told about loads and stores in their range
+    sniffers: Vec<(RangeInclusive<u64>, Box<dyn BusSniffer>)>,
+    pub stats: MemStats,
 }
 
+// indexes into Bus.regions
+const CLINT: usize = 0;
+const PLIC: usize = 1;
+const VIRTIO: usize = 2;
+const VIRTIO_RNG: usize = 3;
+const VIRTIO_CONSOLE: usize = 4;
+const DRAM: usize = 5;
+const UART: usize = 6;
+const ROM: usize = 7;
+const DM: usize = 8;
+
 impl Bus {
-    pub fn new(code: Vec<u8>, disk_image: Vec<u8>) -> Bus {
+    pub fn new(config: &MachineConfig, code: Vec<u8>, disk_image: Vec<u8>) -> Bus {
+        Self::with_dram(config, Dram::new(config.dram_size, code), disk_image)
+    }
+
+    // devices are always this bus's own, dram may be shared with other harts
+    pub fn with_dram(config: &MachineConfig, mut dram: Dram, disk_image: Vec<u8>) -> Bus {
+        dram.set_memory_model(config.memory_model);
         Self {
-            dram: Dram::new(code),
-            uart: Uart::new(),
+            dram,
+            rom: Rom::new(config.dram_base),
+            debug_module: DebugModule::new(),
+            uart: Uart::new(config.uart_stdin),
             plic: Plic::new(),
-            clint: Clint::new(),
+            clint: Clint::new(config.num_harts),
             virtio_blk: VirtioBlock::new(disk_image),
+            virtio_rng: VirtioRng::new(),
+            virtio_net: None,
+            virtio_console: VirtioConsole::new(config.virtio_console_stdin),
+            regions: [
+                (config.clint_base, config.clint_size, CLINT_BASE),
+                (config.plic_base, config.plic_size, PLIC_BASE),
+                (config.virtio_base, config.virtio_size, VIRTIO_BASE),
+                (
+                    config.virtio_rng_base,
+                    config.virtio_rng_size,
+                    VIRTIO_RNG_BASE,
+                ),
+                (
+                    config.virtio_console_base,
+                    config.virtio_console_size,
+                    VIRTIO_CONSOLE_BASE,
+                ),
+                (config.dram_base, config.dram_size, DRAM_BASE),
+                (config.uart_base, config.uart_size, UART_BASE),
+                (config.rom_base, config.rom_size, ROM_BASE),
+                (config.dm_base, config.dm_size, DM_BASE),
+            ],
+            protected: Vec::new(),
+            sniffers: Vec::new(),
+            stats: MemStats::default(),
         }
     }
 
+    // a handle to this bus's memory for another hart
+    pub fn shared_dram(&self) -> Dram {
+        self.dram.shared()
+    }
+
+    // true once another hart holds a handle to this bus's memory
+    pub fn dram_is_shared(&self) -> bool {
+        self.dram.is_shared()
+    }
+
+    // one cpu step has passed
+    pub fn tick(&mut self) {
+        self.clint.tick();
+    }
+
+    // devices are written against the default memory map (param.rs),
+    // so addresses are moved back there from wherever the config put the region
+    fn route(&self, addr: u64) -> Option<(usize, u64)> {
+        self.regions
+            .iter()
+            .position(|&(base, size, _)| addr >= base && addr - base < size)
+            .map(|i| {
+                let (base, _, default_base) = self.regions[i];
+                (i, addr - base + default_base)
+            })
+    }
+
+    // count an access to the region it was routed to
+    fn count(&mut self, region: Option<usize>, write: bool) {
+        let counter = match (region, write) {
+            (None, _) => return,
+            (Some(DRAM), false) => &mut self.stats.dram_reads,
+            (Some(DRAM), true) => &mut self.stats.dram_writes,
+            (Some(UART), false) => &mut self.stats.uart_reads,
+            (Some(UART), true) => &mut self.stats.uart_writes,
+            (Some(_), false) => &mut self.stats.mmio_reads,
+            (Some(_), true) => &mut self.stats.mmio_writes,
+        };
+        *counter += 1;
+    }
+
+    // Accesses to [start, start + size) fail unless perms allow them, whoever
+    // makes them: the guest, page table walks or a device reading dram.
+    pub fn protect_region(&mut self, start: u64, size: u64, perms: RegionPerms) {
+        self.protected.push((start, size, perms));
+    }
+
+    // Every load and store that succeeds at an address in range is passed on to
+    // sniffer afterwards. Instruction fetches aren't, neither are dram atomics.
+    pub fn add_sniffer(&mut self, range: RangeInclusive<u64>, sniffer: Box<dyn BusSniffer>) {
+        self.sniffers.push((range, sniffer));
+    }
+
+    fn sniff(&mut self, store: bool, addr: u64, size: u64, value: u64) {
+        for (range, sniffer) in self.sniffers.iter_mut() {
+            if !range.contains(&addr) {
+                continue;
+            }
+            match store {
+                true => sniffer.on_store(addr, size, value),
+                false => sniffer.on_load(addr, size, value),
+            }
+        }
+    }
+
+    // false if any byte of the access is in a region that doesn't allow it
+    fn permits(&self, addr: u64, size: u64, allowed: impl Fn(RegionPerms) -> bool) -> bool {
+        let end = addr.saturating_add(size / 8);
+        self.protected.iter().all(|&(start, len, perms)| {
+            allowed(perms) || end <= start || addr >= start.saturating_add(len)
+        })
+    }
+
     pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
-        match &addr {
-            CLINT_BASE..=CLINT_END => self.clint.load(addr, size),
-            PLIC_BASE..=PLIC_END => self.plic.load(addr, size),
-            VIRTIO_BASE..=VIRTIO_END => self.virtio_blk.load(addr, size),
-            DRAM_BASE..DRAM_END => self.dram.load(addr, size),
-            UART_BASE..UART_END => self.uart.load(addr, size),
-            // static values (needed for C without paging)
-            //0x1000..0xFFFF => self.dram.load(addr + DRAM_BASE, size),
+        if !self.permits(addr, size, |p| p.read) {
+            return Err(Exception::LoadAccessFault(addr));
+        }
+        let value = self.load_routed(addr, size)?;
+        if !self.sniffers.is_empty() {
+            self.sniff(false, addr, size, value);
+        }
+        Ok(value)
+    }
+
+    // dram only, for debugging: no side effects and not counted in the stats
+    pub fn peek(&self, addr: u64, size: u64) -> Option<u64> {
+        match self.route(addr) {
+            Some((DRAM, a)) => self.dram.load(a, size).ok(),
+            _ => None,
+        }
+    }
+
+    // instruction fetch of the 16-bit parcel at addr, held to execute permission instead
+    // of read. It's read out of its aligned word, some devices only take 32-bit accesses.
+    pub fn fetch(&mut self, addr: u64) -> Result<u64, Exception> {
+        if !self.permits(addr, 16, |p| p.execute) {
+            return Err(Exception::InstructionAccessFault(addr));
+        }
+        let word = self.load_routed(addr & !0b11, 32)?;
+        Ok((word >> ((addr & 0b10) * 8)) & 0xffff)
+    }
+
+    fn load_routed(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        let route = self.route(addr);
+        self.count(route.map(|(region, _)| region), false);
+        match route {
+            Some((CLINT, a)) => self.clint.load(a, size),
+            Some((PLIC, a)) => self.plic.load(a, size),
+            Some((VIRTIO, a)) => self.virtio_blk.load(a, size),
+            Some((VIRTIO_RNG, a)) => self.virtio_rng.load(a, size),
+            Some((VIRTIO_CONSOLE, a)) => self.virtio_console.load(a, size),
+            Some((DRAM, a)) => self.dram.load(a, size),
+            Some((UART, a)) => self.uart.load(a, size),
+            Some((ROM, a)) => self.rom.load(a, size),
+            Some((DM, a)) => self.debug_module.load(a, size),
             _ => Err(Exception::LoadAccessFault(addr)),
         }
     }
 
     pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
-        match &addr {
-            CLINT_BASE..=CLINT_END => self.clint.store(addr, size, value),
-            PLIC_BASE..=PLIC_END => self.plic.store(addr, size, value),
-            VIRTIO_BASE..=VIRTIO_END => self.virtio_blk.store(addr, size, value),
-            DRAM_BASE..DRAM_END => self.dram.store(addr, size, value),
-            UART_BASE..UART_END => self.uart.store(addr, size, value),
-            // static values (needed for C without paging)
-            //0x1000..0xFFFF => self.dram.store(addr + DRAM_BASE, size, value),
+        if !self.permits(addr, size, |p| p.write) {
+            return Err(Exception::StoreAMOAccessFault(addr));
+        }
+        let route = self.route(addr);
+        self.count(route.map(|(region, _)| region), true);
+        let result = match route {
+            Some((CLINT, a)) => self.clint.store(a, size, value),
+            Some((PLIC, a)) => self.plic.store(a, size, value),
+            Some((VIRTIO, a)) => self.virtio_blk.store(a, size, value),
+            Some((VIRTIO_RNG, a)) => self.virtio_rng.store(a, size, value),
+            Some((VIRTIO_CONSOLE, a)) => self.virtio_console.store(a, size, value),
+            Some((DRAM, a)) => self.dram.store(a, size, value),
+            Some((UART, a)) => self.uart.store(a, size, value),
+            Some((ROM, a)) => self.rom.store(a, size, value),
+            Some((DM, a)) => self.debug_module.store(a, size, value),
             _ => Err(Exception::StoreAMOAccessFault(addr)),
+        };
+        if result.is_ok() && !self.sniffers.is_empty() {
+            self.sniff(true, addr, size, value);
+        }
+        result
+    }
+
+    // atomic on dram, device registers have no other users and fall back to load and store
+    pub fn amo(
+        &mut self,
+        addr: u64,
+        size: u64,
+        op: AmoOp,
+        operand: u64,
+        order: Ordering,
+    ) -> Result<u64, Exception> {
+        if !self.permits(addr, size, |p| p.read && p.write) {
+            return Err(Exception::StoreAMOAccessFault(addr));
+        }
+        if let Some((DRAM, a)) = self.route(addr) {
+            self.stats.dram_reads += 1;
+            self.stats.dram_writes += 1;
+            return self.dram.amo(a, size, op, operand, order);
+        }
+        let old = self
+            .load(addr, size)
+            .map_err(|_| Exception::StoreAMOAccessFault(addr))?;
+        self.store(addr, size, op.apply(old, operand, size))?;
+        Ok(old)
+    }
+
+    // sc on dram stores only if memory still holds what lr loaded
+    pub fn compare_exchange(
+        &mut self,
+        addr: u64,
+        size: u64,
+        expected: u64,
+        new: u64,
+        order: Ordering,
+    ) -> Result<bool, Exception> {
+        if !self.permits(addr, size, |p| p.read && p.write) {
+            return Err(Exception::StoreAMOAccessFault(addr));
+        }
+        if let Some((DRAM, a)) = self.route(addr) {
+            let stored = self.dram.compare_exchange(a, size, expected, new, order)?;
+            self.stats.dram_reads += 1;
+            self.stats.dram_writes += stored as u64;
+            return Ok(stored);
         }
+        self.store(addr, size, new)?;
+        Ok(true)
     }
 }
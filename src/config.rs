@@ -0,0 +1,232 @@
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    sync::atomic::Ordering,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::param::*;
+
+// ISA extensions the cpu will decode, the rest raise IllegalInstruction
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExtensionSet {
+    pub m: bool,
+    // the multiply half of M on its own (mul, mulh, mulhsu, mulhu, mulw), M includes it
+    pub zmmul: bool,
+    pub a: bool,
+    pub zicsr: bool,
+    pub zifencei: bool,
+    pub zba: bool,
+    // czero.eqz and czero.nez
+    pub zicond: bool,
+    // half-precision loads, stores, arithmetic and conversions, rounds to nearest even only
+    pub zfh: bool,
+    // scalar AES and SHA-2 instructions (Zkne, Zknd, Zknh)
+    pub zkn: bool,
+    // crypto bit manipulation: brev8, pack, packh and zip / unzip widened to 64 bits
+    pub zbkb: bool,
+    // wrs.nto / wrs.sto, only wait when dram is shared with another hart
+    pub zawrs: bool,
+    // 64 KiB NAPOT pages in Sv39, the N bit in a pte page faults without it
+    pub svnapot: bool,
+    // page-based memory types in pte bits 62:61, only IO changes anything (no AMOs)
+    pub svpbmt: bool,
+    // sinval.vma, sfence.w.inval and sfence.inval.ir
+    pub svinval: bool,
+    // RVV baseline with VLEN = 128: vset{i}vl{i}, unit-stride loads / stores and vadd
+    pub v: bool,
+    // vector AES and SHA-2 (Zvkned, Zvknhb), on element groups of the V registers
+    pub zvkn: bool,
+    // single-precision arithmetic on the integer registers (add, sub, mul, div, sqrt),
+    // there is no F extension, so misa.F stays clear
+    pub zfinx: bool,
+    // hypervisor CSRs for HS-mode, guests can't be entered
+    pub h: bool,
+    // compressed instructions (RV64C without c.fld/c.fsd and their sp forms) and IALIGN = 16
+    pub c: bool,
+}
+
+impl Default for ExtensionSet {
+    fn default() -> Self {
+        Self {
+            m: true,
+            zmmul: false,
+            a: true,
+            zicsr: true,
+            zifencei: true,
+            zba: true,
+            zicond: true,
+            zfh: true,
+            zkn: true,
+            zbkb: true,
+            zawrs: true,
+            svnapot: true,
+            svpbmt: true,
+            svinval: true,
+            v: true,
+            zvkn: true,
+            zfinx: false,
+            h: true,
+            c: false,
+        }
+    }
+}
+
+impl ExtensionSet {
+    // misa value: MXL = 64 and a bit per single-letter extension, S and U are always there
+    pub fn misa(&self) -> u64 {
+        let letter = |c: u8| 1u64 << (c - b'A');
+        let mut misa = (2 << 62) | letter(b'I') | letter(b'S') | letter(b'U');
+        for (enabled, c) in [
+            (self.m, b'M'),
+            (self.a, b'A'),
+            (self.c, b'C'),
+            (self.v, b'V'),
+            (self.h, b'H'),
+        ] {
+            if enabled {
+                misa |= letter(c);
+            }
+        }
+        misa
+    }
+
+    // misa of an RV32 hart, MXL = 32 in bits 31:30
+    pub fn misa_rv32(&self) -> u64 {
+        (1 << 30) | (self.misa() & ((1 << 26) - 1))
+    }
+
+    // What an RV32 hart keeps: I, M, A, Zicsr, Zifencei, Zba, Zicond, Zawrs and Svinval.
+    // The others are RV64 only here, Svnapot and Svpbmt are Sv39 pte bits.
+    pub fn rv32(self) -> Self {
+        Self {
+            zfh: false,
+            zkn: false,
+            zbkb: false,
+            svnapot: false,
+            svpbmt: false,
+            v: false,
+            zvkn: false,
+            zfinx: false,
+            h: false,
+            c: false,
+            ..self
+        }
+    }
+}
+
+// How plain loads and stores to dram are ordered between harts.
+// Fences and the aq/rl bits of atomics order accesses under every model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum MemoryOrderingModel {
+    // RISC-V weak memory ordering, plain accesses are relaxed
+    #[default]
+    Rvwmo,
+    // x86-like total store order, loads acquire and stores release
+    Tso,
+    // every access is sequentially consistent
+    Sequential,
+}
+
+impl MemoryOrderingModel {
+    pub fn load_ordering(self) -> Ordering {
+        match self {
+            Self::Rvwmo => Ordering::Relaxed,
+            Self::Tso => Ordering::Acquire,
+            Self::Sequential => Ordering::SeqCst,
+        }
+    }
+
+    pub fn store_ordering(self) -> Ordering {
+        match self {
+            Self::Rvwmo => Ordering::Relaxed,
+            Self::Tso => Ordering::Release,
+            Self::Sequential => Ordering::SeqCst,
+        }
+    }
+}
+
+// Memory map and cpu parameters, defaults are the values from param.rs.
+// Missing keys in a TOML file fall back to the defaults.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MachineConfig {
+    pub dram_base: u64,
+    pub dram_size: u64,
+    pub rom_base: u64,
+    pub rom_size: u64,
+    pub dm_base: u64,
+    pub dm_size: u64,
+    pub uart_base: u64,
+    pub uart_size: u64,
+    // host stdin is the uart input, off when stdin is used by something else
+    pub uart_stdin: bool,
+    pub clint_base: u64,
+    pub clint_size: u64,
+    pub plic_base: u64,
+    pub plic_size: u64,
+    pub virtio_base: u64,
+    pub virtio_size: u64,
+    pub virtio_rng_base: u64,
+    pub virtio_rng_size: u64,
+    pub virtio_console_base: u64,
+    pub virtio_console_size: u64,
+    // host stdin is the console input, only one of uart/console should take it
+    pub virtio_console_stdin: bool,
+    // harts with an mtimecmp in the CLINT, more harts come from Cpu::new_hart
+    pub num_harts: u64,
+    pub enabled_extensions: ExtensionSet,
+    pub memory_model: MemoryOrderingModel,
+    pub boot_pc: u64,
+}
+
+impl Default for MachineConfig {
+    fn default() -> Self {
+        Self {
+            dram_base: DRAM_BASE,
+            dram_size: DRAM_SIZE,
+            rom_base: ROM_BASE,
+            rom_size: ROM_SIZE,
+            dm_base: DM_BASE,
+            dm_size: DM_SIZE,
+            uart_base: UART_BASE,
+            uart_size: UART_SIZE,
+            uart_stdin: true,
+            clint_base: CLINT_BASE,
+            clint_size: CLINT_SIZE,
+            plic_base: PLIC_BASE,
+            plic_size: PLIC_SIZE,
+            virtio_base: VIRTIO_BASE,
+            virtio_size: VIRTIO_SIZE,
+            virtio_rng_base: VIRTIO_RNG_BASE,
+            virtio_rng_size: VIRTIO_RNG_SIZE,
+            virtio_console_base: VIRTIO_CONSOLE_BASE,
+            virtio_console_size: VIRTIO_CONSOLE_SIZE,
+            virtio_console_stdin: false,
+            num_harts: 1,
+            enabled_extensions: ExtensionSet::default(),
+            memory_model: MemoryOrderingModel::default(),
+            boot_pc: DRAM_BASE,
+        }
+    }
+}
+
+impl MachineConfig {
+    pub fn from_toml(text: &str) -> io::Result<Self> {
+        toml::from_str(text).map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).unwrap()
+    }
+
+    pub fn load(path: &str) -> io::Result<Self> {
+        Self::from_toml(&fs::read_to_string(path)?)
+    }
+
+    pub fn dram_end(&self) -> u64 {
+        self.dram_base + self.dram_size - 1
+    }
+}
@@ -0,0 +1,109 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+// longest recorded block
+const MAX_BLOCK_LEN: usize = 256;
+
+// (pc, inst) pairs of a block, in execution order
+type Block = Vec<(u64, u64)>;
+
+// Straight-line runs of fetched instructions, replayed without going
+// through translate and the bus again. Every replayed pc is checked
+// against the real one, so branches and traps just fall back to fetch.
+pub struct BasicBlockCache {
+    // entry pc -> (privilege mode, (pc, inst) pairs)
+    blocks: HashMap<u64, (u64, Arc<Block>)>,
+    // physical pages holding cached code, stores there flush everything
+    pages: HashSet<u64>,
+    // block being replayed and index of the next instruction
+    running: Option<(Arc<Block>, usize)>,
+    // block being recorded: entry pc, mode and instructions so far
+    recording: Option<(u64, u64, Block)>,
+}
+
+impl Default for BasicBlockCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BasicBlockCache {
+    pub fn new() -> Self {
+        Self {
+            blocks: HashMap::new(),
+            pages: HashSet::new(),
+            running: None,
+            recording: None,
+        }
+    }
+
+    // instruction at pc, if it is known from a cached block
+    pub fn lookup(&mut self, pc: u64, mode: u64) -> Option<u64> {
+        if let Some((block, i)) = self.running.take() {
+            let (block_pc, inst) = block[i];
+            if block_pc == pc {
+                if i + 1 < block.len() {
+                    self.running = Some((block, i + 1));
+                }
+                return Some(inst);
+            }
+        }
+
+        let block = match self.blocks.get(&pc) {
+            Some((m, block)) if *m == mode => block,
+            _ => return None,
+        };
+        let inst = block[0].1;
+        if block.len() > 1 {
+            self.running = Some((Arc::clone(block), 1));
+        }
+        self.finish_recording();
+        Some(inst)
+    }
+
+    // instruction fetched the slow way, p_pc is its physical address
+    pub fn record(&mut self, pc: u64, p_pc: u64, inst: u64, mode: u64) {
+        self.pages.insert(p_pc >> 12);
+        if let Some((_, m, insts)) = self.recording.as_mut() {
+            let (last, _) = insts[insts.len() - 1];
+            // compressed instructions are 2 bytes long
+            let next = pc == last + 4 || pc == last + 2;
+            if *m == mode && next && insts.len() < MAX_BLOCK_LEN {
+                insts.push((pc, inst));
+                return;
+            }
+        }
+        self.finish_recording();
+        self.recording = Some((pc, mode, vec![(pc, inst)]));
+    }
+
+    fn finish_recording(&mut self) {
+        if let Some((entry, mode, insts)) = self.recording.take() {
+            self.blocks.entry(entry).or_insert((mode, Arc::new(insts)));
+        }
+    }
+
+    // physical address p_addr was written
+    pub fn invalidate(&mut self, p_addr: u64) {
+        if self.pages.contains(&(p_addr >> 12)) {
+            self.flush();
+        }
+    }
+
+    pub fn flush(&mut self) {
+        self.blocks.clear();
+        self.pages.clear();
+        self.running = None;
+        self.recording = None;
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+}
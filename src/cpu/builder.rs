@@ -0,0 +1,297 @@
+use std::{
+    env,
+    io::{self, Write},
+    path::Path,
+};
+
+use crate::{
+    config::MachineConfig,
+    cpu::{
+        block_cache::BasicBlockCache,
+        coverage,
+        cpu::{Cpu, Xlen, HISTORY_SIZE, WFI_TIMEOUT},
+        timing::{SimpleTiming, TimingModel},
+        trace::ReferenceTrace,
+    },
+    csr::{MASK_SEIP, MASK_SSIP, MASK_STIP, MIDELEG, MISA},
+    device::{
+        uart::Uart,
+        virtio::{virtio::VirtioBlock, virtio_net::VirtioNet},
+    },
+    dwarf::DwarfLineTable,
+    event_log::EventLog,
+    firmware::opensbi_stub,
+    sbi::SbiHandler,
+};
+
+// Fixed inputs for reproducible runs: no host stdin, seeded rng
+// and mtime counting cpu steps.
+#[derive(Debug, Clone, Default)]
+pub struct DeterministicMode {
+    // bytes the uart receives, in order
+    pub uart_input: Vec<u8>,
+    pub rng_seed: u64,
+}
+
+pub struct CpuBuilder {
+    config: MachineConfig,
+    code: Vec<u8>,
+    disk_image: Vec<u8>,
+    // file-backed disk, replaces disk_image
+    disk_file: Option<VirtioBlock>,
+    virtio_net: Option<VirtioNet>,
+    reference_trace: Option<ReferenceTrace>,
+    history_size: usize,
+    sbi: bool,
+    opensbi: bool,
+    block_cache: bool,
+    deterministic: Option<DeterministicMode>,
+    wall_clock: bool,
+    event_log: Option<EventLog>,
+    coverage: bool,
+    wfi_timeout: u64,
+    symbols: Vec<(u64, u64, String)>,
+    source_lines: DwarfLineTable,
+    instruction_trace: Option<Box<dyn Write + Send>>,
+    single_step_debug: bool,
+    debug_output: Option<Box<dyn Write + Send>>,
+    xlen: Xlen,
+    timing_model: Box<dyn TimingModel>,
+}
+
+impl CpuBuilder {
+    pub fn new(code: Vec<u8>, disk_image: Vec<u8>) -> Self {
+        Self {
+            config: MachineConfig::default(),
+            code,
+            disk_image,
+            disk_file: None,
+            virtio_net: None,
+            reference_trace: None,
+            history_size: HISTORY_SIZE,
+            sbi: false,
+            opensbi: false,
+            block_cache: false,
+            deterministic: None,
+            wall_clock: false,
+            event_log: EventLog::from_env(),
+            coverage: false,
+            wfi_timeout: WFI_TIMEOUT,
+            symbols: Vec::new(),
+            source_lines: DwarfLineTable::new(),
+            instruction_trace: None,
+            single_step_debug: env::var("RUSTV_DEBUG").as_deref() == Ok("1"),
+            debug_output: None,
+            xlen: Xlen::Rv64,
+            timing_model: Box::new(SimpleTiming),
+        }
+    }
+
+    pub fn config(mut self, config: MachineConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    // virtio disk read from and written back to the file at path instead of memory
+    pub fn disk_image_file(mut self, path: impl AsRef<Path>) -> io::Result<Self> {
+        self.disk_file = Some(VirtioBlock::new_from_file(path.as_ref())?);
+        Ok(self)
+    }
+
+    // network card sending and receiving through net's backend
+    pub fn virtio_net(mut self, net: VirtioNet) -> Self {
+        self.virtio_net = Some(net);
+        self
+    }
+
+    // Panic as soon as a retired instruction leaves the registers different from
+    // the trace at path, see trace::record_trace for how one is made
+    pub fn reference_trace(mut self, path: impl AsRef<Path>) -> io::Result<Self> {
+        self.reference_trace = Some(ReferenceTrace::open(path.as_ref())?);
+        Ok(self)
+    }
+
+    // Zfinx single-precision instructions, off by default
+    pub fn enable_zfinx(mut self) -> Self {
+        self.config.enabled_extensions.zfinx = true;
+        self
+    }
+
+    // compressed instructions, off by default
+    pub fn enable_c(mut self) -> Self {
+        self.config.enabled_extensions.c = true;
+        self
+    }
+
+    // A 32-bit hart, RV32IMA with Sv32 paging. Extensions that only have RV64
+    // encodings here are turned off, see ExtensionSet::rv32.
+    pub fn rv32(mut self) -> Self {
+        self.xlen = Xlen::Rv32;
+        self
+    }
+
+    // cycles each instruction adds to Cpu::cycles, SimpleTiming by default
+    pub fn timing_model(mut self, model: Box<dyn TimingModel>) -> Self {
+        self.timing_model = model;
+        self
+    }
+
+    // multiplies without divides, div / rem and their w forms are illegal instructions
+    pub fn with_zmmul_only(mut self) -> Self {
+        self.config.enabled_extensions.m = false;
+        self.config.enabled_extensions.zmmul = true;
+        self
+    }
+
+    // the full M extension, on by default
+    pub fn with_m_extension(mut self) -> Self {
+        self.config.enabled_extensions.m = true;
+        self
+    }
+
+    // how many executed instructions are kept for print_history
+    pub fn history_size(mut self, n: usize) -> Self {
+        self.history_size = n;
+        self
+    }
+
+    // answer S-mode ecalls with the built-in SBI implementation
+    pub fn with_sbi(mut self) -> Self {
+        self.sbi = true;
+        self
+    }
+
+    // Boot the firmware stub at dram_base with the code loaded as the kernel at
+    // opensbi_stub::KERNEL_OFFSET. S-mode ecalls trap into the stub, not with_sbi.
+    pub fn with_opensbi(mut self) -> Self {
+        self.opensbi = true;
+        self
+    }
+
+    // replay cached basic blocks instead of fetching every instruction, off by default
+    pub fn block_cache(mut self, enabled: bool) -> Self {
+        self.block_cache = enabled;
+        self
+    }
+
+    pub fn deterministic(mut self, mode: DeterministicMode) -> Self {
+        self.deterministic = Some(mode);
+        self
+    }
+
+    // mtime follows the host clock at 10 MHz instead of counting steps,
+    // deterministic mode overrides it
+    pub fn with_wall_clock(mut self) -> Self {
+        self.wall_clock = true;
+        self
+    }
+
+    // JSON trap log, taken from RUSTV_LOG_JSON / RUSTV_LOG_FILE by default
+    pub fn event_log(mut self, log: EventLog) -> Self {
+        self.event_log = Some(log);
+        self
+    }
+
+    // record which instruction words retire, see Cpu::coverage_report
+    pub fn with_coverage(mut self) -> Self {
+        self.coverage = true;
+        self
+    }
+
+    // mtime ticks wfi waits for an interrupt before it returns as a nop
+    pub fn wfi_timeout(mut self, ticks: u64) -> Self {
+        self.wfi_timeout = ticks;
+        self
+    }
+
+    // (start, size, name) symbols of the program, for <symbol+offset> in messages
+    pub fn symbols(mut self, mut symbols: Vec<(u64, u64, String)>) -> Self {
+        symbols.sort();
+        self.symbols = symbols;
+        self
+    }
+
+    // pc -> (file, line), see dwarf::parse_line_table, for (file:line) in traces
+    pub fn source_lines(mut self, lines: DwarfLineTable) -> Self {
+        self.source_lines = lines;
+        self
+    }
+
+    // write a line for every retired instruction to out, see Cpu::trace_line
+    pub fn instruction_trace(mut self, out: Box<dyn Write + Send>) -> Self {
+        self.instruction_trace = Some(out);
+        self
+    }
+
+    // print every executed instruction with its registers, see Cpu::single_step_line,
+    // also turned on by RUSTV_DEBUG=1
+    pub fn with_single_step_debug(mut self) -> Self {
+        self.single_step_debug = true;
+        self
+    }
+
+    // where with_single_step_debug prints, stderr by default
+    pub fn debug_output(mut self, out: Box<dyn Write + Send>) -> Self {
+        self.debug_output = Some(out);
+        self
+    }
+
+    pub fn build(mut self) -> Cpu {
+        if self.deterministic.is_some() {
+            self.config.uart_stdin = false;
+            self.config.virtio_console_stdin = false;
+        }
+        if self.opensbi {
+            self.code = opensbi_stub::image(self.config.dram_base, &self.code);
+            // booting through the rom also ends up there, with the dtb in a1
+            if self.config.boot_pc != self.config.rom_base {
+                self.config.boot_pc = self.config.dram_base;
+            }
+        }
+        if self.xlen == Xlen::Rv32 {
+            self.config.enabled_extensions = self.config.enabled_extensions.rv32();
+        }
+        let mut cpu = Cpu::new(&self.config, self.code, self.disk_image);
+        if self.xlen == Xlen::Rv32 {
+            cpu.xlen = Xlen::Rv32;
+            cpu.csr
+                .store(MISA, self.config.enabled_extensions.misa_rv32());
+        }
+        if let Some(disk) = self.disk_file {
+            cpu.bus.virtio_blk = disk;
+        }
+        cpu.bus.virtio_net = self.virtio_net;
+        if self.wall_clock {
+            cpu.bus.clint.use_wall_clock();
+        }
+        if let Some(mode) = self.deterministic {
+            cpu.bus.uart = Uart::with_script(mode.uart_input);
+            cpu.bus.virtio_rng.seed(mode.rng_seed);
+            cpu.bus.clint.use_counter();
+        }
+        cpu.history_size = self.history_size;
+        cpu.wfi_timeout = self.wfi_timeout;
+        cpu.timing_model = self.timing_model;
+        cpu.symbols = self.symbols;
+        cpu.source_lines = self.source_lines;
+        cpu.instruction_trace = self.instruction_trace;
+        cpu.debug_single_step = self.single_step_debug;
+        cpu.debug_output = self.debug_output;
+        cpu.reference_trace = self.reference_trace;
+        cpu.event_log = self.event_log;
+        if self.sbi {
+            cpu.sbi = Some(SbiHandler::new());
+            // like M-mode firmware, hand the supervisor interrupts to S-mode
+            let mideleg = cpu.csr.load(MIDELEG);
+            cpu.csr
+                .store(MIDELEG, mideleg | MASK_SSIP | MASK_STIP | MASK_SEIP);
+        }
+        if self.block_cache {
+            cpu.block_cache = Some(BasicBlockCache::new());
+        }
+        if self.coverage {
+            cpu.coverage = Some(vec![0; coverage::bitmap_words(self.config.dram_size)]);
+        }
+        cpu
+    }
+}
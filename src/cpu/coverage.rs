@@ -0,0 +1,89 @@
+// Instruction coverage: one bit per 4-byte word of dram, set when an instruction
+// there retires. Addresses outside dram wrap around into the bitmap.
+use std::io::{self, Write};
+
+#[derive(Debug, Clone, Copy)]
+pub struct CoverageReport {
+    pub covered_pcs: u64,
+    pub total_possible: u64,
+    pub coverage_percent: f64,
+}
+
+impl CoverageReport {
+    fn new(covered_pcs: u64, total_possible: u64) -> Self {
+        let coverage_percent = match total_possible {
+            0 => 0.0,
+            n => covered_pcs as f64 * 100.0 / n as f64,
+        };
+        Self {
+            covered_pcs,
+            total_possible,
+            coverage_percent,
+        }
+    }
+}
+
+pub fn bitmap_words(dram_size: u64) -> usize {
+    (dram_size / 4 / 64).max(1) as usize
+}
+
+fn bit(bitmap: &[u64], base: u64, pc: u64) -> (usize, u64) {
+    let index = (pc.wrapping_sub(base) / 4) % (bitmap.len() as u64 * 64);
+    ((index / 64) as usize, 1 << (index % 64))
+}
+
+pub fn mark(bitmap: &mut [u64], base: u64, pc: u64) {
+    let (word, mask) = bit(bitmap, base, pc);
+    bitmap[word] |= mask;
+}
+
+pub fn is_covered(bitmap: &[u64], base: u64, pc: u64) -> bool {
+    let (word, mask) = bit(bitmap, base, pc);
+    bitmap[word] & mask != 0
+}
+
+// every bit of the bitmap
+pub fn report(bitmap: &[u64]) -> CoverageReport {
+    let covered = bitmap.iter().map(|w| w.count_ones() as u64).sum();
+    CoverageReport::new(covered, bitmap.len() as u64 * 64)
+}
+
+// instruction words in [start, end)
+pub fn report_range(bitmap: &[u64], base: u64, start: u64, end: u64) -> CoverageReport {
+    let total = end.saturating_sub(start).div_ceil(4);
+    let covered = (start..end)
+        .step_by(4)
+        .filter(|&pc| is_covered(bitmap, base, pc))
+        .count() as u64;
+    CoverageReport::new(covered, total)
+}
+
+// One LCOV record per (address, size, name) symbol, with the symbol as the source
+// file and its instruction words as lines 1, 2, ...
+pub fn write_lcov(
+    out: &mut impl Write,
+    bitmap: &[u64],
+    base: u64,
+    symbols: &[(u64, u64, String)],
+) -> io::Result<()> {
+    writeln!(out, "TN:")?;
+    for (addr, size, name) in symbols {
+        let pcs: Vec<u64> = (*addr..addr + size).step_by(4).collect();
+        let hits: Vec<bool> = pcs.iter().map(|&pc| is_covered(bitmap, base, pc)).collect();
+        let hit = hits.iter().filter(|&&h| h).count();
+        let entered = hits.first().copied().unwrap_or(false) as u64;
+
+        writeln!(out, "SF:{}", name)?;
+        writeln!(out, "FN:1,{}", name)?;
+        writeln!(out, "FNDA:{},{}", entered, name)?;
+        writeln!(out, "FNF:1")?;
+        writeln!(out, "FNH:{}", entered)?;
+        for (line, h) in hits.iter().enumerate() {
+            writeln!(out, "DA:{},{}", line + 1, *h as u64)?;
+        }
+        writeln!(out, "LF:{}", hits.len())?;
+        writeln!(out, "LH:{}", hit)?;
+        writeln!(out, "end_of_record")?;
+    }
+    Ok(())
+}
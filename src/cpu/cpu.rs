@@ -1,24 +1,90 @@
 use core::panic;
-use std::cmp::{max, min};
-use std::thread::AccessError;
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::hint;
+use std::io::{self, BufWriter, Write};
+use std::mem::offset_of;
+use std::path::Path;
+use std::sync::atomic::{self, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, AccessError};
 use std::usize;
 
-use crate::bus::Bus;
-use crate::device::virtio::virtqueue::{VirtioBlkRequest, VirtqAvail, VirtqDesc, VirtqUsed};
+use crate::bus::{Bus, MemStats, RegionPerms};
+use crate::config::{ExtensionSet, MachineConfig, MemoryOrderingModel};
+use crate::cpu::block_cache::BasicBlockCache;
+use crate::cpu::coverage::{self, CoverageReport};
+use crate::cpu::crypto::*;
+use crate::cpu::disasm::{self, BranchCond, DecodedInst, LoadWidth, StoreWidth};
+use crate::cpu::float::*;
+use crate::cpu::timing::{SimpleTiming, TimingModel};
+use crate::cpu::tlb::Tlb;
+use crate::cpu::trace::{ReferenceTrace, TraceRecord};
+use crate::cpu::vector::{self, NUM_VREGS, VTYPE_VILL};
+use crate::debug_module::{
+    AccessRegister, CMDERR_EXCEPTION, CMDERR_NOT_SUPPORTED, REGNO_CSR_END, REGNO_FPR, REGNO_GPR,
+};
+use crate::device::virtio::virtio_console::{CONSOLE_RECEIVEQ, CONSOLE_TRANSMITQ};
+use crate::device::virtio::virtqueue::{
+    VirtQUsedusedElem, VirtioBlkRequest, VirtqAvail, VirtqDesc, VirtqUsed,
+};
+use crate::dram::AmoOp;
+use crate::dwarf::DwarfLineTable;
+use crate::event_log::EventLog;
 use crate::exept::Exception;
-use crate::interrupt::interrupt::Interrupt;
+use crate::interrupt::interrupt::{Interrupt, MASK_INTERRUPT_BIT};
 use crate::param::{
-    DESC_NUM, DRAM_BASE, DRAM_END, PAGE_SIZE, PLIC_SCLAIM, SECTOR_SIZE, UART_IRQ, VIRTIO_BLK_T_IN,
-    VIRTIO_BLK_T_OUT, VIRTIO_IRQ,
+    DESC_NUM, PAGE_SIZE, PLIC_MCONTEXT, PLIC_SCONTEXT, SECTOR_SIZE, UART_IRQ, VIRTIO_BLK_ID_BYTES,
+    VIRTIO_BLK_S_IOERR, VIRTIO_BLK_S_OK, VIRTIO_BLK_S_UNSUPP, VIRTIO_BLK_T_FLUSH,
+    VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT, VIRTIO_CONSOLE_IRQ, VIRTIO_IRQ,
+    VIRTIO_RNG_IRQ, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE,
 };
+use crate::pmp::{check_pmp, PMP_R, PMP_W, PMP_X};
+use crate::sbi::SbiHandler;
 use crate::{bus, csr, sign_extend};
 use crate::{csr::*, err_illegal_instruction};
 
+// yields before wrs.nto / wrs.sto return with the reservation still held
+const WRS_NTO_SPINS: u32 = 1 << 16;
+const WRS_STO_SPINS: u32 = 64;
+
+// frames Cpu::backtrace follows at most
+const MAX_BACKTRACE: usize = 64;
+
+// mtime ticks wfi waits for an interrupt before returning anyway, 0.1 s of wall clock
+pub const WFI_TIMEOUT: u64 = 1_000_000;
+
 const I_IMMEDIATE: u64 = 0xfff0_0000;
 const U_IMMEDIATE: u64 = 0xffff_f000;
 
+// Sv39 virtual page number and satp.ASID
+const MASK_VPN: u64 = (1 << 27) - 1;
+const MASK_ASID: u64 = 0xffff;
+// Svnapot: leaf is a part of a naturally aligned power-of-two range,
+// the only defined size is 64 KiB, encoded as ppn[3:0] = 0b1000
+const PTE_N: u64 = 1 << 63;
+// Svpbmt memory type, pte bits 62:61
+const PTE_PBMT: u64 = 0b11 << 61;
+pub const PBMT_PMA: u8 = 0;
+pub const PBMT_NC: u8 = 1;
+pub const PBMT_IO: u8 = 2;
+const NAPOT_64K: u64 = 0b1000;
+// Sv32 satp: MODE is bit 31, ASID bits 30:22 and the root table's PPN bits 21:0
+const SV32_SATP_MODE: u64 = 1 << 31;
+const SV32_MASK_ASID: u64 = 0x1ff;
+const SV32_MASK_PPN: u64 = (1 << 22) - 1;
+// registers, the pc and data addresses of an RV32 hart
+const MASK_XLEN32: u64 = 0xffff_ffff;
+
+// size of cache block for CBO instructions
+const CACHE_BLOCK_SIZE: u64 = 64;
+
+// default number of (pc, inst) pairs kept in history
+pub const HISTORY_SIZE: usize = 64;
+
 // fancy names for registers
-const RVABI: [&str; 32] = [
+pub const RVABI: [&str; 32] = [
     "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
     "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
     "t5", "t6",
@@ -30,58 +96,832 @@ const User: Mode = 0b00;
 const Supervisor: Mode = 0b01;
 const Machine: Mode = 0b11;
 
+pub enum StepResult {
+    // instruction retired or trap taken
+    Ok,
+    // fetched instruction is 0, nothing left to run
+    Halt,
+    // exception the emulator can't recover from, already passed to handle_exception
+    Fatal(Exception),
+    // the set_budget budget is used up, nothing was executed
+    BudgetExhausted,
+}
+
+// why a run loop stopped
+#[derive(Debug, Clone, Copy)]
+pub enum ExitReason {
+    // fetched instruction is 0
+    Clean,
+    FatalException(Exception),
+    // clock limit or with_timeout limit reached
+    Timeout,
+    // guest asked the SBI to shut down
+    Shutdown,
+    // the set_budget budget ran out, more can be added to resume
+    BudgetExhausted,
+    // run_until_pc got to its target, the instruction there hasn't run yet
+    PcReached,
+}
+
 pub enum AccessType {
     Instruction,
     Load,
     Store,
 }
 
+// register width, see CpuBuilder::rv32
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Xlen {
+    Rv32,
+    #[default]
+    Rv64,
+}
+
+// address, width and value loaded by the last lr
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reservation {
+    pub addr: u64,
+    pub size: u64,
+    pub value: u64,
+}
+
+// callbacks set with set_retire_hook and set_store_hook
+type RetireHook = Box<dyn Fn(u64, u64) + Send>;
+type StoreHook = Box<dyn Fn(u64, u64, u64) + Send>;
+// called by run loops once the with_timeout cycle limit is hit
+type TimeoutFn = Box<dyn FnOnce(&Cpu) + Send>;
+
 pub struct Cpu {
     //RISC-V has 32 registers
     pub regs: [u64; 32],
+    // floating point registers, narrower values are NaN-boxed
+    pub fregs: [u64; 32],
+    // vector registers, VLENB bytes each with element 0 in the lowest bytes
+    pub vregs: Vec<Vec<u8>>,
+    pub vl: u64,
+    pub vtype: u64,
+    // element a trapping vector load or store stopped at, resumed from there
+    pub vstart: u64,
     // pc register contains the memory address of the next instruction
     pub pc: u64,
     pub mode: Mode,
+    // Rv32 keeps the registers and the pc to 32 bits, the upper half reads as zero
+    pub xlen: Xlen,
     pub bus: bus::Bus,
     pub csr: csr::Csr,
+    pub extensions: ExtensionSet,
+    // machine the cpu was built for, used by reset
+    pub config: MachineConfig,
     pub enable_paging: bool,
     pub page_table: u64,
+    // (asid, vpn) -> ppn + pte flags
+    pub tlb: Tlb,
+    // last executed instructions, most recent first
+    pub history: VecDeque<(u64, u64)>,
+    pub history_size: usize,
+    // answers S-mode ecalls in place of M-mode firmware, if enabled
+    pub sbi: Option<SbiHandler>,
+    // decoded straight-line code, if enabled
+    pub block_cache: Option<BasicBlockCache>,
+    // set by lr, cleared by sc and by any store from this hart
+    pub reservation: Option<Reservation>,
+    // monitoring callbacks, (pc, inst) for every retired instruction
+    // and (addr, size, value) for every store
+    on_retire: Option<RetireHook>,
+    on_store: Option<StoreHook>,
+    // structured trap log, if enabled
+    pub event_log: Option<EventLog>,
+    // cycles since the cpu was built or reset, weighted by timing_model
+    pub cycles: u64,
+    // cycles each instruction takes
+    pub timing_model: Box<dyn TimingModel>,
+    // instructions retired since the cpu was built or reset
+    pub instret: u64,
+    // bit per instruction word of dram that has retired, if enabled
+    pub coverage: Option<Vec<u64>>,
+    // cycle limit for run loops and what to call once it's hit
+    timeout_cycles: Option<u64>,
+    timeout_fn: Option<TimeoutFn>,
+    // cycles left before step() stops, None runs without a budget
+    budget: Option<u64>,
+    // mtime ticks wfi waits for an interrupt
+    pub wfi_timeout: u64,
+    // (start, size, name) from the program's ELF symbol table, sorted by start
+    pub symbols: Vec<(u64, u64, String)>,
+    // every retired instruction is checked against it, if enabled
+    pub reference_trace: Option<ReferenceTrace>,
+    // pc -> (file, line) from the program's DWARF line table
+    pub source_lines: DwarfLineTable,
+    // a line per retired instruction, see trace_line, if enabled
+    pub instruction_trace: Option<Box<dyn Write + Send>>,
+    // a line per executed instruction with its registers, see single_step_line
+    pub debug_single_step: bool,
+    // where those lines go, stderr if None
+    pub debug_output: Option<Box<dyn Write + Send>>,
 }
 
 impl Cpu {
-    pub fn new(code: Vec<u8>, disk_image: Vec<u8>) -> Self {
+    pub fn new(config: &MachineConfig, code: Vec<u8>, disk_image: Vec<u8>) -> Self {
+        Self::with_bus(config, Bus::new(config, code, disk_image))
+    }
+
+    pub fn with_bus(config: &MachineConfig, bus: Bus) -> Self {
         let mut regs = [0; 32];
         //sp - stack pointer
-        regs[2] = DRAM_END;
+        regs[2] = config.dram_end();
+        let mut csr = Csr::new();
+        csr.store(MISA, config.enabled_extensions.misa());
         Self {
             regs,
-            pc: DRAM_BASE,
-            bus: Bus::new(code, disk_image),
-            csr: Csr::new(),
+            fregs: [0; 32],
+            vregs: vec![vec![0; vector::VLENB as usize]; NUM_VREGS],
+            vl: 0,
+            vtype: VTYPE_VILL,
+            vstart: 0,
+            pc: config.boot_pc,
+            xlen: Xlen::Rv64,
+            bus,
+            extensions: config.enabled_extensions,
+            config: config.clone(),
+            csr,
             mode: Machine,
             page_table: 0,
             enable_paging: false,
+            tlb: Tlb::new(),
+            history: VecDeque::with_capacity(HISTORY_SIZE),
+            history_size: HISTORY_SIZE,
+            sbi: None,
+            block_cache: None,
+            reservation: None,
+            on_retire: None,
+            on_store: None,
+            event_log: None,
+            cycles: 0,
+            timing_model: Box::new(SimpleTiming),
+            instret: 0,
+            coverage: None,
+            timeout_cycles: None,
+            timeout_fn: None,
+            budget: None,
+            wfi_timeout: WFI_TIMEOUT,
+            symbols: Vec::new(),
+            reference_trace: None,
+            source_lines: DwarfLineTable::new(),
+            instruction_trace: None,
+            debug_single_step: false,
+            debug_output: None,
+        }
+    }
+
+    // Another hart on the same memory, to be run on its own thread. It gets its own
+    // devices without host stdin or a disk, except for the CLINT mtimecmp registers,
+    // and mhartid set to hartid.
+    pub fn new_hart(&self, hartid: u64) -> Cpu {
+        let mut config = self.config.clone();
+        config.uart_stdin = false;
+        config.virtio_console_stdin = false;
+        let mut bus = Bus::with_dram(&config, self.bus.shared_dram(), vec![]);
+        bus.clint = self.bus.clint.shared();
+        let mut hart = Cpu::with_bus(&config, bus);
+        hart.csr.store(MHARTID, hartid);
+        hart
+    }
+
+    // back to the power-on state, memory and devices are left as they are
+    pub fn reset(&mut self) {
+        self.regs = [0; 32];
+        self.fregs = [0; 32];
+        self.vregs = vec![vec![0; vector::VLENB as usize]; NUM_VREGS];
+        self.vl = 0;
+        self.vtype = VTYPE_VILL;
+        self.vstart = 0;
+        self.regs[2] = self.config.dram_end();
+        self.pc = self.config.boot_pc;
+        self.mode = Machine;
+        self.csr.reset();
+        self.enable_paging = false;
+        self.page_table = 0;
+        self.tlb.clear();
+        self.reservation = None;
+        self.flush_icache();
+        self.history.clear();
+        self.cycles = 0;
+        self.instret = 0;
+        if let Some(sbi) = self.sbi.as_mut() {
+            sbi.shutdown = false;
+            sbi.timer_armed = false;
+        }
+    }
+
+    // drop cached code, called by fence.i and on address space changes
+    pub fn flush_icache(&mut self) {
+        if let Some(cache) = self.block_cache.as_mut() {
+            cache.flush();
+        }
+    }
+
+    // see Bus::protect_region, cached blocks are dropped so fetches are checked too
+    pub fn protect_region(&mut self, start: u64, size: u64, perms: RegionPerms) {
+        self.bus.protect_region(start, size, perms);
+        self.flush_icache();
+    }
+
+    // remember executed instruction for post-mortem debugging
+    pub fn push_history(&mut self, pc: u64, inst: u64) {
+        if self.history_size == 0 {
+            return;
+        }
+        self.history.push_front((pc, inst));
+        while self.history.len() > self.history_size {
+            self.history.pop_back();
         }
     }
 
     // Load value from dram
     pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
-        let p_addr = self.translate(addr, AccessType::Load)?;
-        self.bus.load(p_addr, size)
+        let (p_addr, _) = self.translate_data(addr, size, AccessType::Load)?;
+        self.load_phys(p_addr, size)
+    }
+
+    // load from a physical address, skipping the tlb and page tables
+    pub fn load_phys(&mut self, paddr: u64, size: u64) -> Result<u64, Exception> {
+        let value = self.bus.load(paddr, size)?;
+        if self.big_endian() {
+            return Ok(swap_bytes(value, size));
+        }
+        Ok(value)
+    }
+
+    // M-mode loads and stores aren't translated, unless mstatus.MPRV
+    // makes them use the privilege in MPP. The physical address is checked against PMP,
+    // returned with the page's memory type.
+    fn translate_data(
+        &mut self,
+        addr: u64,
+        size: u64,
+        access_type: AccessType,
+    ) -> Result<(u64, u8), Exception> {
+        // RV32 addresses wrap at 4 GiB
+        let addr = match self.xlen {
+            Xlen::Rv32 => addr & MASK_XLEN32,
+            Xlen::Rv64 => addr,
+        };
+        let mstatus = self.csr.load(MSTATUS);
+        let mode = match self.mode {
+            Machine if mstatus & MASK_MPRV != 0 => (mstatus & MASK_MPP) >> 11,
+            mode => mode,
+        };
+        let (perm, fault) = match access_type {
+            AccessType::Load => (PMP_R, Exception::LoadAccessFault(addr)),
+            _ => (PMP_W, Exception::StoreAMOAccessFault(addr)),
+        };
+        let (p_addr, pbmt) = match mode {
+            Machine => (addr, PBMT_PMA),
+            _ => self.translate(addr, access_type)?,
+        };
+        if !check_pmp(&self.csr, p_addr, size / 8, perm, mode == Machine) {
+            return Err(fault);
+        }
+        Ok((p_addr, pbmt))
+    }
+
+    // stop run loops once cycles reaches max_cycles, timeout_fn sees the cpu at that point
+    pub fn with_timeout(
+        mut self,
+        max_cycles: u64,
+        timeout_fn: impl FnOnce(&Cpu) + Send + 'static,
+    ) -> Self {
+        self.timeout_cycles = Some(max_cycles);
+        self.timeout_fn = Some(Box::new(timeout_fn));
+        self
+    }
+
+    // true once the with_timeout limit is hit, the callback only runs the first time
+    pub fn timeout_reached(&mut self) -> bool {
+        match self.timeout_cycles {
+            Some(max_cycles) if self.cycles >= max_cycles => {
+                if let Some(timeout_fn) = self.timeout_fn.take() {
+                    timeout_fn(self);
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Arms a budget of cycles, once it's used up step() returns BudgetExhausted
+    // without executing anything. Lets several machines share one thread in slices.
+    pub fn set_budget(&mut self, cycles: u64) {
+        self.budget = Some(cycles);
+    }
+
+    // more cycles on top of what's left, arms the budget if it isn't
+    pub fn add_budget(&mut self, cycles: u64) {
+        let left = self.budget.unwrap_or(0);
+        self.budget = Some(left.saturating_add(cycles));
+    }
+
+    // cycles the timing model charges for inst, anything it can't decode takes one
+    fn latency(&self, inst: u64) -> u64 {
+        if let Some(latency) = self.timing_model.fixed_latency() {
+            return latency;
+        }
+        match disasm::decode(inst as u32) {
+            Ok(decoded) => self.timing_model.latency(&decoded),
+            Err(_) => 1,
+        }
+    }
+
+    fn spend_cycles(&mut self, cycles: u64) {
+        self.cycles += cycles;
+        if let Some(left) = self.budget.as_mut() {
+            *left = left.saturating_sub(cycles);
+        }
+    }
+
+    pub fn remaining_budget(&self) -> Option<u64> {
+        self.budget
+    }
+
+    // called with (pc, inst) after each successfully executed instruction
+    pub fn set_retire_hook(&mut self, f: impl Fn(u64, u64) + Send + 'static) {
+        self.on_retire = Some(Box::new(f));
+    }
+
+    // called with (virtual addr, size, value) before each store reaches the bus
+    pub fn set_store_hook(&mut self, f: impl Fn(u64, u64, u64) + Send + 'static) {
+        self.on_store = Some(Box::new(f));
+    }
+
+    // mstatus.MBE / SBE / UBE for the current mode, instruction fetches are always little-endian
+    pub fn big_endian(&self) -> bool {
+        let mask = match self.mode {
+            Machine => MASK_MBE,
+            Supervisor => MASK_SBE,
+            _ => MASK_UBE,
+        };
+        self.csr.load(MSTATUS) & mask != 0
     }
 
     // Store value to dram
     pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
-        let p_addr = self.translate(addr, AccessType::Store)?;
-        self.bus.store(p_addr, size, value)
+        let (p_addr, _) = self.translate_data(addr, size, AccessType::Store)?;
+        if let Some(hook) = &self.on_store {
+            hook(addr, size, value);
+        }
+        self.store_phys(p_addr, size, value)
+    }
+
+    // store to a physical address, skipping the tlb and page tables
+    pub fn store_phys(&mut self, paddr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        // any store from this hart breaks an lr/sc sequence
+        self.reservation = None;
+        if let Some(cache) = self.block_cache.as_mut() {
+            cache.invalidate(paddr);
+        }
+        if self.big_endian() {
+            self.bus.store(paddr, size, swap_bytes(value, size))?;
+        } else {
+            self.bus.store(paddr, size, value)?;
+        }
+        if let Some(command) = self.bus.debug_module.take_command() {
+            self.abstract_command(command);
+        }
+        Ok(())
+    }
+
+    // Debug module access register command, moves a register to or from data0/data1.
+    // Failures are reported in abstractcs.cmderr, never as a trap.
+    fn abstract_command(&mut self, command: u32) {
+        let Some(access) = AccessRegister::decode(command) else {
+            self.bus.debug_module.set_cmderr(CMDERR_NOT_SUPPORTED);
+            return;
+        };
+        // running the program buffer isn't supported
+        if access.postexec {
+            self.bus.debug_module.set_cmderr(CMDERR_NOT_SUPPORTED);
+            return;
+        }
+        if access.transfer {
+            if access.aarsize != 2 && access.aarsize != 3 {
+                self.bus.debug_module.set_cmderr(CMDERR_NOT_SUPPORTED);
+                return;
+            }
+            let regno = access.regno;
+            let csr_addr = regno as usize;
+            if access.write {
+                let value = match access.aarsize {
+                    2 => self.bus.debug_module.data() as u32 as u64,
+                    _ => self.bus.debug_module.data(),
+                };
+                match regno {
+                    0..=REGNO_CSR_END => {
+                        self.csr.store(csr_addr, value);
+                        self.update_paging(csr_addr);
+                    }
+                    // x0 stays zero
+                    REGNO_GPR => (),
+                    r if r < REGNO_FPR => self.regs[(r - REGNO_GPR) as usize] = value,
+                    r if r < REGNO_FPR + 32 => self.fregs[(r - REGNO_FPR) as usize] = value,
+                    _ => {
+                        self.bus.debug_module.set_cmderr(CMDERR_EXCEPTION);
+                        return;
+                    }
+                }
+            } else {
+                let value = match regno {
+                    0..=REGNO_CSR_END => self.load_csr(csr_addr),
+                    r if r < REGNO_FPR => self.regs[(r - REGNO_GPR) as usize],
+                    r if r < REGNO_FPR + 32 => self.fregs[(r - REGNO_FPR) as usize],
+                    _ => {
+                        self.bus.debug_module.set_cmderr(CMDERR_EXCEPTION);
+                        return;
+                    }
+                };
+                self.bus.debug_module.set_data(value, access.aarsize);
+            }
+        }
+        if access.postincrement {
+            self.bus.debug_module.increment_regno();
+        }
+    }
+
+    // atomic read-modify-write for the A extension, returns the old value
+    fn amo(
+        &mut self,
+        addr: u64,
+        size: u64,
+        op: AmoOp,
+        operand: u64,
+        order: Ordering,
+    ) -> Result<u64, Exception> {
+        let (p_addr, pbmt) = self.translate_data(addr, size, AccessType::Store)?;
+        // IO pages don't support atomics
+        if pbmt == PBMT_IO {
+            return Err(Exception::StoreAMOAccessFault(addr));
+        }
+        self.reservation = None;
+        if let Some(cache) = self.block_cache.as_mut() {
+            cache.invalidate(p_addr);
+        }
+        let old = if self.shared_atomic(addr, size) {
+            self.bus.amo(p_addr, size, op, operand, order)?
+        } else {
+            let old = self.load(addr, size)?;
+            let new = op.apply(old, operand, size);
+            let new = if self.big_endian() {
+                swap_bytes(new, size)
+            } else {
+                new
+            };
+            self.bus.store(p_addr, size, new)?;
+            old
+        };
+        if let Some(hook) = &self.on_store {
+            hook(addr, size, op.apply(old, operand, size));
+        }
+        Ok(old)
+    }
+
+    // Succeeds if lr reserved addr and memory still holds the value it loaded. Like
+    // most emulators this misses another hart storing that same value in between.
+    fn store_conditional(
+        &mut self,
+        addr: u64,
+        size: u64,
+        value: u64,
+        order: Ordering,
+    ) -> Result<bool, Exception> {
+        let expected = match self.reservation.take() {
+            Some(r) if r.addr == addr => r.value,
+            _ => return Ok(false),
+        };
+        let (p_addr, _) = self.translate_data(addr, size, AccessType::Store)?;
+        if let Some(cache) = self.block_cache.as_mut() {
+            cache.invalidate(p_addr);
+        }
+        if let Some(hook) = &self.on_store {
+            hook(addr, size, value);
+        }
+        if !self.shared_atomic(addr, size) {
+            let value = if self.big_endian() {
+                swap_bytes(value, size)
+            } else {
+                value
+            };
+            self.bus.store(p_addr, size, value)?;
+            return Ok(true);
+        }
+        self.bus
+            .compare_exchange(p_addr, size, expected, value, order)
+    }
+
+    // Misaligned atomics (the default sp is odd) and big-endian ones are done as a
+    // plain load and store, they're only atomic for a hart running alone.
+    fn shared_atomic(&self, addr: u64, size: u64) -> bool {
+        addr.is_multiple_of(size / 8) && !self.big_endian()
+    }
+
+    // Zawrs: stall while the reservation set is intact. With one hart nothing else can
+    // break it, so this returns at once. With dram shared between harts the thread
+    // yields until another hart changes the reserved value, at most spins times so a
+    // hart whose peers have stopped still makes progress and takes interrupts.
+    fn wait_on_reservation(&mut self, spins: u32) -> Result<(), Exception> {
+        let Some(r) = self.reservation else {
+            return Ok(());
+        };
+        if !self.bus.dram_is_shared() {
+            return Ok(());
+        }
+        for _ in 0..spins {
+            if self.load(r.addr, r.size)? != r.value {
+                self.reservation = None;
+                break;
+            }
+            thread::yield_now();
+        }
+        Ok(())
+    }
+
+    // Waits for an interrupt enabled in mie to become pending, whatever the global
+    // enables say, or for mtime to move wfi_timeout ticks. When mtime counts steps it
+    // can't move while waiting, so wfi looks once and returns.
+    fn wfi(&mut self) {
+        let entry = self.bus.clint.mtime();
+        while !self.wfi_wakeup() && self.bus.clint.follows_wall_clock() {
+            if self.bus.clint.mtime().wrapping_sub(entry) >= self.wfi_timeout {
+                break;
+            }
+            thread::yield_now();
+        }
+    }
+
+    // an interrupt wfi should return for, devices other than the uart only
+    // interrupt right after a store, so they can't start while waiting
+    fn wfi_wakeup(&self) -> bool {
+        let clint = &self.bus.clint;
+        let mtime = clint.mtime();
+        let mut mip = self.csr.load(MIP);
+        if mtime >= clint.mtimecmp(self.csr.load(MHARTID)) {
+            mip |= MASK_MTIP;
+        }
+        if clint.software_pending(self.csr.load(MHARTID)) == Some(true) {
+            mip |= MASK_MSIP;
+        }
+        let sstc = self.csr.load(MENVCFG) & MASK_STCE != 0
+            || self.sbi.as_ref().is_some_and(|sbi| sbi.timer_armed);
+        if sstc && mtime >= self.csr.load(STIMECMP) {
+            mip |= MASK_STIP;
+        }
+        mip & self.csr.load(MIE) != 0 || self.bus.uart.interrupt_pending()
+    }
+
+    // ordering of an atomic from its aq and rl bits, the stronger models make every atomic SeqCst
+    fn amo_ordering(&self, funct7: u32) -> Ordering {
+        if self.config.memory_model != MemoryOrderingModel::Rvwmo {
+            return Ordering::SeqCst;
+        }
+        match (funct7 & 0b10 != 0, funct7 & 0b01 != 0) {
+            (false, false) => Ordering::Relaxed,
+            (true, false) => Ordering::Acquire,
+            (false, true) => Ordering::Release,
+            (true, true) => Ordering::SeqCst,
+        }
     }
 
     pub fn fetch(&mut self) -> Result<u64, Exception> {
-        let p_pc = self.translate(self.pc, AccessType::Instruction)?;
-        match self.bus.load(p_pc, 32) {
-            Ok(inst) => Ok(inst),
-            Err(_e) => Err(Exception::InstructionAccessFault(self.pc)),
+        if let Some(cache) = self.block_cache.as_mut() {
+            if let Some(inst) = cache.lookup(self.pc, self.mode) {
+                return Ok(inst);
+            }
+        }
+        let (p_pc, inst) = self.fetch_at(self.pc)?;
+        if let Some(cache) = self.block_cache.as_mut() {
+            cache.record(self.pc, p_pc, inst, self.mode);
+        }
+        Ok(inst)
+    }
+
+    // Fetches the instruction at addr, returns its physical address and the instruction.
+    // The upper half of a 32-bit instruction is translated and checked on its own, it
+    // can be on the next page, and a 16-bit one can end where memory does.
+    pub fn fetch_at(&mut self, addr: u64) -> Result<(u64, u64), Exception> {
+        let (p_addr, low) = self.fetch_parcel(addr)?;
+        if low & 0b11 != 0b11 {
+            return Ok((p_addr, low));
+        }
+        let (_, high) = self.fetch_parcel(addr.wrapping_add(2))?;
+        Ok((p_addr, high << 16 | low))
+    }
+
+    // 16 bits of an instruction, faults report the address of the half that failed
+    fn fetch_parcel(&mut self, addr: u64) -> Result<(u64, u64), Exception> {
+        let (p_addr, _) = self.translate(addr, AccessType::Instruction)?;
+        if !check_pmp(&self.csr, p_addr, 2, PMP_X, self.mode == Machine) {
+            return Err(Exception::InstructionAccessFault(addr));
+        }
+        match self.bus.fetch(p_addr) {
+            Ok(parcel) => Ok((p_addr, parcel)),
+            Err(_e) => Err(Exception::InstructionAccessFault(addr)),
+        }
+    }
+
+    // the instruction at pc, fetched the way step fetches it
+    pub fn current_instruction(&mut self) -> Result<DecodedInst, Exception> {
+        disasm::decode(self.fetch()? as u32)
+    }
+
+    // fetch, execute and take pending interrupt
+    pub fn step(&mut self) -> StepResult {
+        if self.budget == Some(0) {
+            return StepResult::BudgetExhausted;
+        }
+        self.bus.tick();
+        // a fetch that runs nothing takes a cycle
+        let inst = match self.fetch() {
+            Ok(0) => {
+                self.spend_cycles(1);
+                return StepResult::Halt;
+            }
+            Ok(inst) => inst,
+            Err(e) => {
+                self.spend_cycles(1);
+                return self.trap(e);
+            }
+        };
+        self.spend_cycles(self.latency(inst));
+
+        // the line is started before execute, while the sources hold their inputs
+        let debug_line = self.debug_single_step.then(|| self.single_step_line(inst));
+        let result = match self.xlen {
+            Xlen::Rv32 => self.execute_rv32(inst),
+            Xlen::Rv64 => self.execute(inst),
+        };
+        if let Some((line, rd)) = debug_line {
+            self.print_single_step(line, rd, &result);
+        }
+        match result {
+            // a jump or taken branch off the instruction alignment faults on the jump
+            Ok(pc) if pc & !pc_align_mask(self.extensions.c) != 0 => {
+                if let StepResult::Fatal(e) = self.trap(Exception::InstructionAddrMisaligned(pc)) {
+                    return StepResult::Fatal(e);
+                }
+            }
+            Ok(pc) => {
+                self.push_history(self.pc, inst);
+                self.instret += 1;
+                if let Some(bitmap) = self.coverage.as_mut() {
+                    coverage::mark(bitmap, self.config.dram_base, self.pc);
+                }
+                if let Some(hook) = &self.on_retire {
+                    hook(self.pc, inst);
+                }
+                if let Some(trace) = self.reference_trace.as_mut() {
+                    trace.check(&TraceRecord::new(self.pc, inst, &self.regs));
+                }
+                if self.instruction_trace.is_some() {
+                    let line = self.trace_line(self.pc, inst);
+                    if let Some(out) = self.instruction_trace.as_mut() {
+                        // a broken trace must not stop the emulator
+                        let _ = writeln!(out, "{}", line);
+                    }
+                }
+                self.pc = pc;
+            }
+            Err(e) => {
+                if let StepResult::Fatal(e) = self.trap(e) {
+                    return StepResult::Fatal(e);
+                }
+            }
+        }
+
+        if let Some(interrupt) = self.check_pending_interrupt() {
+            self.log_event("interrupt", &format!("{:?}", interrupt), interrupt.code());
+            self.handle_interrupt(interrupt)
+        }
+        StepResult::Ok
+    }
+
+    // Steps up to max_cycles instructions, or until the program stops. The cpu
+    // stays usable, another call carries on where this one returned.
+    pub fn run_for(&mut self, max_cycles: u64) -> ExitReason {
+        self.run_while(max_cycles, |_| true)
+    }
+
+    // run_for that also stops once the pc is target_pc, before running anything there
+    pub fn run_until_pc(&mut self, target_pc: u64, max_cycles: u64) -> ExitReason {
+        self.run_while(max_cycles, |cpu| cpu.pc != target_pc)
+    }
+
+    // run_until_pc to the start of symbol in the program's symbol table
+    pub fn run_until_symbol(&mut self, symbol: &str, max_cycles: u64) -> ExitReason {
+        match self.symbol_address(symbol) {
+            Some(addr) => self.run_until_pc(addr, max_cycles),
+            None => panic!("Unknown symbol {}", symbol),
+        }
+    }
+
+    // the run_for loop, PcReached once keep_going says no before a step
+    fn run_while(&mut self, max_cycles: u64, keep_going: impl Fn(&Cpu) -> bool) -> ExitReason {
+        for _ in 0..max_cycles {
+            if !keep_going(self) {
+                return ExitReason::PcReached;
+            }
+            if self.is_shutdown() {
+                return ExitReason::Shutdown;
+            }
+            if self.timeout_reached() {
+                return ExitReason::Timeout;
+            }
+            match self.step() {
+                StepResult::Ok => (),
+                StepResult::Halt => return ExitReason::Clean,
+                StepResult::BudgetExhausted => return ExitReason::BudgetExhausted,
+                StepResult::Fatal(e) => {
+                    // already in the event log if there is one
+                    if self.event_log.is_none() {
+                        println!("{}", e.display_with_symbols(self));
+                    }
+                    self.dump_registers();
+                    self.dump_csrs();
+                    self.print_history();
+                    self.print_backtrace();
+                    return ExitReason::FatalException(e);
+                }
+            }
+        }
+        ExitReason::Timeout
+    }
+
+    // run until the program halts, faults, shuts down or with_timeout expires
+    pub fn run_to_halt(&mut self) -> ExitReason {
+        self.run_for(u64::MAX)
+    }
+
+    fn trap(&mut self, e: Exception) -> StepResult {
+        let event = if e.is_fatal() {
+            "fatal_exception"
+        } else {
+            "exception"
+        };
+        self.log_event(event, e.name(), e.value());
+        self.handle_exception(e);
+        if e.is_fatal() {
+            return StepResult::Fatal(e);
+        }
+        StepResult::Ok
+    }
+
+    // record a trap about to be taken at the current pc and mode
+    fn log_event(&mut self, event: &str, kind: &str, value: u64) {
+        if let Some(log) = self.event_log.as_mut() {
+            log.record(
+                event,
+                kind,
+                self.pc,
+                value,
+                self.cycles,
+                mode_name(self.mode),
+            );
+        }
+    }
+
+    // RV32 on the RV64 datapath. Registers hold their 32-bit values zero-extended,
+    // the instruction sees them sign-extended, the way RV64 keeps 32-bit values, and
+    // whatever it writes is cut back to 32 bits. Csr instructions see them
+    // zero-extended, so satp and the trap vectors get plain 32-bit values.
+    fn execute_rv32(&mut self, inst: u64) -> Result<u64, Exception> {
+        let (funct7, rs2, rs1, funct3, rd, opcode) = decode_r(inst as u32);
+        let Some(inst) = rv32_inst(inst) else {
+            err_illegal_instruction!(inst);
+        };
+        let result = match (opcode, funct3, funct7) {
+            (0x73, _, _) => self.execute(inst),
+            // mulh, mulhsu and mulhu, the upper half is bits 63:32 of the product
+            (0x33, 1..=3, 1) if self.extensions.m || self.extensions.zmmul => {
+                let (a, b) = (self.regs[rs1], self.regs[rs2]);
+                let signed = |v: u64| v as u32 as i32 as i64;
+                let product = match funct3 {
+                    1 => signed(a) * signed(b),
+                    2 => signed(a) * b as i64,
+                    _ => (a * b) as i64,
+                };
+                if rd != 0 {
+                    self.regs[rd] = (product >> 32) as u64;
+                }
+                Ok(self.pc + 4)
+            }
+            _ => {
+                for reg in self.regs.iter_mut() {
+                    *reg = *reg as u32 as i32 as u64;
+                }
+                self.execute(inst)
+            }
+        };
+        for reg in self.regs.iter_mut() {
+            *reg &= MASK_XLEN32;
         }
+        result.map(|pc| pc & MASK_XLEN32)
     }
 
     pub fn execute(&mut self, inst: u64) -> Result<u64, Exception> {
@@ -89,6 +929,23 @@ impl Cpu {
         // by spec x0 is ALWAYS zero
         self.regs[0] = 0;
 
+        // the low two bits of a 32-bit instruction are 0b11, anything else is 16 bits wide
+        if inst & 0b11 != 0b11 {
+            let inst = inst & 0xffff;
+            if !self.extensions.c {
+                err_illegal_instruction!(inst);
+            }
+            return match inst & 0b11 {
+                0b00 => self.execute_c_q0(inst as u16),
+                0b01 => self.execute_c_q1(inst as u16),
+                _ => self.execute_c_q2(inst as u16),
+            };
+        }
+
+        if !self.extension_enabled(opcode, funct3, funct7) {
+            err_illegal_instruction!(inst);
+        }
+
         // for debug
         //println!("{:x}: {:x} {:x} -> {:x}", opcode, funct3, funct7, inst);
 
@@ -110,8 +967,53 @@ impl Cpu {
                     _ => err_illegal_instruction!(inst),
                 }
             }
+            0x07 => {
+                // I flh - load half into f register, NaN-boxed
+                let addr = self.regs[rs1].wrapping_add(get_i_imm(inst));
+                match funct3 {
+                    0x1 => self.fregs[rd] = box_h(self.load(addr, 16)? as u16),
+                    // vle8 / vle16 / vle32 / vle64
+                    0x0 | 0x5..=0x7 => self.vector_unit_stride(inst, false)?,
+                    _ => err_illegal_instruction!(inst),
+                }
+            }
             0x0f => {
-                // A fence instruction does nothing because this emulator executes an instruction sequentially on a single thread.
+                match funct3 {
+                    0x0 => {
+                        let pred = (inst >> 24) & 0xf;
+                        let succ = (inst >> 20) & 0xf;
+                        if (inst >> 20) & 0xfff == 0x010 && rd == 0 && rs1 == 0 {
+                            // pause (Zihintpause) is fence w, 0, a hint inside spin-wait loops
+                            self.log_event("hint", "pause", 0);
+                            hint::spin_loop();
+                        } else if pred != 0 && succ != 0 {
+                            // fence, orders this hart's accesses for harts on other threads.
+                            // An empty predecessor or successor set orders nothing.
+                            atomic::fence(Ordering::SeqCst);
+                        }
+                    }
+                    0x1 => {
+                        // fence.i
+                        // instruction fetches after fence.i must observe stores made before it
+                        self.flush_icache();
+                    }
+                    0x2 => {
+                        // CBO (cache-block operations), there are no caches to manage,
+                        // only cbo.zero has a visible effect
+                        match get_i_imm(inst) {
+                            0x0..=0x2 => {} // cbo.inval, cbo.clean, cbo.flush
+                            0x4 => {
+                                // cbo.zero
+                                let base = self.regs[rs1] & !(CACHE_BLOCK_SIZE - 1);
+                                for i in (0..CACHE_BLOCK_SIZE).step_by(8) {
+                                    self.store(base + i, 64, 0)?;
+                                }
+                            }
+                            _ => err_illegal_instruction!(inst),
+                        }
+                    }
+                    _ => err_illegal_instruction!(inst),
+                }
             }
             0x13 => {
                 // I
@@ -124,8 +1026,28 @@ impl Cpu {
                         self.regs[rd] = self.regs[rs1].wrapping_add(imm);
                     }
                     0x1 => {
-                        //S (without rs2) slli - rd = rs1 << rs2
-                        self.regs[rd] = self.regs[rs1] << shamt;
+                        // rs2 field selects the Zkn / Zbkb operation
+                        let x = self.regs[rs1];
+                        self.regs[rd] = match (funct7, rs2) {
+                            //S (without rs2) slli - rd = rs1 << rs2
+                            (0x0 | 0x1, _) => x << shamt,
+                            (0x08, 0x0) => sha256sum0(x),
+                            (0x08, 0x1) => sha256sum1(x),
+                            (0x08, 0x2) => sha256sig0(x),
+                            (0x08, 0x3) => sha256sig1(x),
+                            (0x08, 0x4) => sha512sum0(x),
+                            (0x08, 0x5) => sha512sum1(x),
+                            (0x08, 0x6) => sha512sig0(x),
+                            (0x08, 0x7) => sha512sig1(x),
+                            (0x18, 0x0) => aes64im(x),
+                            (0x04, 0x0f) => zip(x),
+                            // aes64ks1i, rnum in the low 4 bits
+                            (0x18, 0x10..=0x1f) => match aes64ks1i(x, rs2 as u64 & 0xf) {
+                                Some(value) => value,
+                                None => err_illegal_instruction!(inst),
+                            },
+                            _ => err_illegal_instruction!(inst),
+                        };
                     }
                     0x2 => {
                         //I slti - 1 to rd if signed rs1 < signed imm, else 0
@@ -149,6 +1071,13 @@ impl Cpu {
                     }
                     0x5 => {
                         match funct7 >> 1 {
+                            // brev8 and unzip (Zbkb) use the full funct7 and rs2 as funct12
+                            _ if (funct7, rs2) == (0x34, 0x07) => {
+                                self.regs[rd] = brev8(self.regs[rs1]);
+                            }
+                            _ if (funct7, rs2) == (0x04, 0x0f) => {
+                                self.regs[rd] = unzip(self.regs[rs1]);
+                            }
                             0x0 => {
                                 //S (without rs2) srli - rd = rs1 >> rs2
                                 self.regs[rd] = self.regs[rs1].wrapping_shr(shamt);
@@ -221,155 +1150,67 @@ impl Cpu {
                     _ => err_illegal_instruction!(inst),
                 }
             }
-            0x2f => {
-                let funct5 = funct7 >> 2;
-                match (funct3, funct5) {
-                    (0x2, 0x0) => {
-                        // amoadd.w
-                        let t = self.load(self.regs[rs1], 32)?;
-                        self.store(self.regs[rs1], 32, t.wrapping_add(self.regs[rs2]))?;
-                        self.regs[rd] = t;
-                    }
-                    (0x2, 0x1) => {
-                        // amoswap.w
-                        let t = self.load(self.regs[rs1], 32)?;
-                        self.store(self.regs[rs1], 32, self.regs[rs2])?;
-                        self.regs[rd] = t;
-                    }
-                    (0x2, 0x2) => {
-                        // lr.w
-                        self.regs[rd] = self.load(self.regs[rs1], 32)?;
-                    }
-                    (0x2, 0x3) => {
-                        // sc.w, no condition
-                        self.store(self.regs[rs1], 32, self.regs[rs2])?;
-                    }
-                    (0x2, 0x4) => {
-                        // amoxor.w
-                        let t = self.load(self.regs[rs1], 32)?;
-                        self.store(self.regs[rs1], 32, self.regs[rs2] ^ t)?;
-                        self.regs[rd] = t;
-                    }
-                    (0x2, 0x8) => {
-                        // amoor.w
-                        let t = self.load(self.regs[rs1], 32)?;
-                        self.store(self.regs[rs1], 32, self.regs[rs2] | t)?;
-                        self.regs[rd] = t;
-                    }
-                    (0x2, 0xc) => {
-                        // amoand.w
-                        let t = self.load(self.regs[rs1], 32)?;
-                        self.store(self.regs[rs1], 32, self.regs[rs2] & t)?;
-                        self.regs[rd] = t;
-                    }
-                    (0x2, 0x10) => {
-                        // amomin.w
-                        let t = self.load(self.regs[rs1], 32)?;
-                        self.store(
-                            self.regs[rs1],
-                            32,
-                            min(self.regs[rs2] as i32, t as i32) as u64,
-                        )?;
-                        self.regs[rd] = t;
-                    }
-                    (0x2, 0x14) => {
-                        // amomax.w
-                        let t = self.load(self.regs[rs1], 32)?;
-                        self.store(
-                            self.regs[rs1],
-                            32,
-                            max(self.regs[rs2] as i32, t as i32) as u64,
-                        )?;
-                        self.regs[rd] = t;
-                    }
-                    (0x2, 0x18) => {
-                        // amomax.w
-                        let t = self.load(self.regs[rs1], 32)?;
-                        self.store(self.regs[rs1], 32, min(self.regs[rs2], t))?;
-                        self.regs[rd] = t;
-                    }
-                    (0x2, 0x1c) => {
-                        // amomaxu.w
-                        let t = self.load(self.regs[rs1], 32)?;
-                        self.store(self.regs[rs1], 32, max(self.regs[rs2], t))?;
-                        self.regs[rd] = t;
-                    }
-                    (0x3, 0x0) => {
-                        // amoadd.d
-                        let t = self.load(self.regs[rs1], 64)?;
-                        self.store(self.regs[rs1], 64, t.wrapping_add(self.regs[rs2]))?;
-                        self.regs[rd] = t;
-                    }
-                    (0x3, 0x1) => {
-                        // amoswap.d
-                        let t = self.load(self.regs[rs1], 64)?;
-                        self.store(self.regs[rs1], 64, self.regs[rs2])?;
-                        self.regs[rd] = t;
-                    }
-                    (0x3, 0x2) => {
-                        // lr.d
-                        self.regs[rd] = self.load(self.regs[rs1], 64)?;
-                    }
-                    (0x3, 0x3) => {
-                        // sc.d, no condition
-                        self.store(self.regs[rs1], 64, self.regs[rs2])?;
-                    }
-                    (0x3, 0x4) => {
-                        // amoxor.w
-                        let t = self.load(self.regs[rs1], 64)?;
-                        self.store(self.regs[rs1], 64, self.regs[rs2] ^ t)?;
-                        self.regs[rd] = t;
-                    }
-                    (0x3, 0x8) => {
-                        // amoor.w
-                        let t = self.load(self.regs[rs1], 64)?;
-                        self.store(self.regs[rs1], 64, self.regs[rs2] | t)?;
-                        self.regs[rd] = t;
-                    }
-                    (0x3, 0xc) => {
-                        // amoand.w
-                        let t = self.load(self.regs[rs1], 64)?;
-                        self.store(self.regs[rs1], 64, self.regs[rs2] & t)?;
-                        self.regs[rd] = t;
-                    }
-                    (0x3, 0x10) => {
-                        // amomin.w
-                        let t = self.load(self.regs[rs1], 64)?;
-                        self.store(
-                            self.regs[rs1],
-                            64,
-                            min(self.regs[rs2] as i64, t as i64) as u64,
-                        )?;
-                        self.regs[rd] = t;
-                    }
-                    (0x3, 0x14) => {
-                        // amomax.w
-                        let t = self.load(self.regs[rs1], 64)?;
-                        self.store(
-                            self.regs[rs1],
-                            64,
-                            max(self.regs[rs2] as i64, t as i64) as u64,
-                        )?;
-                        self.regs[rd] = t;
-                    }
-                    (0x3, 0x18) => {
-                        // amomax.w
-                        let t = self.load(self.regs[rs1], 64)?;
-                        self.store(self.regs[rs1], 64, min(self.regs[rs2], t))?;
-                        self.regs[rd] = t;
-                    }
-                    (0x3, 0x1c) => {
-                        // amomaxu.w
-                        let t = self.load(self.regs[rs1], 64)?;
-                        self.store(self.regs[rs1], 64, max(self.regs[rs2], t))?;
-                        self.regs[rd] = t;
-                    }
+            0x27 => {
+                // S fsh - store low 16 bits of f register
+                let addr = self.regs[rs1].wrapping_add(get_s_imm(inst));
+                match funct3 {
+                    0x1 => self.store(addr, 16, self.fregs[rs2] & 0xffff)?,
+                    // vse8 / vse16 / vse32 / vse64
+                    0x0 | 0x5..=0x7 => self.vector_unit_stride(inst, true)?,
                     _ => err_illegal_instruction!(inst),
                 }
             }
+            0x2f => {
+                let size = match funct3 {
+                    0x2 => 32,
+                    0x3 => 64,
+                    _ => err_illegal_instruction!(inst),
+                };
+                let order = self.amo_ordering(funct7);
+                let addr = self.regs[rs1];
+                let value = match funct7 >> 2 {
+                    0x2 => {
+                        // lr
+                        let value = self.load(addr, size)?;
+                        if matches!(order, Ordering::Acquire | Ordering::SeqCst) {
+                            atomic::fence(Ordering::Acquire);
+                        }
+                        self.reservation = Some(Reservation { addr, size, value });
+                        value
+                    }
+                    // sc, rd is 0 on success
+                    0x3 => !self.store_conditional(addr, size, self.regs[rs2], order)? as u64,
+                    funct5 => {
+                        let op = match funct5 {
+                            0x0 => AmoOp::Add,
+                            0x1 => AmoOp::Swap,
+                            0x4 => AmoOp::Xor,
+                            0x8 => AmoOp::Or,
+                            0xc => AmoOp::And,
+                            0x10 => AmoOp::Min,
+                            0x14 => AmoOp::Max,
+                            0x18 => AmoOp::MinU,
+                            0x1c => AmoOp::MaxU,
+                            _ => err_illegal_instruction!(inst),
+                        };
+                        self.amo(addr, size, op, self.regs[rs2], order)?
+                    }
+                };
+                // .w results are sign-extended
+                self.regs[rd] = match size {
+                    32 => value as i32 as i64 as u64,
+                    _ => value,
+                };
+            }
             0x33 => {
                 let shamt = get_shamt_6(self.regs[rs2]);
                 match (funct3, funct7) {
+                    (0x0, 0x0) if rd == 0 && rs1 == 0 && (2..=5).contains(&rs2) => {
+                        // Zihintntl, add x0, x0, x2..x5: locality of the next access,
+                        // there is no cache for it to steer
+                        let hint = ["ntl.p1", "ntl.pall", "ntl.s1", "ntl.all"][rs2 - 2];
+                        self.log_event("hint", hint, 0);
+                    }
                     (0x0, 0x0) => {
                         //R add - add rs1 with rs2, store to rd
                         self.regs[rd] = self.regs[rs1].wrapping_add(self.regs[rs2]);
@@ -400,6 +1241,10 @@ impl Cpu {
                             self.regs[rd] = 0;
                         }
                     }
+                    (0x2, 0x10) => {
+                        // R sh1add (Zba) - rd = rs2 + (rs1 << 1)
+                        self.regs[rd] = self.regs[rs2].wrapping_add(self.regs[rs1] << 1);
+                    }
                     (0x2, 0x1) => {
                         // R mulhsu - multiply rs1(s) by rs2(u) as 128, store to rd upper 64
                         let val: i128 =
@@ -424,10 +1269,21 @@ impl Cpu {
                         //R xor - rd = rs1 ^ rs2
                         self.regs[rd] = self.regs[rs1] ^ self.regs[rs2];
                     }
+                    (0x4, 0x4) => {
+                        // R pack (Zbkb) - low words of rs1 and rs2, rs1 in the low half
+                        self.regs[rd] = pack(self.regs[rs1], self.regs[rs2]);
+                    }
+                    (0x4, 0x10) => {
+                        // R sh2add (Zba) - rd = rs2 + (rs1 << 2)
+                        self.regs[rd] = self.regs[rs2].wrapping_add(self.regs[rs1] << 2);
+                    }
                     (0x4, 0x1) => {
                         //R div - divide rs1 by rs2 (both signed), store to rd
                         if self.regs[rs2] == 0 {
                             self.regs[rd] = -1 as i64 as u64;
+                        } else if self.regs[rs1] as i64 == i64::MIN && self.regs[rs2] as i64 == -1 {
+                            // overflow, the dividend is returned unchanged
+                            self.regs[rd] = self.regs[rs1];
                         } else {
                             self.regs[rd] =
                                 (self.regs[rs1] as i64).wrapping_div(self.regs[rs2] as i64) as u64;
@@ -453,6 +1309,10 @@ impl Cpu {
                         //R or - rd = rs1 | rs2
                         self.regs[rd] = self.regs[rs1] | self.regs[rs2];
                     }
+                    (0x6, 0x10) => {
+                        // R sh3add (Zba) - rd = rs2 + (rs1 << 3)
+                        self.regs[rd] = self.regs[rs2].wrapping_add(self.regs[rs1] << 3);
+                    }
                     (0x6, 0x1) => {
                         //R rem - signed remainder of div: rs1 by rs2 (both signed), store to rd
                         if self.regs[rs2] == 0 {
@@ -462,10 +1322,34 @@ impl Cpu {
                                 (self.regs[rs1] as i64).wrapping_rem(self.regs[rs2] as i64) as u64;
                         }
                     }
+                    (0x0, 0x19) => {
+                        // R aes64es (Zkne) - final encryption round
+                        self.regs[rd] = aes64es(self.regs[rs1], self.regs[rs2]);
+                    }
+                    (0x0, 0x1b) => {
+                        // R aes64esm (Zkne) - middle encryption round
+                        self.regs[rd] = aes64esm(self.regs[rs1], self.regs[rs2]);
+                    }
+                    (0x0, 0x1d) => {
+                        // R aes64ds (Zknd) - final decryption round
+                        self.regs[rd] = aes64ds(self.regs[rs1], self.regs[rs2]);
+                    }
+                    (0x0, 0x1f) => {
+                        // R aes64dsm (Zknd) - middle decryption round
+                        self.regs[rd] = aes64dsm(self.regs[rs1], self.regs[rs2]);
+                    }
+                    (0x0, 0x3f) => {
+                        // R aes64ks2 - second half of the key schedule step
+                        self.regs[rd] = aes64ks2(self.regs[rs1], self.regs[rs2]);
+                    }
                     (0x7, 0x0) => {
                         //R and - rd = rs1 & rs2
                         self.regs[rd] = self.regs[rs1] & self.regs[rs2];
                     }
+                    (0x7, 0x4) => {
+                        // R packh (Zbkb) - low bytes of rs1 and rs2, zero-extended
+                        self.regs[rd] = packh(self.regs[rs1], self.regs[rs2]);
+                    }
                     (0x7, 0x1) => {
                         //R remu - unsigned remainder of divu: rs1 by rs2 (both unsigned), store to rd
                         if self.regs[rs2] == 0 {
@@ -474,6 +1358,20 @@ impl Cpu {
                             self.regs[rd] = self.regs[rs1].wrapping_rem(self.regs[rs2]);
                         }
                     }
+                    (0x5, 0x7) => {
+                        // R czero.eqz (Zicond) - rd = rs2 == 0 ? 0 : rs1
+                        self.regs[rd] = match self.regs[rs2] {
+                            0 => 0,
+                            _ => self.regs[rs1],
+                        };
+                    }
+                    (0x7, 0x7) => {
+                        // R czero.nez (Zicond) - rd = rs2 != 0 ? 0 : rs1
+                        self.regs[rd] = match self.regs[rs2] {
+                            0 => self.regs[rs1],
+                            _ => 0,
+                        };
+                    }
                     _ => err_illegal_instruction!(inst),
                 }
             }
@@ -505,10 +1403,28 @@ impl Cpu {
                         //R sllw - rd = rs1 << rs2
                         self.regs[rd] = (self.regs[rs1] as u32).wrapping_shl(shamt) as i32 as u64;
                     }
+                    (0x2, 0x10) => {
+                        // R sh1add.uw (Zba) - rd = rs2 + (zext(rs1[31:0]) << 1)
+                        self.regs[rd] =
+                            self.regs[rs2].wrapping_add((self.regs[rs1] as u32 as u64) << 1);
+                    }
+                    (0x4, 0x10) => {
+                        // R sh2add.uw (Zba) - rd = rs2 + (zext(rs1[31:0]) << 2)
+                        self.regs[rd] =
+                            self.regs[rs2].wrapping_add((self.regs[rs1] as u32 as u64) << 2);
+                    }
+                    (0x6, 0x10) => {
+                        // R sh3add.uw (Zba) - rd = rs2 + (zext(rs1[31:0]) << 3)
+                        self.regs[rd] =
+                            self.regs[rs2].wrapping_add((self.regs[rs1] as u32 as u64) << 3);
+                    }
                     (0x4, 0x01) => {
                         //R divw - divide rs1 with rs2, store to rd
                         if self.regs[rs2] as i32 == 0 {
                             self.regs[rd] = -1 as i64 as u64;
+                        } else if self.regs[rs1] as i32 == i32::MIN && self.regs[rs2] as i32 == -1 {
+                            // overflow, the dividend is returned unchanged
+                            self.regs[rd] = sign_extend!(i32, self.regs[rs1]);
                         } else {
                             self.regs[rd] = sign_extend!(
                                 i32,
@@ -522,12 +1438,11 @@ impl Cpu {
                     }
                     (0x5, 0x01) => {
                         //R divuw - divide (unsigned) rs1 with rs2, store to rd
-                        self.regs[rd] = match self.regs[rs2] {
-                            0 => 0xffffffff_ffffffff,
-                            _ => {
-                                let dividend = self.regs[rs1];
-                                let divisor = self.regs[rs2];
-                                dividend.wrapping_div(divisor)
+                        self.regs[rd] = match self.regs[rs2] as u32 {
+                            0 => u64::MAX,
+                            divisor => {
+                                let dividend = self.regs[rs1] as u32;
+                                sign_extend!(i32, dividend.wrapping_div(divisor))
                             }
                         };
                     }
@@ -560,6 +1475,71 @@ impl Cpu {
                     _ => err_illegal_instruction!(inst),
                 }
             }
+            0x53 if matches!(funct7, 0x00 | 0x04 | 0x08 | 0x0c | 0x2c) => {
+                self.execute_zfinx(inst, funct7, rd, rs1, rs2)?
+            }
+            0x53 => {
+                // Zfh, always rounds to nearest even
+                let (a, b) = (unbox_h(self.fregs[rs1]), unbox_h(self.fregs[rs2]));
+                let (value, flags) = match (funct7, rs2) {
+                    (0x02, _) => {
+                        // fadd.h
+                        let (r, flags) = half_binop(HalfOp::Add, a, b);
+                        (box_h(r), flags)
+                    }
+                    (0x06, _) => {
+                        // fsub.h
+                        let (r, flags) = half_binop(HalfOp::Sub, a, b);
+                        (box_h(r), flags)
+                    }
+                    (0x0a, _) => {
+                        // fmul.h
+                        let (r, flags) = half_binop(HalfOp::Mul, a, b);
+                        (box_h(r), flags)
+                    }
+                    (0x0e, _) => {
+                        // fdiv.h
+                        let (r, flags) = half_binop(HalfOp::Div, a, b);
+                        (box_h(r), flags)
+                    }
+                    (0x2e, 0) => {
+                        // fsqrt.h
+                        let (r, flags) = half_sqrt(a);
+                        (box_h(r), flags)
+                    }
+                    (0x20, 2) => {
+                        // fcvt.s.h
+                        let (r, flags) = half_to_f32(a);
+                        (box_s(r), flags)
+                    }
+                    (0x21, 2) => {
+                        // fcvt.d.h
+                        half_to_f64(a)
+                    }
+                    (0x22, 0) => {
+                        // fcvt.h.s
+                        let (r, flags) = f32_to_half(unbox_s(self.fregs[rs1]));
+                        (box_h(r), flags)
+                    }
+                    (0x22, 1) => {
+                        // fcvt.h.d
+                        let (r, flags) = f64_to_half(f64::from_bits(self.fregs[rs1]));
+                        (box_h(r), flags)
+                    }
+                    (0x72, 0) if funct3 == 0 => {
+                        // fmv.x.h - integer register gets the raw bits, sign-extended
+                        self.regs[rd] = sign_extend!(i16, self.fregs[rs1]);
+                        return Ok(self.pc.wrapping_add(4));
+                    }
+                    (0x7a, 0) if funct3 == 0 => {
+                        // fmv.h.x
+                        (box_h(self.regs[rs1] as u16), 0)
+                    }
+                    _ => err_illegal_instruction!(inst),
+                };
+                self.fregs[rd] = value;
+                self.csr.store(FFLAGS, self.csr.load(FFLAGS) | flags);
+            }
             0x63 => {
                 // S - add imm12 to pc if
                 let imm = get_b_imm(inst);
@@ -626,8 +1606,26 @@ impl Cpu {
                 let imm = get_j_imm(inst);
                 return Ok(self.pc.wrapping_add(imm));
             }
+            0x57 => match funct3 {
+                // vsetvli / vsetivli / vsetvl
+                0x7 => self.vset(inst, rd, rs1, rs2)?,
+                // vadd.vv / vadd.vx
+                0x0 | 0x4 if funct7 >> 1 == 0 => self.vadd(inst, funct3, rd, rs1, rs2)?,
+                _ => err_illegal_instruction!(inst),
+            },
+            0x77 => self.execute_vector_crypto(inst, funct3, rd, rs1, rs2)?,
             0x73 => {
                 let csr_addr = ((inst & 0xfff00000) >> 20) as usize;
+                if funct3 != 0 && !self.csr_accessible(csr_addr) {
+                    err_illegal_instruction!(inst);
+                }
+                // csrrs/csrrc with x0 and csrrsi/csrrci with 0 only read,
+                // csrrw/csrrwi with x0 as rd only write
+                let writes = matches!(funct3, 0x1 | 0x5) || (funct3 != 0 && rs1 != 0);
+                if writes && csr_addr >> 10 == 0b11 {
+                    // read-only csr
+                    err_illegal_instruction!(inst);
+                }
                 match funct3 {
                     0x0 => {
                         match (rs2, funct7) {
@@ -668,7 +1666,7 @@ impl Cpu {
                                 // set the pc to CSRs[sepc].
                                 // whenever IALIGN=32, bit sepc[1] is masked on reads so that it appears to be 0. This
                                 // masking occurs also for the implicit read by the SRET instruction.
-                                let new_pc = self.csr.load(SEPC) & !0b11;
+                                let new_pc = self.csr.load(SEPC) & pc_align_mask(self.extensions.c);
                                 return Ok(new_pc);
                             }
                             (0x2, 0x18) => {
@@ -687,77 +1685,567 @@ impl Cpu {
                                 // If MPP != M, sets MPRV=0
                                 mstatus &= !MASK_MPRV;
                                 self.csr.store(MSTATUS, mstatus);
-                                // set the pc to CSRs[mepc].
-                                let new_pc = self.csr.load(MEPC) & !0b11;
+                                // set the pc to CSRs[mepc], masked the same way as sepc.
+                                let new_pc = self.csr.load(MEPC) & pc_align_mask(self.extensions.c);
                                 return Ok(new_pc);
                             }
-                            (_, 0x9) => {
-                                // sfence.vma
-                                // Do nothing.
+                            (0x5, 0x8) => {
+                                // wfi, mstatus.TW makes it illegal below M-mode
+                                if self.mode != Machine && self.csr.load(MSTATUS) & MASK_TW != 0 {
+                                    err_illegal_instruction!(inst);
+                                }
+                                self.wfi();
+                            }
+                            (0xd | 0x1d, 0x0) if self.extensions.zawrs => {
+                                // wrs.nto / wrs.sto, the short timeout gives up sooner
+                                let spins = if rs2 == 0xd {
+                                    WRS_NTO_SPINS
+                                } else {
+                                    WRS_STO_SPINS
+                                };
+                                self.wait_on_reservation(spins)?;
+                            }
+                            // sfence.vma
+                            (_, 0x9) => self.sfence_vma(rs1, rs2),
+                            (_, 0xb) if self.extensions.svinval => {
+                                // sinval.vma, sfence.vma without the ordering that
+                                // sfence.w.inval before it and sfence.inval.ir after it add
+                                self.sfence_vma(rs1, rs2);
+                            }
+                            (0x0 | 0x1, 0xc) if self.extensions.svinval => {
+                                // sfence.w.inval / sfence.inval.ir, a single hart's accesses
+                                // and page table walks are already in order
                             }
                             _ => err_illegal_instruction!(inst),
                         }
                     }
                     0x1 => {
-                        // csrrw
-                        let t = self.csr.load(csr_addr);
-                        self.csr.store(csr_addr, self.regs[rs1]);
-                        self.regs[rd] = t;
+                        // csrrw, x0 as rd doesn't read the csr but still writes it
+                        let value = self.regs[rs1];
+                        if rd != 0 {
+                            self.regs[rd] = self.load_csr(csr_addr);
+                        }
+                        self.csr.store(csr_addr, value);
 
                         self.update_paging(csr_addr);
                     }
                     0x2 => {
-                        // csrrs
-                        let t = self.csr.load(csr_addr);
-                        self.csr.store(csr_addr, t | self.regs[rs1]);
-                        self.regs[rd] = t;
-
-                        self.update_paging(csr_addr);
+                        // csrrs, x0 as rs1 only reads the csr
+                        let t = self.load_csr(csr_addr);
+                        if rs1 != 0 {
+                            self.csr.store(csr_addr, t | self.regs[rs1]);
+                            self.update_paging(csr_addr);
+                        }
+                        if rd != 0 {
+                            self.regs[rd] = t;
+                        }
                     }
                     0x3 => {
-                        // csrrc
-                        let t = self.csr.load(csr_addr);
-                        self.csr.store(csr_addr, t & (!self.regs[rs1]));
-                        self.regs[rd] = t;
-
-                        self.update_paging(csr_addr);
+                        // csrrc, x0 as rs1 only reads the csr
+                        let t = self.load_csr(csr_addr);
+                        if rs1 != 0 {
+                            self.csr.store(csr_addr, t & (!self.regs[rs1]));
+                            self.update_paging(csr_addr);
+                        }
+                        if rd != 0 {
+                            self.regs[rd] = t;
+                        }
                     }
                     0x5 => {
-                        // csrrwi
+                        // csrrwi, x0 as rd doesn't read the csr but still writes it
                         let zimm = rs1 as u64;
-                        self.regs[rd] = self.csr.load(csr_addr);
+                        if rd != 0 {
+                            self.regs[rd] = self.load_csr(csr_addr);
+                        }
                         self.csr.store(csr_addr, zimm);
 
                         self.update_paging(csr_addr);
                     }
                     0x6 => {
-                        // csrrsi
+                        // csrrsi, zero zimm only reads the csr
                         let zimm = rs1 as u64;
-                        let t = self.csr.load(csr_addr);
-                        self.csr.store(csr_addr, t | zimm);
-                        self.regs[rd] = t;
-
-                        self.update_paging(csr_addr);
+                        let t = self.load_csr(csr_addr);
+                        if zimm != 0 {
+                            self.csr.store(csr_addr, t | zimm);
+                            self.update_paging(csr_addr);
+                        }
+                        if rd != 0 {
+                            self.regs[rd] = t;
+                        }
                     }
                     0x7 => {
-                        // csrrci
+                        // csrrci, zero zimm only reads the csr
                         let zimm = rs1 as u64;
-                        let t = self.csr.load(csr_addr);
-                        self.csr.store(csr_addr, t & (!zimm));
-                        self.regs[rd] = t;
-
-                        self.update_paging(csr_addr);
+                        let t = self.load_csr(csr_addr);
+                        if zimm != 0 {
+                            self.csr.store(csr_addr, t & (!zimm));
+                            self.update_paging(csr_addr);
+                        }
+                        if rd != 0 {
+                            self.regs[rd] = t;
+                        }
                     }
                     _ => err_illegal_instruction!(inst),
                 }
             }
 
-            _ => err_illegal_instruction!(inst),
+            _ => err_illegal_instruction!(inst),
+        }
+        Ok(self.pc.wrapping_add(4))
+    }
+
+    // Zfinx: single-precision arithmetic on the integer registers. Operands are the
+    // low 32 bits, results are sign-extended, and like Zfh it rounds to nearest even.
+    fn execute_zfinx(
+        &mut self,
+        inst: u64,
+        funct7: u32,
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+    ) -> Result<(), Exception> {
+        let a = f32::from_bits(self.regs[rs1] as u32);
+        let b = f32::from_bits(self.regs[rs2] as u32);
+        let (value, flags) = match (funct7, rs2) {
+            (0x00, _) => single_binop(HalfOp::Add, a, b), // fadd.s
+            (0x04, _) => single_binop(HalfOp::Sub, a, b), // fsub.s
+            (0x08, _) => single_binop(HalfOp::Mul, a, b), // fmul.s
+            (0x0c, _) => single_binop(HalfOp::Div, a, b), // fdiv.s
+            (0x2c, 0) => single_sqrt(a),                  // fsqrt.s
+            _ => err_illegal_instruction!(inst),
+        };
+        self.regs[rd] = value as i32 as u64;
+        self.csr.store(FFLAGS, self.csr.load(FFLAGS) | flags);
+        Ok(())
+    }
+
+    // Sets vtype and vl = min(avl, VLMAX). rs1 = x0 asks for VLMAX, or keeps vl
+    // when rd is x0 too. An unsupported vtype sets vill and vl = 0.
+    fn vset(&mut self, inst: u64, rd: usize, rs1: usize, rs2: usize) -> Result<(), Exception> {
+        let (avl, vtype) = match inst >> 30 {
+            // vsetvli, zimm[10:0]
+            0b00 | 0b01 => (None, (inst >> 20) & 0x7ff),
+            // vsetivli, avl is the rs1 field, zimm[9:0]
+            0b11 => (Some(rs1 as u64), (inst >> 20) & 0x3ff),
+            // vsetvl, vtype from rs2
+            _ if (inst >> 25) & 0x7f == 0x40 => (None, self.regs[rs2]),
+            _ => err_illegal_instruction!(inst),
+        };
+        match vector::decode_vtype(vtype) {
+            Some((sew, lmul8)) => {
+                let vlmax = vector::vlmax(sew, lmul8);
+                let avl = match avl {
+                    Some(avl) => avl,
+                    None if rs1 != 0 => self.regs[rs1],
+                    None if rd != 0 => vlmax,
+                    None => self.vl,
+                };
+                self.vtype = vtype;
+                self.vl = min(avl, vlmax);
+            }
+            None => {
+                self.vtype = VTYPE_VILL;
+                self.vl = 0;
+            }
+        }
+        self.vstart = 0;
+        self.regs[rd] = self.vl;
+        Ok(())
+    }
+
+    // (SEW, LMUL in eighths), vector instructions are illegal while vill is set
+    fn vector_config(&self, inst: u64) -> Result<(u64, u64), Exception> {
+        match vector::decode_vtype(self.vtype) {
+            Some(config) => Ok(config),
+            None => err_illegal_instruction!(inst),
+        }
+    }
+
+    // Unit-stride vle<eew>.v / vse<eew>.v. A fault leaves vstart at the faulting
+    // element so the access resumes there after the trap.
+    fn vector_unit_stride(&mut self, inst: u64, store: bool) -> Result<(), Exception> {
+        let (sew, lmul8) = self.vector_config(inst)?;
+        let vd = ((inst >> 7) & 0x1f) as usize;
+        let rs1 = ((inst >> 15) & 0x1f) as usize;
+        let masked = (inst >> 25) & 1 == 0;
+        // only nf = 0, mew = 0, mop = 0 and lumop / sumop = 0
+        if (inst >> 26) & 0x3f != 0 || (inst >> 20) & 0x1f != 0 {
+            err_illegal_instruction!(inst);
+        }
+        let eew = match (inst >> 12) & 0x7 {
+            0x0 => 8,
+            0x5 => 16,
+            0x6 => 32,
+            _ => 64,
+        };
+        // the data group has the same element count at EEW instead of SEW
+        let emul8 = eew * lmul8 / sew;
+        if !(1..=64).contains(&emul8) || !vd.is_multiple_of(vector::group_regs(emul8)) {
+            err_illegal_instruction!(inst);
+        }
+        if masked && vd == 0 && !store {
+            err_illegal_instruction!(inst);
+        }
+
+        let base = self.regs[rs1];
+        let bytes = eew / 8;
+        for i in self.vstart..self.vl {
+            if masked && !vector::mask_bit(&self.vregs, i) {
+                continue;
+            }
+            let addr = base.wrapping_add(i * bytes);
+            let result = if store {
+                let value = vector::read_element(&self.vregs, vd, i, bytes);
+                self.store(addr, eew, value)
+            } else {
+                self.load(addr, eew)
+                    .map(|value| vector::write_element(&mut self.vregs, vd, i, bytes, value))
+            };
+            if let Err(e) = result {
+                self.vstart = i;
+                return Err(e);
+            }
+        }
+        self.vstart = 0;
+        Ok(())
+    }
+
+    // vadd.vv vd, vs2, vs1 and vadd.vx vd, vs2, rs1, wrapping at SEW
+    fn vadd(
+        &mut self,
+        inst: u64,
+        funct3: u32,
+        vd: usize,
+        rs1: usize,
+        vs2: usize,
+    ) -> Result<(), Exception> {
+        let (sew, lmul8) = self.vector_config(inst)?;
+        let masked = (inst >> 25) & 1 == 0;
+        let group = vector::group_regs(lmul8);
+        let vector_rs1 = funct3 == 0x0;
+        if !vd.is_multiple_of(group)
+            || !vs2.is_multiple_of(group)
+            || (vector_rs1 && !rs1.is_multiple_of(group))
+        {
+            err_illegal_instruction!(inst);
+        }
+        if masked && vd == 0 {
+            err_illegal_instruction!(inst);
+        }
+
+        let bytes = sew / 8;
+        for i in self.vstart..self.vl {
+            if masked && !vector::mask_bit(&self.vregs, i) {
+                continue;
+            }
+            let a = vector::read_element(&self.vregs, vs2, i, bytes);
+            let b = match vector_rs1 {
+                true => vector::read_element(&self.vregs, rs1, i, bytes),
+                false => self.regs[rs1],
+            };
+            vector::write_element(&mut self.vregs, vd, i, bytes, a.wrapping_add(b));
+        }
+        self.vstart = 0;
+        Ok(())
+    }
+
+    // Zvkn on OP-VE (funct3 = OPMVV). Works on element groups of four elements:
+    // AES needs SEW = 32 (128-bit groups), SHA-2 takes SEW = 32 for SHA-256 and
+    // 64 for SHA-512. vl and vstart must be whole groups and nothing is masked.
+    fn execute_vector_crypto(
+        &mut self,
+        inst: u64,
+        funct3: u32,
+        vd: usize,
+        vs1: usize,
+        vs2: usize,
+    ) -> Result<(), Exception> {
+        let (sew, lmul8) = self.vector_config(inst)?;
+        let funct6 = inst >> 26;
+        let sha = (0x2d..=0x2f).contains(&funct6);
+        if funct3 != 0x2 || (inst >> 25) & 1 == 0 || (sew != 32 && !(sha && sew == 64)) {
+            err_illegal_instruction!(inst);
+        }
+        // a group can't be wider than the register group holding it
+        let group = vector::group_regs(lmul8);
+        if 4 * sew * 8 > vector::VLEN * lmul8
+            || !self.vl.is_multiple_of(4)
+            || !self.vstart.is_multiple_of(4)
+            || !vd.is_multiple_of(group)
+            || !vs2.is_multiple_of(group)
+        {
+            err_illegal_instruction!(inst);
+        }
+
+        let bytes = sew / 8;
+        let read = |vregs: &[Vec<u8>], reg: usize, g: u64| -> [u64; 4] {
+            std::array::from_fn(|k| vector::read_element(vregs, reg, g * 4 + k as u64, bytes))
+        };
+        // an AES group as (bits 63:0, bits 127:64)
+        let read128 = |vregs: &[Vec<u8>], reg: usize, g: u64| {
+            let w = read(vregs, reg, g);
+            (w[0] | w[1] << 32, w[2] | w[3] << 32)
+        };
+        for g in self.vstart / 4..self.vl / 4 {
+            let result = match (funct6, vs1) {
+                // vaes*.vv take round key group g of vs2, vaes*.vs always group 0
+                (0x28 | 0x29, 0..=3 | 7) => {
+                    let state = read128(&self.vregs, vd, g);
+                    let key = read128(&self.vregs, vs2, if funct6 == 0x29 { 0 } else { g });
+                    let (lo, hi) = match vs1 {
+                        0 => aes_dec_round(state, key, true),  // vaesdm
+                        1 => aes_dec_round(state, key, false), // vaesdf
+                        2 => aes_enc_round(state, key, true),  // vaesem
+                        3 => aes_enc_round(state, key, false), // vaesef
+                        7 if funct6 == 0x29 => (state.0 ^ key.0, state.1 ^ key.1), // vaesz.vs
+                        _ => err_illegal_instruction!(inst),
+                    };
+                    [lo, lo >> 32, hi, hi >> 32]
+                }
+                // vaeskf1.vi, the round number is the vs1 field
+                (0x22, _) => {
+                    let key = read128(&self.vregs, vs2, g);
+                    let (lo, hi) = aes128_key_round(key, vs1 as u64);
+                    [lo, lo >> 32, hi, hi >> 32]
+                }
+                // vaeskf2.vi, vd holds the round key two rounds back
+                (0x2a, _) => {
+                    let key_b = read128(&self.vregs, vd, g);
+                    let key = read128(&self.vregs, vs2, g);
+                    let (lo, hi) = aes256_key_round(key_b, key, vs1 as u64);
+                    [lo, lo >> 32, hi, hi >> 32]
+                }
+                // vsha2ms.vv
+                (0x2d, _) => sha2_message_schedule(
+                    read(&self.vregs, vd, g),
+                    read(&self.vregs, vs2, g),
+                    read(&self.vregs, vs1, g),
+                    sew,
+                ),
+                // vsha2ch.vv uses message words in elements 3:2 of vs1, vsha2cl.vv 1:0
+                (0x2e | 0x2f, _) => {
+                    let w = read(&self.vregs, vs1, g);
+                    let words = match funct6 {
+                        0x2e => [w[2], w[3]],
+                        _ => [w[0], w[1]],
+                    };
+                    let abef = read(&self.vregs, vs2, g);
+                    let cdgh = read(&self.vregs, vd, g);
+                    sha2_compress(abef, cdgh, words, sew)
+                }
+                _ => err_illegal_instruction!(inst),
+            };
+            for (k, value) in result.into_iter().enumerate() {
+                vector::write_element(&mut self.vregs, vd, g * 4 + k as u64, bytes, value);
+            }
+        }
+        self.vstart = 0;
+        Ok(())
+    }
+
+    // csr read by an instruction, counters live outside Csr
+    fn load_csr(&self, csr_addr: usize) -> u64 {
+        match csr_addr {
+            VL => self.vl,
+            VTYPE => self.vtype,
+            VLENB => vector::VLENB,
+            // RV32 reads the upper halves of the counters separately
+            CYCLEH | TIMEH | INSTRETH if self.xlen == Xlen::Rv32 => {
+                self.load_csr(csr_addr - (CYCLEH - CYCLE)) >> 32
+            }
+            // and has the interrupt bit of the causes at bit 31
+            MCAUSE | SCAUSE if self.xlen == Xlen::Rv32 => {
+                let cause = self.csr.load(csr_addr);
+                (cause & 0x7fff_ffff) | ((cause & MASK_INTERRUPT_BIT) >> 32)
+            }
+            _ => {
+                let counters = CpuCounters {
+                    cycles: self.cycles,
+                    instret: self.instret,
+                    time: self.bus.clint.mtime(),
+                };
+                self.csr.load_with_counters(csr_addr, &counters)
+            }
+        }
+    }
+
+    // csr address bits 9:8 are the lowest privilege that may access it,
+    // Smstateen can further hide state from modes below M
+    fn csr_accessible(&self, csr_addr: usize) -> bool {
+        let level = match (csr_addr >> 8) & 0b11 {
+            // hypervisor CSRs belong to HS-mode, which is S-mode with H
+            0b10 if self.extensions.h => Supervisor,
+            level => level as Mode,
+        };
+        if self.mode < level {
+            return false;
+        }
+        if self.mode == Machine {
+            return true;
+        }
+        match csr_addr {
+            SSTATEEN0..=SSTATEEN3 => {
+                let mstateen = self.csr.load(csr_addr - SSTATEEN0 + MSTATEEN0);
+                mstateen & MASK_STATEEN_SE0 != 0
+            }
+            SENVCFG => self.csr.load(MSTATEEN0) & MASK_STATEEN_ENVCFG != 0,
+            _ => true,
+        }
+    }
+
+    // c.addi4spn, c.lw, c.ld, c.sw and c.sd
+    fn execute_c_q0(&mut self, inst: u16) -> Result<u64, Exception> {
+        self.execute_expanded(inst, disasm::decode_c_q0(inst)?)
+    }
+
+    // c.addi, c.addiw, c.li, c.addi16sp, c.lui, the shifts and register ops on
+    // x8 - x15, c.j, c.beqz and c.bnez
+    fn execute_c_q1(&mut self, inst: u16) -> Result<u64, Exception> {
+        self.execute_expanded(inst, disasm::decode_c_q1(inst)?)
+    }
+
+    // c.slli, the sp-relative loads and stores, c.jr, c.mv, c.ebreak, c.jalr and c.add
+    fn execute_c_q2(&mut self, inst: u16) -> Result<u64, Exception> {
+        self.execute_expanded(inst, disasm::decode_c_q2(inst)?)
+    }
+
+    // A compressed instruction done as the one it expands to, except that it's 2
+    // bytes long: that's where execution carries on and what a jump links.
+    // Returns the new pc like execute.
+    fn execute_expanded(&mut self, inst: u16, expanded: DecodedInst) -> Result<u64, Exception> {
+        let next_pc = self.pc.wrapping_add(2);
+        let reg = |r: u8| r as usize;
+        match expanded {
+            DecodedInst::Addi { rd, rs1, imm } => {
+                self.regs[reg(rd)] = self.regs[reg(rs1)].wrapping_add(imm as u64);
+            }
+            DecodedInst::Addiw { rd, rs1, imm } => {
+                self.regs[reg(rd)] =
+                    sign_extend!(i32, self.regs[reg(rs1)].wrapping_add(imm as u64));
+            }
+            DecodedInst::Andi { rd, rs1, imm } => {
+                self.regs[reg(rd)] = self.regs[reg(rs1)] & imm as u64;
+            }
+            DecodedInst::Lui { rd, imm } => self.regs[reg(rd)] = imm as u64,
+            DecodedInst::Slli { rd, rs1, shamt } => {
+                self.regs[reg(rd)] = self.regs[reg(rs1)] << shamt;
+            }
+            DecodedInst::Srli { rd, rs1, shamt } => {
+                self.regs[reg(rd)] = self.regs[reg(rs1)] >> shamt;
+            }
+            DecodedInst::Srai { rd, rs1, shamt } => {
+                self.regs[reg(rd)] = (self.regs[reg(rs1)] as i64 >> shamt) as u64;
+            }
+            DecodedInst::Add { rd, rs1, rs2 } => {
+                self.regs[reg(rd)] = self.regs[reg(rs1)].wrapping_add(self.regs[reg(rs2)]);
+            }
+            DecodedInst::Sub { rd, rs1, rs2 } => {
+                self.regs[reg(rd)] = self.regs[reg(rs1)].wrapping_sub(self.regs[reg(rs2)]);
+            }
+            DecodedInst::Xor { rd, rs1, rs2 } => {
+                self.regs[reg(rd)] = self.regs[reg(rs1)] ^ self.regs[reg(rs2)];
+            }
+            DecodedInst::Or { rd, rs1, rs2 } => {
+                self.regs[reg(rd)] = self.regs[reg(rs1)] | self.regs[reg(rs2)];
+            }
+            DecodedInst::And { rd, rs1, rs2 } => {
+                self.regs[reg(rd)] = self.regs[reg(rs1)] & self.regs[reg(rs2)];
+            }
+            DecodedInst::Subw { rd, rs1, rs2 } => {
+                let value = self.regs[reg(rs1)].wrapping_sub(self.regs[reg(rs2)]);
+                self.regs[reg(rd)] = sign_extend!(i32, value);
+            }
+            DecodedInst::Addw { rd, rs1, rs2 } => {
+                let value = self.regs[reg(rs1)].wrapping_add(self.regs[reg(rs2)]);
+                self.regs[reg(rd)] = sign_extend!(i32, value);
+            }
+            DecodedInst::Jal { rd, offset } => {
+                self.regs[reg(rd)] = next_pc;
+                return Ok(self.pc.wrapping_add(offset as u64));
+            }
+            DecodedInst::Jalr { rd, rs1, offset } => {
+                // the target is read before rd is written, c.jalr ra works
+                let target = self.regs[reg(rs1)].wrapping_add(offset as u64) & !1;
+                self.regs[reg(rd)] = next_pc;
+                return Ok(target);
+            }
+            DecodedInst::Ebreak => return Err(Exception::Breakpoint(self.pc)),
+            DecodedInst::Branch {
+                cond,
+                rs1,
+                rs2,
+                offset,
+            } => {
+                let (a, b) = (self.regs[reg(rs1)], self.regs[reg(rs2)]);
+                let taken = match cond {
+                    BranchCond::Eq => a == b,
+                    BranchCond::Ne => a != b,
+                    _ => err_illegal_instruction!(inst as u64),
+                };
+                if taken {
+                    return Ok(self.pc.wrapping_add(offset as u64));
+                }
+            }
+            DecodedInst::Load {
+                rd,
+                rs1,
+                offset,
+                width,
+            } => {
+                let addr = self.regs[reg(rs1)].wrapping_add(offset as u64);
+                self.regs[reg(rd)] = match width {
+                    LoadWidth::W => sign_extend!(i32, self.load(addr, 32)?),
+                    _ => self.load(addr, 64)?,
+                };
+            }
+            DecodedInst::Store {
+                rs1,
+                rs2,
+                offset,
+                width,
+            } => {
+                let addr = self.regs[reg(rs1)].wrapping_add(offset as u64);
+                let size = match width {
+                    StoreWidth::W => 32,
+                    _ => 64,
+                };
+                self.store(addr, size, self.regs[reg(rs2)])?;
+            }
+            _ => err_illegal_instruction!(inst as u64),
+        }
+        Ok(next_pc)
+    }
+
+    // false if the instruction belongs to an extension disabled in MachineConfig
+    fn extension_enabled(&self, opcode: u32, funct3: u32, funct7: u32) -> bool {
+        let ext = &self.extensions;
+        match (opcode, funct3, funct7) {
+            (0x0f, 0x1, _) => ext.zifencei,
+            (0x2f, _, _) => ext.a,
+            // funct3 0-3 multiply, 4-7 divide and remainder
+            (0x33 | 0x3b, 0x0..=0x3, 0x1) => ext.m || ext.zmmul,
+            (0x33 | 0x3b, _, 0x1) => ext.m,
+            (0x33 | 0x3b, _, 0x10) => ext.zba,
+            (0x33, 0x5 | 0x7, 0x07) => ext.zicond,
+            (0x73, 0x1..=0x7, _) => ext.zicsr,
+            (0x07 | 0x27, 0x0 | 0x5..=0x7, _) | (0x57, _, _) => ext.v,
+            (0x77, _, _) => ext.v && ext.zvkn,
+            (0x53, _, 0x00 | 0x04 | 0x08 | 0x0c | 0x2c) => ext.zfinx,
+            (0x07 | 0x27 | 0x53, _, _) => ext.zfh,
+            (0x13, 0x1, 0x08 | 0x18) => ext.zkn,
+            (0x33, 0x0, 0x19 | 0x1b | 0x1d | 0x1f | 0x3f) => ext.zkn,
+            (0x13, 0x1 | 0x5, 0x04 | 0x34) | (0x33, 0x4 | 0x7, 0x04) => ext.zbkb,
+            _ => true,
         }
-        Ok(self.pc.wrapping_add(4))
     }
 
     pub fn handle_exception(&mut self, e: Exception) {
+        if let Exception::EnvironmentCallFromSMode(_) = e {
+            if self.handle_sbi_call() {
+                return;
+            }
+        }
+
         let pc = self.pc;
         let mode = self.mode;
         let cause = e.code();
@@ -793,6 +2281,76 @@ impl Cpu {
         self.csr.store(STATUS, status);
     }
 
+    // SBI calling convention: a7 - extension id, a6 - function id, a0..a5 - arguments
+    fn handle_sbi_call(&mut self) -> bool {
+        let sbi = match self.sbi.as_mut() {
+            Some(sbi) => sbi,
+            None => return false,
+        };
+
+        let args = [
+            self.regs[10],
+            self.regs[11],
+            self.regs[12],
+            self.regs[13],
+            self.regs[14],
+            self.regs[15],
+        ];
+        let (a0, a1) = sbi.handle(
+            &mut self.bus,
+            &mut self.csr,
+            self.regs[17],
+            self.regs[16],
+            args,
+        );
+        self.regs[10] = a0;
+        self.regs[11] = a1;
+        // as if the firmware did sepc + 4 and returned
+        self.pc = self.pc.wrapping_add(4);
+        true
+    }
+
+    // empty if coverage isn't enabled
+    pub fn get_mem_stats(&self) -> &MemStats {
+        &self.bus.stats
+    }
+
+    pub fn reset_mem_stats(&mut self) {
+        self.bus.stats = MemStats::default();
+    }
+
+    pub fn coverage_bitmap(&self) -> &[u64] {
+        self.coverage.as_deref().unwrap_or(&[])
+    }
+
+    pub fn coverage_report(&self) -> CoverageReport {
+        coverage::report(self.coverage_bitmap())
+    }
+
+    // coverage of the instruction words in [start, end)
+    pub fn coverage_report_range(&self, start: u64, end: u64) -> CoverageReport {
+        match &self.coverage {
+            Some(bitmap) => coverage::report_range(bitmap, self.config.dram_base, start, end),
+            None => coverage::report(&[]),
+        }
+    }
+
+    // elf_symbols are (address, size, name)
+    pub fn dump_coverage_lcov(
+        &self,
+        path: &Path,
+        elf_symbols: &[(u64, u64, String)],
+    ) -> std::io::Result<()> {
+        let bitmap = self.coverage.as_deref().unwrap_or(&[0]);
+        let mut out = BufWriter::new(File::create(path)?);
+        coverage::write_lcov(&mut out, bitmap, self.config.dram_base, elf_symbols)?;
+        out.flush()
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.sbi.as_ref().is_some_and(|sbi| sbi.shutdown)
+    }
+
     pub fn handle_interrupt(&mut self, interrupt: Interrupt) {
         let pc = self.pc;
         let mode = self.mode;
@@ -819,7 +2377,8 @@ impl Cpu {
         match tvec_mode {
             // DIrect
             0 => self.pc = tvec_base,
-            1 => self.pc = tvec_base + cause << 2,
+            // Vectored, the offset is the cause number without the interrupt bit
+            1 => self.pc = tvec_base + ((cause & !MASK_INTERRUPT_BIT) << 2),
             _ => unreachable!(),
         };
 
@@ -837,8 +2396,53 @@ impl Cpu {
         self.csr.store(STATUS, status);
     }
 
+    // mark interrupt as pending, it's taken once enabled like any other
+    pub fn inject_interrupt(&mut self, interrupt: Interrupt) {
+        match interrupt {
+            Interrupt::MachineExternalInterrupt => self.csr.set_external_interrupt(true),
+            _ => self.csr.store(MIP, self.csr.load(MIP) | interrupt.mask()),
+        }
+    }
+
+    // same as inject_interrupt, for devices running on another thread
+    pub fn inject_interrupt_from_thread(cpu: &Arc<Mutex<Cpu>>, interrupt: Interrupt) {
+        cpu.lock().unwrap().inject_interrupt(interrupt);
+    }
+
     pub fn check_pending_interrupt(&mut self) -> Option<Interrupt> {
         use Interrupt::*;
+        // Sstc, STIP follows mtime >= stimecmp once menvcfg.STCE is set,
+        // or once the SBI firmware has programmed a timer in stimecmp
+        let sbi_timer = self.sbi.as_ref().is_some_and(|sbi| sbi.timer_armed);
+        if self.csr.load(MENVCFG) & MASK_STCE != 0 || sbi_timer {
+            let mip = self.csr.load(MIP);
+            if self.bus.clint.mtime() >= self.csr.load(STIMECMP) {
+                self.csr.store(MIP, mip | MASK_STIP);
+            } else {
+                self.csr.store(MIP, mip & !MASK_STIP);
+            }
+        }
+
+        // CLINT timer, MTIP follows mtime >= this hart's mtimecmp once it's programmed
+        if let Some(pending) = self.bus.clint.timer_pending(self.csr.load(MHARTID)) {
+            let mip = self.csr.load(MIP);
+            if pending {
+                self.csr.store(MIP, mip | MASK_MTIP);
+            } else {
+                self.csr.store(MIP, mip & !MASK_MTIP);
+            }
+        }
+
+        // CLINT software interrupt, MSIP follows bit 0 of this hart's msip once written
+        if let Some(pending) = self.bus.clint.software_pending(self.csr.load(MHARTID)) {
+            let mip = self.csr.load(MIP);
+            if pending {
+                self.csr.store(MIP, mip | MASK_MSIP);
+            } else {
+                self.csr.store(MIP, mip & !MASK_MSIP);
+            }
+        }
+
         // is mie on
         if (self.mode == Machine) && (self.csr.load(MSTATUS) & MASK_MIE) == 0 {
             return None;
@@ -850,11 +2454,22 @@ impl Cpu {
 
         // interrupts for external devices
         if self.bus.uart.is_interrupting() {
-            self.bus.store(PLIC_SCLAIM, 32, UART_IRQ).unwrap();
-            self.csr.store(MIP, self.csr.load(MIP) | MASK_SEIP);
+            self.bus.plic.raise(UART_IRQ);
         } else if self.bus.virtio_blk.is_interrupting() {
             self.disk_access();
-            self.bus.store(PLIC_SCLAIM, 32, VIRTIO_IRQ).unwrap();
+            // the disk may have written over cached code
+            self.flush_icache();
+            self.bus.plic.raise(VIRTIO_IRQ);
+        } else if self.bus.virtio_rng.is_interrupting() {
+            self.rng_access();
+            self.bus.plic.raise(VIRTIO_RNG_IRQ);
+        } else if self.bus.virtio_console.is_interrupting() && self.console_access() {
+            self.bus.plic.raise(VIRTIO_CONSOLE_IRQ);
+        }
+        // M-mode context first, a source enabled in both goes to M-mode
+        if self.bus.plic.is_pending(PLIC_MCONTEXT) {
+            self.csr.set_external_interrupt(true);
+        } else if self.bus.plic.is_pending(PLIC_SCONTEXT) {
             self.csr.store(MIP, self.csr.load(MIP) | MASK_SEIP);
         }
 
@@ -870,6 +2485,9 @@ impl Cpu {
         ] {
             if (pending & m) != 0 {
                 self.csr.store(MIP, self.csr.load(MIP) & !m);
+                if m == MASK_MEIP {
+                    self.csr.set_external_interrupt(false);
+                }
                 return Some(i);
             }
         }
@@ -878,22 +2496,101 @@ impl Cpu {
     }
 
     fn update_paging(&mut self, csr_addr: usize) {
+        // cached code was fetched under the old PMP settings
+        if matches!(csr_addr, PMPCFG0 | PMPCFG2 | PMPADDR0..=PMPADDR15 | MSECCFG) {
+            self.flush_icache();
+            return;
+        }
         if csr_addr != SATP {
             return;
         }
 
         let satp = self.csr.load(SATP);
-        self.page_table = (satp & MASK_PPN) * PAGE_SIZE;
+        self.tlb.clear();
+        self.flush_icache();
+        if self.xlen == Xlen::Rv32 {
+            self.page_table = (satp & SV32_MASK_PPN) * PAGE_SIZE;
+            self.enable_paging = satp & SV32_SATP_MODE != 0; // Sv32
+            return;
+        }
 
+        self.page_table = (satp & MASK_PPN) * PAGE_SIZE;
         let mode = satp >> 60;
         self.enable_paging = mode == 8; // Sv39
     }
 
-    pub fn translate(&mut self, addr: u64, access_type: AccessType) -> Result<u64, Exception> {
+    // Physical address and Svpbmt memory type (PBMT_PMA, PBMT_NC or PBMT_IO) of addr.
+    // NC pages behave like main memory here, ordering them is up to the guest's fences.
+    pub fn translate(
+        &mut self,
+        addr: u64,
+        access_type: AccessType,
+    ) -> Result<(u64, u8), Exception> {
         if !self.enable_paging {
-            return Ok(addr);
+            return Ok((addr, PBMT_PMA));
+        }
+
+        let satp = self.csr.load(SATP);
+        let asid = match self.xlen {
+            Xlen::Rv32 => (satp >> 22) & SV32_MASK_ASID,
+            Xlen::Rv64 => (satp >> 44) & MASK_ASID,
+        };
+        let vpn = (addr >> 12) & MASK_VPN;
+        let offset = addr & 0xfff;
+        if let Some(entry) = self.tlb.lookup(asid, vpn) {
+            self.bus.stats.tlb_hits += 1;
+            let ppn = (entry >> 10) & MASK_PPN;
+            return Ok(((ppn << 12) | offset, (entry >> 61) as u8 & 0b11));
         }
+        self.bus.stats.tlb_misses += 1;
+
+        let (p_addr, pte) = self.walk_page_table(addr, access_type).inspect_err(|e| {
+            if matches!(
+                e,
+                Exception::InstructionPageFault(_)
+                    | Exception::LoadPageFault(_)
+                    | Exception::StoreAMOPageFault(_)
+            ) {
+                self.bus.stats.page_faults += 1;
+            }
+        })?;
+        // cache the 4 KiB page that was hit, even if it's a part of a superpage
+        self.tlb.insert(
+            asid,
+            vpn,
+            ((p_addr >> 12) << 10) | (pte & (PTE_PBMT | 0x3ff)),
+        );
+        Ok((p_addr, ((pte & PTE_PBMT) >> 61) as u8))
+    }
+
+    // sfence.vma rs1, rs2: rs1 = x0 - all pages, rs2 = x0 - all address spaces
+    fn sfence_vma(&mut self, rs1: usize, rs2: usize) {
+        let vpn = match rs1 {
+            0 => None,
+            _ => Some((self.regs[rs1] >> 12) & MASK_VPN),
+        };
+        let asid = match rs2 {
+            0 => None,
+            _ => Some(self.regs[rs2] & MASK_ASID),
+        };
+        self.flush_tlb(asid, vpn);
+        self.flush_icache();
+    }
+
+    // sfence.vma, None matches every asid / page
+    pub fn flush_tlb(&mut self, asid: Option<u64>, vpn: Option<u64>) {
+        self.tlb.flush(asid, vpn);
+    }
 
+    // returns physical address and leaf pte
+    fn walk_page_table(
+        &mut self,
+        addr: u64,
+        access_type: AccessType,
+    ) -> Result<(u64, u64), Exception> {
+        if self.xlen == Xlen::Rv32 {
+            return self.walk_sv32(addr, access_type);
+        }
         let levels = 3;
         let vpn = [
             (addr >> 12) & 0x1ff, //L0
@@ -906,6 +2603,10 @@ impl Cpu {
         let mut pte;
         loop {
             pte = self.bus.load(a + vpn[i as usize] * 8, 64)?;
+            // page tables are supervisor data, their byte order follows SBE
+            if self.csr.load(MSTATUS) & MASK_SBE != 0 {
+                pte = pte.swap_bytes();
+            }
 
             let v = pte & 1;
             let r = (pte >> 1) & 1;
@@ -915,11 +2616,7 @@ impl Cpu {
             // If pte.v = 0, or if pte.r = 0 and pte.w = 1, stop and raise a page-fault
             // exception corresponding to the original access type.
             if v == 0 || (r == 0 && w == 1) {
-                match access_type {
-                    AccessType::Instruction => return Err(Exception::InstructionPageFault(addr)),
-                    AccessType::Load => return Err(Exception::LoadPageFault(addr)),
-                    AccessType::Store => return Err(Exception::StoreAMOPageFault(addr)),
-                }
+                return Err(page_fault(addr, access_type));
             }
 
             // leaf pte
@@ -927,16 +2624,17 @@ impl Cpu {
                 break;
             }
 
+            // N and PBMT are reserved in non-leaf ptes
+            if pte & (PTE_N | PTE_PBMT) != 0 {
+                return Err(page_fault(addr, access_type));
+            }
+
             // text page
             i -= 1;
             let ppn = (pte >> 10) & 0x0fff_ffff_ffff;
             a = ppn * PAGE_SIZE;
             if i < 0 {
-                match access_type {
-                    AccessType::Instruction => return Err(Exception::InstructionPageFault(addr)),
-                    AccessType::Load => return Err(Exception::LoadPageFault(addr)),
-                    AccessType::Store => return Err(Exception::StoreAMOPageFault(addr)),
-                }
+                return Err(page_fault(addr, access_type));
             }
         }
 
@@ -946,107 +2644,362 @@ impl Cpu {
             (pte >> 28) & 0x03ff_ffff,
         ];
 
+        // superpage must be aligned to its size, low ppn fields are zero
+        if (i == 1 && ppn[0] != 0) || (i == 2 && (ppn[0] != 0 || ppn[1] != 0)) {
+            return Err(page_fault(addr, access_type));
+        }
+
+        let napot = pte & PTE_N != 0;
+        if napot && (!self.extensions.svnapot || i != 0 || ppn[0] & 0xf != NAPOT_64K) {
+            return Err(page_fault(addr, access_type));
+        }
+
+        // memory types need Svpbmt enabled by menvcfg.PBMTE, the fourth is reserved
+        let pbmt = pte & PTE_PBMT;
+        let pbmte = self.extensions.svpbmt && self.csr.load(MENVCFG) & MASK_PBMTE != 0;
+        if pbmt == PTE_PBMT || (pbmt != 0 && !pbmte) {
+            return Err(page_fault(addr, access_type));
+        }
+
         let offset = addr & 0xfff;
         match i {
             0 => {
-                let ppn = (pte >> 10) & 0x0fff_ffff_ffff;
-                Ok((ppn << 12) | offset)
+                let mut ppn = (pte >> 10) & 0x0fff_ffff_ffff;
+                if napot {
+                    // low 4 bits of the page number come from the virtual address
+                    ppn = (ppn & !0xf) | (vpn[0] & 0xf);
+                }
+                Ok(((ppn << 12) | offset, pte))
             }
             1 => {
                 // Superpage translation. 2 MiB
-                Ok((ppn[2] << 30) | (ppn[1] << 21) | (vpn[0] << 12) | offset)
+                Ok((
+                    (ppn[2] << 30) | (ppn[1] << 21) | (vpn[0] << 12) | offset,
+                    pte,
+                ))
             }
             2 => {
                 // Superpage translation. 1 GiB
-                Ok((ppn[2] << 30) | (vpn[1] << 21) | (vpn[0] << 12) | offset)
+                Ok((
+                    (ppn[2] << 30) | (vpn[1] << 21) | (vpn[0] << 12) | offset,
+                    pte,
+                ))
             }
-            _ => match access_type {
-                AccessType::Instruction => return Err(Exception::InstructionPageFault(addr)),
-                AccessType::Load => return Err(Exception::LoadPageFault(addr)),
-                AccessType::Store => return Err(Exception::StoreAMOPageFault(addr)),
-            },
+            _ => Err(page_fault(addr, access_type)),
+        }
+    }
+
+    // Sv32: two levels of 4-byte ptes with 10-bit vpn fields and 4 MiB superpages,
+    // physical addresses are 34 bits
+    fn walk_sv32(&mut self, addr: u64, access_type: AccessType) -> Result<(u64, u64), Exception> {
+        let vpn = [(addr >> 12) & 0x3ff, (addr >> 22) & 0x3ff];
+
+        let mut a = self.page_table;
+        let mut i = 1;
+        let pte = loop {
+            let mut pte = self.bus.load(a + vpn[i] * 4, 32)?;
+            if self.csr.load(MSTATUS) & MASK_SBE != 0 {
+                pte = (pte as u32).swap_bytes() as u64;
+            }
+
+            let v = pte & 1;
+            let r = (pte >> 1) & 1;
+            let w = (pte >> 2) & 1;
+            let x = (pte >> 3) & 1;
+            if v == 0 || (r == 0 && w == 1) {
+                return Err(page_fault(addr, access_type));
+            }
+            // leaf pte
+            if r == 1 || x == 1 {
+                break pte;
+            }
+            if i == 0 {
+                return Err(page_fault(addr, access_type));
+            }
+            i -= 1;
+            a = (pte >> 10) * PAGE_SIZE;
+        };
+
+        let ppn = pte >> 10;
+        let offset = addr & 0xfff;
+        match i {
+            0 => Ok(((ppn << 12) | offset, pte)),
+            // superpage must be aligned to its size, ppn[0] is zero
+            _ if ppn & 0x3ff != 0 => Err(page_fault(addr, access_type)),
+            // Superpage translation. 4 MiB
+            _ => Ok(((ppn << 12) | (vpn[0] << 12) | offset, pte)),
         }
     }
 
+    // process every block request the driver has made available
     pub fn disk_access(&mut self) {
-        // size of descriptor table el
-        const DESC_SIZE: u64 = size_of::<VirtqDesc>() as u64;
         let desc_addr = self.bus.virtio_blk.desc_addr();
-        let avail_addr = desc_addr + DESC_NUM as u64 * DESC_SIZE;
-        let used_addr = desc_addr + PAGE_SIZE;
-        // casting addresses
-        let virtq_avail = unsafe { &(*(avail_addr as *const VirtqAvail)) };
-        let virtq_used = unsafe { &(*(used_addr as *const VirtqUsed)) };
+        let avail_idx = self.virtq_avail(desc_addr, None) as u16;
+        while let Some(idx) = self.bus.virtio_blk.next_avail(avail_idx) {
+            let head = self.virtq_avail(desc_addr, Some(idx));
+
+            // (addr, len, flags) of every descriptor in the chain,
+            // capped so a looping chain can't hang the emulator
+            let mut chain = Vec::new();
+            let mut index = head;
+            for _ in 0..DESC_NUM {
+                let (addr, len, flags, next) = self.virtq_desc(desc_addr, index);
+                chain.push((addr, len, flags as u16));
+                if flags as u16 & VIRTQ_DESC_F_NEXT == 0 {
+                    break;
+                }
+                index = next;
+            }
 
-        // indexing idx to available ring
-        let idx = self
-            .bus
-            .load(&virtq_avail.idx as *const _ as u64, 16)
-            .unwrap() as usize;
-        let index = self
-            .bus
-            .load(&virtq_avail.ring[idx % DESC_NUM] as *const _ as u64, 16)
-            .unwrap();
+            let written = self.blk_request(&chain);
+            self.virtq_push_used(desc_addr, head, written);
+        }
+    }
 
-        //The first descriptor:
-        // which contains the request information and a pointer to the data descriptor.
-        let desc_addr0 = desc_addr + DESC_SIZE * index;
-        let virtq_desc0 = unsafe { &(*(desc_addr0 as *const VirtqDesc)) };
+    // header comes first, the status byte last (device-writable), data is in between,
+    // returns the number of bytes written for the driver
+    fn blk_request(&mut self, chain: &[(u64, u64, u16)]) -> u64 {
         // The addr field points to a virtio block request. We need the sector number stored
         // in the sector field. The iotype tells us whether to read or write.
-        let req_addr = self
-            .bus
-            .load(&virtq_desc0.addr as *const _ as u64, 64)
-            .unwrap();
-        let virtq_blk_req = unsafe { &(*(req_addr as *const VirtioBlkRequest)) };
-        let blk_sector = self
+        let (req_addr, _, _) = chain[0];
+        let header = self
             .bus
-            .load(&virtq_blk_req.sector as *const _ as u64, 64)
-            .unwrap();
-        let iotype = self
-            .bus
-            .load(&virtq_blk_req.iotype as *const _ as u64, 32)
-            .unwrap() as u32;
-        // The next field points to the second descriptor. (data descriptor)
-        let next0 = self
-            .bus
-            .load(&virtq_desc0.next as *const _ as u64, 16)
-            .unwrap();
+            .load(req_addr + offset_of!(VirtioBlkRequest, sector) as u64, 64)
+            .and_then(|sector| {
+                let iotype = self
+                    .bus
+                    .load(req_addr + offset_of!(VirtioBlkRequest, iotype) as u64, 32)?;
+                Ok((sector, iotype as u32))
+            });
+
+        let (data, status) = match chain {
+            // a flush has no data, only the header and the status
+            [_, data @ .., status] if status.2 & VIRTQ_DESC_F_WRITE != 0 => (data, Some(status.0)),
+            [_, data @ ..] => (data, None),
+            [] => unreachable!(),
+        };
 
-        // the second descriptor.
-        let desc_addr1 = desc_addr + DESC_SIZE * next0;
-        let virtq_desc1 = unsafe { &(*(desc_addr1 as *const VirtqDesc)) };
-        let addr1 = self
-            .bus
-            .load(&virtq_desc1.addr as *const _ as u64, 64)
-            .unwrap();
-        let len1 = self
-            .bus
-            .load(&virtq_desc1.len as *const _ as u64, 32)
-            .unwrap();
+        // a header the device can't read fails the request without touching the disk
+        let (blk_sector, iotype) = match header {
+            Ok(header) => header,
+            Err(e) => {
+                println!("virtio: bad block request header: {:?}", e);
+                if let Some(addr) = status {
+                    self.bus.store(addr, 8, VIRTIO_BLK_S_IOERR as u64).unwrap();
+                    return 1;
+                }
+                return 0;
+            }
+        };
 
+        let mut written = 0;
+        let mut result = VIRTIO_BLK_S_OK;
+        let mut offset = blk_sector.wrapping_mul(SECTOR_SIZE);
+        // a transfer reaching past the disk fails as a whole, nothing is read or written
+        let len = data.iter().map(|&(_, len, _)| len).sum();
+        let past_end =
+            blk_sector > u64::MAX / SECTOR_SIZE || !self.bus.virtio_blk.in_range(offset, len);
         match iotype {
+            VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT if past_end => {
+                println!(
+                    "virtio: request past the end of the disk at sector {}",
+                    blk_sector
+                );
+                result = VIRTIO_BLK_S_IOERR;
+            }
             VIRTIO_BLK_T_OUT => {
-                for i in 0..len1 {
-                    let data = self.bus.load(addr1 + i, 8).unwrap();
-                    self.bus
-                        .virtio_blk
-                        .write_disk(blk_sector * SECTOR_SIZE + i, data);
+                'out: for &(addr, len, _) in data {
+                    for i in 0..len {
+                        let data = self.bus.load(addr + i, 8).unwrap();
+                        if let Err(e) = self.bus.virtio_blk.write_disk(offset + i, data) {
+                            println!("virtio: disk write failed: {}", e);
+                            result = VIRTIO_BLK_S_IOERR;
+                            break 'out;
+                        }
+                    }
+                    offset += len;
                 }
             }
             VIRTIO_BLK_T_IN => {
-                for i in 0..len1 {
-                    let data = self.bus.virtio_blk.read_disk(blk_sector * SECTOR_SIZE + i);
-                    self.bus.store(addr1 + i, 8, data as u64).unwrap();
+                'read: for &(addr, len, _) in data {
+                    for i in 0..len {
+                        let data = match self.bus.virtio_blk.read_disk(offset + i) {
+                            Ok(data) => data,
+                            Err(e) => {
+                                println!("virtio: disk read failed: {}", e);
+                                result = VIRTIO_BLK_S_IOERR;
+                                break 'read;
+                            }
+                        };
+                        self.bus.store(addr + i, 8, data).unwrap();
+                        written += 1;
+                    }
+                    offset += len;
                 }
             }
-            _ => unreachable!(),
+            VIRTIO_BLK_T_FLUSH => {
+                if let Err(e) = self.bus.virtio_blk.flush_disk() {
+                    println!("virtio: disk flush failed: {}", e);
+                    result = VIRTIO_BLK_S_IOERR;
+                }
+            }
+            VIRTIO_BLK_T_GET_ID => {
+                // id string is padded with zeroes, not null-terminated if it fills the buffer
+                if let Some(&(addr, len, _)) = data.first() {
+                    let id = self.bus.virtio_blk.device_id();
+                    for i in 0..min(len, VIRTIO_BLK_ID_BYTES) {
+                        let data = id.get(i as usize).copied().unwrap_or(0);
+                        self.bus.store(addr + i, 8, data as u64).unwrap();
+                        written += 1;
+                    }
+                }
+            }
+            _ => {
+                // do not crash the emulator on a request we don't understand, skip it
+                println!("virtio: unsupported block request type {}", iotype);
+                result = VIRTIO_BLK_S_UNSUPP;
+            }
+        }
+
+        if let Some(addr) = status {
+            self.bus.store(addr, 8, result as u64).unwrap();
+            written += 1;
+        }
+        written
+    }
+
+    // fill every buffer the driver has made available with random bytes
+    pub fn rng_access(&mut self) {
+        let desc_addr = self.bus.virtio_rng.desc_addr();
+        let avail_idx = self.virtq_avail(desc_addr, None) as u16;
+        while let Some(idx) = self.bus.virtio_rng.next_avail(avail_idx) {
+            // a single device-writable buffer per request
+            let index = self.virtq_avail(desc_addr, Some(idx));
+            let (addr, len, _, _) = self.virtq_desc(desc_addr, index);
+            for i in 0..len {
+                let data = self.bus.virtio_rng.random_byte();
+                self.bus.store(addr + i, 8, data as u64).unwrap();
+            }
+
+            self.virtq_push_used(desc_addr, index, len);
         }
+    }
 
-        let new_id = self.bus.virtio_blk.get_new_id();
+    // mark the descriptor chain starting at index as used
+    fn virtq_push_used(&mut self, desc_addr: u64, index: u64, len: u64) {
+        const ELEM_SIZE: u64 = size_of::<VirtQUsedusedElem>() as u64;
+        let used_addr = desc_addr + PAGE_SIZE;
+        let idx_addr = used_addr + offset_of!(VirtqUsed, idx) as u64;
+        let used_idx = self.bus.load(idx_addr, 16).unwrap();
+        let elem = used_addr
+            + offset_of!(VirtqUsed, ring) as u64
+            + ELEM_SIZE * (used_idx % DESC_NUM as u64);
+        self.bus
+            .store(elem + offset_of!(VirtQUsedusedElem, id) as u64, 32, index)
+            .unwrap();
         self.bus
-            .store(&virtq_used.idx as *const _ as u64, 16, new_id % 8)
+            .store(elem + offset_of!(VirtQUsedusedElem, len) as u64, 32, len)
             .unwrap();
+        self.bus
+            .store(idx_addr, 16, (used_idx as u16).wrapping_add(1) as u64)
+            .unwrap();
+    }
+
+    // (avail.idx, head of the descriptor chain at ring position idx)
+    fn virtq_avail(&mut self, desc_addr: u64, idx: Option<u16>) -> u64 {
+        const DESC_SIZE: u64 = size_of::<VirtqDesc>() as u64;
+        let avail_addr = desc_addr + DESC_NUM as u64 * DESC_SIZE;
+        let addr = match idx {
+            Some(idx) => {
+                avail_addr
+                    + offset_of!(VirtqAvail, ring) as u64
+                    + 2 * (idx as usize % DESC_NUM) as u64
+            }
+            None => avail_addr + offset_of!(VirtqAvail, idx) as u64,
+        };
+        self.bus.load(addr, 16).unwrap()
+    }
+
+    // (addr, len, flags, next) of a descriptor
+    fn virtq_desc(&mut self, desc_addr: u64, index: u64) -> (u64, u64, u64, u64) {
+        const DESC_SIZE: u64 = size_of::<VirtqDesc>() as u64;
+        let desc = desc_addr + DESC_SIZE * (index % DESC_NUM as u64);
+        let field = |offset: usize| desc + offset as u64;
+        (
+            self.bus
+                .load(field(offset_of!(VirtqDesc, addr)), 64)
+                .unwrap(),
+            self.bus
+                .load(field(offset_of!(VirtqDesc, len)), 32)
+                .unwrap(),
+            self.bus
+                .load(field(offset_of!(VirtqDesc, flags)), 16)
+                .unwrap(),
+            self.bus
+                .load(field(offset_of!(VirtqDesc, next)), 16)
+                .unwrap(),
+        )
+    }
+
+    // print transmitq buffers and fill receiveq buffers with host input,
+    // returns true if the driver got any buffer back
+    pub fn console_access(&mut self) -> bool {
+        let mut used = false;
+
+        // transmitq, every descriptor in a chain is device-readable
+        let desc_addr = self.bus.virtio_console.desc_addr(CONSOLE_TRANSMITQ);
+        let avail_idx = self.virtq_avail(desc_addr, None) as u16;
+        while let Some(idx) = self
+            .bus
+            .virtio_console
+            .next_avail(CONSOLE_TRANSMITQ, avail_idx)
+        {
+            let head = self.virtq_avail(desc_addr, Some(idx));
+            // capped like disk_access, a buffer the bus can't read is dropped
+            let mut index = head;
+            for _ in 0..DESC_NUM {
+                let (addr, len, flags, next) = self.virtq_desc(desc_addr, index);
+                let bytes: Result<Vec<u8>, _> = (0..len)
+                    .map(|i| self.bus.load(addr + i, 8).map(|b| b as u8))
+                    .collect();
+                if let Ok(bytes) = bytes {
+                    self.bus.virtio_console.write_output(&bytes);
+                }
+                if flags as u16 & VIRTQ_DESC_F_NEXT == 0 {
+                    break;
+                }
+                index = next;
+            }
+            self.virtq_push_used(desc_addr, head, 0);
+            used = true;
+        }
+
+        // receiveq, a single device-writable buffer per request
+        let desc_addr = self.bus.virtio_console.desc_addr(CONSOLE_RECEIVEQ);
+        let avail_idx = self.virtq_avail(desc_addr, None) as u16;
+        while self.bus.virtio_console.has_input() {
+            let Some(idx) = self
+                .bus
+                .virtio_console
+                .next_avail(CONSOLE_RECEIVEQ, avail_idx)
+            else {
+                break;
+            };
+            let head = self.virtq_avail(desc_addr, Some(idx));
+            let (addr, len, _, _) = self.virtq_desc(desc_addr, head);
+            let mut n = 0;
+            while n < len {
+                match self.bus.virtio_console.read_input() {
+                    Some(byte) => self.bus.store(addr + n, 8, byte as u64).unwrap(),
+                    None => break,
+                }
+                n += 1;
+            }
+            self.virtq_push_used(desc_addr, head, n);
+            used = true;
+        }
+
+        used
     }
 
     pub fn reg(&self, r: &str) -> u64 {
@@ -1070,30 +3023,141 @@ impl Cpu {
                 }
                 panic!("Invalid register {}", r);
             }
+            "fflags" => self.csr.load(FFLAGS),
+            "fcsr" => self.csr.load(FCSR),
+            "mvendorid" => self.csr.load(MVENDORID),
+            "marchid" => self.csr.load(MARCHID),
+            "mimpid" => self.csr.load(MIMPID),
             "mhartid" => self.csr.load(MHARTID),
             "mstatus" => self.csr.load(MSTATUS),
+            "misa" => self.csr.load(MISA),
             "mtvec" => self.csr.load(MTVEC),
             "mepc" => self.csr.load(MEPC),
             "mcause" => self.csr.load(MCAUSE),
             "mtval" => self.csr.load(MTVAL),
             "medeleg" => self.csr.load(MEDELEG),
+            "mideleg" => self.csr.load(MIDELEG),
+            "mie" => self.csr.load(MIE),
             "mscratch" => self.csr.load(MSCRATCH),
-            "MIP" => self.csr.load(MIP),
+            "mip" | "MIP" => self.csr.load(MIP),
             "mcounteren" => self.csr.load(MCOUNTEREN),
             "sstatus" => self.csr.load(SSTATUS),
+            "sie" => self.csr.load(SIE),
             "stvec" => self.csr.load(STVEC),
             "sepc" => self.csr.load(SEPC),
             "scause" => self.csr.load(SCAUSE),
             "stval" => self.csr.load(STVAL),
             "sscratch" => self.csr.load(SSCRATCH),
-            "SIP" => self.csr.load(SIP),
-            "SATP" => self.csr.load(SATP),
+            "sip" | "SIP" => self.csr.load(SIP),
+            "satp" | "SATP" => self.csr.load(SATP),
             _ => panic!("Invalid register {}", r),
         }
     }
 
     pub fn dump_registers(&self) {
         println!("{:-^80}", "registers");
+        println!("PC: {}", self.format_addr(self.pc));
+        println!("{}", self.format_registers());
+    }
+
+    // Name and offset of the symbol addr is in: the one with the highest start at
+    // or below addr that still covers it, a label without a size covers everything
+    // up to the next symbol.
+    pub fn symbol_at(&self, addr: u64) -> Option<(&str, u64)> {
+        let end = self.symbols.partition_point(|&(start, _, _)| start <= addr);
+        self.symbols[..end]
+            .iter()
+            .rev()
+            .find(|&&(start, size, _)| size == 0 || addr - start < size)
+            .map(|(start, _, name)| (name.as_str(), addr - start))
+    }
+
+    // start of the symbol called name
+    pub fn symbol_address(&self, name: &str) -> Option<u64> {
+        self.symbols
+            .iter()
+            .find(|(_, _, symbol)| symbol == name)
+            .map(|&(start, _, _)| start)
+    }
+
+    // addr in hex, followed by <symbol+offset> when it has one
+    pub fn format_addr(&self, addr: u64) -> String {
+        match self.symbol_at(addr) {
+            Some((name, 0)) => format!("{:#x} <{}>", addr, name),
+            Some((name, offset)) => format!("{:#x} <{}+{:#x}>", addr, name, offset),
+            None => format!("{:#x}", addr),
+        }
+    }
+
+    // file and line the instruction at pc was compiled from, if the program has debug info
+    pub fn source_location(&self, pc: u64) -> Option<(&str, u32)> {
+        self.source_lines
+            .get(&pc)
+            .map(|(file, line)| (file.as_str(), *line))
+    }
+
+    // "PC=0x80000000 inst=0x00b50533 add a0, a0, a1 mode=M a0=0x1 a1=0x2", the
+    // pc, instruction, mode and source registers before inst runs, and the
+    // register it writes
+    fn single_step_line(&self, inst: u64) -> (String, Option<u8>) {
+        let decoded = disasm::decode(inst as u32);
+        let text = match &decoded {
+            Ok(decoded) => disasm::format(decoded),
+            Err(_) => "unknown".to_string(),
+        };
+        let (rd, sources) = match &decoded {
+            Ok(decoded) => disasm::x_registers(decoded),
+            Err(_) => (None, vec![]),
+        };
+        let mut line = format!(
+            "PC={:#x} inst={:#010x} {} mode={}",
+            self.pc,
+            inst,
+            text,
+            &mode_name(self.mode)[..1]
+        );
+        for reg in sources {
+            line += &format!(" {}={:#x}", RVABI[reg as usize], self.regs[reg as usize]);
+        }
+        (line, rd)
+    }
+
+    // ends the single_step_line with what rd holds now, or the exception raised
+    fn print_single_step(
+        &mut self,
+        mut line: String,
+        rd: Option<u8>,
+        result: &Result<u64, Exception>,
+    ) {
+        match (result, rd) {
+            (Err(e), _) => line += &format!(" -> {}", e),
+            (Ok(_), Some(rd)) => {
+                let value = if rd == 0 { 0 } else { self.regs[rd as usize] };
+                line += &format!(" -> {}={:#x}", RVABI[rd as usize], value);
+            }
+            (Ok(_), None) => (),
+        }
+        // a broken output must not stop the emulator
+        let _ = match self.debug_output.as_mut() {
+            Some(out) => writeln!(out, "{}", line),
+            None => writeln!(io::stderr(), "{}", line),
+        };
+    }
+
+    // pc, instruction word and its disassembly, then (file:line) when it's known
+    pub fn trace_line(&self, pc: u64, inst: u64) -> String {
+        let text = match disasm::decode(inst as u32) {
+            Ok(decoded) => disasm::format(&decoded),
+            Err(_) => "unknown".to_string(),
+        };
+        let mut line = format!("{}: {:#010x} {}", self.format_addr(pc), inst, text);
+        if let Some((file, number)) = self.source_location(pc) {
+            line += &format!(" ({}:{})", file, number);
+        }
+        line
+    }
+
+    pub fn format_registers(&self) -> String {
         let mut output = String::new();
         //self.regs[0] = 0;
 
@@ -1111,7 +3175,69 @@ impl Cpu {
             );
             output = output + &line;
         }
-        println!("{}", output);
+        output
+    }
+
+    pub fn dump_csrs(&self) {
+        println!("{:-^80}", "csrs");
+        println!("{}", self.format_csrs());
+    }
+
+    // non-zero CSRs, one per line
+    pub fn format_csrs(&self) -> String {
+        let mut output = String::new();
+        for (addr, name) in CSR_NAMES.iter().enumerate() {
+            let value = self.csr.load(addr);
+            if value == 0 {
+                continue;
+            }
+            let line = format!("{:#05x}({:^10}) = {:<#18x}\n", addr, name, value);
+            output = output + &line;
+        }
+        output
+    }
+
+    pub fn print_history(&self) {
+        println!("{:-^80}", "history");
+        for (i, (pc, inst)) in self.history.iter().enumerate() {
+            println!("{:3}: {}", i, self.trace_line(*pc, *inst));
+        }
+    }
+
+    // Return addresses of the frames on the stack, innermost first. Follows the
+    // frame pointer chain: each frame keeps ra at fp - 8 and the caller's fp at
+    // fp - 16, the walk stops at fp = 0 or outside dram.
+    pub fn backtrace(&self) -> Vec<u64> {
+        let mut addrs = Vec::new();
+        let mut fp = self.regs[8];
+        // a corrupted stack can link back to itself
+        while fp != 0 && fp >= self.config.dram_base && addrs.len() < MAX_BACKTRACE {
+            let frame = (
+                self.bus.peek(fp.wrapping_sub(8), 64),
+                self.bus.peek(fp.wrapping_sub(16), 64),
+            );
+            let (Some(ra), Some(prev_fp)) = frame else {
+                break;
+            };
+            addrs.push(ra);
+            fp = prev_fp;
+        }
+        addrs
+    }
+
+    // backtrace with <symbol+offset> for addresses the ELF symbols cover
+    pub fn backtrace_symbolic(&self) -> Vec<String> {
+        self.backtrace()
+            .into_iter()
+            .map(|addr| self.format_addr(addr))
+            .collect()
+    }
+
+    pub fn print_backtrace(&self) {
+        println!("{:-^80}", "backtrace");
+        for (i, frame) in self.backtrace_symbolic().iter().enumerate() {
+            println!("{:3}: {}", i, frame);
+        }
     }
 }
 
@@ -1145,11 +3271,13 @@ fn get_i_imm(inst: u64) -> u64 {
     return ((((inst & I_IMMEDIATE) as i32) as i64) >> 20) as u64;
 }
 
+// imm[20|10:1|11|19:12] = inst[31|30:21|20|19:12]
+// inst[31] is sign-extended from bit 20 upwards, the rest are zero-extended into place
 fn get_j_imm(inst: u64) -> u64 {
     return ((inst & 0x80000000) as i32 as i64 >> 11) as u64
         | (inst & 0xff000)
         | ((inst >> 9) & 0x800)
-        | (inst >> 20) & 0x7fe;
+        | ((inst >> 20) & 0x7fe);
 }
 
 fn get_b_imm(inst: u64) -> u64 {
@@ -1162,3 +3290,59 @@ fn get_b_imm(inst: u64) -> u64 {
 fn get_s_imm(inst: u64) -> u64 {
     return (((inst & 0xfe000000) as i32 as i64 >> 20) as u64) | ((inst >> 7) & 0x1f);
 }
+
+// The RV64 instruction doing inst's work for RV32 on sign-extended registers,
+// None for the ones RV32 doesn't have
+fn rv32_inst(inst: u64) -> Option<u64> {
+    let (funct7, _, _, funct3, _, opcode) = decode_r(inst as u32);
+    let word_op = (inst & !0x7f) | 0x3b;
+    match (opcode, funct3) {
+        // the w forms, ld, lwu, sd and the .d atomics
+        (0x1b | 0x3b, _) | (0x03, 3 | 6) | (0x23, 3) | (0x2f, 3) => None,
+        // float, vector and compressed
+        (0x07 | 0x27 | 0x43 | 0x47 | 0x4b | 0x4f | 0x53 | 0x57, _) => None,
+        _ if inst & 0b11 != 0b11 => None,
+        // shift amounts are 5 bits, the w shifts only look at the low 32 bits
+        (0x13, 1 | 5) if inst & (1 << 25) != 0 => None,
+        (0x13, 1 | 5) => Some((inst & !0x7f) | 0x1b),
+        (0x33, 1 | 5) if funct7 & !0x20 == 0 => Some(word_op),
+        // divu and remu want zero-extended operands, divw and the others take 32 bits
+        (0x33, 4..=7) if funct7 == 1 => Some(word_op),
+        _ => Some(inst),
+    }
+}
+
+// mask for xepc reads, bit 1 is only meaningful with IALIGN=16 (C extension)
+pub fn pc_align_mask(c_enabled: bool) -> u64 {
+    if c_enabled {
+        !0b01
+    } else {
+        !0b11
+    }
+}
+
+pub fn mode_name(mode: Mode) -> &'static str {
+    match mode {
+        User => "User",
+        Supervisor => "Supervisor",
+        Machine => "Machine",
+        _ => "Reserved",
+    }
+}
+
+// reverse the low size bits of value byte by byte
+pub fn swap_bytes(value: u64, size: u64) -> u64 {
+    if size <= 8 {
+        return value;
+    }
+    value.swap_bytes() >> (64 - size)
+}
+
+// page fault matching the access that caused it
+fn page_fault(addr: u64, access_type: AccessType) -> Exception {
+    match access_type {
+        AccessType::Instruction => Exception::InstructionPageFault(addr),
+        AccessType::Load => Exception::LoadPageFault(addr),
+        AccessType::Store => Exception::StoreAMOPageFault(addr),
+    }
+}
@@ -0,0 +1,366 @@
+// Zkn scalar cryptography: AES rounds for RV64 (Zkne, Zknd) and SHA-2 sigma functions (Zknh),
+// plus the Zbkb bit permutations
+
+const SBOX: [u8; 256] = [
+    0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,
+    0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,
+    0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,
+    0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07, 0x12, 0x80, 0xe2, 0xeb, 0x27, 0xb2, 0x75,
+    0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0, 0x52, 0x3b, 0xd6, 0xb3, 0x29, 0xe3, 0x2f, 0x84,
+    0x53, 0xd1, 0x00, 0xed, 0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58, 0xcf,
+    0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f, 0x50, 0x3c, 0x9f, 0xa8,
+    0x51, 0xa3, 0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5, 0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2,
+    0xcd, 0x0c, 0x13, 0xec, 0x5f, 0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73,
+    0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88, 0x46, 0xee, 0xb8, 0x14, 0xde, 0x5e, 0x0b, 0xdb,
+    0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c, 0xc2, 0xd3, 0xac, 0x62, 0x91, 0x95, 0xe4, 0x79,
+    0xe7, 0xc8, 0x37, 0x6d, 0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a, 0xae, 0x08,
+    0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f, 0x4b, 0xbd, 0x8b, 0x8a,
+    0x70, 0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e, 0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e,
+    0xe1, 0xf8, 0x98, 0x11, 0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf,
+    0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16,
+];
+
+const INV_SBOX: [u8; 256] = [
+    0x52, 0x09, 0x6a, 0xd5, 0x30, 0x36, 0xa5, 0x38, 0xbf, 0x40, 0xa3, 0x9e, 0x81, 0xf3, 0xd7, 0xfb,
+    0x7c, 0xe3, 0x39, 0x82, 0x9b, 0x2f, 0xff, 0x87, 0x34, 0x8e, 0x43, 0x44, 0xc4, 0xde, 0xe9, 0xcb,
+    0x54, 0x7b, 0x94, 0x32, 0xa6, 0xc2, 0x23, 0x3d, 0xee, 0x4c, 0x95, 0x0b, 0x42, 0xfa, 0xc3, 0x4e,
+    0x08, 0x2e, 0xa1, 0x66, 0x28, 0xd9, 0x24, 0xb2, 0x76, 0x5b, 0xa2, 0x49, 0x6d, 0x8b, 0xd1, 0x25,
+    0x72, 0xf8, 0xf6, 0x64, 0x86, 0x68, 0x98, 0x16, 0xd4, 0xa4, 0x5c, 0xcc, 0x5d, 0x65, 0xb6, 0x92,
+    0x6c, 0x70, 0x48, 0x50, 0xfd, 0xed, 0xb9, 0xda, 0x5e, 0x15, 0x46, 0x57, 0xa7, 0x8d, 0x9d, 0x84,
+    0x90, 0xd8, 0xab, 0x00, 0x8c, 0xbc, 0xd3, 0x0a, 0xf7, 0xe4, 0x58, 0x05, 0xb8, 0xb3, 0x45, 0x06,
+    0xd0, 0x2c, 0x1e, 0x8f, 0xca, 0x3f, 0x0f, 0x02, 0xc1, 0xaf, 0xbd, 0x03, 0x01, 0x13, 0x8a, 0x6b,
+    0x3a, 0x91, 0x11, 0x41, 0x4f, 0x67, 0xdc, 0xea, 0x97, 0xf2, 0xcf, 0xce, 0xf0, 0xb4, 0xe6, 0x73,
+    0x96, 0xac, 0x74, 0x22, 0xe7, 0xad, 0x35, 0x85, 0xe2, 0xf9, 0x37, 0xe8, 0x1c, 0x75, 0xdf, 0x6e,
+    0x47, 0xf1, 0x1a, 0x71, 0x1d, 0x29, 0xc5, 0x89, 0x6f, 0xb7, 0x62, 0x0e, 0xaa, 0x18, 0xbe, 0x1b,
+    0xfc, 0x56, 0x3e, 0x4b, 0xc6, 0xd2, 0x79, 0x20, 0x9a, 0xdb, 0xc0, 0xfe, 0x78, 0xcd, 0x5a, 0xf4,
+    0x1f, 0xdd, 0xa8, 0x33, 0x88, 0x07, 0xc7, 0x31, 0xb1, 0x12, 0x10, 0x59, 0x27, 0x80, 0xec, 0x5f,
+    0x60, 0x51, 0x7f, 0xa9, 0x19, 0xb5, 0x4a, 0x0d, 0x2d, 0xe5, 0x7a, 0x9f, 0x93, 0xc9, 0x9c, 0xef,
+    0xa0, 0xe0, 0x3b, 0x4d, 0xae, 0x2a, 0xf5, 0xb0, 0xc8, 0xeb, 0xbb, 0x3c, 0x83, 0x53, 0x99, 0x61,
+    0x17, 0x2b, 0x04, 0x7e, 0xba, 0x77, 0xd6, 0x26, 0xe1, 0x69, 0x14, 0x63, 0x55, 0x21, 0x0c, 0x7d,
+];
+
+// round constants for aes64ks1i, rnum 0xa has none
+const RCON: [u8; 11] = [
+    0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1b, 0x36, 0x00,
+];
+
+fn byte(x: u64, i: u32) -> u64 {
+    (x >> (8 * i)) & 0xff
+}
+
+// bytes listed least significant first
+fn from_bytes(bytes: [u64; 8]) -> u64 {
+    bytes.iter().rev().fold(0, |acc, b| (acc << 8) | b)
+}
+
+fn sub_bytes(x: u64, sbox: &[u8; 256]) -> u64 {
+    from_bytes(core::array::from_fn(|i| {
+        sbox[byte(x, i as u32) as usize] as u64
+    }))
+}
+
+// the half of ShiftRows that lands in one register, rs1 holds columns 0, 1, rs2 columns 2, 3
+fn shift_rows_fwd(rs1: u64, rs2: u64) -> u64 {
+    from_bytes([
+        byte(rs1, 0),
+        byte(rs1, 5),
+        byte(rs2, 2),
+        byte(rs2, 7),
+        byte(rs1, 4),
+        byte(rs2, 1),
+        byte(rs2, 6),
+        byte(rs1, 3),
+    ])
+}
+
+fn shift_rows_inv(rs1: u64, rs2: u64) -> u64 {
+    from_bytes([
+        byte(rs1, 0),
+        byte(rs2, 5),
+        byte(rs2, 2),
+        byte(rs1, 7),
+        byte(rs1, 4),
+        byte(rs1, 1),
+        byte(rs2, 6),
+        byte(rs2, 3),
+    ])
+}
+
+// multiply in GF(2^8) modulo x^8 + x^4 + x^3 + x + 1
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        a = (a << 1) ^ if a & 0x80 != 0 { 0x1b } else { 0 };
+        b >>= 1;
+    }
+    product
+}
+
+// MixColumns on one 32-bit column, coefficients of the first output byte
+fn mix_column(column: u32, coefficients: [u8; 4]) -> u32 {
+    let s = column.to_le_bytes();
+    let mut out = [0u8; 4];
+    for (i, b) in out.iter_mut().enumerate() {
+        for j in 0..4 {
+            *b ^= gf_mul(s[j], coefficients[(j + 4 - i) % 4]);
+        }
+    }
+    u32::from_le_bytes(out)
+}
+
+fn mix_columns(x: u64, coefficients: [u8; 4]) -> u64 {
+    let low = mix_column(x as u32, coefficients) as u64;
+    let high = mix_column((x >> 32) as u32, coefficients) as u64;
+    (high << 32) | low
+}
+
+const MIX_FWD: [u8; 4] = [2, 3, 1, 1];
+const MIX_INV: [u8; 4] = [0xe, 0xb, 0xd, 0x9];
+
+// aes64es: ShiftRows and SubBytes of the final round
+pub fn aes64es(rs1: u64, rs2: u64) -> u64 {
+    sub_bytes(shift_rows_fwd(rs1, rs2), &SBOX)
+}
+
+// aes64esm: middle round, ShiftRows, SubBytes and MixColumns
+pub fn aes64esm(rs1: u64, rs2: u64) -> u64 {
+    mix_columns(aes64es(rs1, rs2), MIX_FWD)
+}
+
+pub fn aes64ds(rs1: u64, rs2: u64) -> u64 {
+    sub_bytes(shift_rows_inv(rs1, rs2), &INV_SBOX)
+}
+
+pub fn aes64dsm(rs1: u64, rs2: u64) -> u64 {
+    mix_columns(aes64ds(rs1, rs2), MIX_INV)
+}
+
+// InvMixColumns, turns an encryption round key into a decryption one
+pub fn aes64im(rs1: u64) -> u64 {
+    mix_columns(rs1, MIX_INV)
+}
+
+// None for a reserved rnum
+pub fn aes64ks1i(rs1: u64, rnum: u64) -> Option<u64> {
+    let rcon = *RCON.get(rnum as usize)? as u32;
+    let mut word = (rs1 >> 32) as u32;
+    if rnum != 0xa {
+        word = word.rotate_right(8);
+    }
+    let word = sub_bytes(word as u64, &SBOX) as u32 ^ rcon;
+    Some(((word as u64) << 32) | word as u64)
+}
+
+pub fn aes64ks2(rs1: u64, rs2: u64) -> u64 {
+    let w0 = (rs1 >> 32) as u32 ^ rs2 as u32;
+    let w1 = w0 ^ (rs2 >> 32) as u32;
+    ((w1 as u64) << 32) | w0 as u64
+}
+
+// SHA-256 functions work on the low word, results are sign-extended
+pub fn sha256sig0(x: u64) -> u64 {
+    let x = x as u32;
+    (x.rotate_right(7) ^ x.rotate_right(18) ^ (x >> 3)) as i32 as u64
+}
+
+pub fn sha256sig1(x: u64) -> u64 {
+    let x = x as u32;
+    (x.rotate_right(17) ^ x.rotate_right(19) ^ (x >> 10)) as i32 as u64
+}
+
+pub fn sha256sum0(x: u64) -> u64 {
+    let x = x as u32;
+    (x.rotate_right(2) ^ x.rotate_right(13) ^ x.rotate_right(22)) as i32 as u64
+}
+
+pub fn sha256sum1(x: u64) -> u64 {
+    let x = x as u32;
+    (x.rotate_right(6) ^ x.rotate_right(11) ^ x.rotate_right(25)) as i32 as u64
+}
+
+pub fn sha512sig0(x: u64) -> u64 {
+    x.rotate_right(1) ^ x.rotate_right(8) ^ (x >> 7)
+}
+
+pub fn sha512sig1(x: u64) -> u64 {
+    x.rotate_right(19) ^ x.rotate_right(61) ^ (x >> 6)
+}
+
+pub fn sha512sum0(x: u64) -> u64 {
+    x.rotate_right(28) ^ x.rotate_right(34) ^ x.rotate_right(39)
+}
+
+pub fn sha512sum1(x: u64) -> u64 {
+    x.rotate_right(14) ^ x.rotate_right(18) ^ x.rotate_right(41)
+}
+
+// Zbkb: reverse the bits of every byte
+pub fn brev8(x: u64) -> u64 {
+    from_bytes(core::array::from_fn(|i| {
+        (byte(x, i as u32) as u8).reverse_bits() as u64
+    }))
+}
+
+// the low halves of rs1 and rs2, rs1 in the low word
+pub fn pack(rs1: u64, rs2: u64) -> u64 {
+    (rs1 & 0xffff_ffff) | rs2 << 32
+}
+
+pub fn packh(rs1: u64, rs2: u64) -> u64 {
+    (rs1 & 0xff) | (rs2 & 0xff) << 8
+}
+
+// Bit i of the low word goes to bit 2i, bit i of the high word to bit 2i + 1.
+// zip and unzip are RV32-only in the spec, this is the same permutation on 64 bits.
+pub fn zip(x: u64) -> u64 {
+    let mut result = 0;
+    for i in 0..32 {
+        result |= ((x >> i) & 1) << (2 * i);
+        result |= ((x >> (32 + i)) & 1) << (2 * i + 1);
+    }
+    result
+}
+
+pub fn unzip(x: u64) -> u64 {
+    let mut result = 0;
+    for i in 0..32 {
+        result |= ((x >> (2 * i)) & 1) << i;
+        result |= ((x >> (2 * i + 1)) & 1) << (32 + i);
+    }
+    result
+}
+
+// Zvkn works on 128-bit element groups, (bits 63:0, bits 127:64) of the AES state
+
+// vaesem / vaesef: ShiftRows, SubBytes, MixColumns for middle rounds, then AddRoundKey
+pub fn aes_enc_round(state: (u64, u64), key: (u64, u64), middle: bool) -> (u64, u64) {
+    let (lo, hi) = state;
+    let (lo, hi) = match middle {
+        true => (aes64esm(lo, hi), aes64esm(hi, lo)),
+        false => (aes64es(lo, hi), aes64es(hi, lo)),
+    };
+    (lo ^ key.0, hi ^ key.1)
+}
+
+// vaesdm / vaesdf: InvShiftRows, InvSubBytes, AddRoundKey, then InvMixColumns for middle rounds
+pub fn aes_dec_round(state: (u64, u64), key: (u64, u64), middle: bool) -> (u64, u64) {
+    let (lo, hi) = state;
+    let (lo, hi) = (aes64ds(lo, hi) ^ key.0, aes64ds(hi, lo) ^ key.1);
+    match middle {
+        true => (aes64im(lo), aes64im(hi)),
+        false => (lo, hi),
+    }
+}
+
+// next four key words, w[0] from temp, each later one xor the one before
+fn aes_key_words(temp: u32, prev: (u64, u64)) -> (u64, u64) {
+    let w0 = temp ^ prev.0 as u32;
+    let w1 = w0 ^ (prev.0 >> 32) as u32;
+    let w2 = w1 ^ prev.1 as u32;
+    let w3 = w2 ^ (prev.1 >> 32) as u32;
+    (
+        ((w1 as u64) << 32) | w0 as u64,
+        ((w3 as u64) << 32) | w2 as u64,
+    )
+}
+
+// vaeskf1: AES-128 round key rnd (1-10) from the previous one, others have bit 3 flipped
+pub fn aes128_key_round(key: (u64, u64), rnd: u64) -> (u64, u64) {
+    let rnd = if rnd == 0 || rnd > 10 { rnd ^ 8 } else { rnd };
+    let temp = aes64ks1i(key.1, rnd - 1).unwrap() as u32;
+    aes_key_words(temp, key)
+}
+
+// vaeskf2: AES-256 round key rnd (2-14) from the two before it, others have bit 3 flipped.
+// Odd rounds only take SubWord of the last word, no RotWord or round constant.
+pub fn aes256_key_round(key_b: (u64, u64), key: (u64, u64), rnd: u64) -> (u64, u64) {
+    let rnd = if !(2..=14).contains(&rnd) {
+        rnd ^ 8
+    } else {
+        rnd
+    };
+    let rnum = match rnd % 2 {
+        1 => 0xa,
+        _ => rnd / 2 - 1,
+    };
+    let temp = aes64ks1i(key.1, rnum).unwrap() as u32;
+    aes_key_words(temp, key_b)
+}
+
+// vsha2ms: the next four message schedule words W[16..19] from
+// w0 = W[0..3], w9 = {W[4], W[9], W[10], W[11]} and w12 = W[12..15], SHA-256 when sew is 32
+pub fn sha2_message_schedule(w0: [u64; 4], w9: [u64; 4], w12: [u64; 4], sew: u64) -> [u64; 4] {
+    let sig0 = |x| {
+        if sew == 32 {
+            sha256sig0(x)
+        } else {
+            sha512sig0(x)
+        }
+    };
+    let sig1 = |x| {
+        if sew == 32 {
+            sha256sig1(x)
+        } else {
+            sha512sig1(x)
+        }
+    };
+    let mut w = [0u64; 20];
+    w[..4].copy_from_slice(&w0);
+    w[4] = w9[0];
+    w[9..12].copy_from_slice(&w9[1..]);
+    w[12..16].copy_from_slice(&w12);
+    for i in 16..20 {
+        w[i] = sig1(w[i - 2])
+            .wrapping_add(w[i - 7])
+            .wrapping_add(sig0(w[i - 15]))
+            .wrapping_add(w[i - 16]);
+        if sew == 32 {
+            w[i] &= 0xffff_ffff;
+        }
+    }
+    [w[16], w[17], w[18], w[19]]
+}
+
+// vsha2ch / vsha2cl: two compression rounds. abef is {f, e, b, a} and cdgh {h, g, d, c}
+// from element 0 up, words are the two message words plus round constants.
+// Returns the new {f, e, b, a}.
+pub fn sha2_compress(abef: [u64; 4], cdgh: [u64; 4], words: [u64; 2], sew: u64) -> [u64; 4] {
+    let sum0 = |x| {
+        if sew == 32 {
+            sha256sum0(x)
+        } else {
+            sha512sum0(x)
+        }
+    };
+    let sum1 = |x| {
+        if sew == 32 {
+            sha256sum1(x)
+        } else {
+            sha512sum1(x)
+        }
+    };
+    let mask = match sew {
+        32 => 0xffff_ffff,
+        _ => u64::MAX,
+    };
+    let [mut f, mut e, mut b, mut a] = abef;
+    let [mut h, mut g, mut d, mut c] = cdgh;
+    for w in words {
+        let ch = (e & f) ^ (!e & g);
+        let maj = (a & b) ^ (a & c) ^ (b & c);
+        let t1 = h.wrapping_add(sum1(e)).wrapping_add(ch).wrapping_add(w) & mask;
+        let t2 = sum0(a).wrapping_add(maj) & mask;
+        h = g;
+        g = f;
+        f = e;
+        e = d.wrapping_add(t1) & mask;
+        d = c;
+        c = b;
+        b = a;
+        a = t1.wrapping_add(t2) & mask;
+    }
+    [f, e, b, a]
+}
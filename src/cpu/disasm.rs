@@ -0,0 +1,1933 @@
+// Instructions decoded into their fields, for tools that reason about programs
+// without executing them. decode covers everything Cpu::execute handles, whether
+// or not the extension it belongs to is enabled.
+use crate::{cpu::cpu::RVABI, csr::CSR_NAMES, dram::AmoOp, exept::Exception};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadWidth {
+    B,
+    H,
+    W,
+    D,
+    Bu,
+    Hu,
+    Wu,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreWidth {
+    B,
+    H,
+    W,
+    D,
+}
+
+// .w or .d of the A extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AmoWidth {
+    W,
+    D,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BranchCond {
+    Eq,
+    Ne,
+    Lt,
+    Ge,
+    Ltu,
+    Geu,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CsrOp {
+    Rw,
+    Rs,
+    Rc,
+}
+
+// vaes<op>.vv / .vs, Z only comes as .vs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VaesOp {
+    Dm,
+    Df,
+    Em,
+    Ef,
+    Z,
+}
+
+// Register fields are register numbers, immediates are sign-extended. Lui and
+// Auipc hold the immediate shifted into place, branches and jumps the offset
+// from the instruction. rm is the rounding mode field, 7 is dynamic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodedInst {
+    // RV64I
+    Lui {
+        rd: u8,
+        imm: i32,
+    },
+    Auipc {
+        rd: u8,
+        imm: i32,
+    },
+    Jal {
+        rd: u8,
+        offset: i32,
+    },
+    Jalr {
+        rd: u8,
+        rs1: u8,
+        offset: i32,
+    },
+    Branch {
+        cond: BranchCond,
+        rs1: u8,
+        rs2: u8,
+        offset: i32,
+    },
+    Load {
+        rd: u8,
+        rs1: u8,
+        offset: i32,
+        width: LoadWidth,
+    },
+    Store {
+        rs1: u8,
+        rs2: u8,
+        offset: i32,
+        width: StoreWidth,
+    },
+    Addi {
+        rd: u8,
+        rs1: u8,
+        imm: i32,
+    },
+    Slti {
+        rd: u8,
+        rs1: u8,
+        imm: i32,
+    },
+    Sltiu {
+        rd: u8,
+        rs1: u8,
+        imm: i32,
+    },
+    Xori {
+        rd: u8,
+        rs1: u8,
+        imm: i32,
+    },
+    Ori {
+        rd: u8,
+        rs1: u8,
+        imm: i32,
+    },
+    Andi {
+        rd: u8,
+        rs1: u8,
+        imm: i32,
+    },
+    Slli {
+        rd: u8,
+        rs1: u8,
+        shamt: u8,
+    },
+    Srli {
+        rd: u8,
+        rs1: u8,
+        shamt: u8,
+    },
+    Srai {
+        rd: u8,
+        rs1: u8,
+        shamt: u8,
+    },
+    Addiw {
+        rd: u8,
+        rs1: u8,
+        imm: i32,
+    },
+    Slliw {
+        rd: u8,
+        rs1: u8,
+        shamt: u8,
+    },
+    Srliw {
+        rd: u8,
+        rs1: u8,
+        shamt: u8,
+    },
+    Sraiw {
+        rd: u8,
+        rs1: u8,
+        shamt: u8,
+    },
+    Add {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Sub {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Sll {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Slt {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Sltu {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Xor {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Srl {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Sra {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Or {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    And {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Addw {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Subw {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Sllw {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Srlw {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Sraw {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    // pred and succ are the iorw bits
+    Fence {
+        pred: u8,
+        succ: u8,
+    },
+    Pause,
+    Ecall,
+    Ebreak,
+    // Zifencei
+    FenceI,
+    // privileged
+    Sret,
+    Mret,
+    Wfi,
+    SfenceVma {
+        rs1: u8,
+        rs2: u8,
+    },
+    // Svinval
+    SinvalVma {
+        rs1: u8,
+        rs2: u8,
+    },
+    SfenceWInval,
+    SfenceInvalIr,
+    // Zicsr
+    Csr {
+        op: CsrOp,
+        rd: u8,
+        rs1: u8,
+        csr: u16,
+    },
+    Csri {
+        op: CsrOp,
+        rd: u8,
+        zimm: u8,
+        csr: u16,
+    },
+    // M
+    Mul {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Mulh {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Mulhsu {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Mulhu {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Div {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Divu {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Rem {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Remu {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Mulw {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Divw {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Divuw {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Remw {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Remuw {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    // A
+    Lr {
+        width: AmoWidth,
+        rd: u8,
+        rs1: u8,
+        aq: bool,
+        rl: bool,
+    },
+    Sc {
+        width: AmoWidth,
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+        aq: bool,
+        rl: bool,
+    },
+    Amo {
+        op: AmoOp,
+        width: AmoWidth,
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+        aq: bool,
+        rl: bool,
+    },
+    // Zba
+    Sh1add {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Sh2add {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Sh3add {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Sh1addUw {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Sh2addUw {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Sh3addUw {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    // Zicond
+    CzeroEqz {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    CzeroNez {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    // Zbkb
+    Pack {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Packh {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Brev8 {
+        rd: u8,
+        rs1: u8,
+    },
+    Zip {
+        rd: u8,
+        rs1: u8,
+    },
+    Unzip {
+        rd: u8,
+        rs1: u8,
+    },
+    // Zkn
+    Aes64es {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Aes64esm {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Aes64ds {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Aes64dsm {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Aes64ks2 {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Aes64im {
+        rd: u8,
+        rs1: u8,
+    },
+    Aes64ks1i {
+        rd: u8,
+        rs1: u8,
+        rnum: u8,
+    },
+    Sha256sig0 {
+        rd: u8,
+        rs1: u8,
+    },
+    Sha256sig1 {
+        rd: u8,
+        rs1: u8,
+    },
+    Sha256sum0 {
+        rd: u8,
+        rs1: u8,
+    },
+    Sha256sum1 {
+        rd: u8,
+        rs1: u8,
+    },
+    Sha512sig0 {
+        rd: u8,
+        rs1: u8,
+    },
+    Sha512sig1 {
+        rd: u8,
+        rs1: u8,
+    },
+    Sha512sum0 {
+        rd: u8,
+        rs1: u8,
+    },
+    Sha512sum1 {
+        rd: u8,
+        rs1: u8,
+    },
+    // Zicbom / Zicboz
+    CboInval {
+        rs1: u8,
+    },
+    CboClean {
+        rs1: u8,
+    },
+    CboFlush {
+        rs1: u8,
+    },
+    CboZero {
+        rs1: u8,
+    },
+    // Zawrs
+    WrsNto,
+    WrsSto,
+    // Zfh, on the f registers
+    Flh {
+        rd: u8,
+        rs1: u8,
+        offset: i32,
+    },
+    Fsh {
+        rs1: u8,
+        rs2: u8,
+        offset: i32,
+    },
+    FaddH {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+        rm: u8,
+    },
+    FsubH {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+        rm: u8,
+    },
+    FmulH {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+        rm: u8,
+    },
+    FdivH {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+        rm: u8,
+    },
+    FsqrtH {
+        rd: u8,
+        rs1: u8,
+        rm: u8,
+    },
+    FcvtSH {
+        rd: u8,
+        rs1: u8,
+        rm: u8,
+    },
+    FcvtDH {
+        rd: u8,
+        rs1: u8,
+        rm: u8,
+    },
+    FcvtHS {
+        rd: u8,
+        rs1: u8,
+        rm: u8,
+    },
+    FcvtHD {
+        rd: u8,
+        rs1: u8,
+        rm: u8,
+    },
+    // fmv.x.h writes an x register, fmv.h.x reads one
+    FmvXH {
+        rd: u8,
+        rs1: u8,
+    },
+    FmvHX {
+        rd: u8,
+        rs1: u8,
+    },
+    // Zfinx, on the x registers
+    FaddS {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+        rm: u8,
+    },
+    FsubS {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+        rm: u8,
+    },
+    FmulS {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+        rm: u8,
+    },
+    FdivS {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+        rm: u8,
+    },
+    FsqrtS {
+        rd: u8,
+        rs1: u8,
+        rm: u8,
+    },
+    // V, vm is true for unmasked instructions
+    Vsetvli {
+        rd: u8,
+        rs1: u8,
+        vtypei: u16,
+    },
+    Vsetivli {
+        rd: u8,
+        uimm: u8,
+        vtypei: u16,
+    },
+    Vsetvl {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Vle {
+        eew: u8,
+        vd: u8,
+        rs1: u8,
+        vm: bool,
+    },
+    Vse {
+        eew: u8,
+        vs3: u8,
+        rs1: u8,
+        vm: bool,
+    },
+    VaddVv {
+        vd: u8,
+        vs2: u8,
+        vs1: u8,
+        vm: bool,
+    },
+    VaddVx {
+        vd: u8,
+        vs2: u8,
+        rs1: u8,
+        vm: bool,
+    },
+    // Zvkn, vs is the .vs form taking round key group 0
+    Vaes {
+        op: VaesOp,
+        vs: bool,
+        vd: u8,
+        vs2: u8,
+    },
+    Vaeskf1 {
+        vd: u8,
+        vs2: u8,
+        uimm: u8,
+    },
+    Vaeskf2 {
+        vd: u8,
+        vs2: u8,
+        uimm: u8,
+    },
+    Vsha2ms {
+        vd: u8,
+        vs2: u8,
+        vs1: u8,
+    },
+    Vsha2ch {
+        vd: u8,
+        vs2: u8,
+        vs1: u8,
+    },
+    Vsha2cl {
+        vd: u8,
+        vs2: u8,
+        vs1: u8,
+    },
+}
+
+use DecodedInst::*;
+
+fn i_imm(inst: u32) -> i32 {
+    inst as i32 >> 20
+}
+
+fn s_imm(inst: u32) -> i32 {
+    (inst as i32 >> 25 << 5) | ((inst >> 7) & 0x1f) as i32
+}
+
+// imm[12|10:5] = inst[31:25], imm[4:1|11] = inst[11:7]
+fn b_imm(inst: u32) -> i32 {
+    (inst as i32 >> 31 << 12)
+        | (((inst >> 7) & 1) << 11) as i32
+        | (((inst >> 25) & 0x3f) << 5) as i32
+        | (((inst >> 8) & 0xf) << 1) as i32
+}
+
+// imm[20|10:1|11|19:12] = inst[31|30:21|20|19:12]
+fn j_imm(inst: u32) -> i32 {
+    (inst as i32 >> 31 << 20)
+        | (inst & 0xff000) as i32
+        | (((inst >> 20) & 1) << 11) as i32
+        | (((inst >> 21) & 0x3ff) << 1) as i32
+}
+
+// IllegalInstruction for anything Cpu::execute doesn't handle
+pub fn decode(inst: u32) -> Result<DecodedInst, Exception> {
+    // the low two bits of a 32-bit instruction are 0b11, anything else is 16 bits wide
+    match inst & 0b11 {
+        0b00 => return decode_c_q0(inst as u16),
+        0b01 => return decode_c_q1(inst as u16),
+        0b10 => return decode_c_q2(inst as u16),
+        _ => (),
+    }
+    let opcode = inst & 0x7f;
+    let rd = ((inst >> 7) & 0x1f) as u8;
+    let funct3 = (inst >> 12) & 0x7;
+    let rs1 = ((inst >> 15) & 0x1f) as u8;
+    let rs2 = ((inst >> 20) & 0x1f) as u8;
+    let funct7 = inst >> 25;
+    let illegal = Exception::IllegalInstruction(inst as u64);
+    let shamt = ((inst >> 20) & 0x3f) as u8;
+    let rm = funct3 as u8;
+    let vm = (inst >> 25) & 1 == 1;
+
+    let decoded = match opcode {
+        0x03 => {
+            let width = match funct3 {
+                0x0 => LoadWidth::B,
+                0x1 => LoadWidth::H,
+                0x2 => LoadWidth::W,
+                0x3 => LoadWidth::D,
+                0x4 => LoadWidth::Bu,
+                0x5 => LoadWidth::Hu,
+                0x6 => LoadWidth::Wu,
+                _ => return Err(illegal),
+            };
+            Load {
+                rd,
+                rs1,
+                offset: i_imm(inst),
+                width,
+            }
+        }
+        0x07 => match funct3 {
+            0x1 => Flh {
+                rd,
+                rs1,
+                offset: i_imm(inst),
+            },
+            // unit stride only, nf, mew, mop and lumop all zero
+            0x0 | 0x5..=0x7 if inst >> 26 == 0 && rs2 == 0 => Vle {
+                eew: vector_eew(funct3),
+                vd: rd,
+                rs1,
+                vm,
+            },
+            _ => return Err(illegal),
+        },
+        0x0f => match funct3 {
+            0x0 if (inst >> 20) & 0xfff == 0x010 && rd == 0 && rs1 == 0 => Pause,
+            0x0 => Fence {
+                pred: ((inst >> 24) & 0xf) as u8,
+                succ: ((inst >> 20) & 0xf) as u8,
+            },
+            0x1 => FenceI,
+            0x2 => match i_imm(inst) {
+                0x0 => CboInval { rs1 },
+                0x1 => CboClean { rs1 },
+                0x2 => CboFlush { rs1 },
+                0x4 => CboZero { rs1 },
+                _ => return Err(illegal),
+            },
+            _ => return Err(illegal),
+        },
+        0x13 => {
+            let imm = i_imm(inst);
+            match funct3 {
+                0x0 => Addi { rd, rs1, imm },
+                0x1 => match (funct7, rs2) {
+                    (0x0 | 0x1, _) => Slli { rd, rs1, shamt },
+                    (0x08, 0x0) => Sha256sum0 { rd, rs1 },
+                    (0x08, 0x1) => Sha256sum1 { rd, rs1 },
+                    (0x08, 0x2) => Sha256sig0 { rd, rs1 },
+                    (0x08, 0x3) => Sha256sig1 { rd, rs1 },
+                    (0x08, 0x4) => Sha512sum0 { rd, rs1 },
+                    (0x08, 0x5) => Sha512sum1 { rd, rs1 },
+                    (0x08, 0x6) => Sha512sig0 { rd, rs1 },
+                    (0x08, 0x7) => Sha512sig1 { rd, rs1 },
+                    (0x18, 0x0) => Aes64im { rd, rs1 },
+                    (0x04, 0x0f) => Zip { rd, rs1 },
+                    // rnum above 0xa is reserved
+                    (0x18, 0x10..=0x1a) => Aes64ks1i {
+                        rd,
+                        rs1,
+                        rnum: rs2 & 0xf,
+                    },
+                    _ => return Err(illegal),
+                },
+                0x2 => Slti { rd, rs1, imm },
+                0x3 => Sltiu { rd, rs1, imm },
+                0x4 => Xori { rd, rs1, imm },
+                0x5 => match (funct7, rs2) {
+                    (0x34, 0x07) => Brev8 { rd, rs1 },
+                    (0x04, 0x0f) => Unzip { rd, rs1 },
+                    (0x00 | 0x01, _) => Srli { rd, rs1, shamt },
+                    (0x20 | 0x21, _) => Srai { rd, rs1, shamt },
+                    _ => return Err(illegal),
+                },
+                0x6 => Ori { rd, rs1, imm },
+                0x7 => Andi { rd, rs1, imm },
+                _ => return Err(illegal),
+            }
+        }
+        0x17 => Auipc {
+            rd,
+            imm: (inst & 0xfffff000) as i32,
+        },
+        0x1b => {
+            let shamt = shamt & 0x1f;
+            match (funct3, funct7) {
+                (0x0, _) => Addiw {
+                    rd,
+                    rs1,
+                    imm: i_imm(inst),
+                },
+                (0x1, _) => Slliw { rd, rs1, shamt },
+                (0x5, 0x00) => Srliw { rd, rs1, shamt },
+                (0x5, 0x20) => Sraiw { rd, rs1, shamt },
+                _ => return Err(illegal),
+            }
+        }
+        0x23 => {
+            let width = match funct3 {
+                0x0 => StoreWidth::B,
+                0x1 => StoreWidth::H,
+                0x2 => StoreWidth::W,
+                0x3 => StoreWidth::D,
+                _ => return Err(illegal),
+            };
+            Store {
+                rs1,
+                rs2,
+                offset: s_imm(inst),
+                width,
+            }
+        }
+        0x27 => match funct3 {
+            0x1 => Fsh {
+                rs1,
+                rs2,
+                offset: s_imm(inst),
+            },
+            0x0 | 0x5..=0x7 if inst >> 26 == 0 && rs2 == 0 => Vse {
+                eew: vector_eew(funct3),
+                vs3: rd,
+                rs1,
+                vm,
+            },
+            _ => return Err(illegal),
+        },
+        0x2f => {
+            let width = match funct3 {
+                0x2 => AmoWidth::W,
+                0x3 => AmoWidth::D,
+                _ => return Err(illegal),
+            };
+            let aq = (funct7 >> 1) & 1 == 1;
+            let rl = funct7 & 1 == 1;
+            let op = match funct7 >> 2 {
+                0x2 => {
+                    return Ok(Lr {
+                        width,
+                        rd,
+                        rs1,
+                        aq,
+                        rl,
+                    })
+                }
+                0x3 => {
+                    return Ok(Sc {
+                        width,
+                        rd,
+                        rs1,
+                        rs2,
+                        aq,
+                        rl,
+                    })
+                }
+                0x0 => AmoOp::Add,
+                0x1 => AmoOp::Swap,
+                0x4 => AmoOp::Xor,
+                0x8 => AmoOp::Or,
+                0xc => AmoOp::And,
+                0x10 => AmoOp::Min,
+                0x14 => AmoOp::Max,
+                0x18 => AmoOp::MinU,
+                0x1c => AmoOp::MaxU,
+                _ => return Err(illegal),
+            };
+            Amo {
+                op,
+                width,
+                rd,
+                rs1,
+                rs2,
+                aq,
+                rl,
+            }
+        }
+        0x33 => match (funct3, funct7) {
+            (0x0, 0x00) => Add { rd, rs1, rs2 },
+            (0x0, 0x01) => Mul { rd, rs1, rs2 },
+            (0x0, 0x20) => Sub { rd, rs1, rs2 },
+            (0x1, 0x00) => Sll { rd, rs1, rs2 },
+            (0x1, 0x01) => Mulh { rd, rs1, rs2 },
+            (0x2, 0x00) => Slt { rd, rs1, rs2 },
+            (0x2, 0x10) => Sh1add { rd, rs1, rs2 },
+            (0x2, 0x01) => Mulhsu { rd, rs1, rs2 },
+            (0x3, 0x00) => Sltu { rd, rs1, rs2 },
+            (0x3, 0x01) => Mulhu { rd, rs1, rs2 },
+            (0x4, 0x00) => Xor { rd, rs1, rs2 },
+            (0x4, 0x04) => Pack { rd, rs1, rs2 },
+            (0x4, 0x10) => Sh2add { rd, rs1, rs2 },
+            (0x4, 0x01) => Div { rd, rs1, rs2 },
+            (0x5, 0x00) => Srl { rd, rs1, rs2 },
+            (0x5, 0x01) => Divu { rd, rs1, rs2 },
+            (0x5, 0x20) => Sra { rd, rs1, rs2 },
+            (0x5, 0x07) => CzeroEqz { rd, rs1, rs2 },
+            (0x6, 0x00) => Or { rd, rs1, rs2 },
+            (0x6, 0x10) => Sh3add { rd, rs1, rs2 },
+            (0x6, 0x01) => Rem { rd, rs1, rs2 },
+            (0x0, 0x19) => Aes64es { rd, rs1, rs2 },
+            (0x0, 0x1b) => Aes64esm { rd, rs1, rs2 },
+            (0x0, 0x1d) => Aes64ds { rd, rs1, rs2 },
+            (0x0, 0x1f) => Aes64dsm { rd, rs1, rs2 },
+            (0x0, 0x3f) => Aes64ks2 { rd, rs1, rs2 },
+            (0x7, 0x00) => And { rd, rs1, rs2 },
+            (0x7, 0x04) => Packh { rd, rs1, rs2 },
+            (0x7, 0x01) => Remu { rd, rs1, rs2 },
+            (0x7, 0x07) => CzeroNez { rd, rs1, rs2 },
+            _ => return Err(illegal),
+        },
+        0x37 => Lui {
+            rd,
+            imm: (inst & 0xfffff000) as i32,
+        },
+        0x3b => match (funct3, funct7) {
+            (0x0, 0x00) => Addw { rd, rs1, rs2 },
+            (0x0, 0x01) => Mulw { rd, rs1, rs2 },
+            (0x0, 0x20) => Subw { rd, rs1, rs2 },
+            (0x1, 0x00) => Sllw { rd, rs1, rs2 },
+            (0x2, 0x10) => Sh1addUw { rd, rs1, rs2 },
+            (0x4, 0x10) => Sh2addUw { rd, rs1, rs2 },
+            (0x6, 0x10) => Sh3addUw { rd, rs1, rs2 },
+            (0x4, 0x01) => Divw { rd, rs1, rs2 },
+            (0x5, 0x00) => Srlw { rd, rs1, rs2 },
+            (0x5, 0x01) => Divuw { rd, rs1, rs2 },
+            (0x5, 0x20) => Sraw { rd, rs1, rs2 },
+            (0x6, 0x01) => Remw { rd, rs1, rs2 },
+            (0x7, 0x01) => Remuw { rd, rs1, rs2 },
+            _ => return Err(illegal),
+        },
+        0x53 => match (funct7, rs2) {
+            (0x00, _) => FaddS { rd, rs1, rs2, rm },
+            (0x04, _) => FsubS { rd, rs1, rs2, rm },
+            (0x08, _) => FmulS { rd, rs1, rs2, rm },
+            (0x0c, _) => FdivS { rd, rs1, rs2, rm },
+            (0x2c, 0) => FsqrtS { rd, rs1, rm },
+            (0x02, _) => FaddH { rd, rs1, rs2, rm },
+            (0x06, _) => FsubH { rd, rs1, rs2, rm },
+            (0x0a, _) => FmulH { rd, rs1, rs2, rm },
+            (0x0e, _) => FdivH { rd, rs1, rs2, rm },
+            (0x2e, 0) => FsqrtH { rd, rs1, rm },
+            (0x20, 2) => FcvtSH { rd, rs1, rm },
+            (0x21, 2) => FcvtDH { rd, rs1, rm },
+            (0x22, 0) => FcvtHS { rd, rs1, rm },
+            (0x22, 1) => FcvtHD { rd, rs1, rm },
+            (0x72, 0) if funct3 == 0 => FmvXH { rd, rs1 },
+            (0x7a, 0) if funct3 == 0 => FmvHX { rd, rs1 },
+            _ => return Err(illegal),
+        },
+        0x57 => match funct3 {
+            0x7 => match inst >> 30 {
+                0b00 | 0b01 => Vsetvli {
+                    rd,
+                    rs1,
+                    vtypei: ((inst >> 20) & 0x7ff) as u16,
+                },
+                0b11 => Vsetivli {
+                    rd,
+                    uimm: rs1,
+                    vtypei: ((inst >> 20) & 0x3ff) as u16,
+                },
+                _ if funct7 == 0x40 => Vsetvl { rd, rs1, rs2 },
+                _ => return Err(illegal),
+            },
+            0x0 if funct7 >> 1 == 0 => VaddVv {
+                vd: rd,
+                vs2: rs2,
+                vs1: rs1,
+                vm,
+            },
+            0x4 if funct7 >> 1 == 0 => VaddVx {
+                vd: rd,
+                vs2: rs2,
+                rs1,
+                vm,
+            },
+            _ => return Err(illegal),
+        },
+        0x63 => {
+            let cond = match funct3 {
+                0x0 => BranchCond::Eq,
+                0x1 => BranchCond::Ne,
+                0x4 => BranchCond::Lt,
+                0x5 => BranchCond::Ge,
+                0x6 => BranchCond::Ltu,
+                0x7 => BranchCond::Geu,
+                _ => return Err(illegal),
+            };
+            Branch {
+                cond,
+                rs1,
+                rs2,
+                offset: b_imm(inst),
+            }
+        }
+        0x67 => Jalr {
+            rd,
+            rs1,
+            offset: i_imm(inst),
+        },
+        0x6f => Jal {
+            rd,
+            offset: j_imm(inst),
+        },
+        // Zvkn is OPMVV and never masked
+        0x77 if funct3 != 0x2 || !vm => return Err(illegal),
+        0x77 => {
+            let (vd, vs2, vs1) = (rd, rs2, rs1);
+            match (inst >> 26, vs1) {
+                (0x28 | 0x29, 0..=3) => Vaes {
+                    op: [VaesOp::Dm, VaesOp::Df, VaesOp::Em, VaesOp::Ef][vs1 as usize],
+                    vs: inst >> 26 == 0x29,
+                    vd,
+                    vs2,
+                },
+                (0x29, 7) => Vaes {
+                    op: VaesOp::Z,
+                    vs: true,
+                    vd,
+                    vs2,
+                },
+                (0x22, _) => Vaeskf1 { vd, vs2, uimm: vs1 },
+                (0x2a, _) => Vaeskf2 { vd, vs2, uimm: vs1 },
+                (0x2d, _) => Vsha2ms { vd, vs2, vs1 },
+                (0x2e, _) => Vsha2ch { vd, vs2, vs1 },
+                (0x2f, _) => Vsha2cl { vd, vs2, vs1 },
+                _ => return Err(illegal),
+            }
+        }
+        0x73 => {
+            let csr = (inst >> 20) as u16;
+            match funct3 {
+                0x0 => match (rs2, funct7) {
+                    (0x0, 0x0) => Ecall,
+                    (0x1, 0x0) => Ebreak,
+                    (0x2, 0x8) => Sret,
+                    (0x2, 0x18) => Mret,
+                    (0x5, 0x8) => Wfi,
+                    (0xd, 0x0) => WrsNto,
+                    (0x1d, 0x0) => WrsSto,
+                    (_, 0x9) => SfenceVma { rs1, rs2 },
+                    (_, 0xb) => SinvalVma { rs1, rs2 },
+                    (0x0, 0xc) => SfenceWInval,
+                    (0x1, 0xc) => SfenceInvalIr,
+                    _ => return Err(illegal),
+                },
+                0x1 => Csr {
+                    op: CsrOp::Rw,
+                    rd,
+                    rs1,
+                    csr,
+                },
+                0x2 => Csr {
+                    op: CsrOp::Rs,
+                    rd,
+                    rs1,
+                    csr,
+                },
+                0x3 => Csr {
+                    op: CsrOp::Rc,
+                    rd,
+                    rs1,
+                    csr,
+                },
+                0x5 => Csri {
+                    op: CsrOp::Rw,
+                    rd,
+                    zimm: rs1,
+                    csr,
+                },
+                0x6 => Csri {
+                    op: CsrOp::Rs,
+                    rd,
+                    zimm: rs1,
+                    csr,
+                },
+                0x7 => Csri {
+                    op: CsrOp::Rc,
+                    rd,
+                    zimm: rs1,
+                    csr,
+                },
+                _ => return Err(illegal),
+            }
+        }
+        _ => return Err(illegal),
+    };
+    Ok(decoded)
+}
+
+// x8 - x15, the registers a 3-bit compressed field names
+fn c_reg(inst: u16, shift: u32) -> u8 {
+    8 + ((inst >> shift) & 0x7) as u8
+}
+
+// len bits of inst from bit from, moved up to bit to of an immediate
+fn c_bits(inst: u16, from: u32, len: u32, to: u32) -> i32 {
+    (((inst >> from) & ((1 << len) - 1)) as i32) << to
+}
+
+// the low width bits of imm as a signed number
+fn sext(imm: i32, width: u32) -> i32 {
+    imm << (32 - width) >> (32 - width)
+}
+
+// RV64C quadrant 0, decoded to the instruction each one expands to. c.fld and
+// c.fsd need D, which this hart doesn't have.
+pub fn decode_c_q0(inst: u16) -> Result<DecodedInst, Exception> {
+    let illegal = Exception::IllegalInstruction(inst as u64);
+    let funct3 = inst >> 13;
+    let rd = c_reg(inst, 2);
+    let rs1 = c_reg(inst, 7);
+    let bits = |from, len, to| c_bits(inst, from, len, to);
+    // uimm[5:3] = inst[12:10], then uimm[2|6] = inst[6:5] for words, uimm[7:6] for doublewords
+    let w_offset = bits(10, 3, 3) | bits(6, 1, 2) | bits(5, 1, 6);
+    let d_offset = bits(10, 3, 3) | bits(5, 2, 6);
+    let decoded = match funct3 {
+        // c.addi4spn, nzuimm[5:4|9:6|2|3] = inst[12:11|10:7|6|5], 0 is reserved
+        // and covers c.illegal, the all-zero halfword
+        0x0 => {
+            let imm = bits(11, 2, 4) | bits(7, 4, 6) | bits(6, 1, 2) | bits(5, 1, 3);
+            if imm == 0 {
+                return Err(illegal);
+            }
+            Addi { rd, rs1: 2, imm }
+        }
+        0x2 => Load {
+            rd,
+            rs1,
+            offset: w_offset,
+            width: LoadWidth::W,
+        },
+        0x3 => Load {
+            rd,
+            rs1,
+            offset: d_offset,
+            width: LoadWidth::D,
+        },
+        0x6 => Store {
+            rs1,
+            rs2: rd,
+            offset: w_offset,
+            width: StoreWidth::W,
+        },
+        0x7 => Store {
+            rs1,
+            rs2: rd,
+            offset: d_offset,
+            width: StoreWidth::D,
+        },
+        _ => return Err(illegal),
+    };
+    Ok(decoded)
+}
+
+// RV64C quadrant 1, decoded to the instruction each one expands to. c.jal is
+// RV32 only, its encoding is c.addiw here. The HINT encodings (c.nop with an
+// immediate, c.li / c.lui to x0, shifts by 0) decode like any other.
+pub fn decode_c_q1(inst: u16) -> Result<DecodedInst, Exception> {
+    let illegal = Exception::IllegalInstruction(inst as u64);
+    let bits = |from, len, to| c_bits(inst, from, len, to);
+    let rd = ((inst >> 7) & 0x1f) as u8;
+    let rd_c = c_reg(inst, 7);
+    let rs2_c = c_reg(inst, 2);
+    // imm[5] = inst[12], imm[4:0] = inst[6:2], also the shift amount
+    let imm6 = bits(12, 1, 5) | bits(2, 5, 0);
+    let imm = sext(imm6, 6);
+    let decoded = match inst >> 13 {
+        0x0 => Addi { rd, rs1: rd, imm },
+        0x1 if rd != 0 => Addiw { rd, rs1: rd, imm },
+        0x2 => Addi { rd, rs1: 0, imm },
+        // c.addi16sp, nzimm[9|4|6|8:7|5] = inst[12|6|5|4:3|2]
+        0x3 if rd == 2 => {
+            let imm =
+                bits(12, 1, 9) | bits(6, 1, 4) | bits(5, 1, 6) | bits(3, 2, 7) | bits(2, 1, 5);
+            if imm == 0 {
+                return Err(illegal);
+            }
+            Addi {
+                rd,
+                rs1: rd,
+                imm: sext(imm, 10),
+            }
+        }
+        // c.lui, nzimm[17:12] in place of imm[5:0]
+        0x3 if imm != 0 => Lui { rd, imm: imm << 12 },
+        0x4 => match (inst >> 10) & 0x3 {
+            0x0 => Srli {
+                rd: rd_c,
+                rs1: rd_c,
+                shamt: imm6 as u8,
+            },
+            0x1 => Srai {
+                rd: rd_c,
+                rs1: rd_c,
+                shamt: imm6 as u8,
+            },
+            0x2 => Andi {
+                rd: rd_c,
+                rs1: rd_c,
+                imm,
+            },
+            _ => {
+                let (rd, rs1, rs2) = (rd_c, rd_c, rs2_c);
+                match ((inst >> 12) & 1, (inst >> 5) & 0x3) {
+                    (0, 0x0) => Sub { rd, rs1, rs2 },
+                    (0, 0x1) => Xor { rd, rs1, rs2 },
+                    (0, 0x2) => Or { rd, rs1, rs2 },
+                    (0, 0x3) => And { rd, rs1, rs2 },
+                    (1, 0x0) => Subw { rd, rs1, rs2 },
+                    (1, 0x1) => Addw { rd, rs1, rs2 },
+                    _ => return Err(illegal),
+                }
+            }
+        },
+        // c.j, offset[11|4|9:8|10|6|7|3:1|5] = inst[12|11|10:9|8|7|6|5:3|2]
+        0x5 => {
+            let offset = bits(12, 1, 11)
+                | bits(11, 1, 4)
+                | bits(9, 2, 8)
+                | bits(8, 1, 10)
+                | bits(7, 1, 6)
+                | bits(6, 1, 7)
+                | bits(3, 3, 1)
+                | bits(2, 1, 5);
+            Jal {
+                rd: 0,
+                offset: sext(offset, 12),
+            }
+        }
+        // c.beqz / c.bnez, offset[8|4:3|7:6|2:1|5] = inst[12|11:10|6:5|4:3|2]
+        0x6 | 0x7 => {
+            let offset =
+                bits(12, 1, 8) | bits(10, 2, 3) | bits(5, 2, 6) | bits(3, 2, 1) | bits(2, 1, 5);
+            Branch {
+                cond: if inst >> 13 == 0x6 {
+                    BranchCond::Eq
+                } else {
+                    BranchCond::Ne
+                },
+                rs1: rd_c,
+                rs2: 0,
+                offset: sext(offset, 9),
+            }
+        }
+        _ => return Err(illegal),
+    };
+    Ok(decoded)
+}
+
+// RV64C quadrant 2, decoded to the instruction each one expands to. c.fldsp and
+// c.fsdsp need D like their quadrant 0 forms.
+pub fn decode_c_q2(inst: u16) -> Result<DecodedInst, Exception> {
+    let illegal = Exception::IllegalInstruction(inst as u64);
+    let bits = |from, len, to| c_bits(inst, from, len, to);
+    let rd = ((inst >> 7) & 0x1f) as u8;
+    let rs2 = ((inst >> 2) & 0x1f) as u8;
+    let decoded = match inst >> 13 {
+        0x0 => Slli {
+            rd,
+            rs1: rd,
+            shamt: (bits(12, 1, 5) | bits(2, 5, 0)) as u8,
+        },
+        // c.lwsp, uimm[5|4:2|7:6] = inst[12|6:4|3:2], rd 0 is reserved
+        0x2 if rd != 0 => Load {
+            rd,
+            rs1: 2,
+            offset: bits(12, 1, 5) | bits(4, 3, 2) | bits(2, 2, 6),
+            width: LoadWidth::W,
+        },
+        // c.ldsp, uimm[5|4:3|8:6] = inst[12|6:5|4:2]
+        0x3 if rd != 0 => Load {
+            rd,
+            rs1: 2,
+            offset: bits(12, 1, 5) | bits(5, 2, 3) | bits(2, 3, 6),
+            width: LoadWidth::D,
+        },
+        0x4 => match ((inst >> 12) & 1, rd, rs2) {
+            // c.jr, rs1 0 is reserved
+            (0, 0, 0) => return Err(illegal),
+            (0, rs1, 0) => Jalr {
+                rd: 0,
+                rs1,
+                offset: 0,
+            },
+            // c.mv
+            (0, rd, rs2) => Add { rd, rs1: 0, rs2 },
+            (1, 0, 0) => Ebreak,
+            // c.jalr
+            (1, rs1, 0) => Jalr {
+                rd: 1,
+                rs1,
+                offset: 0,
+            },
+            // c.add
+            (_, rd, rs2) => Add { rd, rs1: rd, rs2 },
+        },
+        // c.swsp, uimm[5:2|7:6] = inst[12:9|8:7]
+        0x6 => Store {
+            rs1: 2,
+            rs2,
+            offset: bits(9, 4, 2) | bits(7, 2, 6),
+            width: StoreWidth::W,
+        },
+        // c.sdsp, uimm[5:3|8:6] = inst[12:10|9:7]
+        0x7 => Store {
+            rs1: 2,
+            rs2,
+            offset: bits(10, 3, 3) | bits(7, 3, 6),
+            width: StoreWidth::D,
+        },
+        _ => return Err(illegal),
+    };
+    Ok(decoded)
+}
+
+// (written x register, x registers read) of an instruction, what the f and v
+// registers hold isn't included
+pub fn x_registers(inst: &DecodedInst) -> (Option<u8>, Vec<u8>) {
+    match *inst {
+        Add { rd, rs1, rs2 }
+        | Sub { rd, rs1, rs2 }
+        | Sll { rd, rs1, rs2 }
+        | Slt { rd, rs1, rs2 }
+        | Sltu { rd, rs1, rs2 }
+        | Xor { rd, rs1, rs2 }
+        | Srl { rd, rs1, rs2 }
+        | Sra { rd, rs1, rs2 }
+        | Or { rd, rs1, rs2 }
+        | And { rd, rs1, rs2 }
+        | Addw { rd, rs1, rs2 }
+        | Subw { rd, rs1, rs2 }
+        | Sllw { rd, rs1, rs2 }
+        | Srlw { rd, rs1, rs2 }
+        | Sraw { rd, rs1, rs2 }
+        | Mul { rd, rs1, rs2 }
+        | Mulh { rd, rs1, rs2 }
+        | Mulhsu { rd, rs1, rs2 }
+        | Mulhu { rd, rs1, rs2 }
+        | Div { rd, rs1, rs2 }
+        | Divu { rd, rs1, rs2 }
+        | Rem { rd, rs1, rs2 }
+        | Remu { rd, rs1, rs2 }
+        | Mulw { rd, rs1, rs2 }
+        | Divw { rd, rs1, rs2 }
+        | Divuw { rd, rs1, rs2 }
+        | Remw { rd, rs1, rs2 }
+        | Remuw { rd, rs1, rs2 }
+        | Sc { rd, rs1, rs2, .. }
+        | Amo { rd, rs1, rs2, .. }
+        | Sh1add { rd, rs1, rs2 }
+        | Sh2add { rd, rs1, rs2 }
+        | Sh3add { rd, rs1, rs2 }
+        | Sh1addUw { rd, rs1, rs2 }
+        | Sh2addUw { rd, rs1, rs2 }
+        | Sh3addUw { rd, rs1, rs2 }
+        | CzeroEqz { rd, rs1, rs2 }
+        | CzeroNez { rd, rs1, rs2 }
+        | Pack { rd, rs1, rs2 }
+        | Packh { rd, rs1, rs2 }
+        | Aes64es { rd, rs1, rs2 }
+        | Aes64esm { rd, rs1, rs2 }
+        | Aes64ds { rd, rs1, rs2 }
+        | Aes64dsm { rd, rs1, rs2 }
+        | Aes64ks2 { rd, rs1, rs2 }
+        | FaddS { rd, rs1, rs2, .. }
+        | FsubS { rd, rs1, rs2, .. }
+        | FmulS { rd, rs1, rs2, .. }
+        | FdivS { rd, rs1, rs2, .. }
+        | Vsetvl { rd, rs1, rs2 } => (Some(rd), vec![rs1, rs2]),
+        Jalr { rd, rs1, .. }
+        | Load { rd, rs1, .. }
+        | Addi { rd, rs1, .. }
+        | Slti { rd, rs1, .. }
+        | Sltiu { rd, rs1, .. }
+        | Xori { rd, rs1, .. }
+        | Ori { rd, rs1, .. }
+        | Andi { rd, rs1, .. }
+        | Slli { rd, rs1, .. }
+        | Srli { rd, rs1, .. }
+        | Srai { rd, rs1, .. }
+        | Addiw { rd, rs1, .. }
+        | Slliw { rd, rs1, .. }
+        | Srliw { rd, rs1, .. }
+        | Sraiw { rd, rs1, .. }
+        | Csr { rd, rs1, .. }
+        | Lr { rd, rs1, .. }
+        | Brev8 { rd, rs1 }
+        | Zip { rd, rs1 }
+        | Unzip { rd, rs1 }
+        | Aes64im { rd, rs1 }
+        | Aes64ks1i { rd, rs1, .. }
+        | Sha256sig0 { rd, rs1 }
+        | Sha256sig1 { rd, rs1 }
+        | Sha256sum0 { rd, rs1 }
+        | Sha256sum1 { rd, rs1 }
+        | Sha512sig0 { rd, rs1 }
+        | Sha512sig1 { rd, rs1 }
+        | Sha512sum0 { rd, rs1 }
+        | Sha512sum1 { rd, rs1 }
+        | FsqrtS { rd, rs1, .. }
+        | Vsetvli { rd, rs1, .. } => (Some(rd), vec![rs1]),
+        Lui { rd, .. }
+        | Auipc { rd, .. }
+        | Jal { rd, .. }
+        | Csri { rd, .. }
+        | FmvXH { rd, .. }
+        | Vsetivli { rd, .. } => (Some(rd), vec![]),
+        Branch { rs1, rs2, .. }
+        | Store { rs1, rs2, .. }
+        | SfenceVma { rs1, rs2 }
+        | SinvalVma { rs1, rs2 } => (None, vec![rs1, rs2]),
+        Flh { rs1, .. }
+        | Fsh { rs1, .. }
+        | FmvHX { rs1, .. }
+        | CboInval { rs1 }
+        | CboClean { rs1 }
+        | CboFlush { rs1 }
+        | CboZero { rs1 }
+        | Vle { rs1, .. }
+        | Vse { rs1, .. }
+        | VaddVx { rs1, .. } => (None, vec![rs1]),
+        _ => (None, vec![]),
+    }
+}
+
+// element width of a unit-stride vector load / store from its width field
+fn vector_eew(funct3: u32) -> u8 {
+    match funct3 {
+        0x0 => 8,
+        0x5 => 16,
+        0x6 => 32,
+        _ => 64,
+    }
+}
+
+const FABI: [&str; 32] = [
+    "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7", "fs0", "fs1", "fa0", "fa1", "fa2",
+    "fa3", "fa4", "fa5", "fa6", "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7", "fs8", "fs9",
+    "fs10", "fs11", "ft8", "ft9", "ft10", "ft11",
+];
+
+const ROUNDING_MODES: [&str; 8] = ["rne", "rtz", "rdn", "rup", "rmm", "0b101", "0b110", "dyn"];
+
+fn x(reg: u8) -> &'static str {
+    RVABI[reg as usize]
+}
+
+fn f(reg: u8) -> &'static str {
+    FABI[reg as usize]
+}
+
+// ", rtz" and the like, nothing for the dynamic rounding mode
+fn rounding(rm: u8) -> String {
+    match rm {
+        7 => String::new(),
+        rm => format!(", {}", ROUNDING_MODES[rm as usize]),
+    }
+}
+
+// ", v0.t" for masked vector instructions
+fn mask(vm: bool) -> &'static str {
+    match vm {
+        true => "",
+        false => ", v0.t",
+    }
+}
+
+fn csr_name(csr: u16) -> String {
+    match CSR_NAMES.get(csr as usize) {
+        Some(name) if !name.is_empty() => name.to_string(),
+        _ => format!("{:#x}", csr),
+    }
+}
+
+// "iorw" letters of a fence predecessor / successor set
+fn fence_set(bits: u8) -> String {
+    let set: String = "iorw"
+        .chars()
+        .enumerate()
+        .filter(|(i, _)| bits & (8 >> i) != 0)
+        .map(|(_, c)| c)
+        .collect();
+    match set.is_empty() {
+        true => "0".to_string(),
+        false => set,
+    }
+}
+
+// e32, m1, ta, ma, or the raw value if the encoding is reserved. Named even where
+// this hart doesn't support it, that only makes vset set vill.
+fn vtype_name(vtype: u16) -> String {
+    let lmul = ["m1", "m2", "m4", "m8", "", "mf8", "mf4", "mf2"][(vtype & 0b111) as usize];
+    let vsew = (vtype >> 3) & 0b111;
+    if vtype >> 8 != 0 || vsew > 3 || lmul.is_empty() {
+        return format!("{:#x}", vtype);
+    }
+    let ta = if vtype & (1 << 6) != 0 { "ta" } else { "tu" };
+    let ma = if vtype & (1 << 7) != 0 { "ma" } else { "mu" };
+    format!("e{}, {}, {}, {}", 8 << vsew, lmul, ta, ma)
+}
+
+fn aq_rl(aq: bool, rl: bool) -> &'static str {
+    match (aq, rl) {
+        (false, false) => "",
+        (true, false) => ".aq",
+        (false, true) => ".rl",
+        (true, true) => ".aqrl",
+    }
+}
+
+fn amo_width(width: AmoWidth) -> &'static str {
+    match width {
+        AmoWidth::W => "w",
+        AmoWidth::D => "d",
+    }
+}
+
+// mnemonic, without the width or ordering suffixes Lr / Sc / Amo / Vle / Vse /
+// Vaes get in format
+fn name(di: &DecodedInst) -> &'static str {
+    match di {
+        Lui { .. } => "lui",
+        Auipc { .. } => "auipc",
+        Jal { .. } => "jal",
+        Jalr { .. } => "jalr",
+        Branch { cond, .. } => match cond {
+            BranchCond::Eq => "beq",
+            BranchCond::Ne => "bne",
+            BranchCond::Lt => "blt",
+            BranchCond::Ge => "bge",
+            BranchCond::Ltu => "bltu",
+            BranchCond::Geu => "bgeu",
+        },
+        Load { width, .. } => match width {
+            LoadWidth::B => "lb",
+            LoadWidth::H => "lh",
+            LoadWidth::W => "lw",
+            LoadWidth::D => "ld",
+            LoadWidth::Bu => "lbu",
+            LoadWidth::Hu => "lhu",
+            LoadWidth::Wu => "lwu",
+        },
+        Store { width, .. } => match width {
+            StoreWidth::B => "sb",
+            StoreWidth::H => "sh",
+            StoreWidth::W => "sw",
+            StoreWidth::D => "sd",
+        },
+        Addi { .. } => "addi",
+        Slti { .. } => "slti",
+        Sltiu { .. } => "sltiu",
+        Xori { .. } => "xori",
+        Ori { .. } => "ori",
+        Andi { .. } => "andi",
+        Slli { .. } => "slli",
+        Srli { .. } => "srli",
+        Srai { .. } => "srai",
+        Addiw { .. } => "addiw",
+        Slliw { .. } => "slliw",
+        Srliw { .. } => "srliw",
+        Sraiw { .. } => "sraiw",
+        Add { .. } => "add",
+        Sub { .. } => "sub",
+        Sll { .. } => "sll",
+        Slt { .. } => "slt",
+        Sltu { .. } => "sltu",
+        Xor { .. } => "xor",
+        Srl { .. } => "srl",
+        Sra { .. } => "sra",
+        Or { .. } => "or",
+        And { .. } => "and",
+        Addw { .. } => "addw",
+        Subw { .. } => "subw",
+        Sllw { .. } => "sllw",
+        Srlw { .. } => "srlw",
+        Sraw { .. } => "sraw",
+        Fence { .. } => "fence",
+        Pause => "pause",
+        Ecall => "ecall",
+        Ebreak => "ebreak",
+        FenceI => "fence.i",
+        Sret => "sret",
+        Mret => "mret",
+        Wfi => "wfi",
+        SfenceVma { .. } => "sfence.vma",
+        SinvalVma { .. } => "sinval.vma",
+        SfenceWInval => "sfence.w.inval",
+        SfenceInvalIr => "sfence.inval.ir",
+        Csr { op, .. } => match op {
+            CsrOp::Rw => "csrrw",
+            CsrOp::Rs => "csrrs",
+            CsrOp::Rc => "csrrc",
+        },
+        Csri { op, .. } => match op {
+            CsrOp::Rw => "csrrwi",
+            CsrOp::Rs => "csrrsi",
+            CsrOp::Rc => "csrrci",
+        },
+        Mul { .. } => "mul",
+        Mulh { .. } => "mulh",
+        Mulhsu { .. } => "mulhsu",
+        Mulhu { .. } => "mulhu",
+        Div { .. } => "div",
+        Divu { .. } => "divu",
+        Rem { .. } => "rem",
+        Remu { .. } => "remu",
+        Mulw { .. } => "mulw",
+        Divw { .. } => "divw",
+        Divuw { .. } => "divuw",
+        Remw { .. } => "remw",
+        Remuw { .. } => "remuw",
+        Lr { .. } => "lr",
+        Sc { .. } => "sc",
+        Amo { op, .. } => match op {
+            AmoOp::Swap => "amoswap",
+            AmoOp::Add => "amoadd",
+            AmoOp::Xor => "amoxor",
+            AmoOp::And => "amoand",
+            AmoOp::Or => "amoor",
+            AmoOp::Min => "amomin",
+            AmoOp::Max => "amomax",
+            AmoOp::MinU => "amominu",
+            AmoOp::MaxU => "amomaxu",
+        },
+        Sh1add { .. } => "sh1add",
+        Sh2add { .. } => "sh2add",
+        Sh3add { .. } => "sh3add",
+        Sh1addUw { .. } => "sh1add.uw",
+        Sh2addUw { .. } => "sh2add.uw",
+        Sh3addUw { .. } => "sh3add.uw",
+        CzeroEqz { .. } => "czero.eqz",
+        CzeroNez { .. } => "czero.nez",
+        Pack { .. } => "pack",
+        Packh { .. } => "packh",
+        Brev8 { .. } => "brev8",
+        Zip { .. } => "zip",
+        Unzip { .. } => "unzip",
+        Aes64es { .. } => "aes64es",
+        Aes64esm { .. } => "aes64esm",
+        Aes64ds { .. } => "aes64ds",
+        Aes64dsm { .. } => "aes64dsm",
+        Aes64ks2 { .. } => "aes64ks2",
+        Aes64im { .. } => "aes64im",
+        Aes64ks1i { .. } => "aes64ks1i",
+        Sha256sig0 { .. } => "sha256sig0",
+        Sha256sig1 { .. } => "sha256sig1",
+        Sha256sum0 { .. } => "sha256sum0",
+        Sha256sum1 { .. } => "sha256sum1",
+        Sha512sig0 { .. } => "sha512sig0",
+        Sha512sig1 { .. } => "sha512sig1",
+        Sha512sum0 { .. } => "sha512sum0",
+        Sha512sum1 { .. } => "sha512sum1",
+        CboInval { .. } => "cbo.inval",
+        CboClean { .. } => "cbo.clean",
+        CboFlush { .. } => "cbo.flush",
+        CboZero { .. } => "cbo.zero",
+        WrsNto => "wrs.nto",
+        WrsSto => "wrs.sto",
+        Flh { .. } => "flh",
+        Fsh { .. } => "fsh",
+        FaddH { .. } => "fadd.h",
+        FsubH { .. } => "fsub.h",
+        FmulH { .. } => "fmul.h",
+        FdivH { .. } => "fdiv.h",
+        FsqrtH { .. } => "fsqrt.h",
+        FcvtSH { .. } => "fcvt.s.h",
+        FcvtDH { .. } => "fcvt.d.h",
+        FcvtHS { .. } => "fcvt.h.s",
+        FcvtHD { .. } => "fcvt.h.d",
+        FmvXH { .. } => "fmv.x.h",
+        FmvHX { .. } => "fmv.h.x",
+        FaddS { .. } => "fadd.s",
+        FsubS { .. } => "fsub.s",
+        FmulS { .. } => "fmul.s",
+        FdivS { .. } => "fdiv.s",
+        FsqrtS { .. } => "fsqrt.s",
+        Vsetvli { .. } => "vsetvli",
+        Vsetivli { .. } => "vsetivli",
+        Vsetvl { .. } => "vsetvl",
+        Vle { .. } => "vle",
+        Vse { .. } => "vse",
+        VaddVv { .. } => "vadd.vv",
+        VaddVx { .. } => "vadd.vx",
+        Vaes { op, .. } => match op {
+            VaesOp::Dm => "vaesdm",
+            VaesOp::Df => "vaesdf",
+            VaesOp::Em => "vaesem",
+            VaesOp::Ef => "vaesef",
+            VaesOp::Z => "vaesz",
+        },
+        Vaeskf1 { .. } => "vaeskf1.vi",
+        Vaeskf2 { .. } => "vaeskf2.vi",
+        Vsha2ms { .. } => "vsha2ms.vv",
+        Vsha2ch { .. } => "vsha2ch.vv",
+        Vsha2cl { .. } => "vsha2cl.vv",
+    }
+}
+
+// Standard assembly syntax with ABI register names, branch and jump targets as
+// offsets from the instruction
+pub fn format(di: &DecodedInst) -> String {
+    let name = name(di);
+    match *di {
+        Lui { rd, imm } | Auipc { rd, imm } => {
+            format!("{} {}, {:#x}", name, x(rd), (imm as u32) >> 12)
+        }
+        Jal { rd, offset } => format!("{} {}, {}", name, x(rd), offset),
+        Jalr { rd, rs1, offset }
+        | Load {
+            rd, rs1, offset, ..
+        } => {
+            format!("{} {}, {}({})", name, x(rd), offset, x(rs1))
+        }
+        Branch {
+            rs1, rs2, offset, ..
+        } => format!("{} {}, {}, {}", name, x(rs1), x(rs2), offset),
+        Store {
+            rs1, rs2, offset, ..
+        } => format!("{} {}, {}({})", name, x(rs2), offset, x(rs1)),
+        Addi { rd, rs1, imm }
+        | Slti { rd, rs1, imm }
+        | Sltiu { rd, rs1, imm }
+        | Xori { rd, rs1, imm }
+        | Ori { rd, rs1, imm }
+        | Andi { rd, rs1, imm }
+        | Addiw { rd, rs1, imm } => format!("{} {}, {}, {}", name, x(rd), x(rs1), imm),
+        Slli { rd, rs1, shamt }
+        | Srli { rd, rs1, shamt }
+        | Srai { rd, rs1, shamt }
+        | Slliw { rd, rs1, shamt }
+        | Srliw { rd, rs1, shamt }
+        | Sraiw { rd, rs1, shamt } => format!("{} {}, {}, {}", name, x(rd), x(rs1), shamt),
+        Aes64ks1i { rd, rs1, rnum } => format!("{} {}, {}, {}", name, x(rd), x(rs1), rnum),
+        Add { rd, rs1, rs2 }
+        | Sub { rd, rs1, rs2 }
+        | Sll { rd, rs1, rs2 }
+        | Slt { rd, rs1, rs2 }
+        | Sltu { rd, rs1, rs2 }
+        | Xor { rd, rs1, rs2 }
+        | Srl { rd, rs1, rs2 }
+        | Sra { rd, rs1, rs2 }
+        | Or { rd, rs1, rs2 }
+        | And { rd, rs1, rs2 }
+        | Addw { rd, rs1, rs2 }
+        | Subw { rd, rs1, rs2 }
+        | Sllw { rd, rs1, rs2 }
+        | Srlw { rd, rs1, rs2 }
+        | Sraw { rd, rs1, rs2 }
+        | Mul { rd, rs1, rs2 }
+        | Mulh { rd, rs1, rs2 }
+        | Mulhsu { rd, rs1, rs2 }
+        | Mulhu { rd, rs1, rs2 }
+        | Div { rd, rs1, rs2 }
+        | Divu { rd, rs1, rs2 }
+        | Rem { rd, rs1, rs2 }
+        | Remu { rd, rs1, rs2 }
+        | Mulw { rd, rs1, rs2 }
+        | Divw { rd, rs1, rs2 }
+        | Divuw { rd, rs1, rs2 }
+        | Remw { rd, rs1, rs2 }
+        | Remuw { rd, rs1, rs2 }
+        | Sh1add { rd, rs1, rs2 }
+        | Sh2add { rd, rs1, rs2 }
+        | Sh3add { rd, rs1, rs2 }
+        | Sh1addUw { rd, rs1, rs2 }
+        | Sh2addUw { rd, rs1, rs2 }
+        | Sh3addUw { rd, rs1, rs2 }
+        | CzeroEqz { rd, rs1, rs2 }
+        | CzeroNez { rd, rs1, rs2 }
+        | Pack { rd, rs1, rs2 }
+        | Packh { rd, rs1, rs2 }
+        | Aes64es { rd, rs1, rs2 }
+        | Aes64esm { rd, rs1, rs2 }
+        | Aes64ds { rd, rs1, rs2 }
+        | Aes64dsm { rd, rs1, rs2 }
+        | Aes64ks2 { rd, rs1, rs2 }
+        | Vsetvl { rd, rs1, rs2 } => format!("{} {}, {}, {}", name, x(rd), x(rs1), x(rs2)),
+        Brev8 { rd, rs1 }
+        | Zip { rd, rs1 }
+        | Unzip { rd, rs1 }
+        | Aes64im { rd, rs1 }
+        | Sha256sig0 { rd, rs1 }
+        | Sha256sig1 { rd, rs1 }
+        | Sha256sum0 { rd, rs1 }
+        | Sha256sum1 { rd, rs1 }
+        | Sha512sig0 { rd, rs1 }
+        | Sha512sig1 { rd, rs1 }
+        | Sha512sum0 { rd, rs1 }
+        | Sha512sum1 { rd, rs1 } => format!("{} {}, {}", name, x(rd), x(rs1)),
+        Fence { pred, succ } => format!("{} {}, {}", name, fence_set(pred), fence_set(succ)),
+        Pause | Ecall | Ebreak | FenceI | Sret | Mret | Wfi | WrsNto | WrsSto | SfenceWInval
+        | SfenceInvalIr => name.to_string(),
+        SfenceVma { rs1, rs2 } | SinvalVma { rs1, rs2 } => {
+            format!("{} {}, {}", name, x(rs1), x(rs2))
+        }
+        Csr { rd, rs1, csr, .. } => format!("{} {}, {}, {}", name, x(rd), csr_name(csr), x(rs1)),
+        Csri { rd, zimm, csr, .. } => format!("{} {}, {}, {}", name, x(rd), csr_name(csr), zimm),
+        Lr {
+            width,
+            rd,
+            rs1,
+            aq,
+            rl,
+        } => format!(
+            "{}.{}{} {}, ({})",
+            name,
+            amo_width(width),
+            aq_rl(aq, rl),
+            x(rd),
+            x(rs1)
+        ),
+        Sc {
+            width,
+            rd,
+            rs1,
+            rs2,
+            aq,
+            rl,
+        }
+        | Amo {
+            width,
+            rd,
+            rs1,
+            rs2,
+            aq,
+            rl,
+            ..
+        } => format!(
+            "{}.{}{} {}, {}, ({})",
+            name,
+            amo_width(width),
+            aq_rl(aq, rl),
+            x(rd),
+            x(rs2),
+            x(rs1)
+        ),
+        CboInval { rs1 } | CboClean { rs1 } | CboFlush { rs1 } | CboZero { rs1 } => {
+            format!("{} ({})", name, x(rs1))
+        }
+        Flh { rd, rs1, offset } => format!("{} {}, {}({})", name, f(rd), offset, x(rs1)),
+        Fsh { rs1, rs2, offset } => format!("{} {}, {}({})", name, f(rs2), offset, x(rs1)),
+        FaddH { rd, rs1, rs2, rm }
+        | FsubH { rd, rs1, rs2, rm }
+        | FmulH { rd, rs1, rs2, rm }
+        | FdivH { rd, rs1, rs2, rm } => {
+            format!("{} {}, {}, {}{}", name, f(rd), f(rs1), f(rs2), rounding(rm))
+        }
+        FsqrtH { rd, rs1, rm }
+        | FcvtSH { rd, rs1, rm }
+        | FcvtDH { rd, rs1, rm }
+        | FcvtHS { rd, rs1, rm }
+        | FcvtHD { rd, rs1, rm } => format!("{} {}, {}{}", name, f(rd), f(rs1), rounding(rm)),
+        FmvXH { rd, rs1 } => format!("{} {}, {}", name, x(rd), f(rs1)),
+        FmvHX { rd, rs1 } => format!("{} {}, {}", name, f(rd), x(rs1)),
+        FaddS { rd, rs1, rs2, rm }
+        | FsubS { rd, rs1, rs2, rm }
+        | FmulS { rd, rs1, rs2, rm }
+        | FdivS { rd, rs1, rs2, rm } => {
+            format!("{} {}, {}, {}{}", name, x(rd), x(rs1), x(rs2), rounding(rm))
+        }
+        FsqrtS { rd, rs1, rm } => format!("{} {}, {}{}", name, x(rd), x(rs1), rounding(rm)),
+        Vsetvli { rd, rs1, vtypei } => {
+            format!("{} {}, {}, {}", name, x(rd), x(rs1), vtype_name(vtypei))
+        }
+        Vsetivli { rd, uimm, vtypei } => {
+            format!("{} {}, {}, {}", name, x(rd), uimm, vtype_name(vtypei))
+        }
+        Vle { eew, vd, rs1, vm } => {
+            format!("{}{}.v v{}, ({}){}", name, eew, vd, x(rs1), mask(vm))
+        }
+        Vse { eew, vs3, rs1, vm } => {
+            format!("{}{}.v v{}, ({}){}", name, eew, vs3, x(rs1), mask(vm))
+        }
+        VaddVv { vd, vs2, vs1, vm } => format!("{} v{}, v{}, v{}{}", name, vd, vs2, vs1, mask(vm)),
+        VaddVx { vd, vs2, rs1, vm } => {
+            format!("{} v{}, v{}, {}{}", name, vd, vs2, x(rs1), mask(vm))
+        }
+        Vaes { vs, vd, vs2, .. } => {
+            let form = if vs { "vs" } else { "vv" };
+            format!("{}.{} v{}, v{}", name, form, vd, vs2)
+        }
+        Vaeskf1 { vd, vs2, uimm } | Vaeskf2 { vd, vs2, uimm } => {
+            format!("{} v{}, v{}, {}", name, vd, vs2, uimm)
+        }
+        Vsha2ms { vd, vs2, vs1 } | Vsha2ch { vd, vs2, vs1 } | Vsha2cl { vd, vs2, vs1 } => {
+            format!("{} v{}, v{}, v{}", name, vd, vs2, vs1)
+        }
+    }
+}
@@ -0,0 +1,239 @@
+use half::f16;
+
+// fflags bits
+pub const FFLAG_NX: u64 = 1 << 0;
+pub const FFLAG_UF: u64 = 1 << 1;
+pub const FFLAG_OF: u64 = 1 << 2;
+pub const FFLAG_DZ: u64 = 1 << 3;
+pub const FFLAG_NV: u64 = 1 << 4;
+
+pub const CANONICAL_NAN_H: u16 = 0x7e00;
+pub const CANONICAL_NAN_S: u32 = 0x7fc0_0000;
+pub const CANONICAL_NAN_D: u64 = 0x7ff8_0000_0000_0000;
+
+// narrower values live in the f registers NaN-boxed, upper bits all ones
+pub fn box_h(value: u16) -> u64 {
+    0xffff_ffff_ffff_0000 | value as u64
+}
+
+pub fn box_s(value: u32) -> u64 {
+    0xffff_ffff_0000_0000 | value as u64
+}
+
+// improperly boxed values read as the canonical NaN
+pub fn unbox_h(reg: u64) -> f16 {
+    if reg >> 16 != 0xffff_ffff_ffff {
+        return f16::from_bits(CANONICAL_NAN_H);
+    }
+    f16::from_bits(reg as u16)
+}
+
+pub fn unbox_s(reg: u64) -> f32 {
+    if reg >> 32 != 0xffff_ffff {
+        return f32::from_bits(CANONICAL_NAN_S);
+    }
+    f32::from_bits(reg as u32)
+}
+
+fn is_snan_h(x: f16) -> bool {
+    x.is_nan() && x.to_bits() & 0x0200 == 0
+}
+
+fn is_snan_s(x: f32) -> bool {
+    x.is_nan() && x.to_bits() & 0x0040_0000 == 0
+}
+
+fn is_snan_d(x: f64) -> bool {
+    x.is_nan() && x.to_bits() & 0x0008_0000_0000_0000 == 0
+}
+
+pub enum HalfOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+// Binary16 arithmetic is done in f64, which holds sums and products of two halves
+// exactly, and rounded once to nearest even. Returns (bits, fflags).
+pub fn half_binop(op: HalfOp, a: f16, b: f16) -> (u16, u64) {
+    if a.is_nan() || b.is_nan() {
+        let flags = if is_snan_h(a) || is_snan_h(b) {
+            FFLAG_NV
+        } else {
+            0
+        };
+        return (CANONICAL_NAN_H, flags);
+    }
+
+    let (x, y) = (a.to_f64(), b.to_f64());
+    let exact = match op {
+        HalfOp::Add => x + y,
+        HalfOp::Sub => x - y,
+        HalfOp::Mul => x * y,
+        HalfOp::Div => x / y,
+    };
+    // inf - inf, 0 * inf, 0 / 0, inf / inf
+    if exact.is_nan() {
+        return (CANONICAL_NAN_H, FFLAG_NV);
+    }
+    if let HalfOp::Div = op {
+        if y == 0.0 && x.is_finite() {
+            return (f16::from_f64(exact).to_bits(), FFLAG_DZ);
+        }
+    }
+    // only infinite operands get here with an infinite result, that's exact
+    if exact.is_infinite() {
+        return (f16::from_f64(exact).to_bits(), 0);
+    }
+
+    let result = f16::from_f64(exact);
+    let inexact = match op {
+        // the quotient in f64 is rounded too, check it by multiplying back
+        HalfOp::Div => result.to_f64() * y != x,
+        _ => result.to_f64() != exact,
+    };
+    (result.to_bits(), rounding_flags(result, inexact))
+}
+
+pub fn half_sqrt(a: f16) -> (u16, u64) {
+    if a.is_nan() {
+        let flags = if is_snan_h(a) { FFLAG_NV } else { 0 };
+        return (CANONICAL_NAN_H, flags);
+    }
+    let x = a.to_f64();
+    // sqrt(-0) is -0
+    if x < 0.0 {
+        return (CANONICAL_NAN_H, FFLAG_NV);
+    }
+    let result = f16::from_f64(x.sqrt());
+    let inexact = result.to_f64() * result.to_f64() != x;
+    (result.to_bits(), rounding_flags(result, inexact))
+}
+
+// tininess is detected after rounding
+fn rounding_flags(result: f16, inexact: bool) -> u64 {
+    if !inexact {
+        return 0;
+    }
+    let mut flags = FFLAG_NX;
+    if result.is_infinite() {
+        flags |= FFLAG_OF;
+    }
+    if result.to_f64().abs() < f16::MIN_POSITIVE.to_f64() {
+        flags |= FFLAG_UF;
+    }
+    flags
+}
+
+// widening conversions are exact
+pub fn half_to_f32(a: f16) -> (u32, u64) {
+    if a.is_nan() {
+        let flags = if is_snan_h(a) { FFLAG_NV } else { 0 };
+        return (CANONICAL_NAN_S, flags);
+    }
+    (a.to_f32().to_bits(), 0)
+}
+
+pub fn half_to_f64(a: f16) -> (u64, u64) {
+    if a.is_nan() {
+        let flags = if is_snan_h(a) { FFLAG_NV } else { 0 };
+        return (CANONICAL_NAN_D, flags);
+    }
+    (a.to_f64().to_bits(), 0)
+}
+
+pub fn f32_to_half(a: f32) -> (u16, u64) {
+    if a.is_nan() {
+        let flags = if is_snan_s(a) { FFLAG_NV } else { 0 };
+        return (CANONICAL_NAN_H, flags);
+    }
+    let result = f16::from_f32(a);
+    let inexact = a.is_finite() && result.to_f32() != a;
+    (result.to_bits(), rounding_flags(result, inexact))
+}
+
+pub fn f64_to_half(a: f64) -> (u16, u64) {
+    if a.is_nan() {
+        let flags = if is_snan_d(a) { FFLAG_NV } else { 0 };
+        return (CANONICAL_NAN_H, flags);
+    }
+    let result = f16::from_f64(a);
+    let inexact = a.is_finite() && result.to_f64() != a;
+    (result.to_bits(), rounding_flags(result, inexact))
+}
+
+// Binary32 arithmetic for Zfinx, HalfOp names the same four operations. The host
+// rounds to nearest even, inexact is found with an exact error term. Returns (bits, fflags).
+pub fn single_binop(op: HalfOp, a: f32, b: f32) -> (u32, u64) {
+    if a.is_nan() || b.is_nan() {
+        let flags = if is_snan_s(a) || is_snan_s(b) {
+            FFLAG_NV
+        } else {
+            0
+        };
+        return (CANONICAL_NAN_S, flags);
+    }
+
+    let result = match op {
+        HalfOp::Add => a + b,
+        HalfOp::Sub => a - b,
+        HalfOp::Mul => a * b,
+        HalfOp::Div => a / b,
+    };
+    if result.is_nan() {
+        return (CANONICAL_NAN_S, FFLAG_NV);
+    }
+    if let HalfOp::Div = op {
+        if b == 0.0 && a.is_finite() {
+            return (result.to_bits(), FFLAG_DZ);
+        }
+    }
+    if !a.is_finite() || !b.is_finite() {
+        return (result.to_bits(), 0);
+    }
+
+    let (x, y, r) = (a as f64, b as f64, result as f64);
+    let inexact = result.is_infinite()
+        || match op {
+            // two-sum: the rounding error of a + b is exactly representable
+            HalfOp::Add | HalfOp::Sub => {
+                let b = if let HalfOp::Sub = op { -b } else { b };
+                let a_part = result - b;
+                let b_part = result - a_part;
+                (a - a_part) + (b - b_part) != 0.0
+            }
+            // products of two 24-bit significands are exact in f64
+            HalfOp::Mul => r != x * y,
+            HalfOp::Div => r * y != x,
+        };
+    (result.to_bits(), single_rounding_flags(result, inexact))
+}
+
+pub fn single_sqrt(a: f32) -> (u32, u64) {
+    if a.is_nan() {
+        let flags = if is_snan_s(a) { FFLAG_NV } else { 0 };
+        return (CANONICAL_NAN_S, flags);
+    }
+    // sqrt(-0) is -0
+    if a < 0.0 {
+        return (CANONICAL_NAN_S, FFLAG_NV);
+    }
+    let result = a.sqrt();
+    let inexact = (result as f64) * (result as f64) != a as f64;
+    (result.to_bits(), single_rounding_flags(result, inexact))
+}
+
+fn single_rounding_flags(result: f32, inexact: bool) -> u64 {
+    if !inexact {
+        return 0;
+    }
+    let mut flags = FFLAG_NX;
+    if result.is_infinite() {
+        flags |= FFLAG_OF;
+    }
+    if result.abs() < f32::MIN_POSITIVE {
+        flags |= FFLAG_UF;
+    }
+    flags
+}
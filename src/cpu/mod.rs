@@ -1,5 +1,18 @@
+pub mod block_cache;
+pub mod builder;
+pub mod coverage;
 pub mod cpu;
+pub mod crypto;
+pub mod disasm;
+pub mod float;
+pub mod timing;
+pub mod tlb;
+pub mod trace;
+pub mod vector;
 
 pub mod test_framework;
+#[cfg(test)]
 mod test_inst;
+#[cfg(test)]
+mod tests;
 mod utils;
@@ -1,12 +1,34 @@
 use std::{
+    env,
     fs::File,
     io::{Read, Write},
+    path::Path,
     process::Command,
 };
 
-use crate::cpu::cpu::Cpu;
+use crate::cpu::{
+    builder::CpuBuilder,
+    cpu::{Cpu, ExitReason},
+};
+use crate::exept::Exception;
+use crate::{elf::Elf, param::DRAM_BASE};
 const TEST_FOLDER: &str = "tests/";
 const BINARY_FOLDER: &str = "tests/target/";
+// -march, -mabi and --target of programs for an RV64 and an RV32 hart
+const RV64_TARGET: [&str; 3] = ["-march=rv64g", "-mabi=lp64", "--target=riscv64"];
+const RV32_TARGET: [&str; 3] = ["-march=rv32ima", "-mabi=ilp32", "--target=riscv32"];
+// written by build.rs when clang and llvm-objcopy can target riscv64
+const TOOLCHAIN_MARKER: &str = "tests/target/toolchain_available";
+
+// RUSTV_SKIP_COMPILE set, tests that assemble code skip then
+pub fn skip_compile() -> bool {
+    env::var_os("RUSTV_SKIP_COMPILE").is_some()
+}
+
+// false without the toolchain or with RUSTV_SKIP_COMPILE set
+pub fn toolchain_available() -> bool {
+    !skip_compile() && Path::new(TOOLCHAIN_MARKER).exists()
+}
 
 //clang -S source -nostdlib -march=rv64i -mabi=lp64 -mno-relax
 //add support for folders
@@ -16,9 +38,7 @@ fn generate_rv_assembly(source: &str, dest: &str) {
         .arg("-S")
         .arg(source)
         .arg("-nostdlib")
-        .arg("-march=rv64g")
-        .arg("-mabi=lp64")
-        .arg("--target=riscv64")
+        .args(RV64_TARGET)
         .arg("-mno-relax")
         .arg("-o")
         .arg(dest)
@@ -28,14 +48,12 @@ fn generate_rv_assembly(source: &str, dest: &str) {
 }
 
 //clang -Wl,-Ttext=0x0 -nostdlib -march=rv64i -mabi=lp64 -mno-relax -o source dest
-fn generate_rv_obj(source: &str, dest: &str) {
+fn generate_rv_obj(source: &str, dest: &str, target: [&str; 3]) {
     let cc = "clang";
     let output = Command::new(cc)
         .arg("-Wl,-Ttext=0x0")
         .arg("-nostdlib")
-        .arg("-march=rv64g")
-        .arg("-mabi=lp64")
-        .arg("--target=riscv64")
+        .args(target)
         .arg("-mno-relax")
         .arg("-o")
         .arg(dest)
@@ -59,25 +77,123 @@ fn generate_rv_bin(source: &str, dest: &str) {
 }
 
 // generate riscv binary from asm, run it for n_clocks
-pub fn rv_asm_helper(code: &str, testname: &str, n_clock: i64) -> Result<Cpu, std::io::Error> {
+pub fn rv_asm_helper(
+    code: &str,
+    testname: &str,
+    n_clock: i64,
+) -> Result<(Cpu, ExitReason), std::io::Error> {
+    let code = rv_asm_binary(code, testname)?;
+    run_program(code, vec![0], n_clock)
+}
+
+// rv_asm_helper with disk_data as the virtio-blk disk image
+pub fn rv_asm_helper_with_disk(
+    code: &str,
+    testname: &str,
+    disk_data: Vec<u8>,
+    n_clock: i64,
+) -> Result<(Cpu, ExitReason), std::io::Error> {
+    let code = rv_asm_binary(code, testname)?;
+    run_program(code, disk_data, n_clock)
+}
+
+// rv_asm_helper with the disk image read from disk_path
+pub fn rv_asm_helper_with_disk_file(
+    code: &str,
+    testname: &str,
+    disk_path: &str,
+    n_clock: i64,
+) -> Result<(Cpu, ExitReason), std::io::Error> {
+    let disk_data = std::fs::read(disk_path)?;
+    rv_asm_helper_with_disk(code, testname, disk_data, n_clock)
+}
+
+// rv_asm_helper with the C extension enabled, for hand-encoded compressed instructions
+pub fn rv_asm_helper_with_c(
+    code: &str,
+    testname: &str,
+    n_clock: i64,
+) -> Result<(Cpu, ExitReason), std::io::Error> {
+    let code = rv_asm_binary(code, testname)?;
+    let cpu = CpuBuilder::new(code, vec![0]).enable_c().build();
+    run(cpu, n_clock)
+}
+
+// generate riscv binary from asm
+pub fn rv_asm_binary(code: &str, testname: &str) -> Result<Vec<u8>, std::io::Error> {
+    asm_binary(code, testname, RV64_TARGET)
+}
+
+// rv_asm_binary for a CpuBuilder::rv32 hart
+pub fn rv32_asm_binary(code: &str, testname: &str) -> Result<Vec<u8>, std::io::Error> {
+    asm_binary(code, testname, RV32_TARGET)
+}
+
+fn asm_binary(code: &str, testname: &str, target: [&str; 3]) -> Result<Vec<u8>, std::io::Error> {
     let asm_path = TEST_FOLDER.to_owned() + testname + ".s";
     let mut file = File::create(&asm_path)?;
 
     let binary_path = BINARY_FOLDER.to_owned() + testname;
     let final_path = BINARY_FOLDER.to_owned() + testname + ".bin";
     file.write(&code.as_bytes())?;
-    generate_rv_obj(&asm_path, &binary_path);
+    generate_rv_obj(&asm_path, &binary_path, target);
     generate_rv_bin(&binary_path, &final_path);
 
     let mut file_bin = File::open(final_path)?;
     let mut code = Vec::new();
     file_bin.read_to_end(&mut code)?;
-    run_cpu(code, vec![0], n_clock)
+    Ok(code)
 }
 
-// generate riscv binary from C, run it for n_clocks
-pub fn rv_c_helper(path: &str, testname: &str, n_clock: i64) -> Result<Cpu, std::io::Error> {
+// Symbols of a program rv_asm_binary built. It's linked at 0 and loaded at
+// DRAM_BASE, so that's added to every address.
+pub fn rv_asm_symbols(testname: &str) -> Result<Vec<(u64, u64, String)>, std::io::Error> {
+    let elf = Elf::parse(&std::fs::read(BINARY_FOLDER.to_owned() + testname)?)?;
+    Ok(elf
+        .symbols
+        .into_iter()
+        .map(|(start, size, name)| (start + DRAM_BASE, size, name))
+        .collect())
+}
+
+// generate riscv binary from C, run it for n_clocks, -1 - until it stops, or
+// with stop_at until the pc gets to that symbol
+pub fn rv_c_helper(
+    path: &str,
+    testname: &str,
+    n_clock: i64,
+    stop_at: Option<&str>,
+) -> Result<(Cpu, ExitReason), std::io::Error> {
+    let Some(symbol) = stop_at else {
+        return run_program(rv_c_binary(path, testname)?, vec![0], n_clock);
+    };
+    // the pre-compiled binary has no symbols, the ELF is linked at 0
+    let elf = Elf::parse(&rv_c_debug_elf(path, testname)?)?;
+    let symbols = elf
+        .symbols
+        .iter()
+        .map(|(start, size, name)| (start + DRAM_BASE, *size, name.clone()))
+        .collect();
+    let mut cpu = CpuBuilder::new(elf.image(0), vec![0])
+        .symbols(symbols)
+        .build();
+    let max_cycles = match n_clock {
+        -1 => u64::MAX,
+        n => n as u64,
+    };
+    let reason = cpu.run_until_symbol(symbol, max_cycles);
+    Ok((cpu, program_exit(reason)))
+}
+
+// generate riscv binary from C, m_tests/<name>.c pre-compiled by build.rs is used as is
+pub fn rv_c_binary(path: &str, testname: &str) -> Result<Vec<u8>, std::io::Error> {
     let c_path = path;
+    if let Some(stem) = Path::new(c_path).file_stem() {
+        let prebuilt = BINARY_FOLDER.to_owned() + &stem.to_string_lossy() + ".bin";
+        if let Ok(code) = std::fs::read(prebuilt) {
+            return Ok(code);
+        }
+    }
 
     let asm_path = TEST_FOLDER.to_owned() + testname + ".s";
     generate_rv_assembly(c_path, &asm_path);
@@ -85,54 +201,69 @@ pub fn rv_c_helper(path: &str, testname: &str, n_clock: i64) -> Result<Cpu, std:
     let binary_path = BINARY_FOLDER.to_owned() + testname;
     let final_path = BINARY_FOLDER.to_owned() + testname + ".bin";
 
-    generate_rv_obj(&asm_path, &binary_path);
+    generate_rv_obj(&asm_path, &binary_path, RV64_TARGET);
     generate_rv_bin(&binary_path, &final_path);
 
     let mut file_bin = File::open(final_path)?;
     let mut code = Vec::new();
     file_bin.read_to_end(&mut code)?;
-    run_cpu(code, vec![0], n_clock)
-}
-
-pub fn run_cpu(code: Vec<u8>, disk_image: Vec<u8>, n_clock: i64) -> Result<Cpu, std::io::Error> {
-    let mut cpu = Cpu::new(code, disk_image);
-    let mut n_clock = n_clock;
-
-    while n_clock != 0 || n_clock == -1 {
-        let inst = match cpu.fetch() {
-            Ok(0) => break,
-            //Ok(0xfee79ce3) => break,
-            Ok(inst) => inst,
-            Err(e) => {
-                cpu.handle_exception(e);
-                if e.is_fatal() {
-                    println!("{}", e);
-                    break;
-                }
-                continue;
-            }
-        };
-
-        match cpu.execute(inst) {
-            Ok(pc) => cpu.pc = pc,
-            Err(e) => {
-                cpu.handle_exception(e);
-                if e.is_fatal() {
-                    println!("{}", e);
-                    break;
-                }
-            }
-        }
+    Ok(code)
+}
 
-        match cpu.check_pending_interrupt() {
-            Some(interrupt) => cpu.handle_interrupt(interrupt),
-            None => (),
-        }
+// ELF of the C program at path built with -g, it keeps the DWARF the raw binary loses
+pub fn rv_c_debug_elf(path: &str, testname: &str) -> Result<Vec<u8>, std::io::Error> {
+    let elf_path = BINARY_FOLDER.to_owned() + testname;
+    let output = Command::new("clang")
+        .arg("-g")
+        .arg("-Wl,-Ttext=0x0")
+        .arg("-nostdlib")
+        .arg("-march=rv64g")
+        .arg("-mabi=lp64")
+        .arg("--target=riscv64")
+        .arg("-mno-relax")
+        .arg("-o")
+        .arg(&elf_path)
+        .arg(path)
+        .output()?;
+    println!("{}", String::from_utf8_lossy(&output.stderr));
+    std::fs::read(elf_path)
+}
 
-        if n_clock != -1 {
-            n_clock -= 1;
-        }
+// test programs return from main to address 0, the fetch fault there ends them cleanly
+pub fn program_exit(reason: ExitReason) -> ExitReason {
+    match reason {
+        ExitReason::FatalException(Exception::InstructionAccessFault(0)) => ExitReason::Clean,
+        reason => reason,
     }
+}
+
+fn run_program(
+    code: Vec<u8>,
+    disk_image: Vec<u8>,
+    n_clock: i64,
+) -> Result<(Cpu, ExitReason), std::io::Error> {
+    let (cpu, reason) = run(CpuBuilder::new(code, disk_image).build(), n_clock)?;
+    Ok((cpu, program_exit(reason)))
+}
+
+// run for n_clocks, reset and run for n_clocks again,
+// returns registers after the first run and cpu after the second
+pub fn run_cpu_reset_after_n(
+    code: Vec<u8>,
+    disk_image: Vec<u8>,
+    n_clock: i64,
+) -> Result<([u64; 32], Cpu), std::io::Error> {
+    let (mut cpu, _) = run(CpuBuilder::new(code, disk_image).build(), n_clock)?;
+    let regs = cpu.regs;
+    cpu.reset();
+    Ok((regs, run(cpu, n_clock)?.0))
+}
 
-    Ok(cpu)
+// run already built cpu for n_clocks, -1 - until it stops
+pub fn run(mut cpu: Cpu, n_clock: i64) -> Result<(Cpu, ExitReason), std::io::Error> {
+    let reason = match n_clock {
+        -1 => cpu.run_to_halt(),
+        n => cpu.run_for(n as u64),
+    };
+    Ok((cpu, reason))
 }
@@ -1,40 +1,126 @@
+use std::{
+    collections::VecDeque,
+    io::{Cursor, Write},
+    mem::discriminant,
+    sync::{atomic::Ordering, Arc, Mutex},
+    thread,
+};
+
 use crate::{
-    cpu::test_framework::rv_asm_helper, cpu::test_framework::rv_c_helper, param::DRAM_BASE,
+    bus::{MemStats, RecordingSniffer, RegionPerms},
+    config::MachineConfig,
+    cpu::builder::{CpuBuilder, DeterministicMode},
+    cpu::cpu::AccessType,
+    cpu::cpu::{pc_align_mask, Cpu, ExitReason, Reservation, StepResult},
+    cpu::float::*,
+    cpu::test_framework::*,
+    cpu::timing::SifiveTiming,
+    cpu::trace::{record_trace, TraceRecord, TRACE_RECORD_SIZE},
+    csr::*,
+    debug_module::*,
+    debugger::Debugger,
+    device::virtio::{
+        backends::{tap::TapBackend, NetworkBackend},
+        virtio_net::VirtioNet,
+    },
+    dram::{AmoOp, Dram},
+    dwarf::parse_line_table,
+    elf::Elf,
+    event_log::EventLog,
+    exept::Exception,
+    interrupt::interrupt::Interrupt,
+    param::*,
 };
 
+// returns from the test with RUSTV_SKIP_COMPILE set, fails it if code can't be assembled here
+macro_rules! require_toolchain {
+    ($name: expr) => {
+        if skip_compile() {
+            println!("skipping {}: RUSTV_SKIP_COMPILE is set", $name);
+            return;
+        }
+        assert!(
+            toolchain_available(),
+            "{}: no riscv64 clang / llvm-objcopy, set RUSTV_SKIP_COMPILE to skip",
+            $name
+        );
+    };
+}
+
+// "a0" => value checks registers, instret => n first checks the retired instruction count,
+// rv32: first builds and runs the code for an RV32 hart and returns it
 macro_rules! riscv_asm_test {
-        ($code:expr, $name: expr, $clock:expr, $($real:expr => $expect:expr),* ) => {
-            match rv_asm_helper($code, $name, $clock) {
-                Ok(cpu) => {
-                    $(if cpu.reg($real)!= $expect {
-                        cpu.dump_registers();
-                        panic!("left {}, right {}", cpu.reg($real), $expect);
-                    })*
-                }
-                Err(e) => {
-                    println!("error: {}", e);
-                     assert!(false);
-                    }
-                }
-            }
+    (@run [$compile:ident $(.$opt:ident)*] $code:expr, $name: expr, $clock:expr, $($real:expr => $expect:expr),* ) => {{
+        require_toolchain!($name);
+        let binary = $compile($code, $name).unwrap_or_else(|e| panic!("{}: {}", $name, e));
+        let mut cpu = CpuBuilder::new(binary, vec![0])$(.$opt())*.build();
+        if let ExitReason::FatalException(e) = program_exit(cpu.run_for($clock)) {
+            panic!("{}: fatal exception {} at pc {:#x}", $name, e, cpu.pc);
+        }
+        $(if cpu.reg($real) != $expect {
+            cpu.dump_registers();
+            panic!("left {}, right {}", cpu.reg($real), $expect);
+        })*
+        cpu
+    }};
+    (rv32: $code:expr, $name: expr, $clock:expr, $($real:expr => $expect:expr),* ) => {
+        riscv_asm_test!(@run [rv32_asm_binary .rv32] $code, $name, $clock, $($real => $expect),*)
+    };
+    ($code:expr, $name: expr, $clock:expr, instret => $instret:expr, $($real:expr => $expect:expr),* ) => {
+        let cpu = riscv_asm_test!(@run [rv_asm_binary] $code, $name, $clock, $($real => $expect),*);
+        if cpu.instret != $instret {
+            cpu.print_history();
+            panic!("{}: {} instructions retired, expected {}", $name, cpu.instret, $instret);
+        }
+    };
+    ($code:expr, $name: expr, $clock:expr, $($real:expr => $expect:expr),* ) => {
+        riscv_asm_test!(@run [rv_asm_binary] $code, $name, $clock, $($real => $expect),*);
+    };
+}
+
+// Runs code and checks it raised an exception of the same variant as expected, the
+// value it carries isn't compared. Traps go to a zero word that ends the run, so
+// mcause still holds what was raised when the exception isn't fatal.
+macro_rules! riscv_asm_exception_test {
+    ($code:expr, $name: expr, $clock:expr, $expected:expr) => {{
+        require_toolchain!($name);
+        let binary = rv_asm_binary($code, $name).unwrap_or_else(|e| panic!("{}: {}", $name, e));
+        let mut cpu = CpuBuilder::new(binary, vec![0]).build();
+        cpu.csr.store(MTVEC, DRAM_BASE + 0x100000);
+        let expected: Exception = $expected;
+        match program_exit(cpu.run_for($clock)) {
+            ExitReason::FatalException(e) => assert!(
+                discriminant(&e) == discriminant(&expected),
+                "{}: raised {}, expected {}",
+                $name,
+                e,
+                expected
+            ),
+            _ => assert!(
+                cpu.csr.load(MCAUSE) == expected.code() && cpu.pc == DRAM_BASE + 0x100000,
+                "{}: mcause {}, expected {}",
+                $name,
+                cpu.csr.load(MCAUSE),
+                expected
+            ),
         }
+    }};
+}
 
 macro_rules! riscv_c_test {
-            ($code:expr, $path: expr, $clock:expr, $($real:expr => $expect:expr),* ) => {
-                match rv_c_helper($code, $path, $clock) {
-                    Ok(cpu) => {
-                        $(if cpu.reg($real)!= $expect {
-                            cpu.dump_registers();
-                            panic!("left {}, right {}", cpu.reg($real), $expect);
-                        })*
-                    }
-                    Err(e) => {
-                        println!("error: {}", e);
-                         assert!(false);
-                        }
-                    }
-                }
-            }
+    ($code:expr, $path: expr, $clock:expr, $($real:expr => $expect:expr),* ) => {
+        require_toolchain!($path);
+        let binary = rv_c_binary($code, $path).unwrap_or_else(|e| panic!("{}: {}", $path, e));
+        let mut cpu = CpuBuilder::new(binary, vec![0]).build();
+        if let ExitReason::FatalException(e) = program_exit(cpu.run_for($clock)) {
+            panic!("{}: fatal exception {} at pc {:#x}", $path, e, cpu.pc);
+        }
+        $(if cpu.reg($real) != $expect {
+            cpu.dump_registers();
+            panic!("left {}, right {}", cpu.reg($real), $expect);
+        })*
+    };
+}
 
 #[test]
 fn test_addi_1() {
@@ -42,6 +128,38 @@ fn test_addi_1() {
     riscv_asm_test!(code, "test_addi_1", 1, "x1" => 42);
 }
 
+#[test]
+fn test_instret_count() {
+    let code = "addi a0, x0, 1
+addi a0, a0, 2
+addi a0, a0, 3";
+    riscv_asm_test!(code, "test_instret_count", 10, instret => 3, "a0" => 6);
+}
+
+#[test]
+fn test_instret_count_loop() {
+    // two instructions before the loop and two per iteration
+    let code = "li a0, 0
+li a1, 5
+loop:
+addi a0, a0, 1
+bne a0, a1, loop";
+    riscv_asm_test!(code, "test_instret_count_loop", 100, instret => 12, "a0" => 5);
+}
+
+#[test]
+fn test_instret_count_call() {
+    let code = "li a0, 20
+jal ra, add22
+j end
+add22:
+addi a0, a0, 22
+ret
+end:
+addi a1, a0, 0";
+    riscv_asm_test!(code, "test_instret_count_call", 100, instret => 6, "a1" => 42);
+}
+
 #[test]
 fn test_addi_2() {
     let code = "addi x1, x0, -42";
@@ -87,10 +205,10 @@ addi x20, x20, 1";
 #[test]
 fn test_jalr() {
     let code = "
-        addi a1, zero, 42
+        addi a1, zero, 44
         jalr a0, -8(a1)
     ";
-    riscv_asm_test!(code, "test_jalr", 2, "a0" => DRAM_BASE + 8, "pc" => 34);
+    riscv_asm_test!(code, "test_jalr", 2, "a0" => DRAM_BASE + 8, "pc" => 36);
 }
 
 #[test]
@@ -297,6 +415,57 @@ is_secret_value:
     riscv_asm_test!(code, "test_func", 100, "x30" => 3, "x31" => 7);
 }
 
+#[test]
+fn test_backtrace() {
+    require_toolchain!("test_backtrace");
+    // test_func with frame pointers, stopped by the ebreak on the second call
+    let code = "
+main:
+    addi sp, sp, -16
+    sd ra, 8(sp)
+    sd s0, 0(sp)
+    addi s0, sp, 16
+    call is_secret_value
+    mv x30, a2
+    li a0, 0x69
+    call is_secret_value
+    mv x31, a2
+    ld ra, 8(sp)
+    ld s0, 0(sp)
+    addi sp, sp, 16
+    ret
+is_secret_value:
+    addi sp, sp, -16
+    sd ra, 8(sp)
+    sd s0, 0(sp)
+    addi s0, sp, 16
+    li a1, 0x69
+    beq a0, a1, .get_sec
+    li a2, 0x3
+    j .ret
+.get_sec:
+    ebreak
+.ret:
+    ld ra, 8(sp)
+    ld s0, 0(sp)
+    addi sp, sp, 16
+    ret
+";
+    let binary = rv_asm_binary(code, "test_backtrace").unwrap();
+    let symbols = rv_asm_symbols("test_backtrace").unwrap();
+    let mut cpu = CpuBuilder::new(binary, vec![0]).symbols(symbols).build();
+    // the breakpoint traps to mtvec = 0, where the run ends
+    assert!(matches!(cpu.run_to_halt(), ExitReason::Clean));
+    assert_eq!(cpu.regs[30], 3);
+
+    // is_secret_value returns past the second call (auipc + jalr), main to ra = 0
+    assert_eq!(cpu.backtrace(), vec![DRAM_BASE + 0x28, 0]);
+    assert_eq!(
+        cpu.backtrace_symbolic(),
+        vec!["0x80000028 <main+0x28>", "0x0"]
+    );
+}
+
 #[test]
 fn test_csrs1() {
     let code = "
@@ -321,6 +490,85 @@ fn test_simple_c() {
     riscv_c_test!("./m_tests/simple.c", "test_simple_c", 10000, "a0" => 42);
 }
 
+#[test]
+fn test_c_with_source_trace() {
+    require_toolchain!("test_c_with_source_trace");
+    let elf = rv_c_debug_elf("./m_tests/simple.c", "test_c_with_source_trace").unwrap();
+    // linked at 0 like the raw binaries, loaded at DRAM_BASE
+    let code = Elf::parse(&elf).unwrap().image(0);
+    let source_lines = parse_line_table(&elf, DRAM_BASE).unwrap();
+    assert!(!source_lines.is_empty());
+
+    let out = SharedBuf::default();
+    let mut cpu = CpuBuilder::new(code, vec![0])
+        .source_lines(source_lines)
+        .instruction_trace(Box::new(out.clone()))
+        .build();
+    program_exit(cpu.run_for(50));
+    assert_eq!(cpu.reg("a0"), 42);
+    assert!(cpu.source_location(DRAM_BASE).is_some());
+
+    let trace = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+    assert!(trace.lines().any(|line| line.contains("simple.c:")));
+}
+
+#[test]
+fn test_single_step_debug() {
+    // li a0, 5; li a1, 7; add a2, a0, a1
+    let code = [0x00500513u32, 0x00700593, 0x00b50633]
+        .iter()
+        .flat_map(|inst| inst.to_le_bytes())
+        .collect();
+    let out = SharedBuf::default();
+    let mut cpu = CpuBuilder::new(code, vec![0])
+        .with_single_step_debug()
+        .debug_output(Box::new(out.clone()))
+        .build();
+    assert!(matches!(cpu.run_to_halt(), ExitReason::Clean));
+
+    let output = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(lines.len(), 3);
+    for (i, line) in lines.iter().enumerate() {
+        assert!(line.starts_with(&format!("PC={:#x} ", DRAM_BASE + 4 * i as u64)));
+    }
+    assert_eq!(
+        lines[0],
+        "PC=0x80000000 inst=0x00500513 addi a0, zero, 5 mode=M zero=0x0 -> a0=0x5"
+    );
+    assert_eq!(
+        lines[2],
+        "PC=0x80000008 inst=0x00b50633 add a2, a0, a1 mode=M a0=0x5 a1=0x7 -> a2=0xc"
+    );
+}
+
+#[test]
+fn test_source_location() {
+    require_toolchain!("test_source_location");
+    // .loc is what a compiler emits for every line of C
+    let code = "
+.file 1 \"prog.c\"
+.loc 1 3 0
+addi a0, zero, 40
+.loc 1 4 0
+addi a0, a0, 2
+";
+    let binary = rv_asm_binary(code, "test_source_location").unwrap();
+    let elf = std::fs::read("tests/target/test_source_location").unwrap();
+    let source_lines = parse_line_table(&elf, DRAM_BASE).unwrap();
+    let mut cpu = CpuBuilder::new(binary, vec![0])
+        .source_lines(source_lines)
+        .build();
+    assert_eq!(cpu.source_location(DRAM_BASE + 4), Some(("prog.c", 4)));
+    assert_eq!(cpu.source_location(DRAM_BASE + 8), None);
+    assert_eq!(
+        cpu.trace_line(DRAM_BASE, 0x02800513),
+        "0x80000000: 0x02800513 addi a0, zero, 40 (prog.c:3)"
+    );
+    cpu.run_for(2);
+    assert_eq!(cpu.reg("a0"), 42);
+}
+
 #[test]
 fn test_fib() {
     riscv_c_test!("./m_tests/fib.c", "test_fib_c", 10000, "a0" => 55);
@@ -343,50 +591,148 @@ ld a0, 0(sp)";
     riscv_asm_test!(code, "test_amoswap_w", 10, "a2" => 0x10, "a0" => 0x20);
 }
 
+// 0x13 and 0x5 share a bit, so add, and, or and xor all leave different values
 #[test]
 fn test_amoadd() {
-    let code = "li a0, 0x10
+    let code = "li a0, 0x13
 addi sp, sp, -8
 sd a0, 0(sp)
 li a1, 0x5
 amoadd.d a2, a1, 0(sp)
 ld a0, 0(sp)";
-    riscv_asm_test!(code, "test_amoadd", 10, "a2" => 0x10, "a0" => 0x15);
+    riscv_asm_test!(code, "test_amoadd", 10, "a2" => 0x13, "a0" => 0x13 + 0x5);
 }
 
 #[test]
 fn test_amoand() {
-    let code = "li a0, 0x10
+    let code = "li a0, 0x13
 addi sp, sp, -8
 sd a0, 0(sp)
 li a1, 0x5
 amoand.d a2, a1, 0(sp)
 ld a0, 0(sp)";
-    riscv_asm_test!(code, "test_amoand", 10, "a2" => 0x10, "a0" => 0x10 & 0x5);
+    riscv_asm_test!(code, "test_amoand", 10, "a2" => 0x13, "a0" => 0x13 & 0x5);
 }
 
 #[test]
 fn test_amoor() {
-    let code = "li a0, 0x10
+    let code = "li a0, 0x13
 addi sp, sp, -8
 sd a0, 0(sp)
 li a1, 0x5
 amoor.w a2, a1, 0(sp)
 ld a0, 0(sp)";
-
-    riscv_asm_test!(code, "test_amoor", 10, "a0" => 0x10 | 0x5);
+    riscv_asm_test!(code, "test_amoor", 10, "a2" => 0x13, "a0" => 0x13 | 0x5);
 }
 
 #[test]
 fn test_amoxor() {
-    let code = "li a0, 0x10
+    let code = "li a0, 0x13
 addi sp, sp, -8
 sd a0, 0(sp)
 li a1, 0x5
-amoor.w a2, a1, 0(sp)
+amoxor.w a2, a1, 0(sp)
 ld a0, 0(sp)";
+    riscv_asm_test!(code, "test_amoxor", 10, "a2" => 0x13, "a0" => 0x13 ^ 0x5);
+}
+
+// .w min / max compare the low 32 bits of both operands as signed words,
+// whatever the upper half of rs2 holds. t0 is the word after the operand.
+#[test]
+fn test_amomin_amomax_w_sign() {
+    let code = "addi sp, sp, -16
+li t0, -1
+sw t0, 4(sp)
+li a0, -2147483648
+sw a0, 0(sp)
+li a1, -2147483647
+amomin.w a2, a1, (sp)
+li a1, 0x80000000
+li a0, 5
+sw a0, 0(sp)
+amomin.w a3, a1, (sp)
+lw a4, 0(sp)
+li a1, -2147483647
+li a0, -1
+sw a0, 0(sp)
+amomax.w a5, a1, (sp)
+lw a6, 0(sp)
+li a1, 0x100000000
+amomax.w a7, a1, (sp)
+lw s2, 0(sp)
+lw t0, 4(sp)";
+    riscv_asm_test!(code, "test_amomin_amomax_w_sign", 50,
+        "a2" => i32::MIN as i64 as u64,
+        "a3" => 5,
+        "a4" => i32::MIN as i64 as u64,
+        "a5" => u64::MAX,
+        "a6" => u64::MAX,
+        "a7" => u64::MAX,
+        "s2" => 0,
+        "t0" => u64::MAX);
+}
+
+// -2 against 3 is smaller signed and larger unsigned
+#[test]
+fn test_amomin_amomax_w() {
+    let code = "addi sp, sp, -32
+li t0, -2
+sw t0, 0(sp)
+sw t0, 8(sp)
+sw t0, 16(sp)
+sw t0, 24(sp)
+li a1, 3
+amomin.w a2, a1, (sp)
+addi t1, sp, 8
+amomax.w a3, a1, (t1)
+addi t1, sp, 16
+amominu.w a4, a1, (t1)
+addi t1, sp, 24
+amomaxu.w a5, a1, (t1)
+lw s2, 0(sp)
+lw s3, 8(sp)
+lw s4, 16(sp)
+lw s5, 24(sp)";
+    riscv_asm_test!(code, "test_amomin_amomax_w", 30,
+        "a2" => -2i64 as u64,
+        "a3" => -2i64 as u64,
+        "a4" => -2i64 as u64,
+        "a5" => -2i64 as u64,
+        "s2" => -2i64 as u64,
+        "s3" => 3,
+        "s4" => 3,
+        "s5" => -2i64 as u64);
+}
 
-    riscv_asm_test!(code, "test_amoxor", 10, "a0" => 0x10 ^ 0x5);
+#[test]
+fn test_amomin_amomax_d() {
+    let code = "addi sp, sp, -32
+li t0, -2
+sd t0, 0(sp)
+sd t0, 8(sp)
+sd t0, 16(sp)
+sd t0, 24(sp)
+li a1, 3
+amomin.d a2, a1, (sp)
+addi t1, sp, 8
+amomax.d a3, a1, (t1)
+addi t1, sp, 16
+amominu.d a4, a1, (t1)
+addi t1, sp, 24
+amomaxu.d a5, a1, (t1)
+ld s2, 0(sp)
+ld s3, 8(sp)
+ld s4, 16(sp)
+ld s5, 24(sp)";
+    riscv_asm_test!(code, "test_amomin_amomax_d", 30,
+        "a2" => -2i64 as u64,
+        "a3" => -2i64 as u64,
+        "a4" => -2i64 as u64,
+        "a5" => -2i64 as u64,
+        "s2" => -2i64 as u64,
+        "s3" => 3,
+        "s4" => 3,
+        "s5" => -2i64 as u64);
 }
 
 #[test]
@@ -481,6 +827,35 @@ divw a2, a0, a1
     riscv_asm_test!(code, "test_divw_overflow", 10, "a2" => 0x80000000 as u32 as i32 as i64 as u64);
 }
 
+#[test]
+fn test_div_overflow() {
+    let code = "li a0, 1
+slli a0, a0, 63
+li a1, -1
+div a2, a0, a1
+rem a3, a0, a1
+";
+    riscv_asm_test!(code, "test_div_overflow", 10, "a2" => i64::MIN as u64, "a3" => 0);
+}
+
+#[test]
+fn test_divuw_divisor_zero() {
+    let code = "li a0, 123
+li a1, 0
+divuw a2, a0, a1
+";
+    riscv_asm_test!(code, "test_divuw_divisor_zero", 10, "a2" => u64::MAX);
+}
+
+#[test]
+fn test_divuw_sign_extend() {
+    let code = "li a0, 0x1ffffffff
+li a1, 1
+divuw a2, a0, a1
+";
+    riscv_asm_test!(code, "test_divuw_sign_extend", 10, "a2" => -1 as i64 as u64);
+}
+
 #[test]
 fn test_mulw_minus_one() {
     let code = "li a0, -1
@@ -519,3 +894,3466 @@ fn test_uart_demo() {
     // not actually a test
     riscv_c_test!("./m_tests/uart_demo.c", "test_uart_demo", 0, "a0" => 0);
 }
+
+#[test]
+fn test_elf_symbols() {
+    require_toolchain!("test_elf_symbols");
+    let code = "start:
+addi a0, x0, 1
+addi a1, x0, 2
+helper:
+addi a2, x0, 3
+ecall
+";
+    let binary = rv_asm_binary(code, "test_elf_symbols").unwrap();
+    let symbols = rv_asm_symbols("test_elf_symbols").unwrap();
+    assert!(symbols.contains(&(DRAM_BASE, 0, "start".to_string())));
+    assert!(symbols.contains(&(DRAM_BASE + 8, 0, "helper".to_string())));
+
+    let mut cpu = CpuBuilder::new(binary, vec![0]).symbols(symbols).build();
+    assert_eq!(cpu.symbol_at(DRAM_BASE), Some(("start", 0)));
+    assert_eq!(cpu.symbol_at(DRAM_BASE + 4), Some(("start", 4)));
+    assert_eq!(cpu.symbol_at(DRAM_BASE + 12), Some(("helper", 4)));
+    assert_eq!(cpu.symbol_at(DRAM_BASE - 4), None);
+    assert_eq!(cpu.format_addr(DRAM_BASE + 4), "0x80000004 <start+0x4>");
+
+    // the ecall after helper traps to mtvec = 0, where the run ends
+    assert!(matches!(cpu.run_to_halt(), ExitReason::Clean));
+    let fault = Exception::LoadAccessFault(0x1000);
+    assert_eq!(fault.display_with_symbols(&cpu), "LoadAccessFault 0x1000");
+    let ecall = Exception::EnvironmentCallFromMMode(cpu.csr.load(MEPC));
+    assert_eq!(
+        ecall.display_with_symbols(&cpu),
+        "EnvironmentCallFromMMode 0x8000000c <helper+0x4>"
+    );
+    // an ELF with sized symbols, one that doesn't cover the address is skipped
+    cpu.symbols = vec![(DRAM_BASE, 4, "small".to_string())];
+    assert_eq!(cpu.symbol_at(DRAM_BASE + 4), None);
+}
+
+#[test]
+fn test_history() {
+    require_toolchain!("test_history");
+    let code = "addi x1, x0, 1
+addi x2, x0, 2
+addi x3, x0, 3
+";
+    let (cpu, _) = rv_asm_helper(code, "test_history", 3).unwrap();
+    assert_eq!(cpu.history.len(), 3);
+    assert_eq!(cpu.history[0], (DRAM_BASE + 8, 0x00300193));
+    assert_eq!(cpu.history[2].0, DRAM_BASE);
+}
+
+#[test]
+fn test_fence_i() {
+    // overwrite the last instruction with "addi x31, x0, 7" before fetching it
+    let code = "auipc t0, 0
+li t1, 0x00700f93
+sw t1, 20(t0)
+fence.i
+addi x31, x0, 1
+";
+    riscv_asm_test!(code, "test_fence_i", 6, "x31" => 7);
+}
+
+#[test]
+fn test_jal_backward() {
+    let code = "jal x0, 12
+addi x31, x0, 5
+jal x0, 8
+jal x1, -8
+";
+    riscv_asm_test!(code, "test_jal_backward", 4, "x31" => 5, "ra" => DRAM_BASE + 16);
+}
+
+#[test]
+fn test_jal_max_forward() {
+    // largest 4-byte aligned J-immediate: 0xffffc
+    let code = "jal x1, 1048572";
+    riscv_asm_test!(code, "test_jal_max_forward", 2, "ra" => DRAM_BASE + 4, "pc" => DRAM_BASE + 0xffffc);
+}
+
+#[test]
+fn test_virtio_init_sequence() {
+    let code = "li t0, 0x10001000
+lw a0, 0(t0)
+lw a1, 8(t0)
+li t1, 1
+sw t1, 0x70(t0)
+lw a2, 0x70(t0)
+ori t1, t1, 2
+sw t1, 0x70(t0)
+lw a3, 0x70(t0)
+ori t1, t1, 8
+sw t1, 0x70(t0)
+lw a4, 0x70(t0)
+ori t1, t1, 4
+sw t1, 0x70(t0)
+lw a5, 0x70(t0)
+li t1, 0x80
+sw t1, 0x70(t0)
+lw a6, 0x70(t0)
+";
+    riscv_asm_test!(code, "test_virtio_init_sequence", 30, "a0" => 0x74726976, "a1" => 2,
+                    "a2" => 1, "a3" => 3, "a4" => 11, "a5" => 15, "a6" => 0);
+}
+
+#[test]
+fn test_sbi_get_spec_version() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).with_sbi().build();
+    // S-mode
+    cpu.mode = 0b01;
+    cpu.regs[17] = 0x10; // a7 - base extension
+    cpu.regs[16] = 0x0; // a6 - sbi_get_spec_version
+    cpu.handle_exception(Exception::EnvironmentCallFromSMode(cpu.pc));
+    assert_eq!(cpu.reg("a0"), 0);
+    assert_eq!(cpu.reg("a1"), 1 << 24);
+    assert_eq!(cpu.pc, DRAM_BASE + 4);
+    assert_eq!(cpu.mode, 0b01);
+}
+
+#[test]
+fn test_machine_ids() {
+    let code = "csrr a0, mvendorid
+csrr a1, marchid
+csrr a2, mimpid
+";
+    riscv_asm_test!(code, "test_machine_ids", 10, "a0" => 0, "a1" => 0x70200, "a2" => 0x70200);
+
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).with_sbi().build();
+    // read-only, writes are dropped and reset keeps them
+    for csr in [MVENDORID, MARCHID, MIMPID] {
+        cpu.csr.store(csr, 0x1234);
+    }
+    cpu.reset();
+    assert_eq!(cpu.reg("mvendorid"), QEMU_MVENDORID);
+    assert_eq!(cpu.reg("marchid"), QEMU_MARCHID);
+    assert_eq!(cpu.reg("mimpid"), QEMU_MIMPID);
+    // and what sbi_get_marchid reports
+    cpu.mode = 0b01;
+    cpu.regs[17] = 0x10;
+    cpu.regs[16] = 0x5;
+    cpu.handle_exception(Exception::EnvironmentCallFromSMode(cpu.pc));
+    assert_eq!(cpu.reg("a0"), 0);
+    assert_eq!(cpu.reg("a1"), 0x70200);
+}
+
+#[test]
+fn test_dump_csrs() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).build();
+    cpu.csr.store(MTVEC, 0x8000_1000);
+    cpu.csr.store(MSCRATCH, 0x42);
+    cpu.csr.store(SEPC, 0x1234);
+    let output = cpu.format_csrs();
+    assert!(output.contains("mtvec"));
+    assert!(output.contains("0x80001000"));
+    assert!(output.contains("mscratch"));
+    assert!(output.contains("0x42"));
+    assert!(output.contains("sepc"));
+    assert!(output.contains("0x1234"));
+    assert!(!output.contains("satp"));
+}
+
+#[test]
+fn test_sfence_vma_tlb() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).build();
+    let root = DRAM_BASE + 0x10000;
+    let l1 = DRAM_BASE + 0x11000;
+    let l0 = DRAM_BASE + 0x12000;
+    let pte = |pa: u64, flags: u64| ((pa >> 12) << 10) | flags;
+    // va 0x1000 -> DRAM_BASE + 0x20000
+    cpu.bus.store(root, 64, pte(l1, 0b1)).unwrap();
+    cpu.bus.store(l1, 64, pte(l0, 0b1)).unwrap();
+    cpu.bus
+        .store(l0 + 8, 64, pte(DRAM_BASE + 0x20000, 0b111))
+        .unwrap();
+    cpu.csr.store(SATP, (8 << 60) | (root >> 12));
+    cpu.page_table = root;
+    cpu.enable_paging = true;
+
+    let (pa, _) = cpu.translate(0x1234, AccessType::Load).unwrap();
+    assert_eq!(pa, DRAM_BASE + 0x20234);
+
+    // remap, stale translation is still cached
+    cpu.bus
+        .store(l0 + 8, 64, pte(DRAM_BASE + 0x30000, 0b111))
+        .unwrap();
+    let (pa, _) = cpu.translate(0x1234, AccessType::Load).unwrap();
+    assert_eq!(pa, DRAM_BASE + 0x20234);
+
+    // sfence.vma zero, zero
+    cpu.execute(0x12000073).unwrap();
+    let (pa, _) = cpu.translate(0x1234, AccessType::Load).unwrap();
+    assert_eq!(pa, DRAM_BASE + 0x30234);
+}
+
+#[test]
+fn test_sinval_vma_tlb() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).build();
+    // a cached page per vpn 1 and 2, asid 0
+    cpu.tlb.insert(0, 1, 0x1000);
+    cpu.tlb.insert(0, 2, 0x2000);
+    cpu.regs[10] = 0x1000;
+    // sfence.w.inval; sinval.vma a0, zero; sfence.inval.ir
+    cpu.execute(0x18000073).unwrap();
+    cpu.execute(0x16050073).unwrap();
+    cpu.execute(0x18100073).unwrap();
+    assert!(cpu.tlb.lookup(0, 1).is_none());
+    assert!(cpu.tlb.lookup(0, 2).is_some());
+
+    // without Svinval they're illegal, unlike sfence.vma
+    cpu.extensions.svinval = false;
+    for inst in [0x18000073, 0x16050073, 0x18100073] {
+        assert!(matches!(
+            cpu.execute(inst),
+            Err(Exception::IllegalInstruction(_))
+        ));
+    }
+    cpu.execute(0x12050073).unwrap();
+}
+
+#[test]
+fn test_svinval() {
+    // encoded with .insn, the assemblers don't take svinval in -march=rv64g
+    let code = "
+li a0, 0x1000
+li a1, 1
+.insn r 0x73, 0x0, 0x0c, zero, zero, zero # sfence.w.inval
+.insn r 0x73, 0x0, 0x0b, zero, a0, a1 # sinval.vma a0, a1
+.insn r 0x73, 0x0, 0x0b, zero, zero, zero # sinval.vma zero, zero
+.insn r 0x73, 0x0, 0x0c, zero, zero, ra # sfence.inval.ir
+li a2, 7
+";
+    riscv_asm_test!(code, "test_svinval", 20, instret => 7, "a2" => 7);
+}
+
+#[test]
+fn test_virtio_rng() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).build();
+    let rng = VIRTIO_RNG_BASE;
+    let queue = DRAM_BASE + 0x10000;
+    let buffer = DRAM_BASE + 0x20000;
+
+    // driver init
+    cpu.bus.store(rng + 0x70, 32, 0b1011).unwrap();
+    cpu.bus.store(rng + 0x28, 32, PAGE_SIZE).unwrap();
+    cpu.bus.store(rng + 0x40, 32, queue / PAGE_SIZE).unwrap();
+    cpu.bus.store(rng + 0x70, 32, 0b1111).unwrap();
+
+    // descriptor 0: 32 device-writable bytes
+    cpu.bus.store(queue, 64, buffer).unwrap();
+    cpu.bus.store(queue + 8, 32, 32).unwrap();
+    cpu.bus.store(queue + 12, 16, 2).unwrap();
+    // avail.ring[0] = 0, avail.idx = 1
+    let avail = queue + 8 * 16;
+    cpu.bus.store(avail + 4, 16, 0).unwrap();
+    cpu.bus.store(avail + 2, 16, 1).unwrap();
+    cpu.bus.store(rng + 0x50, 32, 0).unwrap();
+
+    cpu.csr.store(MSTATUS, MASK_MIE);
+    cpu.check_pending_interrupt();
+
+    let used = queue + PAGE_SIZE;
+    assert_eq!(cpu.bus.load(used + 2, 16).unwrap(), 1);
+    assert_eq!(cpu.bus.load(used + 8, 32).unwrap(), 32);
+    let mut random = 0;
+    for i in (0..32).step_by(8) {
+        random |= cpu.bus.load(buffer + i, 64).unwrap();
+    }
+    assert_ne!(random, 0);
+}
+
+// Zba, encoded with .insn so the test does not depend on assembler support
+
+#[test]
+fn test_sh1add() {
+    let code = "li a0, 3
+li a1, 100
+.insn r 0x33, 0x2, 0x10, a2, a0, a1 # sh1add a2, a0, a1
+";
+    riscv_asm_test!(code, "test_sh1add", 10, "a2" => 106);
+}
+
+#[test]
+fn test_sh2add() {
+    let code = "li a0, 3
+li a1, 100
+.insn r 0x33, 0x4, 0x10, a2, a0, a1 # sh2add a2, a0, a1
+";
+    riscv_asm_test!(code, "test_sh2add", 10, "a2" => 112);
+}
+
+#[test]
+fn test_sh3add() {
+    let code = "li a0, 3
+li a1, 100
+.insn r 0x33, 0x6, 0x10, a2, a0, a1 # sh3add a2, a0, a1
+";
+    riscv_asm_test!(code, "test_sh3add", 10, "a2" => 124);
+}
+
+#[test]
+fn test_czero_eqz_zero() {
+    let code = "li a0, 42
+li a1, 0
+li a2, 1
+.insn r 0x33, 0x5, 0x07, a2, a0, a1 # czero.eqz a2, a0, a1
+";
+    riscv_asm_test!(code, "test_czero_eqz_zero", 10, "a2" => 0);
+}
+
+#[test]
+fn test_czero_eqz_nonzero() {
+    let code = "li a0, 42
+li a1, 7
+li a2, 1
+.insn r 0x33, 0x5, 0x07, a2, a0, a1 # czero.eqz a2, a0, a1
+";
+    riscv_asm_test!(code, "test_czero_eqz_nonzero", 10, "a2" => 42);
+}
+
+#[test]
+fn test_czero_nez_zero() {
+    let code = "li a0, 42
+li a1, 0
+li a2, 1
+.insn r 0x33, 0x7, 0x07, a2, a0, a1 # czero.nez a2, a0, a1
+";
+    riscv_asm_test!(code, "test_czero_nez_zero", 10, "a2" => 42);
+}
+
+#[test]
+fn test_czero_nez_nonzero() {
+    let code = "li a0, 42
+li a1, 7
+li a2, 1
+.insn r 0x33, 0x7, 0x07, a2, a0, a1 # czero.nez a2, a0, a1
+";
+    riscv_asm_test!(code, "test_czero_nez_nonzero", 10, "a2" => 0);
+}
+
+#[test]
+fn test_sh1add_uw() {
+    let code = "li a0, 0x100000003
+li a1, 100
+.insn r 0x3b, 0x2, 0x10, a2, a0, a1 # sh1add.uw a2, a0, a1
+";
+    riscv_asm_test!(code, "test_sh1add_uw", 10, "a2" => 106);
+}
+
+#[test]
+fn test_sh2add_uw() {
+    let code = "li a0, -1
+li a1, 0
+.insn r 0x3b, 0x4, 0x10, a2, a0, a1 # sh2add.uw a2, a0, a1
+";
+    riscv_asm_test!(code, "test_sh2add_uw", 10, "a2" => 0xffff_ffff_u64 << 2);
+}
+
+#[test]
+fn test_sh3add_uw() {
+    let code = "li a0, 0x100000003
+li a1, 100
+.insn r 0x3b, 0x6, 0x10, a2, a0, a1 # sh3add.uw a2, a0, a1
+";
+    riscv_asm_test!(code, "test_sh3add_uw", 10, "a2" => 124);
+}
+
+#[test]
+fn test_config_small_dram() {
+    let mut config = MachineConfig::default();
+    config.dram_size = 0x1000;
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).config(config).build();
+    assert!(cpu.bus.load(DRAM_BASE + 0xff8, 64).is_ok());
+    assert!(matches!(
+        cpu.bus.load(DRAM_BASE + 0x1000, 64),
+        Err(Exception::LoadAccessFault(_))
+    ));
+    assert_eq!(cpu.reg("sp"), DRAM_BASE + 0xfff);
+}
+
+#[test]
+fn test_config_toml() {
+    let config =
+        MachineConfig::from_toml("dram_size = 4096\n[enabled_extensions]\nm = false\n").unwrap();
+    assert_eq!(config.dram_size, 4096);
+    assert_eq!(config.dram_base, DRAM_BASE);
+    assert!(!config.enabled_extensions.m);
+    assert!(config.enabled_extensions.a);
+    let text = config.to_toml();
+    assert_eq!(MachineConfig::from_toml(&text).unwrap().dram_size, 4096);
+}
+
+#[test]
+fn test_plic_claim_complete() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).build();
+    cpu.bus.store(PLIC_PRIORITY + 4 * UART_IRQ, 32, 1).unwrap();
+    cpu.bus.store(PLIC_PENDING, 32, 1 << UART_IRQ).unwrap();
+    // not enabled for S-mode yet
+    assert_eq!(cpu.bus.load(PLIC_SCLAIM, 32).unwrap(), 0);
+    cpu.bus.store(PLIC_SENABLE, 32, 1 << UART_IRQ).unwrap();
+    assert_eq!(cpu.bus.load(PLIC_SCLAIM, 32).unwrap(), UART_IRQ);
+    assert_eq!(cpu.bus.load(PLIC_PENDING, 32).unwrap(), 0);
+    // claimed interrupt is not delivered again
+    assert_eq!(cpu.bus.load(PLIC_SCLAIM, 32).unwrap(), 0);
+
+    // complete, nothing pending until the source re-asserts
+    cpu.bus.store(PLIC_SCLAIM, 32, UART_IRQ).unwrap();
+    assert_eq!(cpu.bus.load(PLIC_SCLAIM, 32).unwrap(), 0);
+    cpu.bus.store(PLIC_PENDING, 32, 1 << UART_IRQ).unwrap();
+    assert_eq!(cpu.bus.load(PLIC_SCLAIM, 32).unwrap(), UART_IRQ);
+}
+
+#[test]
+fn test_debugger_script() {
+    // addi x1, x0, 42; addi x5, x0, 5; addi x6, x0, 6
+    let code = vec![
+        0x93, 0x00, 0xa0, 0x02, 0x93, 0x02, 0x50, 0x00, 0x13, 0x03, 0x60, 0x00,
+    ];
+    let cpu = CpuBuilder::new(code, vec![0]).build();
+    let mut debugger = Debugger::new(cpu);
+    let script = "s\nb 80000008\nc\nm 80000000 4\ndis 80000004 1\nw 80000100 beef 2\nm 80000100 2\nx\nq\ns\n";
+    let mut output = Vec::new();
+    debugger
+        .run(Cursor::new(script.as_bytes()), &mut output)
+        .unwrap();
+    let output = String::from_utf8(output).unwrap();
+    assert_eq!(
+        output,
+        "> 0x80000000: 0x02a00093
+> > 0x80000004: 0x00500293
+breakpoint 0x80000008
+> 0x80000000: 93 00 a0 02
+> 0x80000004: 0x00500293 addi t0, zero, 5
+> > 0x80000100: ef be
+> unknown command: x
+> "
+    );
+    assert_eq!(debugger.cpu.reg("x1"), 42);
+    assert_eq!(debugger.cpu.reg("x5"), 5);
+    assert_eq!(debugger.cpu.reg("x6"), 0);
+}
+
+#[test]
+fn test_debugger_dis() {
+    // c.li a0, 5; addi x1, x0, 42
+    let code = vec![0x15, 0x45, 0x93, 0x00, 0xa0, 0x02];
+    let mut cpu = CpuBuilder::new(code, vec![0]).enable_c().build();
+    // big-endian data accesses don't change how instructions read
+    cpu.csr.store(MSTATUS, cpu.csr.load(MSTATUS) | MASK_MBE);
+    let mut debugger = Debugger::new(cpu);
+    let mut output = Vec::new();
+    debugger
+        .run(Cursor::new(b"dis 80000000 2\n".as_slice()), &mut output)
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "> 0x80000000: 0x00004515 addi a0, zero, 5
+0x80000002: 0x02a00093 addi ra, zero, 42
+> "
+    );
+}
+
+#[test]
+fn test_reset_rerun() {
+    require_toolchain!("test_reset_rerun");
+    let code = "li a0, 0x1234
+addi sp, sp, -8
+sd a0, 0(sp)
+ld a1, 0(sp)
+add a2, a1, a0
+csrrw a3, mscratch, a2
+";
+    let binary = rv_asm_binary(code, "test_reset_rerun").unwrap();
+    let (first, cpu) = run_cpu_reset_after_n(binary, vec![0], 10).unwrap();
+    assert_eq!(first, cpu.regs);
+    // mscratch was cleared by reset, so a3 reads 0 in both runs
+    assert_eq!(cpu.reg("a3"), 0);
+    assert_eq!(cpu.reg("a2"), 0x2468);
+}
+
+#[test]
+fn test_csrrs_x0_no_write() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).build();
+    let satp = (8 << 60) | (DRAM_BASE >> 12);
+    // raw store, paging state isn't updated
+    cpu.csr.store(SATP, satp);
+
+    // csrrs a0, satp, zero
+    cpu.execute(0x18002573).unwrap();
+    assert_eq!(cpu.reg("a0"), satp);
+    // csrrci a1, satp, 0
+    cpu.execute(0x180075f3).unwrap();
+    assert_eq!(cpu.reg("a1"), satp);
+    assert!(!cpu.enable_paging);
+}
+
+#[test]
+fn test_csr_rd_x0() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).build();
+    let csr_inst = |csr: usize, rs1: u64, funct3: u64| {
+        ((csr as u64) << 20) | (rs1 << 15) | (funct3 << 12) | 0x73
+    };
+    cpu.regs[10] = 0xf0;
+    cpu.regs[11] = 0x0f;
+
+    // csrrw zero, mscratch, a0 still writes
+    cpu.execute(csr_inst(MSCRATCH, 10, 0x1)).unwrap();
+    assert_eq!(cpu.csr.load(MSCRATCH), 0xf0);
+    // csrrs zero, mscratch, a1 and csrrc zero, mscratch, a0
+    cpu.execute(csr_inst(MSCRATCH, 11, 0x2)).unwrap();
+    assert_eq!(cpu.csr.load(MSCRATCH), 0xff);
+    cpu.execute(csr_inst(MSCRATCH, 10, 0x3)).unwrap();
+    assert_eq!(cpu.csr.load(MSCRATCH), 0x0f);
+    // csrrwi zero, mscratch, 5, csrrsi zero, mscratch, 2, csrrci zero, mscratch, 1
+    cpu.execute(csr_inst(MSCRATCH, 5, 0x5)).unwrap();
+    assert_eq!(cpu.csr.load(MSCRATCH), 5);
+    cpu.execute(csr_inst(MSCRATCH, 2, 0x6)).unwrap();
+    assert_eq!(cpu.csr.load(MSCRATCH), 7);
+    cpu.execute(csr_inst(MSCRATCH, 1, 0x7)).unwrap();
+    assert_eq!(cpu.csr.load(MSCRATCH), 6);
+    // x0 isn't written, not even until the next instruction clears it
+    assert_eq!(cpu.reg("x0"), 0);
+
+    // with x0 / 0 as the source csrrs, csrrc, csrrsi and csrrci skip the store,
+    // so satp written behind the cpu's back doesn't turn paging on
+    cpu.csr.store(SATP, (8 << 60) | (DRAM_BASE >> 12));
+    for funct3 in [0x2, 0x3, 0x6, 0x7] {
+        cpu.execute(csr_inst(SATP, 0, funct3)).unwrap();
+    }
+    assert!(!cpu.enable_paging);
+
+    // a pure read of a read-only csr is fine, csrrw always writes it
+    cpu.execute(csr_inst(CYCLE, 0, 0x2)).unwrap();
+    assert!(matches!(
+        cpu.execute(csr_inst(CYCLE, 10, 0x1)),
+        Err(Exception::IllegalInstruction(_))
+    ));
+}
+
+// Write sink the test can read back
+#[derive(Clone, Default)]
+struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_virtio_console() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).build();
+    let out = SharedBuf::default();
+    cpu.bus.virtio_console.set_output(Box::new(out.clone()));
+    let console = VIRTIO_CONSOLE_BASE;
+    let rxq = DRAM_BASE + 0x10000;
+    let txq = DRAM_BASE + 0x12000;
+    let rx_buffer = DRAM_BASE + 0x20000;
+    let tx_buffer = DRAM_BASE + 0x21000;
+
+    assert_eq!(cpu.bus.load(console + 0x8, 32).unwrap(), 3);
+    assert_eq!(cpu.bus.load(console + 0x10, 32).unwrap(), 1);
+    assert_eq!(cpu.bus.load(console + 0x100, 16).unwrap(), 80);
+
+    // driver init, one queue per pfn
+    cpu.bus.store(console + 0x70, 32, 0b1011).unwrap();
+    cpu.bus.store(console + 0x28, 32, PAGE_SIZE).unwrap();
+    cpu.bus.store(console + 0x30, 32, 0).unwrap();
+    cpu.bus.store(console + 0x40, 32, rxq / PAGE_SIZE).unwrap();
+    cpu.bus.store(console + 0x30, 32, 1).unwrap();
+    cpu.bus.store(console + 0x40, 32, txq / PAGE_SIZE).unwrap();
+    cpu.bus.store(console + 0x70, 32, 0b1111).unwrap();
+
+    // transmitq: descriptor 0 holds "hello\n"
+    for (i, b) in b"hello\n".iter().enumerate() {
+        cpu.bus.store(tx_buffer + i as u64, 8, *b as u64).unwrap();
+    }
+    cpu.bus.store(txq, 64, tx_buffer).unwrap();
+    cpu.bus.store(txq + 8, 32, 6).unwrap();
+    cpu.bus.store(txq + 8 * 16 + 2, 16, 1).unwrap();
+    cpu.bus.store(console + 0x50, 32, 1).unwrap();
+
+    cpu.csr.store(MSTATUS, MASK_MIE);
+    cpu.check_pending_interrupt();
+    assert_eq!(out.0.lock().unwrap().as_slice(), b"hello\n");
+    assert_eq!(cpu.bus.load(txq + PAGE_SIZE + 2, 16).unwrap(), 1);
+
+    // receiveq: descriptor 0 is a 16 byte device-writable buffer
+    cpu.bus.store(rxq, 64, rx_buffer).unwrap();
+    cpu.bus.store(rxq + 8, 32, 16).unwrap();
+    cpu.bus.store(rxq + 12, 16, 2).unwrap();
+    cpu.bus.store(rxq + 8 * 16 + 2, 16, 1).unwrap();
+    cpu.bus.virtio_console.push_input(b"ls\n");
+    cpu.check_pending_interrupt();
+    assert_eq!(cpu.bus.load(rxq + PAGE_SIZE + 2, 16).unwrap(), 1);
+    assert_eq!(cpu.bus.load(rxq + PAGE_SIZE + 8, 32).unwrap(), 3);
+    assert_eq!(cpu.bus.load(rx_buffer, 16).unwrap(), 0x736c);
+
+    // descriptor 1 points outside memory and chains to itself, it's dropped
+    cpu.bus.store(txq + 16, 64, 0x1_0000_0000_0000).unwrap();
+    cpu.bus.store(txq + 24, 32, 4).unwrap();
+    cpu.bus.store(txq + 28, 16, 1).unwrap();
+    cpu.bus.store(txq + 30, 16, 1).unwrap();
+    cpu.bus.store(txq + 8 * 16 + 6, 16, 1).unwrap();
+    cpu.bus.store(txq + 8 * 16 + 2, 16, 2).unwrap();
+    cpu.bus.store(console + 0x50, 32, 1).unwrap();
+    cpu.check_pending_interrupt();
+    assert_eq!(out.0.lock().unwrap().as_slice(), b"hello\n");
+    assert_eq!(cpu.bus.load(txq + PAGE_SIZE + 2, 16).unwrap(), 2);
+}
+
+#[test]
+fn test_exception_value_code() {
+    // (exception, tval, mcause) from the privileged spec
+    for (e, value, code) in [
+        (
+            Exception::InstructionAddrMisaligned(0x8000_0002),
+            0x8000_0002,
+            0,
+        ),
+        (Exception::InstructionAccessFault(0x1000), 0x1000, 1),
+        (Exception::IllegalInstruction(0xffff_ffff), 0xffff_ffff, 2),
+        (Exception::Breakpoint(0x8000_0000), 0x8000_0000, 3),
+        (Exception::LoadAccessMisaligned(0x8000_0001), 0x8000_0001, 4),
+        (Exception::LoadAccessFault(0x10), 0x10, 5),
+        (
+            Exception::StoreAMOAddrMisaligned(0x8000_0003),
+            0x8000_0003,
+            6,
+        ),
+        (Exception::StoreAMOAccessFault(0x20), 0x20, 7),
+        (Exception::EnvironmentCallFromUMode(0x8000_0000), 0, 8),
+        (Exception::EnvironmentCallFromSMode(0x8000_0000), 0, 9),
+        (Exception::EnvironmentCallFromMMode(0x8000_0000), 0, 11),
+        (Exception::InstructionPageFault(0x4000), 0x4000, 12),
+        (Exception::LoadPageFault(0x5000), 0x5000, 13),
+        (Exception::StoreAMOPageFault(0x6000), 0x6000, 15),
+    ] {
+        assert_eq!(e.value(), value, "{}", e);
+        assert_eq!(e.code(), code, "{}", e);
+    }
+}
+
+#[test]
+fn test_exception_illegal_instruction() {
+    // opcode 0x7f isn't used
+    riscv_asm_exception_test!(
+        "li a0, 1
+.word 0x0000007f",
+        "test_exception_illegal_instruction",
+        10,
+        Exception::IllegalInstruction(0)
+    );
+}
+
+#[test]
+fn test_exception_load_page_fault() {
+    // S-mode with DRAM mapped by one Sv39 gigapage, nothing at 0x1000
+    let code = "li t0, 0x80200000
+li t1, (0x80000 << 10) | 0xcf
+sd t1, 16(t0)
+srli t0, t0, 12
+li t1, 8
+slli t1, t1, 60
+or t0, t0, t1
+csrw satp, t0
+sfence.vma
+li t0, 0x800
+csrs mstatus, t0
+la t0, supervisor
+csrw mepc, t0
+mret
+supervisor:
+li t0, 0x1000
+ld a0, 0(t0)
+";
+    riscv_asm_exception_test!(
+        code,
+        "test_exception_load_page_fault",
+        40,
+        Exception::LoadPageFault(0)
+    );
+}
+
+#[test]
+fn test_exception_instruction_misaligned() {
+    // without C a branch target has to be 4-byte aligned
+    riscv_asm_exception_test!(
+        "beq zero, zero, 6",
+        "test_exception_instruction_misaligned",
+        10,
+        Exception::InstructionAddrMisaligned(0)
+    );
+}
+
+#[test]
+fn test_exception_ecall_m_mode() {
+    riscv_asm_exception_test!(
+        "ecall",
+        "test_exception_ecall_m_mode",
+        10,
+        Exception::EnvironmentCallFromMMode(0)
+    );
+}
+
+#[test]
+fn test_exception_breakpoint() {
+    riscv_asm_exception_test!(
+        "li a0, 1
+ebreak",
+        "test_exception_breakpoint",
+        10,
+        Exception::Breakpoint(0)
+    );
+}
+
+#[test]
+fn test_deterministic_mode() {
+    require_toolchain!("test_deterministic_mode");
+    // reads mtime, uart input and the rng device status around a loop
+    let code = "csrsi mstatus, 8
+li t0, 0x200bff8
+ld a0, 0(t0)
+li t1, 100
+loop:
+addi t1, t1, -1
+bnez t1, loop
+ld a1, 0(t0)
+li t2, 0x10000000
+lbu a2, 0(t2)
+lbu a3, 0(t2)
+";
+    let binary = rv_asm_binary(code, "test_deterministic_mode").unwrap();
+    let mode = DeterministicMode {
+        uart_input: b"xy".to_vec(),
+        rng_seed: 42,
+    };
+    let build = || {
+        CpuBuilder::new(binary.clone(), vec![0])
+            .deterministic(mode.clone())
+            .build()
+    };
+    let (first, _) = run(build(), 220).unwrap();
+    let (second, _) = run(build(), 220).unwrap();
+    assert_eq!(first.regs, second.regs);
+    assert_eq!(first.reg("a1") - first.reg("a0"), 202);
+    assert_eq!(first.reg("a2"), b'x' as u64);
+    assert_eq!(first.reg("a3"), b'y' as u64);
+}
+
+#[test]
+fn test_block_cache_store_invalidates() {
+    // addi a0, a0, 1; jal zero, -4
+    let code: Vec<u8> = [0x00150513u32, 0xffdff06f]
+        .iter()
+        .flat_map(|inst| inst.to_le_bytes())
+        .collect();
+    let cpu = CpuBuilder::new(code, vec![0]).block_cache(true).build();
+    let (mut cpu, _) = run(cpu, 10).unwrap();
+    assert_eq!(cpu.reg("a0"), 5);
+    assert!(!cpu.block_cache.as_ref().unwrap().is_empty());
+
+    // addi a0, a0, 2 over the first instruction
+    cpu.store(DRAM_BASE, 32, 0x00250513).unwrap();
+    assert_eq!(cpu.block_cache.as_ref().unwrap().len(), 0);
+    let (cpu, _) = run(cpu, 10).unwrap();
+    assert_eq!(cpu.reg("a0"), 15);
+}
+
+#[test]
+fn test_sstc_stimecmp() {
+    require_toolchain!("test_sstc_stimecmp");
+    let code = "li t0, 1
+slli t0, t0, 63
+csrs menvcfg, t0
+li t0, 0x20
+csrs mideleg, t0
+csrs mie, t0
+la t0, handler
+csrw stvec, t0
+la t0, supervisor
+csrw mepc, t0
+li t0, 0x1800
+csrc mstatus, t0
+li t0, 0x800
+csrs mstatus, t0
+mret
+supervisor:
+li t0, 0x200bff8
+ld t1, 0(t0)
+addi t1, t1, 50
+csrw 0x14d, t1 # stimecmp
+csrsi sstatus, 2
+loop:
+addi a0, a0, 1
+j loop
+handler:
+csrr a1, scause
+li t0, -1
+csrw 0x14d, t0
+wait:
+j wait
+";
+    let mode = DeterministicMode::default();
+    let binary = rv_asm_binary(code, "test_sstc_stimecmp").unwrap();
+    let cpu = CpuBuilder::new(binary, vec![0]).deterministic(mode).build();
+    let (cpu, _) = run(cpu, 200).unwrap();
+    assert_eq!(cpu.reg("a1"), (1 << 63) | 5);
+    assert!(cpu.reg("a0") > 10);
+}
+
+#[test]
+fn test_inject_interrupt() {
+    // nop
+    let cpu = CpuBuilder::new(vec![0x13, 0, 0, 0], vec![0]).build();
+    let cpu = Arc::new(Mutex::new(cpu));
+    {
+        let mut cpu = cpu.lock().unwrap();
+        cpu.csr.store(MSTATUS, MASK_MIE);
+        cpu.csr.store(MIE, MASK_MTIP);
+        cpu.csr.store(MTVEC, DRAM_BASE + 0x100);
+    }
+    let injector = Arc::clone(&cpu);
+    thread::spawn(move || {
+        Cpu::inject_interrupt_from_thread(&injector, Interrupt::MachineTimerInterrupt)
+    })
+    .join()
+    .unwrap();
+
+    let mut cpu = cpu.lock().unwrap();
+    assert_eq!(cpu.csr.load(MIP) & MASK_MTIP, MASK_MTIP);
+    cpu.step();
+    assert_eq!(
+        cpu.csr.load(MCAUSE),
+        Interrupt::MachineTimerInterrupt.code()
+    );
+    assert_eq!(cpu.csr.load(MEPC), DRAM_BASE + 4);
+    assert_eq!(cpu.pc, DRAM_BASE + 0x100);
+}
+
+#[test]
+fn test_mtvec_vectored_offset() {
+    // nop
+    let mut cpu = CpuBuilder::new(vec![0x13, 0, 0, 0], vec![0]).build();
+    cpu.csr.store(MSTATUS, MASK_MIE);
+    cpu.csr.store(MIE, MASK_MEIP);
+    // base | mode 1
+    cpu.csr.store(MTVEC, DRAM_BASE + 0x100 | 1);
+    cpu.inject_interrupt(Interrupt::MachineExternalInterrupt);
+    cpu.step();
+    assert_eq!(
+        cpu.csr.load(MCAUSE),
+        Interrupt::MachineExternalInterrupt.code()
+    );
+    assert_eq!(cpu.pc, DRAM_BASE + 0x100 + 4 * 11);
+}
+
+#[test]
+fn test_plic_contexts() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).build();
+    cpu.bus.store(PLIC_PRIORITY + 4 * UART_IRQ, 32, 1).unwrap();
+    cpu.bus
+        .store(PLIC_PRIORITY + 4 * VIRTIO_IRQ, 32, 1)
+        .unwrap();
+    cpu.bus.store(PLIC_MENABLE, 32, 1 << VIRTIO_IRQ).unwrap();
+    cpu.bus.store(PLIC_SENABLE, 32, 1 << UART_IRQ).unwrap();
+    cpu.bus.plic.raise(UART_IRQ);
+    cpu.bus.plic.raise(VIRTIO_IRQ);
+
+    cpu.csr.store(MSTATUS, MASK_MIE);
+    cpu.csr.store(MIE, MASK_MEIP | MASK_SEIP);
+    assert!(matches!(
+        cpu.check_pending_interrupt(),
+        Some(Interrupt::MachineExternalInterrupt)
+    ));
+    // each context only sees its own enabled sources
+    assert_eq!(cpu.bus.load(PLIC_SCLAIM, 32).unwrap(), UART_IRQ);
+    assert_eq!(cpu.bus.load(PLIC_MCLAIM, 32).unwrap(), VIRTIO_IRQ);
+    assert_eq!(cpu.bus.load(PLIC_MCLAIM, 32).unwrap(), 0);
+    cpu.bus.store(PLIC_MCLAIM, 32, VIRTIO_IRQ).unwrap();
+    cpu.bus.store(PLIC_SCLAIM, 32, UART_IRQ).unwrap();
+    assert!(cpu.check_pending_interrupt().is_none());
+}
+
+#[test]
+fn test_uart_ier() {
+    let mode = DeterministicMode {
+        uart_input: b"x".to_vec(),
+        rng_seed: 0,
+    };
+    let mut cpu = CpuBuilder::new(vec![0], vec![0])
+        .deterministic(mode)
+        .build();
+    cpu.bus.store(PLIC_PRIORITY + 4 * UART_IRQ, 32, 1).unwrap();
+    cpu.bus.store(PLIC_MENABLE, 32, 1 << UART_IRQ).unwrap();
+    cpu.csr.store(MSTATUS, MASK_MIE);
+    cpu.csr.store(MIE, MASK_MEIP);
+
+    // with IER clear the byte arrives but doesn't interrupt
+    assert!(cpu.check_pending_interrupt().is_none());
+    assert_eq!(
+        cpu.bus.load(UART_BASE + UART_LSR, 8).unwrap() as u8 & MASK_UART_LSR_RX,
+        MASK_UART_LSR_RX
+    );
+    assert!(!cpu.bus.uart.interrupt_pending());
+
+    // enabling it delivers the interrupt still pending
+    cpu.bus
+        .store(UART_BASE + UART_IER, 8, MASK_UART_IER_RX as u64)
+        .unwrap();
+    assert!(matches!(
+        cpu.check_pending_interrupt(),
+        Some(Interrupt::MachineExternalInterrupt)
+    ));
+    assert_eq!(cpu.bus.load(PLIC_MCLAIM, 32).unwrap(), UART_IRQ);
+    cpu.bus.store(PLIC_MCLAIM, 32, UART_IRQ).unwrap();
+    cpu.csr.set_external_interrupt(false);
+    assert_eq!(cpu.bus.load(UART_BASE + UART_RHR, 8).unwrap(), b'x' as u64);
+
+    // a THR write only interrupts with IER bit 1 set
+    cpu.bus
+        .store(UART_BASE + UART_THR, 8, b'\n' as u64)
+        .unwrap();
+    assert!(cpu.check_pending_interrupt().is_none());
+    cpu.bus
+        .store(UART_BASE + UART_IER, 8, MASK_UART_IER_TX as u64)
+        .unwrap();
+    cpu.bus
+        .store(UART_BASE + UART_THR, 8, b'\n' as u64)
+        .unwrap();
+    assert!(matches!(
+        cpu.check_pending_interrupt(),
+        Some(Interrupt::MachineExternalInterrupt)
+    ));
+}
+
+#[test]
+fn test_plic_priority() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).build();
+    let high = 1000;
+    cpu.bus.store(PLIC_PRIORITY + 4 * UART_IRQ, 32, 2).unwrap();
+    cpu.bus
+        .store(PLIC_PRIORITY + 4 * VIRTIO_IRQ, 32, 2)
+        .unwrap();
+    cpu.bus.store(PLIC_PRIORITY + 4 * high, 32, 5).unwrap();
+    // priorities above 7 are clamped
+    cpu.bus.store(PLIC_PRIORITY + 4 * 20, 32, 100).unwrap();
+    assert_eq!(cpu.bus.load(PLIC_PRIORITY + 4 * 20, 32).unwrap(), 7);
+    cpu.bus
+        .store(PLIC_SENABLE, 32, 1 << UART_IRQ | 1 << VIRTIO_IRQ)
+        .unwrap();
+    cpu.bus
+        .store(PLIC_SENABLE + 4 * (high / 32), 32, 1 << (high % 32))
+        .unwrap();
+    cpu.bus.plic.raise(UART_IRQ);
+    cpu.bus.plic.raise(VIRTIO_IRQ);
+    cpu.bus.plic.raise(high);
+    assert_eq!(
+        cpu.bus.load(PLIC_PENDING + 4 * (high / 32), 32).unwrap(),
+        1 << (high % 32)
+    );
+
+    // only sources above the threshold are delivered
+    cpu.bus.store(PLIC_SPRIORITY, 32, 4).unwrap();
+    assert_eq!(cpu.bus.load(PLIC_SCLAIM, 32).unwrap(), high);
+    assert_eq!(cpu.bus.load(PLIC_SCLAIM, 32).unwrap(), 0);
+    // equal priorities go to the lowest id
+    cpu.bus.store(PLIC_SPRIORITY, 32, 0).unwrap();
+    assert_eq!(cpu.bus.load(PLIC_SCLAIM, 32).unwrap(), VIRTIO_IRQ);
+    assert_eq!(cpu.bus.load(PLIC_SCLAIM, 32).unwrap(), UART_IRQ);
+
+    // priority 0 never interrupts
+    cpu.bus.store(PLIC_SCLAIM, 32, high).unwrap();
+    cpu.bus.store(PLIC_PRIORITY + 4 * high, 32, 0).unwrap();
+    cpu.bus.plic.raise(high);
+    assert!(!cpu.bus.plic.is_pending(PLIC_SCONTEXT));
+}
+
+#[test]
+fn test_virtio_blk_chain() {
+    let mut disk = vec![0u8; 4 * SECTOR_SIZE as usize];
+    for (i, b) in disk.iter_mut().enumerate() {
+        *b = (i / SECTOR_SIZE as usize) as u8 + 1;
+    }
+    let mut cpu = CpuBuilder::new(vec![0], disk).build();
+    let queue = DRAM_BASE + 0x10000;
+    let header = DRAM_BASE + 0x20000;
+    let buffer = DRAM_BASE + 0x21000;
+    let status = DRAM_BASE + 0x22000;
+
+    // driver init
+    cpu.bus.store(VIRTIO_STATUS, 32, 0b1011).unwrap();
+    cpu.bus
+        .store(VIRTIO_GUEST_PAGE_SIZE, 32, PAGE_SIZE)
+        .unwrap();
+    cpu.bus
+        .store(VIRTIO_QUEUE_PFN, 32, queue / PAGE_SIZE)
+        .unwrap();
+    cpu.bus.store(VIRTIO_STATUS, 32, 0b1111).unwrap();
+
+    // read sector 2, data split over descriptors 5 and 1
+    cpu.bus.store(header, 32, VIRTIO_BLK_T_IN as u64).unwrap();
+    cpu.bus.store(header + 8, 64, 2).unwrap();
+    cpu.bus.store(status, 8, 0xff).unwrap();
+    let desc = |i: u64| queue + 16 * i;
+    for (i, addr, len, flags, next) in [
+        (3, header, 16, VIRTQ_DESC_F_NEXT, 5),
+        (5, buffer, 256, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE, 1),
+        (
+            1,
+            buffer + 256,
+            256,
+            VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE,
+            0,
+        ),
+        (0, status, 1, VIRTQ_DESC_F_WRITE, 0),
+    ] {
+        cpu.bus.store(desc(i), 64, addr).unwrap();
+        cpu.bus.store(desc(i) + 8, 32, len).unwrap();
+        cpu.bus.store(desc(i) + 12, 16, flags as u64).unwrap();
+        cpu.bus.store(desc(i) + 14, 16, next).unwrap();
+    }
+    // avail.ring[0] = 3, avail.idx = 1
+    let avail = queue + 8 * 16;
+    cpu.bus.store(avail + 4, 16, 3).unwrap();
+    cpu.bus.store(avail + 2, 16, 1).unwrap();
+    cpu.bus.store(VIRTIO_QUEUE_NOTIFY, 32, 0).unwrap();
+
+    cpu.csr.store(MSTATUS, MASK_MIE);
+    cpu.check_pending_interrupt();
+
+    assert_eq!(cpu.bus.load(buffer, 8).unwrap(), 3);
+    assert_eq!(cpu.bus.load(buffer + 511, 8).unwrap(), 3);
+    assert_eq!(cpu.bus.load(status, 8).unwrap(), VIRTIO_BLK_S_OK as u64);
+    // used.idx = 1, used.ring[0] = {3, 513}
+    let used = queue + PAGE_SIZE;
+    assert_eq!(cpu.bus.load(used + 2, 16).unwrap(), 1);
+    assert_eq!(cpu.bus.load(used + 4, 32).unwrap(), 3);
+    assert_eq!(cpu.bus.load(used + 8, 32).unwrap(), 513);
+}
+
+#[test]
+fn test_virtio_blk_descriptor_reads() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![5; SECTOR_SIZE as usize]).build();
+    let queue = DRAM_BASE + 0x10000;
+    let header = DRAM_BASE + 0x20000;
+    let buffer = DRAM_BASE + 0x21000;
+    let status = DRAM_BASE + 0x22000;
+    cpu.bus.store(VIRTIO_STATUS, 32, 0b1011).unwrap();
+    cpu.bus
+        .store(VIRTIO_GUEST_PAGE_SIZE, 32, PAGE_SIZE)
+        .unwrap();
+    cpu.bus
+        .store(VIRTIO_QUEUE_PFN, 32, queue / PAGE_SIZE)
+        .unwrap();
+    cpu.bus.store(VIRTIO_STATUS, 32, 0b1111).unwrap();
+
+    // read sector 0 through descriptors 2 -> 4 -> 6
+    cpu.bus.store(header, 32, VIRTIO_BLK_T_IN as u64).unwrap();
+    let desc = |i: u64| queue + 16 * i;
+    for (i, addr, len, flags, next) in [
+        (2, header, 16, VIRTQ_DESC_F_NEXT, 4),
+        (4, buffer, 512, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE, 6),
+        (6, status, 1, VIRTQ_DESC_F_WRITE, 0),
+    ] {
+        cpu.bus.store(desc(i), 64, addr).unwrap();
+        cpu.bus.store(desc(i) + 8, 32, len).unwrap();
+        cpu.bus.store(desc(i) + 12, 16, flags as u64).unwrap();
+        cpu.bus.store(desc(i) + 14, 16, next).unwrap();
+    }
+    let avail = queue + 8 * 16;
+    cpu.bus.store(avail + 4, 16, 2).unwrap();
+    cpu.bus.store(avail + 2, 16, 1).unwrap();
+
+    // the descriptor table and the avail ring, the used ring is the next page
+    let sniffer = RecordingSniffer::new();
+    cpu.bus
+        .add_sniffer(queue..=queue + PAGE_SIZE - 1, Box::new(sniffer.clone()));
+    cpu.bus.store(VIRTIO_QUEUE_NOTIFY, 32, 0).unwrap();
+    cpu.csr.store(MSTATUS, MASK_MIE);
+    cpu.check_pending_interrupt();
+
+    let mut expected = vec![(false, avail + 2, 16, 1), (false, avail + 4, 16, 2)];
+    for (i, addr, len, flags, next) in [
+        (2, header, 16, VIRTQ_DESC_F_NEXT, 4),
+        (4, buffer, 512, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE, 6),
+        (6, status, 1, VIRTQ_DESC_F_WRITE, 0),
+    ] {
+        expected.extend([
+            (false, desc(i), 64, addr),
+            (false, desc(i) + 8, 32, len),
+            (false, desc(i) + 12, 16, flags as u64),
+            (false, desc(i) + 14, 16, next),
+        ]);
+    }
+    assert_eq!(sniffer.accesses(), expected);
+    assert_eq!(cpu.bus.load(buffer + 511, 8).unwrap(), 5);
+}
+
+#[test]
+fn test_bus_sniffer_uart() {
+    require_toolchain!("test_bus_sniffer_uart");
+    let code = "li t0, 0x10000000
+li t1, 'o'
+sb t1, 0(t0)
+li t1, 'k'
+sb t1, 0(t0)
+lbu a0, 5(t0)
+";
+    let binary = rv_asm_binary(code, "test_bus_sniffer_uart").unwrap();
+    let mut cpu = CpuBuilder::new(binary, vec![0]).build();
+    let sniffer = RecordingSniffer::new();
+    cpu.bus.add_sniffer(
+        UART_BASE..=UART_BASE + UART_SIZE - 1,
+        Box::new(sniffer.clone()),
+    );
+    assert!(matches!(cpu.run_to_halt(), ExitReason::Clean));
+
+    // only the uart range, none of the fetches from dram
+    let lsr = cpu.reg("a0");
+    assert_eq!(
+        sniffer.accesses(),
+        [
+            (true, UART_BASE + UART_THR, 8, b'o' as u64),
+            (true, UART_BASE + UART_THR, 8, b'k' as u64),
+            (false, UART_BASE + UART_LSR, 8, lsr),
+        ]
+    );
+}
+
+#[test]
+fn test_virtio_blk_past_end() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![7; 4 * SECTOR_SIZE as usize]).build();
+    let queue = DRAM_BASE + 0x10000;
+    let header = DRAM_BASE + 0x20000;
+    let buffer = DRAM_BASE + 0x21000;
+    let status = DRAM_BASE + 0x22000;
+    assert_eq!(cpu.bus.load(VIRTIO_CONFIG, 32).unwrap(), 4);
+
+    cpu.bus.store(VIRTIO_STATUS, 32, 0b1011).unwrap();
+    cpu.bus
+        .store(VIRTIO_GUEST_PAGE_SIZE, 32, PAGE_SIZE)
+        .unwrap();
+    cpu.bus
+        .store(VIRTIO_QUEUE_PFN, 32, queue / PAGE_SIZE)
+        .unwrap();
+    cpu.bus.store(VIRTIO_STATUS, 32, 0b1111).unwrap();
+
+    // read two sectors from the last one, the second isn't on the disk
+    let desc = |i: u64| queue + 16 * i;
+    for (i, addr, len, flags) in [
+        (0, header, 16, VIRTQ_DESC_F_NEXT),
+        (1, buffer, 1024, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE),
+        (2, status, 1, VIRTQ_DESC_F_WRITE),
+    ] {
+        cpu.bus.store(desc(i), 64, addr).unwrap();
+        cpu.bus.store(desc(i) + 8, 32, len).unwrap();
+        cpu.bus.store(desc(i) + 12, 16, flags as u64).unwrap();
+        cpu.bus.store(desc(i) + 14, 16, i + 1).unwrap();
+    }
+    let avail = queue + 8 * 16;
+    let used = queue + PAGE_SIZE;
+    let request = |cpu: &mut Cpu, iotype: u32, sector: u64, n: u64| {
+        cpu.bus.store(header, 32, iotype as u64).unwrap();
+        cpu.bus.store(header + 8, 64, sector).unwrap();
+        cpu.bus.store(status, 8, 0xff).unwrap();
+        cpu.bus.store(avail + 4 + 2 * (n - 1), 16, 0).unwrap();
+        cpu.bus.store(avail + 2, 16, n).unwrap();
+        cpu.disk_access();
+        (
+            cpu.bus.load(status, 8).unwrap() as u8,
+            cpu.bus.load(used + 8 * n, 32).unwrap(),
+        )
+    };
+    // only the status byte is written back
+    assert_eq!(
+        request(&mut cpu, VIRTIO_BLK_T_IN, 3, 1),
+        (VIRTIO_BLK_S_IOERR, 1)
+    );
+    // a sector number whose byte offset overflows
+    assert_eq!(
+        request(&mut cpu, VIRTIO_BLK_T_OUT, u64::MAX / 256, 2),
+        (VIRTIO_BLK_S_IOERR, 1)
+    );
+    assert_eq!(cpu.bus.load(buffer, 8).unwrap(), 0);
+    assert_eq!(cpu.bus.virtio_blk.read_disk(0).unwrap(), 7);
+    assert!(cpu.bus.virtio_blk.read_disk(4 * SECTOR_SIZE).is_err());
+    assert!(cpu.bus.virtio_blk.write_disk(4 * SECTOR_SIZE, 0).is_err());
+
+    // the whole disk is fine
+    assert_eq!(
+        request(&mut cpu, VIRTIO_BLK_T_IN, 2, 3),
+        (VIRTIO_BLK_S_OK, 1025)
+    );
+    assert_eq!(cpu.bus.load(buffer + 1023, 8).unwrap(), 7);
+
+    // a header outside memory fails the request instead of the emulator
+    cpu.bus.store(desc(0), 64, 0x1_0000_0000_0000).unwrap();
+    assert_eq!(
+        request(&mut cpu, VIRTIO_BLK_T_IN, 0, 4),
+        (VIRTIO_BLK_S_IOERR, 1)
+    );
+}
+
+#[test]
+fn test_virtio_blk_asm() {
+    require_toolchain!("test_virtio_blk_asm");
+    // legacy virtio-blk setup, then read sector 0 into 0x80021000 with the queue
+    // at 0x80010000, the request header at 0x80020000 and the status at 0x80022000
+    let code = "csrsi mstatus, 8
+li t0, 0x10001000
+li t1, 0b1011
+sw t1, 0x70(t0)
+li t1, 4096
+sw t1, 0x28(t0)
+li t2, 0x80010000
+srli t1, t2, 12
+sw t1, 0x40(t0)
+li t1, 0b1111
+sw t1, 0x70(t0)
+li t3, 0x80020000
+li t4, 0x80021000
+li t5, 0x80022000
+sd t3, 0(t2)
+li t1, 16
+sw t1, 8(t2)
+li t1, 1
+sh t1, 12(t2)
+sh t1, 14(t2)
+sd t4, 16(t2)
+li t1, 512
+sw t1, 24(t2)
+li t1, 3
+sh t1, 28(t2)
+li t1, 2
+sh t1, 30(t2)
+sd t5, 32(t2)
+li t1, 1
+sw t1, 40(t2)
+li t1, 2
+sh t1, 44(t2)
+sw zero, 0(t3)
+sd zero, 8(t3)
+li t1, 0xff
+sb t1, 0(t5)
+sh zero, 132(t2)
+li t1, 1
+sh t1, 130(t2)
+sw zero, 0x50(t0)
+nop
+nop
+ld a0, 0(t4)
+ld a1, 504(t4)
+lbu a2, 0(t5)
+";
+    let disk: Vec<u8> = (0..SECTOR_SIZE).map(|i| i as u8).collect();
+    let check = |cpu: &Cpu| {
+        assert_eq!(cpu.reg("a0"), 0x0706050403020100);
+        assert_eq!(cpu.reg("a1"), 0xfffefdfcfbfaf9f8);
+        assert_eq!(cpu.reg("a2"), VIRTIO_BLK_S_OK as u64);
+    };
+    let (cpu, _) = rv_asm_helper_with_disk(code, "test_virtio_blk_asm", disk.clone(), 100).unwrap();
+    check(&cpu);
+
+    let path = "tests/target/test_virtio_blk_asm.img";
+    std::fs::write(path, &disk).unwrap();
+    let (cpu, _) = rv_asm_helper_with_disk_file(code, "test_virtio_blk_asm", path, 100).unwrap();
+    check(&cpu);
+}
+
+#[test]
+fn test_xepc_align_mask() {
+    assert_eq!(pc_align_mask(false), !0b11);
+    assert_eq!(pc_align_mask(true), !0b01);
+
+    // mret, then sret from the address it lands on
+    let mret_sret = |c: bool| {
+        let mut config = MachineConfig::default();
+        config.enabled_extensions.c = c;
+        let mut cpu = CpuBuilder::new(vec![0], vec![0]).config(config).build();
+        cpu.csr.store(MEPC, DRAM_BASE + 0x102);
+        cpu.csr.store(MSTATUS, 1 << 11);
+        let mret = cpu.execute(0x30200073).unwrap();
+        cpu.csr.store(SEPC, DRAM_BASE + 0x206);
+        // back in S-mode after mret, SPP = U
+        let sret = cpu.execute(0x10200073).unwrap();
+        (mret, sret)
+    };
+    assert_eq!(mret_sret(false), (DRAM_BASE + 0x100, DRAM_BASE + 0x204));
+    assert_eq!(mret_sret(true), (DRAM_BASE + 0x102, DRAM_BASE + 0x206));
+}
+
+#[test]
+fn test_c_quadrant0() {
+    require_toolchain!("test_c_quadrant0");
+    // written as .half, -march=rv64g doesn't assemble compressed instructions
+    let code = "
+addi sp, sp, -64
+li a0, -5
+.half 0x0800 # c.addi4spn s0, sp, 16
+li a5, 1
+.half 0xc048 # c.sw a0, 4(s0)
+.half 0x404c # c.lw a1, 4(s0)
+.half 0xe408 # c.sd a0, 8(s0)
+.half 0x6410 # c.ld a2, 8(s0)
+lwu a3, 4(s0)
+.half 0x1ffc # c.addi4spn a5, sp, 1020
+sub a5, a5, sp
+";
+    let binary = rv_asm_binary(code, "test_c_quadrant0").unwrap();
+    let cpu = CpuBuilder::new(binary.clone(), vec![0]).enable_c().build();
+    let (cpu, reason) = run(cpu, -1).unwrap();
+    assert!(matches!(reason, ExitReason::Clean));
+    assert_eq!(cpu.reg("s0"), cpu.reg("sp") + 16);
+    // c.lw sign-extends like lw
+    assert_eq!(cpu.reg("a1"), -5i64 as u64);
+    assert_eq!(cpu.reg("a2"), -5i64 as u64);
+    assert_eq!(cpu.reg("a3"), 0xffff_fffb);
+    assert_eq!(cpu.reg("a5"), 1020);
+    assert_eq!(cpu.instret, 11);
+
+    // without C the first one is illegal, reported as the 16-bit word
+    let (_, reason) = run(CpuBuilder::new(binary, vec![0]).build(), -1).unwrap();
+    assert!(matches!(
+        reason,
+        ExitReason::FatalException(Exception::IllegalInstruction(0x0800))
+    ));
+
+    // c.illegal, and c.fld without D
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).enable_c().build();
+    for inst in [0x0000, 0x2588] {
+        assert!(matches!(
+            cpu.execute(inst),
+            Err(Exception::IllegalInstruction(i)) if i == inst
+        ));
+    }
+}
+
+// assembles code and runs it to the end on a cpu with C enabled
+#[test]
+fn test_c_quadrant1_immediates() {
+    require_toolchain!("test_c_quadrant1_immediates");
+    let code = "
+li a0, 10
+li a3, 0x7fffffff
+mv t0, sp
+li s0, 0x1234
+li s1, -8
+.half 0x0001 # c.nop
+.half 0x1575 # c.addi a0, -3
+.half 0x2515 # c.addiw a0, 5
+.half 0x2685 # c.addiw a3, 1
+.half 0x5581 # c.li a1, -32
+.half 0x7605 # c.lui a2, 0xfffe1
+.half 0x7139 # c.addi16sp sp, -64
+sub t0, t0, sp
+.half 0x8011 # c.srli s0, 4
+.half 0x8485 # c.srai s1, 1
+.half 0x9841 # c.andi s0, -16
+";
+    let (cpu, reason) = rv_asm_helper_with_c(code, "test_c_quadrant1_immediates", -1).unwrap();
+    assert!(matches!(program_exit(reason), ExitReason::Clean));
+    assert_eq!(cpu.reg("a0"), 12);
+    // the W form wraps and sign-extends
+    assert_eq!(cpu.reg("a3"), 0xffff_ffff_8000_0000);
+    assert_eq!(cpu.reg("a1"), -32i64 as u64);
+    assert_eq!(cpu.reg("a2"), 0xffff_ffff_fffe_1000);
+    assert_eq!(cpu.reg("t0"), 64);
+    assert_eq!(cpu.reg("s0"), 0x120);
+    assert_eq!(cpu.reg("s1"), -4i64 as u64);
+}
+
+#[test]
+fn test_c_quadrant1_registers() {
+    require_toolchain!("test_c_quadrant1_registers");
+    let code = "
+li s0, 12
+li s1, 10
+li a0, 12
+li a1, 12
+li a2, 5
+li a3, 0x7fffffff
+li a4, 1
+li a5, 0
+.half 0x8c25 # c.xor s0, s1
+.half 0x8d45 # c.or a0, s1
+.half 0x8de5 # c.and a1, s1
+.half 0x8e05 # c.sub a2, s1
+.half 0x9eb9 # c.addw a3, a4
+.half 0x9f99 # c.subw a5, a4
+";
+    let (cpu, reason) = rv_asm_helper_with_c(code, "test_c_quadrant1_registers", -1).unwrap();
+    assert!(matches!(program_exit(reason), ExitReason::Clean));
+    assert_eq!(cpu.reg("s0"), 6);
+    assert_eq!(cpu.reg("a0"), 14);
+    assert_eq!(cpu.reg("a1"), 8);
+    assert_eq!(cpu.reg("a2"), -5i64 as u64);
+    assert_eq!(cpu.reg("a3"), 0xffff_ffff_8000_0000);
+    assert_eq!(cpu.reg("a5"), u64::MAX);
+}
+
+#[test]
+fn test_c_quadrant1_control() {
+    require_toolchain!("test_c_quadrant1_control");
+    let code = "
+.half 0x4501 # c.li a0, 0
+.half 0x4401 # c.li s0, 0
+.half 0x448d # c.li s1, 3
+.half 0xc011 # c.beqz s0, 1f
+.half 0x4505 # c.li a0, 1
+.half 0xe011 # 1: c.bnez s0, 2f
+.half 0x4589 # c.li a1, 2
+.half 0xa011 # 2: c.j 3f
+.half 0x460d # c.li a2, 3
+.half 0x14fd # 3: c.addi s1, -1
+.half 0xfcfd # c.bnez s1, 3b
+li a3, 4
+";
+    let (cpu, reason) = rv_asm_helper_with_c(code, "test_c_quadrant1_control", -1).unwrap();
+    assert!(matches!(program_exit(reason), ExitReason::Clean));
+    // taken c.beqz and c.j skip, c.bnez falls through, then loops three times
+    assert_eq!(cpu.reg("a0"), 0);
+    assert_eq!(cpu.reg("a1"), 2);
+    assert_eq!(cpu.reg("a2"), 0);
+    assert_eq!(cpu.reg("a3"), 4);
+    assert_eq!(cpu.instret, 14);
+}
+
+#[test]
+fn test_c_quadrant2_stack() {
+    require_toolchain!("test_c_quadrant2_stack");
+    let code = "
+li a0, 5
+.half 0x050e # c.slli a0, 3
+li a5, 7
+.half 0x873e # c.mv a4, a5
+.half 0x973e # c.add a4, a5
+addi sp, sp, -512
+li a1, -3
+.half 0xc22e # c.swsp a1, 4(sp)
+li s2, 0x123456789
+.half 0xffca # c.sdsp s2, 504(sp)
+.half 0x4612 # c.lwsp a2, 4(sp)
+.half 0x79fe # c.ldsp s3, 504(sp)
+lw a3, 4(sp)
+ld s4, 504(sp)
+la s5, 1f
+1: .half 0x9002 # c.ebreak
+";
+    let (cpu, reason) = rv_asm_helper_with_c(code, "test_c_quadrant2_stack", -1).unwrap();
+    assert!(matches!(program_exit(reason), ExitReason::Clean));
+    assert_eq!(cpu.reg("a0"), 40);
+    assert_eq!(cpu.reg("a4"), 14);
+    // c.lwsp sign-extends, the compressed and full-size forms see the same memory
+    assert_eq!(cpu.reg("a2"), -3i64 as u64);
+    assert_eq!(cpu.reg("a3"), -3i64 as u64);
+    assert_eq!(cpu.reg("s3"), 0x123456789);
+    assert_eq!(cpu.reg("s4"), 0x123456789);
+    assert_eq!(cpu.csr.load(MCAUSE), Exception::Breakpoint(0).code());
+    assert_eq!(cpu.csr.load(MEPC), cpu.reg("s5"));
+}
+
+#[test]
+fn test_c_quadrant2_calls() {
+    require_toolchain!("test_c_quadrant2_calls");
+    let code = "
+la t0, func_ret
+.half 0x9282 # c.jalr t0
+mv s0, ra
+call func_c_jr
+mv s1, ra
+la a5, func_c_jr
+.half 0x9782 # c.jalr a5
+mv s2, ra
+j end
+func_ret:
+addi a0, a0, 1
+ret
+func_c_jr:
+addi a0, a0, 10
+.half 0x8082 # c.jr ra
+end:
+li a1, 1
+";
+    let (cpu, reason) = rv_asm_helper_with_c(code, "test_c_quadrant2_calls", -1).unwrap();
+    assert!(matches!(program_exit(reason), ExitReason::Clean));
+    // c.jalr links past its 2 bytes, ret and c.jr both go back there, and c.jr
+    // returns from a call made with auipc + jalr
+    assert_eq!(cpu.reg("a0"), 21);
+    assert_eq!(cpu.reg("a1"), 1);
+    assert_eq!(cpu.reg("s0"), DRAM_BASE + 0xa);
+    assert_eq!(cpu.reg("s1"), DRAM_BASE + 0x16);
+    assert_eq!(cpu.reg("s2"), DRAM_BASE + 0x24);
+}
+
+#[test]
+fn test_fetch_16_bit_at_dram_end() {
+    let mut config = MachineConfig::default();
+    config.dram_size = 0x1000;
+    config.uart_stdin = false;
+    // c.li a0, 5 in the last two bytes of memory
+    let mut code = vec![0; 0x1000];
+    code[0xffe..].copy_from_slice(&0x4515u16.to_le_bytes());
+    let mut cpu = CpuBuilder::new(code, vec![0])
+        .config(config)
+        .enable_c()
+        .build();
+    cpu.pc = DRAM_BASE + 0xffe;
+    assert!(matches!(cpu.step(), StepResult::Ok));
+    assert_eq!(cpu.reg("a0"), 5);
+    assert_eq!(cpu.pc, DRAM_BASE + 0x1000);
+}
+
+#[test]
+fn test_lr_sc() {
+    let code = "addi sp, sp, -16
+li a1, 7
+lr.w a0, (sp)
+sc.w a2, a1, (sp)
+sc.w a3, a1, (sp)
+lr.d a0, (sp)
+sc.d a4, a1, (sp)
+lw a5, 0(sp)
+";
+    riscv_asm_test!(code, "test_lr_sc", 20, "a2" => 0, "a3" => 1, "a4" => 0, "a5" => 7);
+}
+
+#[test]
+fn test_sc_after_store() {
+    let code = "addi sp, sp, -16
+li a1, 7
+sw zero, 0(sp)
+lr.w a0, (sp)
+sw a1, 0(sp)
+sc.w a2, zero, (sp)
+lw a3, 0(sp)
+";
+    riscv_asm_test!(code, "test_sc_after_store", 20, "a2" => 1, "a3" => 7);
+}
+
+// Zfh isn't in rv64g, instructions are encoded with .insn: fmv.h.x, fmv.x.h and arithmetic
+// are opcode 0x53, funct7 picks the operation.
+#[test]
+fn test_fadd_h() {
+    let code = "li a0, 0x3c00
+li a1, 0x4000
+.insn r 0x53, 0, 0x7a, ft0, a0, x0
+.insn r 0x53, 0, 0x7a, ft1, a1, x0
+.insn r 0x53, 0, 0x02, ft2, ft0, ft1
+.insn r 0x53, 0, 0x06, ft3, ft0, ft1
+.insn r 0x53, 0, 0x72, a2, ft2, x0
+.insn r 0x53, 0, 0x72, a3, ft3, x0
+csrr a4, 0x001
+";
+    // 1.0 + 2.0 = 3.0, 1.0 - 2.0 = -1.0, both exact
+    riscv_asm_test!(code, "test_fadd_h", 20, "a2" => 0x4200, "a3" => 0xffff_ffff_ffff_bc00u64, "a4" => 0);
+}
+
+#[test]
+fn test_fdiv_h_inexact() {
+    let code = "li a0, 0x3c00
+li a1, 0x4200
+.insn r 0x53, 0, 0x7a, ft0, a0, x0
+.insn r 0x53, 0, 0x7a, ft1, a1, x0
+.insn r 0x53, 0, 0x0e, ft2, ft0, ft1
+.insn r 0x53, 0, 0x72, a2, ft2, x0
+csrr a3, 0x001
+";
+    // 1.0 / 3.0 rounds to 0x3555 and raises NX
+    riscv_asm_test!(code, "test_fdiv_h_inexact", 20, "a2" => 0x3555, "a3" => FFLAG_NX);
+}
+
+#[test]
+fn test_fmul_h_overflow() {
+    let code = "li a0, 0x7bff
+li a1, 0x4000
+.insn r 0x53, 0, 0x7a, ft0, a0, x0
+.insn r 0x53, 0, 0x7a, ft1, a1, x0
+.insn r 0x53, 0, 0x0a, ft2, ft0, ft1
+.insn r 0x53, 0, 0x72, a2, ft2, x0
+csrr a3, 0x001
+";
+    // 65504 * 2 is +inf
+    riscv_asm_test!(code, "test_fmul_h_overflow", 20, "a2" => 0x7c00, "a3" => FFLAG_OF | FFLAG_NX);
+}
+
+#[test]
+fn test_fdiv_h_by_zero() {
+    let code = "li a0, 0x3c00
+.insn r 0x53, 0, 0x7a, ft0, a0, x0
+.insn r 0x53, 0, 0x7a, ft1, zero, x0
+.insn r 0x53, 0, 0x0e, ft2, ft0, ft1
+.insn r 0x53, 0, 0x72, a2, ft2, x0
+csrr a3, 0x001
+";
+    riscv_asm_test!(code, "test_fdiv_h_by_zero", 20, "a2" => 0x7c00, "a3" => FFLAG_DZ);
+}
+
+#[test]
+fn test_fsqrt_h() {
+    let code = "li a0, 0x4400
+li a1, 0xbc00
+.insn r 0x53, 0, 0x7a, ft0, a0, x0
+.insn r 0x53, 0, 0x7a, ft1, a1, x0
+.insn r 0x53, 0, 0x2e, ft2, ft0, x0
+csrr a3, 0x001
+.insn r 0x53, 0, 0x2e, ft3, ft1, x0
+.insn r 0x53, 0, 0x72, a2, ft2, x0
+.insn r 0x53, 0, 0x72, a4, ft3, x0
+csrr a5, 0x001
+";
+    // sqrt(4.0) = 2.0 exactly, sqrt(-1.0) is the canonical NaN
+    riscv_asm_test!(code, "test_fsqrt_h", 20, "a2" => 0x4000, "a3" => 0, "a4" => 0x7e00, "a5" => FFLAG_NV);
+}
+
+#[test]
+fn test_fcvt_h() {
+    let code = "li a0, 0x3555
+.insn r 0x53, 0, 0x7a, ft0, a0, x0
+.insn r 0x53, 0, 0x20, ft1, ft0, f2
+.insn r 0x53, 0, 0x22, ft2, ft1, f0
+.insn r 0x53, 0, 0x21, ft3, ft0, f2
+.insn r 0x53, 0, 0x22, ft4, ft3, f1
+.insn r 0x53, 0, 0x72, a1, ft2, x0
+.insn r 0x53, 0, 0x72, a2, ft4, x0
+csrr a3, 0x001
+.insn r 0x53, 0, 0x22, ft5, ft0, f0
+.insn r 0x53, 0, 0x72, a4, ft5, x0
+";
+    // h -> s -> h and h -> d -> h are exact, a NaN-boxed half reads as a single NaN
+    riscv_asm_test!(code, "test_fcvt_h", 30, "a1" => 0x3555, "a2" => 0x3555, "a3" => 0, "a4" => 0x7e00);
+}
+
+#[test]
+fn test_flh_fsh() {
+    let code = "addi sp, sp, -16
+li a0, 0x12344200
+sw a0, 0(sp)
+.insn i 0x07, 1, ft0, 0(sp)
+.insn s 0x27, 1, ft0, 4(sp)
+lw a1, 4(sp)
+.insn r 0x53, 0, 0x72, a2, ft0, x0
+";
+    riscv_asm_test!(code, "test_flh_fsh", 20, "a1" => 0x4200, "a2" => 0x4200);
+}
+
+#[test]
+fn test_zfh_disabled() {
+    let mut config = MachineConfig::default();
+    config.enabled_extensions.zfh = false;
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).config(config).build();
+    // fadd.h ft0, ft0, ft0
+    assert!(matches!(
+        cpu.execute(0x04000053),
+        Err(Exception::IllegalInstruction(_))
+    ));
+}
+
+#[test]
+fn test_zmmul_only() {
+    // mul, mulhu, mulw a2, a0, a1 then div, remu, divw, remuw a2, a0, a1
+    let muls = [0x02b50633, 0x02b53633, 0x02b5063b];
+    let divs = [0x02b54633, 0x02b57633, 0x02b5463b, 0x02b5763b];
+
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).with_zmmul_only().build();
+    assert_eq!(cpu.csr.load(MISA) & (1 << 12), 0);
+    cpu.regs[10] = 12;
+    cpu.regs[11] = 5;
+    for inst in muls {
+        cpu.execute(inst).unwrap();
+    }
+    assert_eq!(cpu.reg("a2"), 60);
+    for inst in divs {
+        assert!(matches!(
+            cpu.execute(inst),
+            Err(Exception::IllegalInstruction(i)) if i == inst
+        ));
+    }
+
+    let mut cpu = CpuBuilder::new(vec![0], vec![0])
+        .with_zmmul_only()
+        .with_m_extension()
+        .build();
+    assert_ne!(cpu.csr.load(MISA) & (1 << 12), 0);
+    cpu.regs[10] = 12;
+    cpu.regs[11] = 5;
+    // 12 / 5 and 12 % 5 are both 2
+    for inst in divs {
+        cpu.execute(inst).unwrap();
+        assert_eq!(cpu.reg("a2"), 2);
+    }
+}
+
+#[test]
+fn test_mbe_big_endian() {
+    let code = "addi sp, sp, -16
+li a0, 0x01020304
+li t0, 1
+slli t0, t0, 37
+csrs mstatus, t0
+sw a0, 0(sp)
+lw a1, 0(sp)
+lh a2, 0(sp)
+csrc mstatus, t0
+lw a3, 0(sp)
+lbu a4, 0(sp)
+";
+    // MBE only changes the byte order, a big-endian store reads back the same
+    riscv_asm_test!(code, "test_mbe_big_endian", 20, "a1" => 0x01020304, "a2" => 0x0102, "a3" => 0x04030201, "a4" => 0x01);
+}
+
+#[test]
+fn test_retire_and_store_hooks() {
+    require_toolchain!("test_retire_and_store_hooks");
+    let code = "li a0, 0x1234
+addi sp, sp, -8
+sd a0, 0(sp)
+ld a1, 0(sp)
+add a2, a1, a0
+";
+    let binary = rv_asm_binary(code, "test_retire_and_store_hooks").unwrap();
+    let mut cpu = CpuBuilder::new(binary, vec![0]).build();
+    let retired = Arc::new(Mutex::new(Vec::new()));
+    let stores = Arc::new(Mutex::new(Vec::new()));
+    let r = retired.clone();
+    cpu.set_retire_hook(move |pc, _| r.lock().unwrap().push(pc));
+    let s = stores.clone();
+    cpu.set_store_hook(move |addr, size, value| s.lock().unwrap().push((addr, size, value)));
+
+    // li is lui + addi, every clock retires one instruction
+    let (cpu, _) = run(cpu, 4).unwrap();
+    let retired = retired.lock().unwrap();
+    assert_eq!(retired.len(), 4);
+    assert_eq!(
+        *retired,
+        (0..4).map(|i| DRAM_BASE + 4 * i).collect::<Vec<_>>()
+    );
+    assert_eq!(*stores.lock().unwrap(), vec![(cpu.reg("sp"), 64, 0x1234)]);
+}
+
+#[test]
+fn test_dram_bounds() {
+    let dram = Dram::new(4096, vec![0]);
+    let end = DRAM_BASE + 4096;
+    assert!(dram.load(end - 8, 64).is_ok());
+    assert!(matches!(dram.load(end - 7, 64), Err(Exception::LoadAccessFault(a)) if a == end - 7));
+    assert!(
+        matches!(dram.store(DRAM_BASE - 1, 8, 0), Err(Exception::StoreAMOAccessFault(a)) if a == DRAM_BASE - 1)
+    );
+
+    // no address may panic: edges of the range plus xorshift noise
+    let mut x: u64 = 0x9e37_79b9_7f4a_7c15;
+    let mut addrs = vec![0, u64::MAX, u64::MAX - 7, DRAM_BASE - 8, end, end - 1];
+    for _ in 0..10000 {
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        addrs.push(x);
+        addrs.push(DRAM_BASE + x % 8192);
+    }
+    for addr in addrs {
+        for size in [8, 16, 24, 32, 64] {
+            let ok = addr >= DRAM_BASE && addr - DRAM_BASE + size / 8 <= 4096;
+            assert_eq!(dram.load(addr, size).is_ok(), ok);
+            assert_eq!(dram.store(addr, size, 0).is_ok(), ok);
+        }
+    }
+}
+
+#[test]
+fn test_dram_amo() {
+    let dram = Dram::new(4096, vec![0]);
+    dram.store(DRAM_BASE, 64, 0xffff_ffff_0000_0005).unwrap();
+    // the upper word is untouched by a .w on the lower one
+    let old = dram.amo(DRAM_BASE, 32, AmoOp::Min, (-3i32) as u64, Ordering::SeqCst);
+    assert_eq!(old.unwrap(), 5);
+    assert_eq!(dram.load(DRAM_BASE, 64).unwrap(), 0xffff_ffff_ffff_fffd);
+    let old = dram.amo(DRAM_BASE + 4, 32, AmoOp::Add, 1, Ordering::SeqCst);
+    assert_eq!(old.unwrap(), 0xffff_ffff);
+    assert_eq!(dram.load(DRAM_BASE, 64).unwrap(), 0xffff_fffd);
+    assert!(matches!(
+        dram.amo(DRAM_BASE + 2, 32, AmoOp::Swap, 0, Ordering::SeqCst),
+        Err(Exception::StoreAMOAddrMisaligned(a)) if a == DRAM_BASE + 2
+    ));
+
+    assert!(!dram
+        .compare_exchange(DRAM_BASE, 32, 1, 2, Ordering::SeqCst)
+        .unwrap());
+    assert!(dram
+        .compare_exchange(DRAM_BASE, 32, 0xffff_fffd, 2, Ordering::SeqCst)
+        .unwrap());
+    assert_eq!(dram.load(DRAM_BASE, 32).unwrap(), 2);
+}
+
+#[test]
+fn test_two_hart_spinlock() {
+    require_toolchain!("test_two_hart_spinlock");
+    // every hart adds 1 to the counter 10000 times, with a plain load and store
+    // inside an amoswap lock
+    let code = "li t0, 0x80001000
+li t1, 0x80001008
+li t2, 10000
+li t3, 1
+acquire:
+amoswap.w.aq t4, t3, (t0)
+bnez t4, acquire
+lw t5, 0(t1)
+addi t5, t5, 1
+sw t5, 0(t1)
+amoswap.w.rl zero, zero, (t0)
+addi t2, t2, -1
+bnez t2, acquire
+";
+    let binary = rv_asm_binary(code, "test_two_hart_spinlock").unwrap();
+    let mut config = MachineConfig::default();
+    config.uart_stdin = false;
+    let hart0 = CpuBuilder::new(binary, vec![0]).config(config).build();
+    let hart1 = hart0.new_hart(1);
+    assert_eq!(hart1.csr.load(MHARTID), 1);
+
+    let other = thread::spawn(move || run(hart1, -1).unwrap());
+    let (hart0, exit0) = run(hart0, -1).unwrap();
+    let (_, exit1) = other.join().unwrap();
+    assert!(matches!(exit0, ExitReason::Clean));
+    assert!(matches!(exit1, ExitReason::Clean));
+    let counter = hart0.bus.shared_dram().load(DRAM_BASE + 0x1008, 32);
+    assert_eq!(counter.unwrap(), 20000);
+}
+
+#[test]
+fn test_wfi_timeout() {
+    // wfi, addi a0, x0, 1
+    let code = [0x10500073u32, 0x00100513]
+        .iter()
+        .flat_map(|inst| inst.to_le_bytes())
+        .collect::<Vec<u8>>();
+
+    // mtime counts steps, nothing is pending, so wfi returns straight away
+    let mut cpu = CpuBuilder::new(code.clone(), vec![0])
+        .deterministic(DeterministicMode::default())
+        .wfi_timeout(100)
+        .build();
+    assert!(matches!(cpu.run_for(101), ExitReason::Clean));
+    assert_eq!(cpu.reg("a0"), 1);
+    assert!(cpu.cycles <= 101);
+
+    // mtime counts steps unless the builder asks for the wall clock
+    assert!(!CpuBuilder::new(code.clone(), vec![0])
+        .build()
+        .bus
+        .clint
+        .follows_wall_clock());
+
+    // on the wall clock it gives up after 100 ticks, stdin at EOF would wake it through the uart
+    let mut config = MachineConfig::default();
+    config.uart_stdin = false;
+    let mut cpu = CpuBuilder::new(code.clone(), vec![0])
+        .config(config)
+        .with_wall_clock()
+        .wfi_timeout(100)
+        .build();
+    let entry = cpu.bus.clint.mtime();
+    cpu.step();
+    assert!(cpu.bus.clint.mtime() - entry >= 100);
+    assert_eq!(cpu.pc, DRAM_BASE + 4);
+
+    // an interrupt enabled in mie wakes it even with mstatus.MIE clear
+    let mut cpu = CpuBuilder::new(code.clone(), vec![0])
+        .wfi_timeout(u64::MAX)
+        .build();
+    cpu.csr.store(MIE, MASK_MTIP);
+    cpu.store(CLINT_MTIMECMP, 64, 0).unwrap();
+    assert!(matches!(cpu.run_for(10), ExitReason::Clean));
+    assert_eq!(cpu.reg("a0"), 1);
+
+    // S-mode with mstatus.TW set can't wait
+    let mut cpu = CpuBuilder::new(code, vec![0]).build();
+    cpu.csr.store(MSTATUS, MASK_TW);
+    cpu.mode = 0b01;
+    assert!(matches!(
+        cpu.execute(0x10500073),
+        Err(Exception::IllegalInstruction(_))
+    ));
+}
+
+#[test]
+fn test_clint_mtimecmp_per_hart() {
+    let mut config = MachineConfig::default();
+    config.num_harts = 2;
+    let nops = [0x13, 0, 0, 0].repeat(4);
+    let mut hart0 = CpuBuilder::new(nops, vec![0]).config(config).build();
+    let mut hart1 = hart0.new_hart(1);
+    for hart in [&mut hart0, &mut hart1] {
+        hart.csr.store(MSTATUS, MASK_MIE);
+        hart.csr.store(MIE, MASK_MTIP);
+        hart.csr.store(MTVEC, DRAM_BASE + 0x100);
+        // like xv6, which delegates everything, MTI can't go to S-mode
+        hart.csr.store(MIDELEG, 0xffff);
+        assert_eq!(hart.csr.load(MIDELEG), 0xffff & !MASK_MIDELEG_M);
+    }
+    // hart 0 programs both timers, hart 1 is due now and hart 0 far in the future
+    hart0.store(CLINT_MTIMECMP_BASE, 64, u64::MAX - 1).unwrap();
+    hart0.store(CLINT_MTIMECMP_BASE + 8, 64, 0).unwrap();
+    assert_eq!(hart1.load(CLINT_MTIMECMP_BASE + 8, 64).unwrap(), 0);
+    assert_eq!(hart1.bus.clint.mtimecmp(0), u64::MAX - 1);
+    // mtimecmp of a hart past num_harts reads as zero
+    assert_eq!(hart0.load(CLINT_MTIMECMP_BASE + 16, 64).unwrap(), 0);
+
+    hart0.step();
+    hart1.step();
+    assert_eq!(hart0.csr.load(MIP) & MASK_MTIP, 0);
+    assert_eq!(hart0.pc, DRAM_BASE + 4);
+    assert_eq!(
+        hart1.csr.load(MCAUSE),
+        Interrupt::MachineTimerInterrupt.code()
+    );
+    assert_eq!(hart1.pc, DRAM_BASE + 0x100);
+
+    // moving hart 1's mtimecmp back out clears MTIP
+    hart1
+        .store(CLINT_MTIMECMP_BASE + 8, 64, u64::MAX - 1)
+        .unwrap();
+    hart1.check_pending_interrupt();
+    assert_eq!(hart1.csr.load(MIP) & MASK_MTIP, 0);
+}
+
+#[test]
+fn test_clint_msip() {
+    let mut config = MachineConfig::default();
+    config.num_harts = 2;
+    let nops = [0x13, 0, 0, 0].repeat(4);
+    let mut hart0 = CpuBuilder::new(nops, vec![0]).config(config).build();
+    let mut hart1 = hart0.new_hart(1);
+    for hart in [&mut hart0, &mut hart1] {
+        hart.csr.store(MSTATUS, MASK_MIE);
+        hart.csr.store(MIE, MASK_MSIP);
+        hart.csr.store(MTVEC, DRAM_BASE + 0x100);
+    }
+    // only bit 0 sticks
+    hart0.store(CLINT_MSIP, 32, 0xff).unwrap();
+    assert_eq!(hart0.load(CLINT_MSIP, 32).unwrap(), 1);
+
+    hart0.step();
+    assert_eq!(
+        hart0.csr.load(MCAUSE),
+        Interrupt::MachineSoftwareInterrupt.code()
+    );
+    assert_eq!(hart0.pc, DRAM_BASE + 0x100);
+    // it stays pending until msip is written 0
+    hart0.check_pending_interrupt();
+    assert_eq!(hart0.csr.load(MIP) & MASK_MSIP, MASK_MSIP);
+    hart0.store(CLINT_MSIP, 32, 0).unwrap();
+    hart0.check_pending_interrupt();
+    assert_eq!(hart0.csr.load(MIP) & MASK_MSIP, 0);
+
+    // hart 0 interrupts hart 1 through its msip
+    hart0.store(CLINT_MSIP_BASE + 4, 32, 1).unwrap();
+    hart1.step();
+    assert_eq!(
+        hart1.csr.load(MCAUSE),
+        Interrupt::MachineSoftwareInterrupt.code()
+    );
+    assert_eq!(hart1.pc, DRAM_BASE + 0x100);
+}
+
+#[test]
+fn test_smstateen() {
+    // csrr a0, sstateen0 / senvcfg / mstateen0
+    let read_sstateen0 = 0x10c02573;
+    let read_senvcfg = 0x10a02573;
+    let read_mstateen0 = 0x30c02573;
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).build();
+    cpu.csr.store(MSTATEEN0, 0b101);
+    cpu.csr.store(SSTATEEN0, 0b111);
+    // sstateen bits missing from mstateen read as zero
+    assert_eq!(cpu.csr.load(SSTATEEN0), 0b101);
+    assert!(cpu.execute(read_sstateen0).is_ok());
+
+    // S-mode
+    cpu.mode = 0b01;
+    let illegal = |r: Result<u64, Exception>| matches!(r, Err(Exception::IllegalInstruction(_)));
+    assert!(illegal(cpu.execute(read_mstateen0)));
+    assert!(illegal(cpu.execute(read_sstateen0)));
+    assert!(illegal(cpu.execute(read_senvcfg)));
+
+    cpu.csr
+        .store(MSTATEEN0, MASK_STATEEN_SE0 | MASK_STATEEN_ENVCFG | 0b101);
+    assert!(cpu.execute(read_sstateen0).is_ok());
+    assert_eq!(cpu.reg("a0"), 0b101);
+    assert!(cpu.execute(read_senvcfg).is_ok());
+    // sstateen1 is gated by its own mstateen
+    assert!(illegal(cpu.execute(0x10d02573)));
+}
+
+#[test]
+fn test_event_log_json() {
+    require_toolchain!("test_event_log_json");
+    let code = "li a0, 1
+.word 0xffffffff
+";
+    let binary = rv_asm_binary(code, "test_event_log_json").unwrap();
+    let out = SharedBuf::default();
+    let cpu = CpuBuilder::new(binary, vec![0])
+        .event_log(EventLog::new(Box::new(out.clone())))
+        .build();
+    let (_, reason) = run(cpu, 10).unwrap();
+    assert!(matches!(
+        reason,
+        ExitReason::FatalException(Exception::IllegalInstruction(_))
+    ));
+
+    let log = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+    let first = log.lines().next().unwrap();
+    let record: serde_json::Value = serde_json::from_str(first).unwrap();
+    assert_eq!(record["event"], "fatal_exception");
+    assert_eq!(record["exception"], "IllegalInstruction");
+    assert_eq!(record["value"], "0xffffffff");
+    assert_eq!(record["pc"], format!("{:#x}", DRAM_BASE + 4));
+    assert_eq!(record["cycle"], 2);
+    assert_eq!(record["mode"], "Machine");
+}
+
+#[test]
+fn test_zicntr() {
+    let code = "csrr a0, cycle
+csrr a1, cycle
+nop
+csrr a2, instret
+csrr a3, 0xc80
+";
+    riscv_asm_test!(code, "test_zicntr", 5, "a0" => 1, "a1" => 2, "a2" => 3, "a3" => 0);
+}
+
+#[test]
+fn test_zicntr_read_only() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![0])
+        .deterministic(DeterministicMode::default())
+        .build();
+    for _ in 0..3 {
+        cpu.bus.tick();
+    }
+    // csrr a0, time
+    cpu.execute(0xc0102573).unwrap();
+    assert_eq!(cpu.reg("a0"), cpu.bus.clint.mtime());
+    // csrw cycle, a0
+    assert!(matches!(
+        cpu.execute(0xc0051073),
+        Err(Exception::IllegalInstruction(_))
+    ));
+    // csrs cycle, zero is a plain read
+    assert!(cpu.execute(0xc0002073).is_ok());
+}
+
+#[test]
+fn test_exit_reason() {
+    require_toolchain!("test_exit_reason");
+    let code = "li a0, 1
+loop:
+addi a0, a0, 1
+j loop
+";
+    let binary = rv_asm_binary(code, "test_exit_reason").unwrap();
+    let reason = CpuBuilder::new(binary.clone(), vec![0]).build().run_for(20);
+    assert!(matches!(reason, ExitReason::Timeout));
+
+    let seen = Arc::new(Mutex::new(0));
+    let s = seen.clone();
+    let cpu = CpuBuilder::new(binary, vec![0])
+        .build()
+        .with_timeout(30, move |cpu| *s.lock().unwrap() = cpu.cycles);
+    let (cpu, reason) = run(cpu, -1).unwrap();
+    assert!(matches!(reason, ExitReason::Timeout));
+    assert_eq!(*seen.lock().unwrap(), 30);
+    assert_eq!(cpu.cycles, 30);
+
+    let reason = CpuBuilder::new(vec![0x13, 0, 0, 0], vec![0])
+        .build()
+        .run_to_halt();
+    assert!(matches!(reason, ExitReason::Clean));
+}
+
+#[test]
+fn test_run_for_step_and_inspect() {
+    require_toolchain!("test_run_for_step_and_inspect");
+    let code = "li a0, 0
+loop:
+addi a0, a0, 1
+j loop
+";
+    let binary = rv_asm_binary(code, "test_run_for_step_and_inspect").unwrap();
+    let mut cpu = CpuBuilder::new(binary, vec![0]).build();
+    // li, then an addi every second instruction
+    assert!(matches!(cpu.run_for(10), ExitReason::Timeout));
+    assert_eq!(cpu.reg("a0"), 5);
+    cpu.regs[10] = 100;
+    assert!(matches!(cpu.run_for(10), ExitReason::Timeout));
+    assert_eq!(cpu.reg("a0"), 105);
+    assert_eq!(cpu.instret, 20);
+}
+
+#[test]
+fn test_step_budget() {
+    require_toolchain!("test_step_budget");
+    let code = "li a0, 0
+loop:
+addi a0, a0, 1
+j loop
+";
+    let binary = rv_asm_binary(code, "test_step_budget").unwrap();
+    let mut cpu = CpuBuilder::new(binary, vec![0]).build();
+    assert_eq!(cpu.remaining_budget(), None);
+    cpu.set_budget(5);
+    assert!(matches!(cpu.run_for(1000), ExitReason::BudgetExhausted));
+    assert_eq!(cpu.instret, 5);
+    assert_eq!(cpu.remaining_budget(), Some(0));
+    // nothing runs until there is more budget, then it resumes where it stopped
+    assert!(matches!(cpu.step(), StepResult::BudgetExhausted));
+    assert_eq!(cpu.instret, 5);
+    cpu.add_budget(3);
+    assert!(matches!(cpu.run_for(1000), ExitReason::BudgetExhausted));
+    assert_eq!(cpu.instret, 8);
+    assert_eq!(cpu.reg("a0"), 4);
+}
+
+#[test]
+fn test_sifive_timing() {
+    require_toolchain!("test_sifive_timing");
+    let code = "li a0, 7
+li a1, 3
+mul a2, a0, a1
+div a3, a0, a1
+divw a4, a0, a1
+sd a2, -8(sp)
+ld a5, -8(sp)
+";
+    let binary = rv_asm_binary(code, "test_sifive_timing").unwrap();
+    let mut cpu = CpuBuilder::new(binary.clone(), vec![0]).build();
+    cpu.run_to_halt();
+    // a cycle per instruction and one for the fetch that halts
+    assert_eq!(cpu.cycles, 8);
+
+    let build = || {
+        CpuBuilder::new(binary.clone(), vec![0])
+            .timing_model(Box::new(SifiveTiming))
+            .build()
+    };
+    let mut cpu = build();
+    cpu.run_to_halt();
+    assert_eq!(cpu.instret, 7);
+    assert_eq!(cpu.cycles, 1 + 1 + 3 + 35 + 10 + 1 + 3 + 1);
+    assert_eq!(cpu.reg("a5"), 21);
+    // the budget is in cycles too, mul uses up what's left after the two li
+    let mut cpu = build();
+    cpu.set_budget(5);
+    assert!(matches!(cpu.run_for(1000), ExitReason::BudgetExhausted));
+    assert_eq!(cpu.instret, 3);
+}
+
+// Zkn instructions are encoded with .insn. AES vectors are the first round of
+// FIPS-197 appendix B, state bytes are little-endian with columns 0, 1 in the first register.
+#[test]
+fn test_aes64esm_round() {
+    let code = "li a1, 0x2be2f4a0bee33d19
+li a2, 0x0848f8e92a8dc69a
+.insn r 0x33, 0, 0x1b, a3, a1, a2
+.insn r 0x33, 0, 0x1b, a4, a2, a1
+";
+    riscv_asm_test!(code, "test_aes64esm_round", 20,
+        "a3" => 0x9a19cbe0e5816604u64, "a4" => 0x4c2606287ad3f848u64);
+}
+
+#[test]
+fn test_aes64_key_schedule() {
+    let code = "li a1, 0xa6d2ae2816157e2b
+li a2, 0x3c4fcf098815f7ab
+.insn i 0x13, 1, a3, a2, 0x310
+.insn r 0x33, 0, 0x3f, a4, a3, a1
+.insn r 0x33, 0, 0x3f, a5, a4, a2
+";
+    // aes64ks1i with rnum 0, then aes64ks2 twice gives round key 1
+    riscv_asm_test!(code, "test_aes64_key_schedule", 20,
+        "a4" => 0xb12c548817fefaa0u64, "a5" => 0x05766c2a3939a323u64);
+}
+
+#[test]
+fn test_aes64_decrypt_inverts() {
+    let code = "li a1, 0x2be2f4a0bee33d19
+li a2, 0x0848f8e92a8dc69a
+.insn r 0x33, 0, 0x19, a3, a1, a2
+.insn r 0x33, 0, 0x19, a4, a2, a1
+.insn r 0x33, 0, 0x1d, a5, a3, a4
+.insn r 0x33, 0, 0x1d, a6, a4, a3
+.insn r 0x33, 0, 0x1b, t0, a1, a2
+.insn i 0x13, 1, t1, t0, 0x300
+sub t2, t1, a3
+";
+    // aes64ds undoes aes64es, aes64im undoes the MixColumns of aes64esm
+    riscv_asm_test!(code, "test_aes64_decrypt_inverts", 30,
+        "a5" => 0x2be2f4a0bee33d19u64, "a6" => 0x0848f8e92a8dc69au64, "t2" => 0);
+}
+
+#[test]
+fn test_sha2_sigma() {
+    let code = "li a1, 0x12345678
+li a2, 0x87654321
+li a3, 0x0123456789abcdef
+.insn i 0x13, 1, a4, a1, 0x102
+.insn i 0x13, 1, a5, a2, 0x100
+.insn i 0x13, 1, a6, a3, 0x105
+";
+    // sha256sig0, sha256sum0 (sign-extended), sha512sum1
+    riscv_asm_test!(code, "test_sha2_sigma", 20,
+        "a4" => 0xffffffffe7fce6eeu64, "a5" => 0xffffffffedd9edffu64, "a6" => 0x7703112333475567u64);
+}
+
+#[test]
+fn test_aes64ks1i_reserved_rnum() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).build();
+    // aes64ks1i a0, a1, 0xb
+    assert!(matches!(
+        cpu.execute(0x31b59513),
+        Err(Exception::IllegalInstruction(_))
+    ));
+    // slli a0, a1, 1 still decodes
+    cpu.regs[11] = 3;
+    cpu.execute(0x00159513).unwrap();
+    assert_eq!(cpu.reg("a0"), 6);
+}
+
+#[test]
+fn test_brev8() {
+    let code = "li a0, 0x0102040810204080
+.insn i 0x13, 5, a1, a0, 0x687
+";
+    riscv_asm_test!(code, "test_brev8", 10, "a1" => 0x8040201008040201u64);
+}
+
+#[test]
+fn test_brev8_bytes() {
+    let code = "li a0, 0x12f0a5
+.insn i 0x13, 5, a1, a0, 0x687
+.insn i 0x13, 5, a2, a1, 0x687
+";
+    // 00010010 -> 01001000, 11110000 -> 00001111, a5 is a palindrome
+    riscv_asm_test!(code, "test_brev8_bytes", 10, "a1" => 0x480fa5, "a2" => 0x12f0a5);
+}
+
+#[test]
+fn test_pack() {
+    let code = "li a0, 0x1111222233334444
+li a1, 0x5555666677778888
+.insn r 0x33, 4, 0x04, a2, a0, a1
+";
+    riscv_asm_test!(code, "test_pack", 20, "a2" => 0x7777888833334444u64);
+}
+
+#[test]
+fn test_pack_zero() {
+    let code = "li a0, -2
+.insn r 0x33, 4, 0x04, a1, a0, zero
+";
+    // pack with zero is zext.w
+    riscv_asm_test!(code, "test_pack_zero", 10, "a1" => 0xfffffffeu64);
+}
+
+#[test]
+fn test_packh() {
+    let code = "li a0, 0x1234
+li a1, -0x3233
+.insn r 0x33, 7, 0x04, a2, a0, a1
+";
+    riscv_asm_test!(code, "test_packh", 10, "a2" => 0xcd34);
+}
+
+#[test]
+fn test_zip_low() {
+    let code = "li a0, 0xffffffff
+.insn i 0x13, 1, a1, a0, 0x08f
+";
+    riscv_asm_test!(code, "test_zip_low", 10, "a1" => 0x5555555555555555u64);
+}
+
+#[test]
+fn test_zip_high() {
+    let code = "li a0, 0xffffffff00000000
+.insn i 0x13, 1, a1, a0, 0x08f
+";
+    riscv_asm_test!(code, "test_zip_high", 10, "a1" => 0xaaaaaaaaaaaaaaaau64);
+}
+
+#[test]
+fn test_zip_bits() {
+    let code = "li a0, 0x0000000100000003
+.insn i 0x13, 1, a1, a0, 0x08f
+";
+    // low word bits 0, 1 -> 0, 2 and high word bit 0 -> 1
+    riscv_asm_test!(code, "test_zip_bits", 10, "a1" => 0b111);
+}
+
+#[test]
+fn test_unzip() {
+    let code = "li a0, 0x5555555555555555
+li a1, 0b111
+.insn i 0x13, 5, a2, a0, 0x08f
+.insn i 0x13, 5, a3, a1, 0x08f
+";
+    riscv_asm_test!(code, "test_unzip", 20, "a2" => 0xffffffffu64, "a3" => 0x0000000100000003u64);
+}
+
+#[test]
+fn test_zip_unzip_roundtrip() {
+    let code = "li a0, 0x0123456789abcdef
+.insn i 0x13, 1, a1, a0, 0x08f
+.insn i 0x13, 5, a2, a1, 0x08f
+";
+    riscv_asm_test!(code, "test_zip_unzip_roundtrip", 20,
+        "a1" => 0x40434c4f70737c7fu64, "a2" => 0x0123456789abcdefu64);
+}
+
+#[test]
+fn test_zbkb_disabled() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).build();
+    cpu.extensions.zbkb = false;
+    // pack a0, a1, a2
+    assert!(matches!(
+        cpu.execute(0x08c5c533),
+        Err(Exception::IllegalInstruction(_))
+    ));
+    cpu.extensions.zbkb = true;
+    cpu.regs[11] = 0x1234;
+    cpu.regs[12] = 0x5678;
+    cpu.execute(0x08c5c533).unwrap();
+    assert_eq!(cpu.reg("a0"), 0x5678_0000_1234);
+}
+
+#[test]
+fn test_coverage_fib() {
+    // iterative fibonacci, 10 rounds
+    let fib: [u32; 8] = [
+        0x00a00293, // li t0, 10
+        0x00000513, // li a0, 0
+        0x00100593, // li a1, 1
+        0x00b50333, // loop: add t1, a0, a1
+        0x00058513, // mv a0, a1
+        0x00030593, // mv a1, t1
+        0xfff28293, // addi t0, t0, -1
+        0xfe0298e3, // bnez t0, loop
+    ];
+    let code: Vec<u8> = fib.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+    let end = DRAM_BASE + code.len() as u64;
+    let cpu = CpuBuilder::new(code, vec![0]).with_coverage().build();
+    let (cpu, _) = run(cpu, -1).unwrap();
+    assert_eq!(cpu.reg("a0"), 55);
+
+    let report = cpu.coverage_report_range(DRAM_BASE, end);
+    assert_eq!(report.total_possible, 8);
+    assert!(report.coverage_percent >= 80.0);
+    assert_eq!(cpu.coverage_report().covered_pcs, report.covered_pcs);
+
+    let path = std::env::temp_dir().join("rustv_test_coverage_fib.info");
+    let symbols = [
+        (DRAM_BASE, 12, "init".to_string()),
+        (DRAM_BASE + 12, 20, "loop".to_string()),
+    ];
+    cpu.dump_coverage_lcov(&path, &symbols).unwrap();
+    let lcov = std::fs::read_to_string(&path).unwrap();
+    let _ = std::fs::remove_file(&path);
+    assert!(lcov.contains("SF:loop\nFN:1,loop\nFNDA:1,loop\n"));
+    assert!(lcov.contains("DA:5,1\nLF:5\nLH:5\nend_of_record\n"));
+}
+
+#[test]
+fn test_rom_read_only() {
+    let mut config = MachineConfig::default();
+    config.uart_stdin = false;
+    config.boot_pc = ROM_BASE;
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).config(config).build();
+    assert!(matches!(
+        cpu.store(ROM_DTB, 32, 0),
+        Err(Exception::StoreAMOAccessFault(a)) if a == ROM_DTB
+    ));
+    cpu.bus.rom.set_dtb(&[0xd0, 0x0d, 0xfe, 0xed]).unwrap();
+    assert_eq!(cpu.load(ROM_DTB, 32).unwrap(), 0xedfe0dd0);
+
+    // the reset vector hands over to dram with a0 = 0 and a1 = dtb
+    for _ in 0..5 {
+        cpu.step();
+    }
+    assert_eq!(cpu.pc, DRAM_BASE);
+    assert_eq!(cpu.reg("a0"), 0);
+    assert_eq!(cpu.reg("a1"), ROM_DTB);
+}
+
+#[test]
+fn test_wrs_single_hart() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).build();
+    let r = Reservation {
+        addr: DRAM_BASE + 0x100,
+        size: 32,
+        value: 0,
+    };
+    cpu.reservation = Some(r);
+    // wrs.nto and wrs.sto, nothing can break the reservation so they don't wait
+    cpu.execute(0x00d00073).unwrap();
+    cpu.execute(0x01d00073).unwrap();
+    assert_eq!(cpu.reservation, Some(r));
+
+    cpu.extensions.zawrs = false;
+    assert!(matches!(
+        cpu.execute(0x00d00073),
+        Err(Exception::IllegalInstruction(_))
+    ));
+}
+
+#[test]
+fn test_wrs_woken_by_other_hart() {
+    let mut config = MachineConfig::default();
+    config.uart_stdin = false;
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).config(config).build();
+    let other = cpu.new_hart(1);
+    let addr = DRAM_BASE + 0x100;
+    cpu.reservation = Some(Reservation {
+        addr,
+        size: 32,
+        value: 0,
+    });
+
+    let writer = thread::spawn(move || {
+        thread::sleep(std::time::Duration::from_millis(1));
+        other.bus.shared_dram().store(addr, 32, 1).unwrap();
+    });
+    // wrs.nto returns once the reserved word changes
+    cpu.execute(0x00d00073).unwrap();
+    writer.join().unwrap();
+    assert_eq!(cpu.reservation, None);
+}
+
+#[test]
+fn test_pause() {
+    require_toolchain!("test_pause");
+    let code = "li a0, 1
+.insn i 0x0f, 0, x0, x0, 0x010
+.insn i 0x0f, 0, x0, x0, 0
+fence iorw, iorw
+li a1, 2
+";
+    let binary = rv_asm_binary(code, "test_pause").unwrap();
+    let out = SharedBuf::default();
+    let cpu = CpuBuilder::new(binary, vec![0])
+        .event_log(EventLog::new(Box::new(out.clone())))
+        .build();
+    let (cpu, reason) = run(cpu, -1).unwrap();
+    assert!(matches!(reason, ExitReason::Clean));
+    assert_eq!(cpu.reg("a1"), 2);
+
+    // pause is fence w, 0, fence 0, 0 does nothing, only pause is logged
+    let log = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+    assert_eq!(log.lines().count(), 1);
+    let record: serde_json::Value = serde_json::from_str(&log).unwrap();
+    assert_eq!(record["event"], "hint");
+    assert_eq!(record["hint"], "pause");
+    assert_eq!(record["pc"], format!("{:#x}", DRAM_BASE + 4));
+}
+
+#[test]
+fn test_ntl_hints() {
+    require_toolchain!("test_ntl_hints");
+    // ntl.p1, ntl.pall, ntl.s1, ntl.all, then add x0 with other registers isn't a hint
+    let code = "li a0, 1
+add x0, x0, x2
+add x0, x0, x3
+add x0, x0, x4
+add x0, x0, x5
+add x0, x0, x6
+add x0, a0, x2
+li a1, 2
+";
+    let binary = rv_asm_binary(code, "test_ntl_hints").unwrap();
+    let out = SharedBuf::default();
+    let cpu = CpuBuilder::new(binary, vec![0])
+        .event_log(EventLog::new(Box::new(out.clone())))
+        .build();
+    let (cpu, reason) = run(cpu, -1).unwrap();
+    assert!(matches!(reason, ExitReason::Clean));
+    assert_eq!(cpu.reg("a1"), 2);
+    assert_eq!(cpu.reg("x0"), 0);
+
+    let log = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+    let hints: Vec<String> = log
+        .lines()
+        .map(|line| {
+            let record: serde_json::Value = serde_json::from_str(line).unwrap();
+            assert_eq!(record["event"], "hint");
+            record["hint"].as_str().unwrap().to_string()
+        })
+        .collect();
+    assert_eq!(hints, ["ntl.p1", "ntl.pall", "ntl.s1", "ntl.all"]);
+}
+
+#[test]
+fn test_sbi_set_timer_stip() {
+    require_toolchain!("test_sbi_set_timer_stip");
+    // S-mode kernel: arm the timer 20 ticks ahead and spin until it fires
+    let code = "la t0, handler
+csrw stvec, t0
+csrsi sstatus, 2
+rdtime a0
+addi a0, a0, 20
+li a7, 0x54494d45
+li a6, 0
+ecall
+spin:
+addi s0, s0, 1
+j spin
+handler:
+csrr s1, scause
+li a0, -1
+ecall
+";
+    let binary = rv_asm_binary(code, "test_sbi_set_timer_stip").unwrap();
+    let mut cpu = CpuBuilder::new(binary, vec![0])
+        .with_sbi()
+        .deterministic(DeterministicMode::default())
+        .build();
+    cpu.mode = 0b01;
+    let (cpu, reason) = run(cpu, 200).unwrap();
+    assert!(matches!(reason, ExitReason::Clean));
+
+    // STIP was delegated, trapped to stvec while spinning
+    assert_eq!(cpu.reg("s1"), (1 << 63) | 5);
+    assert!(cpu.reg("s0") > 0);
+    assert_eq!(cpu.mode, 0b01);
+    // the second set_timer pushed the next event out and cleared STIP
+    assert_eq!(cpu.csr.load(STIMECMP), u64::MAX);
+    assert_eq!(cpu.csr.load(MIP) & MASK_STIP, 0);
+    assert_ne!(cpu.csr.load(MIE) & MASK_STIE, 0);
+}
+
+#[test]
+fn test_opensbi_stub() {
+    require_toolchain!("test_opensbi_stub");
+    // kernel: base calls, putchar, a timer taken through the stub, then system_reset
+    let code = "mv s2, a0
+li a7, 0x10
+li a6, 0
+ecall
+mv s3, a1
+li a7, 0x10
+li a6, 3
+li a0, 0x48534d
+ecall
+mv s4, a1
+li a7, 0x48534d
+li a6, 2
+li a0, 1
+ecall
+mv s5, a1
+li a7, 1
+li a0, 0x2e
+ecall
+mv s6, a0
+la t0, handler
+csrw stvec, t0
+li t0, 0x20
+csrs sie, t0
+csrsi sstatus, 2
+rdtime a0
+addi a0, a0, 100
+li a7, 0x54494d45
+li a6, 0
+ecall
+spin:
+addi s0, s0, 1
+j spin
+handler:
+csrr s1, scause
+li a7, 0x53525354
+li a6, 0
+li a0, 0
+li a1, 0
+ecall
+li s7, 1
+";
+    let binary = rv_asm_binary(code, "test_opensbi_stub").unwrap();
+    let mut config = MachineConfig::default();
+    config.uart_stdin = false;
+    let cpu = CpuBuilder::new(binary, vec![0])
+        .config(config)
+        .deterministic(DeterministicMode::default())
+        .with_opensbi()
+        .build();
+    assert_eq!(cpu.pc, DRAM_BASE);
+    let (cpu, reason) = run(cpu, 2000).unwrap();
+    assert!(matches!(reason, ExitReason::Clean));
+
+    // entered in S-mode as hart 0, hart 1 never started
+    assert_eq!(cpu.reg("s2"), 0);
+    assert_eq!(cpu.reg("s3"), 1 << 24);
+    assert_eq!(cpu.reg("s4"), 1);
+    assert_eq!(cpu.reg("s5"), 1);
+    assert_eq!(cpu.reg("s6"), 0);
+    // the machine timer came back to the kernel as a supervisor timer interrupt
+    assert_eq!(cpu.reg("s1"), (1 << 63) | 5);
+    assert!(cpu.reg("s0") > 0);
+    // system_reset doesn't return
+    assert_eq!(cpu.reg("s7"), 0);
+    assert_eq!(cpu.mode, 0b11);
+}
+
+#[test]
+fn test_mip_meip_read_only() {
+    let code = "li t0, 0x800
+csrw mip, t0
+csrr a0, mip
+csrs mip, t0
+csrr a1, mip
+";
+    riscv_asm_test!(code, "test_mip_meip_read_only", 10, "a0" => 0, "a1" => 0);
+}
+
+#[test]
+fn test_set_external_interrupt() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).build();
+    cpu.csr.set_external_interrupt(true);
+    // software writes leave MEIP to the PLIC
+    cpu.csr.store(MIP, MASK_SSIP);
+    assert_eq!(cpu.csr.load(MIP), MASK_MEIP | MASK_SSIP);
+    cpu.csr.store(MIP, 0);
+    assert_eq!(cpu.csr.load(MIP), MASK_MEIP);
+    cpu.csr.set_external_interrupt(false);
+    assert_eq!(cpu.csr.load(MIP), 0);
+}
+
+#[test]
+fn test_vadd_i32_arrays() {
+    require_toolchain!("test_vadd_i32_arrays");
+    // a0, a1: source arrays, a3: destination, 8 elements at e32, m2
+    let code = "li a0, 0x80001000
+li a1, 0x80001020
+li a3, 0x80001040
+li a2, 8
+.insn i 0x57, 7, t0, a2, 0x011
+.insn i 0x07, 6, x2, a0, 0x020
+.insn i 0x07, 6, x4, a1, 0x020
+.insn r 0x57, 0, 1, x6, x4, x2
+.insn i 0x27, 6, x6, a3, 0x020
+csrr t1, 0xc20
+csrr t2, 0xc22
+";
+    let binary = rv_asm_binary(code, "test_vadd_i32_arrays").unwrap();
+    let mut cpu = CpuBuilder::new(binary, vec![0]).build();
+    let a: [i32; 8] = [1, 2, 3, 4, 5, 6, 7, i32::MAX];
+    let b: [i32; 8] = [10, 20, 30, 40, -50, -60, -70, 1];
+    for i in 0..8 {
+        cpu.store(DRAM_BASE + 0x1000 + i * 4, 32, a[i as usize] as u32 as u64)
+            .unwrap();
+        cpu.store(DRAM_BASE + 0x1020 + i * 4, 32, b[i as usize] as u32 as u64)
+            .unwrap();
+    }
+    let (mut cpu, reason) = run(cpu, -1).unwrap();
+    assert!(matches!(reason, ExitReason::Clean));
+    assert_eq!(cpu.reg("t0"), 8);
+    assert_eq!(cpu.reg("t1"), 8);
+    assert_eq!(cpu.reg("t2"), 16);
+    for i in 0..8 {
+        let sum = cpu.load(DRAM_BASE + 0x1040 + i * 4, 32).unwrap() as u32 as i32;
+        assert_eq!(sum, a[i as usize].wrapping_add(b[i as usize]));
+    }
+}
+
+#[test]
+fn test_vsetvli_vlmax() {
+    // e8, m1 with rs1 = x0 asks for VLMAX = 16
+    riscv_asm_test!(
+        ".insn i 0x57, 7, a0, x0, 0x000
+li a1, 100
+.insn i 0x57, 7, a2, a1, 0x018
+.insn i 0x57, 7, a3, a1, 0x020",
+        "test_vsetvli_vlmax",
+        5,
+        "a0" => 16,
+        // e64, m1 holds 2, e8 with reserved vsew = 4 sets vill
+        "a2" => 2,
+        "a3" => 0
+    );
+}
+
+#[test]
+fn test_vadd_vx_masked() {
+    require_toolchain!("test_vadd_vx_masked");
+    // vsetivli 4, e8, m1 (imm 0xc00), load 4 bytes into v2, v0 = 0b0101, v6 = v2 + 100 where set
+    let code = "li a0, 0x80001000
+li a3, 0x80001010
+li a5, 100
+.insn i 0x57, 7, t0, x4, -1024
+.insn i 0x07, 0, x2, a0, 0x020
+.insn i 0x07, 0, x6, a0, 0x020
+li t1, 5
+li a4, 0x80001020
+sb t1, 0(a4)
+.insn i 0x07, 0, x0, a4, 0x020
+.insn r 0x57, 4, 0, x6, a5, x2
+.insn i 0x27, 0, x6, a3, 0x020
+";
+    let binary = rv_asm_binary(code, "test_vadd_vx_masked").unwrap();
+    let mut cpu = CpuBuilder::new(binary, vec![0]).build();
+    cpu.store(DRAM_BASE + 0x1000, 32, 0x04030201).unwrap();
+    let (mut cpu, reason) = run(cpu, -1).unwrap();
+    assert!(matches!(reason, ExitReason::Clean));
+    assert_eq!(cpu.reg("t0"), 4);
+    // elements 1 and 3 are masked off and keep the loaded value
+    assert_eq!(cpu.load(DRAM_BASE + 0x1010, 32).unwrap(), 0x04670265);
+}
+
+#[test]
+fn test_zvkn_aes128_block() {
+    require_toolchain!("test_zvkn_aes128_block");
+    // FIPS-197 appendix C.1: key in v1, block in v2, round keys made on the fly
+    let mut code = String::from(
+        "li a0, 0x80001000
+li a1, 0x80001010
+li a2, 0x80001020
+li t1, 4
+.insn i 0x57, 7, t0, t1, 0x010
+.insn i 0x07, 6, x1, a0, 0x020
+.insn i 0x07, 6, x2, a1, 0x020
+.insn r 0x77, 2, 0x53, x2, x7, x1
+",
+    );
+    for round in 1..=10 {
+        // vaeskf1.vi v1, v1, round then vaesem.vv / vaesef.vv v2, v1
+        code += &format!(".insn r 0x77, 2, 0x45, x1, x{round}, x1\n");
+        let vs1 = if round == 10 { 3 } else { 2 };
+        code += &format!(".insn r 0x77, 2, 0x51, x2, x{vs1}, x1\n");
+    }
+    code += ".insn i 0x27, 6, x2, a2, 0x020\n";
+    let binary = rv_asm_binary(&code, "test_zvkn_aes128_block").unwrap();
+    let mut cpu = CpuBuilder::new(binary, vec![0]).build();
+    for i in 0..16 {
+        cpu.store(DRAM_BASE + 0x1000 + i, 8, i).unwrap();
+        cpu.store(DRAM_BASE + 0x1010 + i, 8, i * 0x11).unwrap();
+    }
+    let (mut cpu, reason) = run(cpu, -1).unwrap();
+    assert!(matches!(reason, ExitReason::Clean));
+    let expected = [
+        0x69, 0xc4, 0xe0, 0xd8, 0x6a, 0x7b, 0x04, 0x30, 0xd8, 0xcd, 0xb7, 0x80, 0x70, 0xb4, 0xc5,
+        0x5a,
+    ];
+    for (i, byte) in expected.into_iter().enumerate() {
+        assert_eq!(cpu.load(DRAM_BASE + 0x1020 + i as u64, 8).unwrap(), byte);
+    }
+}
+
+#[test]
+fn test_zvkn_sha256_compress() {
+    require_toolchain!("test_zvkn_sha256_compress");
+    const K: [u32; 64] = [
+        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4,
+        0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe,
+        0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f,
+        0x4a7484aa, 0x5cb0a9dc, 0x76f988da, 0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7,
+        0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc,
+        0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+        0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070, 0x19a4c116,
+        0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+        0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7,
+        0xc67178f2,
+    ];
+    const H0: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+        0x5be0cd19,
+    ];
+    // W at 0x2000, K at 0x2100, a vsha2ms operand is put together at 0x2200,
+    // the state goes in and out at 0x2300 as {f, e, b, a} and {h, g, d, c}
+    let addr = |offset: u64| 0x8000_0000_u64 + offset;
+    let mut code = format!(
+        "li t1, 4
+.insn i 0x57, 7, t0, t1, 0x010
+li a0, {}
+.insn i 0x07, 6, x1, a0, 0x020
+li a0, {}
+.insn i 0x07, 6, x2, a0, 0x020
+.insn r 0x57, 0, 1, x3, x1, x0
+.insn r 0x57, 0, 1, x4, x2, x0
+",
+        addr(0x2300),
+        addr(0x2310)
+    );
+    for quad in 0..16 {
+        let w = |i: u64| addr(0x2000 + i * 4);
+        let i = quad * 4;
+        if quad >= 4 {
+            // vsha2ms.vv with vd = W[i-16..], vs2 = {W[i-12], W[i-7..i-5]}, vs1 = W[i-4..]
+            code += &format!(
+                "li a0, {}
+.insn i 0x07, 6, x5, a0, 0x020
+li a0, {}
+.insn i 0x07, 6, x6, a0, 0x020
+li a0, {}
+.insn i 0x27, 6, x6, a0, 0x020
+li a1, {}
+lw t2, 0(a1)
+sw t2, 0(a0)
+.insn i 0x07, 6, x6, a0, 0x020
+li a0, {}
+.insn i 0x07, 6, x7, a0, 0x020
+.insn r 0x77, 2, 0x5b, x5, x7, x6
+li a0, {}
+.insn i 0x27, 6, x5, a0, 0x020
+",
+                w(i - 16),
+                w(i - 8),
+                addr(0x2200),
+                w(i - 12),
+                w(i - 4),
+                w(i)
+            );
+        }
+        // W + K, vsha2cl.vv then vsha2ch.vv swap the roles of v1 and v2 back
+        code += &format!(
+            "li a0, {}
+.insn i 0x07, 6, x5, a0, 0x020
+li a0, {}
+.insn i 0x07, 6, x8, a0, 0x020
+.insn r 0x57, 0, 1, x9, x8, x5
+.insn r 0x77, 2, 0x5f, x2, x9, x1
+.insn r 0x77, 2, 0x5d, x1, x9, x2
+",
+            w(i),
+            addr(0x2100 + i * 4)
+        );
+    }
+    code += &format!(
+        ".insn r 0x57, 0, 1, x1, x1, x3
+.insn r 0x57, 0, 1, x2, x2, x4
+li a0, {}
+.insn i 0x27, 6, x1, a0, 0x020
+li a0, {}
+.insn i 0x27, 6, x2, a0, 0x020
+",
+        addr(0x2300),
+        addr(0x2310)
+    );
+    let binary = rv_asm_binary(&code, "test_zvkn_sha256_compress").unwrap();
+    let mut cpu = CpuBuilder::new(binary, vec![0]).build();
+
+    // "abc" padded to one block, words are big-endian
+    let mut block = [0u8; 64];
+    block[..4].copy_from_slice(b"abc\x80");
+    block[63] = 24;
+    for (i, word) in block.chunks(4).enumerate() {
+        let word = u32::from_be_bytes(word.try_into().unwrap());
+        cpu.store(DRAM_BASE + 0x2000 + i as u64 * 4, 32, word as u64)
+            .unwrap();
+    }
+    for (i, k) in K.into_iter().enumerate() {
+        cpu.store(DRAM_BASE + 0x2100 + i as u64 * 4, 32, k as u64)
+            .unwrap();
+    }
+    let state = [H0[5], H0[4], H0[1], H0[0], H0[7], H0[6], H0[3], H0[2]];
+    for (i, h) in state.into_iter().enumerate() {
+        cpu.store(DRAM_BASE + 0x2300 + i as u64 * 4, 32, h as u64)
+            .unwrap();
+    }
+    let (mut cpu, reason) = run(cpu, -1).unwrap();
+    assert!(matches!(reason, ExitReason::Clean));
+    let mut digest = [0u32; 8];
+    for (i, h) in [5, 4, 1, 0, 7, 6, 3, 2].into_iter().enumerate() {
+        digest[h] = cpu.load(DRAM_BASE + 0x2300 + i as u64 * 4, 32).unwrap() as u32;
+    }
+    assert_eq!(
+        digest,
+        [
+            0xba7816bf, 0x8f01cfea, 0x414140de, 0x5dae2223, 0xb00361a3, 0x96177a9c, 0xb410ff61,
+            0xf20015ad
+        ]
+    );
+}
+
+#[test]
+fn test_hypervisor_csrs() {
+    // csrw hgatp, a1 / csrr a0, hgatp / csrr a0, hgeip
+    let write_hgatp = 0x68059073;
+    let read_hgatp = 0x68002573;
+    let read_hgeip = 0xe1202573;
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).build();
+    assert_ne!(cpu.csr.load(MISA) & (1 << 7), 0);
+
+    // HS-mode reaches them like supervisor CSRs
+    cpu.mode = 0b01;
+    cpu.regs[11] = (8 << 60) | 0x1234;
+    assert!(cpu.execute(write_hgatp).is_ok());
+    assert!(cpu.execute(read_hgatp).is_ok());
+    assert_eq!(cpu.reg("a0"), (8 << 60) | 0x1234);
+    assert!(cpu.execute(read_hgeip).is_ok());
+    // Sv48x4 isn't supported, hgatp keeps its value
+    cpu.regs[11] = 9 << 60;
+    assert!(cpu.execute(write_hgatp).is_ok());
+    assert_eq!(cpu.csr.load(HGATP), (8 << 60) | 0x1234);
+
+    let illegal = |r: Result<u64, Exception>| matches!(r, Err(Exception::IllegalInstruction(_)));
+    cpu.mode = 0b00;
+    assert!(illegal(cpu.execute(read_hgatp)));
+
+    let mut config = MachineConfig::default();
+    config.enabled_extensions.h = false;
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).config(config).build();
+    assert_eq!(cpu.csr.load(MISA) & (1 << 7), 0);
+    cpu.mode = 0b01;
+    assert!(illegal(cpu.execute(read_hgatp)));
+}
+
+#[test]
+fn test_mem_stats_sort() {
+    require_toolchain!("test_mem_stats_sort");
+    // bubble sort 8 words on the stack, then print one byte to the uart
+    let code = "li sp, 0x80002000
+li t0, 8
+mv t1, sp
+fill:
+sw t0, 0(t1)
+addi t1, t1, 4
+addi t0, t0, -1
+bnez t0, fill
+li t2, 7
+outer:
+mv t1, sp
+mv t3, t2
+inner:
+lw a0, 0(t1)
+lw a1, 4(t1)
+ble a0, a1, next
+sw a1, 0(t1)
+sw a0, 4(t1)
+next:
+addi t1, t1, 4
+addi t3, t3, -1
+bnez t3, inner
+addi t2, t2, -1
+bnez t2, outer
+lw a0, 0(sp)
+lw a1, 28(sp)
+li t0, 0x10000000
+li t1, 0x21
+sb t1, 0(t0)
+";
+    let binary = rv_asm_binary(code, "test_mem_stats_sort").unwrap();
+    let cpu = CpuBuilder::new(binary, vec![0]).build();
+    let (mut cpu, reason) = run(cpu, -1).unwrap();
+    assert!(matches!(reason, ExitReason::Clean));
+    assert_eq!((cpu.reg("a0"), cpu.reg("a1")), (1, 8));
+
+    let stats = cpu.get_mem_stats().clone();
+    // fetches are dram reads too
+    assert!(stats.dram_reads > stats.dram_writes);
+    assert!(stats.dram_writes >= 8);
+    assert_eq!(stats.uart_writes, 1);
+    assert_eq!(stats.tlb_hits + stats.tlb_misses, 0);
+    assert!(stats.to_string().contains("dram writes"));
+
+    cpu.reset_mem_stats();
+    assert_eq!(*cpu.get_mem_stats(), MemStats::default());
+}
+
+#[test]
+fn test_debug_module_access_register() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).build();
+    let dm = |offset: u64| DM_BASE + offset * 4;
+    // access register, transfer, aarsize 3
+    let access = |regno: u64, write: bool| (3 << 20) | (1 << 17) | ((write as u64) << 16) | regno;
+
+    // write a0 = x10 from data0/data1
+    cpu.store_phys(dm(DM_DATA0), 32, 0x5678).unwrap();
+    cpu.store_phys(dm(DM_DATA0 + 1), 32, 0x1234).unwrap();
+    cpu.store_phys(dm(DM_COMMAND), 32, access(0x100a, true))
+        .unwrap();
+    assert_eq!(cpu.reg("a0"), 0x1234_0000_5678);
+
+    // read mscratch back through data0/data1
+    cpu.csr.store(MSCRATCH, 0xdead_beef_0000_0001);
+    cpu.store_phys(dm(DM_COMMAND), 32, access(MSCRATCH as u64, false))
+        .unwrap();
+    assert_eq!(cpu.load_phys(dm(DM_DATA0), 32).unwrap(), 1);
+    assert_eq!(cpu.load_phys(dm(DM_DATA0 + 1), 32).unwrap(), 0xdead_beef);
+
+    // x0 writes are dropped, postincrement moves regno on to x1
+    cpu.store_phys(dm(DM_COMMAND), 32, access(0x1000, true) | (1 << 19))
+        .unwrap();
+    assert_eq!(cpu.reg("zero"), 0);
+    assert_eq!(cpu.load_phys(dm(DM_COMMAND), 32).unwrap() & 0xffff, 0x1001);
+
+    // a quick access command isn't supported, cmderr blocks commands until cleared
+    cpu.store_phys(dm(DM_COMMAND), 32, 1 << 24).unwrap();
+    let cmderr = |cpu: &mut Cpu| (cpu.load_phys(dm(DM_ABSTRACTCS), 32).unwrap() >> 8) & 0b111;
+    assert_eq!(cmderr(&mut cpu), 2);
+    cpu.store_phys(dm(DM_COMMAND), 32, access(0x100a, false))
+        .unwrap();
+    assert_eq!(cpu.load_phys(dm(DM_DATA0), 32).unwrap(), 1);
+    cpu.store_phys(dm(DM_ABSTRACTCS), 32, 0b111 << 8).unwrap();
+    assert_eq!(cmderr(&mut cpu), 0);
+}
+
+#[test]
+fn test_dram_end_boundary() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).build();
+    // DRAM_END is the last byte of dram
+    cpu.bus
+        .store(DRAM_END - 7, 64, 0x1122_3344_5566_7788)
+        .unwrap();
+    assert_eq!(
+        cpu.bus.load(DRAM_END - 7, 64).unwrap(),
+        0x1122_3344_5566_7788
+    );
+    assert_eq!(cpu.bus.load(DRAM_END, 8).unwrap(), 0x11);
+
+    // accesses running past the end fault instead of wrapping or truncating
+    assert!(matches!(
+        cpu.bus.load(DRAM_END - 3, 64),
+        Err(Exception::LoadAccessFault(a)) if a == DRAM_END - 3
+    ));
+    assert!(matches!(
+        cpu.bus.load(DRAM_END, 16),
+        Err(Exception::LoadAccessFault(_))
+    ));
+    assert!(matches!(
+        cpu.bus.store(DRAM_END - 3, 64, 0),
+        Err(Exception::StoreAMOAccessFault(_))
+    ));
+    assert!(matches!(
+        cpu.bus.load(DRAM_END + 1, 8),
+        Err(Exception::LoadAccessFault(_))
+    ));
+}
+
+// queue one block request (header, the (addr, len) data buffers, status)
+// and let the device take it
+fn virtio_blk_request(cpu: &mut Cpu, iotype: u32, sector: u64, data: &[(u64, u64)]) -> u64 {
+    let queue = DRAM_BASE + 0x10000;
+    let header = DRAM_BASE + 0x20000;
+    let status = DRAM_BASE + 0x22000;
+    cpu.bus.store(VIRTIO_STATUS, 32, 0b1011).unwrap();
+    cpu.bus
+        .store(VIRTIO_GUEST_PAGE_SIZE, 32, PAGE_SIZE)
+        .unwrap();
+    cpu.bus
+        .store(VIRTIO_QUEUE_PFN, 32, queue / PAGE_SIZE)
+        .unwrap();
+    cpu.bus.store(VIRTIO_STATUS, 32, 0b1111).unwrap();
+
+    cpu.bus.store(header, 32, iotype as u64).unwrap();
+    cpu.bus.store(header + 8, 64, sector).unwrap();
+    cpu.bus.store(status, 8, 0xff).unwrap();
+    let data_flags = match iotype {
+        VIRTIO_BLK_T_IN => VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE,
+        _ => VIRTQ_DESC_F_NEXT,
+    };
+    let mut chain = vec![(header, 16, VIRTQ_DESC_F_NEXT)];
+    chain.extend(data.iter().map(|&(addr, len)| (addr, len, data_flags)));
+    chain.push((status, 1, VIRTQ_DESC_F_WRITE));
+    for (i, &(addr, len, flags)) in chain.iter().enumerate() {
+        let desc = queue + 16 * i as u64;
+        cpu.bus.store(desc, 64, addr).unwrap();
+        cpu.bus.store(desc + 8, 32, len).unwrap();
+        cpu.bus.store(desc + 12, 16, flags as u64).unwrap();
+        cpu.bus.store(desc + 14, 16, i as u64 + 1).unwrap();
+    }
+    // avail.ring[idx] = 0, then bump avail.idx
+    let avail = queue + 8 * 16;
+    let idx = cpu.bus.load(avail + 2, 16).unwrap();
+    cpu.bus.store(avail + 4 + (idx % 8) * 2, 16, 0).unwrap();
+    cpu.bus.store(avail + 2, 16, idx + 1).unwrap();
+    cpu.bus.store(VIRTIO_QUEUE_NOTIFY, 32, 0).unwrap();
+
+    cpu.csr.store(MSTATUS, MASK_MIE);
+    cpu.check_pending_interrupt();
+    cpu.bus.load(status, 8).unwrap()
+}
+
+#[test]
+fn test_virtio_blk_file_backend() {
+    let path = std::env::temp_dir().join("rustv_test_virtio_blk_file.img");
+    let mut disk = vec![0u8; 4 * SECTOR_SIZE as usize];
+    for (i, b) in disk.iter_mut().enumerate() {
+        *b = (i / SECTOR_SIZE as usize) as u8 + 1;
+    }
+    std::fs::write(&path, &disk).unwrap();
+
+    // a uart interrupt from host stdin would be taken ahead of the disk
+    let mut config = MachineConfig::default();
+    config.uart_stdin = false;
+    let mut cpu = CpuBuilder::new(vec![0], vec![])
+        .config(config)
+        .disk_image_file(&path)
+        .unwrap()
+        .build();
+    assert_eq!(cpu.bus.load(VIRTIO_CONFIG, 32).unwrap(), 4);
+
+    let buffer = DRAM_BASE + 0x21000;
+    let status = virtio_blk_request(&mut cpu, VIRTIO_BLK_T_IN, 2, &[(buffer, SECTOR_SIZE)]);
+    assert_eq!(status, VIRTIO_BLK_S_OK as u64);
+    assert_eq!(cpu.bus.load(buffer, 8).unwrap(), 3);
+    assert_eq!(cpu.bus.load(buffer + 511, 8).unwrap(), 3);
+
+    // write sector 1, flush, and the file has it
+    for i in 0..SECTOR_SIZE {
+        cpu.bus.store(buffer + i, 8, 0xab).unwrap();
+    }
+    let status = virtio_blk_request(&mut cpu, VIRTIO_BLK_T_OUT, 1, &[(buffer, SECTOR_SIZE)]);
+    assert_eq!(status, VIRTIO_BLK_S_OK as u64);
+    // a flush is just the header and the status, as Linux sends it
+    let status = virtio_blk_request(&mut cpu, VIRTIO_BLK_T_FLUSH, 0, &[]);
+    assert_eq!(status, VIRTIO_BLK_S_OK as u64);
+    let file = std::fs::read(&path).unwrap();
+    assert!(file[512..1024].iter().all(|&b| b == 0xab));
+    assert_eq!(file[1024], 3);
+
+    // and reads see the write
+    let status = virtio_blk_request(&mut cpu, VIRTIO_BLK_T_IN, 1, &[(buffer + 0x800, 8)]);
+    assert_eq!(status, VIRTIO_BLK_S_OK as u64);
+    assert_eq!(
+        cpu.bus.load(buffer + 0x800, 64).unwrap(),
+        0xabab_abab_abab_abab
+    );
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn test_ecall_from_m_mode() {
+    // ecall
+    let mut cpu = CpuBuilder::new(vec![0x73, 0, 0, 0], vec![0]).build();
+    let handler = DRAM_BASE + 0x100;
+    cpu.csr.store(MTVEC, handler);
+    cpu.csr.store(STVEC, DRAM_BASE + 0x200);
+    // even with everything delegated, an M-mode ecall stays in M-mode
+    cpu.csr.store(MEDELEG, u64::MAX);
+    assert_eq!(cpu.csr.load(MEDELEG) & (1 << 11), 0);
+    assert!(!cpu.csr.is_medelegated(11));
+
+    assert!(matches!(cpu.step(), StepResult::Ok));
+    assert_eq!(cpu.pc, handler);
+    assert_eq!(cpu.mode, 0b11);
+    assert_eq!(cpu.csr.load(MEPC), DRAM_BASE);
+    assert_eq!(cpu.csr.load(MCAUSE), 11);
+    assert_eq!(cpu.csr.load(MTVAL), 0);
+}
+
+#[test]
+fn test_zfinx_fadd_s() {
+    require_toolchain!("test_zfinx_fadd_s");
+    // fadd.s a2, a0, a1 on integer registers, then the bits go through memory
+    let code = "li a0, 0x3fc00000
+li a1, 0x40100000
+.insn r 0x53, 0, 0x00, a2, a0, a1
+li t0, 0x80001000
+sw a2, 0(t0)
+lw a3, 0(t0)
+csrr a4, fflags
+li a0, 0x3f800000
+li a1, 0x40400000
+.insn r 0x53, 0, 0x0c, a5, a0, a1
+csrr a6, fflags
+li a0, 0xbf800000
+.insn r 0x53, 0, 0x2c, a7, a0, x0
+csrr s2, fflags
+";
+    let binary = rv_asm_binary(code, "test_zfinx_fadd_s").unwrap();
+    let cpu = CpuBuilder::new(binary.clone(), vec![0])
+        .enable_zfinx()
+        .build();
+    let (cpu, reason) = run(cpu, -1).unwrap();
+    assert!(matches!(reason, ExitReason::Clean));
+    // 1.5 + 2.25 = 3.75, exact
+    assert_eq!(cpu.reg("a2"), 0x4070_0000);
+    assert_eq!(cpu.reg("a3"), 0x4070_0000);
+    assert_eq!(cpu.reg("a4"), 0);
+    // 1 / 3 is inexact
+    assert_eq!(cpu.reg("a5"), 0x3eaa_aaab);
+    assert_eq!(cpu.reg("a6"), FFLAG_NX);
+    // sqrt(-1) is the canonical NaN, fflags accrue
+    assert_eq!(cpu.reg("a7"), CANONICAL_NAN_S as u64);
+    assert_eq!(cpu.reg("s2"), FFLAG_NX | FFLAG_NV);
+
+    // off by default
+    let (_, reason) = run(CpuBuilder::new(binary, vec![0]).build(), -1).unwrap();
+    assert!(matches!(
+        reason,
+        ExitReason::FatalException(Exception::IllegalInstruction(_))
+    ));
+}
+
+#[test]
+fn test_single_binop_flags() {
+    let (r, flags) = single_binop(HalfOp::Add, 1.0, 1e-30);
+    assert_eq!((f32::from_bits(r), flags), (1.0, FFLAG_NX));
+    let (r, flags) = single_binop(HalfOp::Sub, 1.5, 0.25);
+    assert_eq!((f32::from_bits(r), flags), (1.25, 0));
+    let (r, flags) = single_binop(HalfOp::Mul, f32::MAX, 2.0);
+    assert_eq!(
+        (f32::from_bits(r), flags),
+        (f32::INFINITY, FFLAG_NX | FFLAG_OF)
+    );
+    let (r, flags) = single_binop(HalfOp::Div, 1.0, 0.0);
+    assert_eq!((f32::from_bits(r), flags), (f32::INFINITY, FFLAG_DZ));
+    let (r, flags) = single_binop(HalfOp::Mul, f32::MIN_POSITIVE, 0.5);
+    assert_eq!((f32::from_bits(r), flags), (f32::MIN_POSITIVE / 2.0, 0));
+    let (_, flags) = single_binop(HalfOp::Mul, f32::from_bits(1), 0.5);
+    assert_eq!(flags, FFLAG_NX | FFLAG_UF);
+}
+
+#[test]
+fn test_reference_trace() {
+    // addi a0, x0, 5; addi a1, a0, 3; add a2, a0, a1
+    let code = [0x00500513u32, 0x00350593, 0x00b50633]
+        .iter()
+        .flat_map(|inst| inst.to_le_bytes())
+        .collect::<Vec<u8>>();
+    let path = std::env::temp_dir().join("rustv_test_reference.trace");
+
+    let mut cpu = CpuBuilder::new(code.clone(), vec![0]).build();
+    let mut trace = Vec::new();
+    assert_eq!(record_trace(&mut cpu, &mut trace, 100).unwrap(), 3);
+    assert_eq!(trace.len(), 3 * TRACE_RECORD_SIZE);
+    let last = TraceRecord::from_bytes(trace[2 * TRACE_RECORD_SIZE..].try_into().unwrap());
+    assert_eq!(last.pc, DRAM_BASE + 8);
+    assert_eq!(last.inst, 0x00b50633);
+    assert_eq!(last.regs[12], 13);
+    std::fs::write(&path, &trace).unwrap();
+
+    // the same program follows its own trace
+    let mut cpu = CpuBuilder::new(code.clone(), vec![0])
+        .reference_trace(&path)
+        .unwrap()
+        .build();
+    assert!(matches!(cpu.run_for(100), ExitReason::Clean));
+
+    // a trace that expects a2 = 14 stops it at the add
+    trace[2 * TRACE_RECORD_SIZE + 16 + 12 * 8] = 14;
+    std::fs::write(&path, &trace).unwrap();
+    let mut cpu = CpuBuilder::new(code, vec![0])
+        .reference_trace(&path)
+        .unwrap()
+        .build();
+    let panic =
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| cpu.run_for(100))).unwrap_err();
+    let _ = std::fs::remove_file(&path);
+    let message = panic.downcast_ref::<String>().unwrap();
+    assert!(message.contains("at instruction 2"));
+    assert!(message.contains("a2: expected 0x000000000000000e actual 0x000000000000000d"));
+    assert!(!message.contains("a1:"));
+}
+
+#[test]
+fn test_protect_region() {
+    // auipc t0, 0; lui t1, 1; add t1, t0, t1; li a0, 42; sd a0, 0(t1); ld a1, 0(t1)
+    let mut code = [
+        0x00000297u32,
+        0x00001337,
+        0x00628333,
+        0x02a00513,
+        0x00a33023,
+        0x00033583,
+    ]
+    .iter()
+    .flat_map(|inst| inst.to_le_bytes())
+    .collect::<Vec<u8>>();
+    let code_perms = RegionPerms {
+        read: true,
+        write: false,
+        execute: true,
+    };
+
+    // the program only writes its data page
+    let mut cpu = CpuBuilder::new(code.clone(), vec![0]).build();
+    cpu.protect_region(DRAM_BASE, 0x1000, code_perms);
+    assert!(matches!(cpu.run_for(100), ExitReason::Clean));
+    assert_eq!(cpu.csr.load(MCAUSE), 0);
+    assert_eq!(cpu.reg("a1"), 42);
+
+    // sd a0, 0(t0) over its own code
+    code.extend_from_slice(&0x00a2b023u32.to_le_bytes());
+    let mut cpu = CpuBuilder::new(code, vec![0]).build();
+    cpu.protect_region(DRAM_BASE, 0x1000, code_perms);
+    assert!(matches!(
+        cpu.run_for(100),
+        ExitReason::FatalException(Exception::StoreAMOAccessFault(a)) if a == DRAM_BASE
+    ));
+    assert_eq!(cpu.csr.load(MEPC), DRAM_BASE + 24);
+    assert_eq!(cpu.bus.load(DRAM_BASE, 32).unwrap(), 0x00000297);
+
+    // no execute permission, no read
+    cpu.protect_region(
+        DRAM_BASE + 0x1000,
+        8,
+        RegionPerms {
+            read: false,
+            write: true,
+            execute: false,
+        },
+    );
+    cpu.pc = DRAM_BASE + 0x1000;
+    assert!(matches!(
+        cpu.fetch(),
+        Err(Exception::InstructionAccessFault(a)) if a == DRAM_BASE + 0x1000
+    ));
+    assert!(cpu.bus.load(DRAM_BASE + 0x1004, 8).is_err());
+    assert!(cpu.bus.store(DRAM_BASE + 0x1004, 8, 0).is_ok());
+    assert!(cpu.bus.load(DRAM_BASE + 0x1008, 64).is_ok());
+}
+
+#[test]
+fn test_rv32_registers() {
+    let code = "li a0, -1
+addi a1, a0, 1
+lui a2, 0x80000
+srai a3, a2, 4
+srli a4, a2, 4
+slli a5, a0, 31
+slt a6, a2, zero
+sltu a7, zero, a2
+sltiu s2, a0, -1
+";
+    riscv_asm_test!(rv32: code, "test_rv32_registers", 20,
+        "a0" => 0xffff_ffff_u64, "a1" => 0, "a2" => 0x8000_0000_u64, "a3" => 0xf800_0000_u64,
+        "a4" => 0x0800_0000, "a5" => 0x8000_0000_u64, "a6" => 1, "a7" => 1, "s2" => 0);
+}
+
+#[test]
+fn test_rv32_muldiv() {
+    let code = "li t0, -2
+li t1, 3
+mul s2, t0, t1
+mulh s3, t0, t1
+mulhu s4, t0, t1
+mulhsu s5, t0, t1
+divu s6, t0, t1
+rem s7, t0, t1
+div s8, t0, zero
+";
+    riscv_asm_test!(rv32: code, "test_rv32_muldiv", 20,
+        "s2" => 0xffff_fffa_u64, "s3" => 0xffff_ffff_u64, "s4" => 2, "s5" => 0xffff_ffff_u64,
+        "s6" => 0x5555_5554, "s7" => 0xffff_fffe_u64, "s8" => 0xffff_ffff_u64);
+}
+
+#[test]
+fn test_rv32_memory_and_satp() {
+    let code = "auipc t0, 0
+li t1, -5
+sw t1, 256(t0)
+lw a0, 256(t0)
+lbu a1, 256(t0)
+lh a2, 256(t0)
+jal ra, 1f
+1:
+csrr a3, misa
+lui t2, 0x80080
+csrw satp, t2
+";
+    let cpu = riscv_asm_test!(rv32: code, "test_rv32_memory_and_satp", 20,
+        "a0" => 0xffff_fffb_u64, "a1" => 0xfb, "a2" => 0xffff_fffb_u64, "ra" => DRAM_BASE + 28);
+    // Sv32 with its root table at DRAM_BASE, misa says MXL = 32
+    assert!(cpu.enable_paging);
+    assert_eq!(cpu.page_table, DRAM_BASE);
+    assert_eq!(cpu.reg("a3") >> 30, 1);
+    assert_eq!(cpu.reg("a3") & (1 << (b'C' - b'A')), 0);
+
+    // ld is RV64 only
+    let ld = 0x0002b503u32; // ld a0, 0(t0)
+    let mut cpu = CpuBuilder::new(ld.to_le_bytes().to_vec(), vec![0])
+        .rv32()
+        .build();
+    assert!(matches!(
+        cpu.run_for(1),
+        ExitReason::FatalException(Exception::IllegalInstruction(_))
+    ));
+}
+
+// Backend that hands every sent frame back as received, the test keeps a handle
+#[derive(Clone, Default)]
+struct LoopbackBackend(Arc<Mutex<VecDeque<Vec<u8>>>>);
+
+impl NetworkBackend for LoopbackBackend {
+    fn send_packet(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.0.lock().unwrap().push_back(data.to_vec());
+        Ok(())
+    }
+
+    fn recv_packet(&mut self, buf: &mut Vec<u8>) -> std::io::Result<bool> {
+        match self.0.lock().unwrap().pop_front() {
+            Some(frame) => {
+                *buf = frame;
+                Ok(true)
+            }
+            None => {
+                buf.clear();
+                Ok(false)
+            }
+        }
+    }
+}
+
+#[test]
+fn test_net_zero_length_frame() {
+    let backend = LoopbackBackend::default();
+    let mut net = VirtioNet::new(Box::new(backend.clone()));
+    net.transmit(&[]).unwrap();
+    assert_eq!(backend.0.lock().unwrap().len(), 1);
+
+    // the empty frame comes back as one, not as nothing waiting
+    let mut buf = vec![0xff; 4];
+    assert!(net.receive(&mut buf).unwrap());
+    assert!(buf.is_empty());
+    buf.push(0xff);
+    assert!(!net.receive(&mut buf).unwrap());
+    assert!(buf.is_empty());
+}
+
+#[test]
+#[ignore = "needs CAP_NET_ADMIN and /dev/net/tun"]
+fn test_tap_zero_length_frame() {
+    let tap = TapBackend::open("rustv-test0").unwrap();
+    let mut net = VirtioNet::new(Box::new(tap));
+    net.transmit(&[]).unwrap();
+    // the interface is down, nothing arrives
+    let mut buf = vec![0xff; 4];
+    assert!(!net.receive(&mut buf).unwrap());
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn test_run_until_symbol() {
+    require_toolchain!("test_run_until_symbol");
+    let code = "li a0, 1
+li a1, 2
+add a2, a0, a1
+middle:
+li a2, 100
+";
+    let binary = rv_asm_binary(code, "test_run_until_symbol").unwrap();
+    let symbols = rv_asm_symbols("test_run_until_symbol").unwrap();
+    let mut cpu = CpuBuilder::new(binary, vec![0]).symbols(symbols).build();
+    // not enough steps to get there
+    assert!(matches!(
+        cpu.run_until_pc(DRAM_BASE + 12, 2),
+        ExitReason::Timeout
+    ));
+    assert!(matches!(
+        cpu.run_until_symbol("middle", 100),
+        ExitReason::PcReached
+    ));
+    assert_eq!(cpu.pc, DRAM_BASE + 12);
+    assert_eq!(cpu.reg("a2"), 3);
+    // already there, nothing runs
+    assert!(matches!(
+        cpu.run_until_pc(DRAM_BASE + 12, 100),
+        ExitReason::PcReached
+    ));
+    assert_eq!(cpu.instret, 3);
+    assert!(matches!(cpu.run_to_halt(), ExitReason::Clean));
+    assert_eq!(cpu.reg("a2"), 100);
+}
@@ -0,0 +1,73 @@
+// Instruction semantics against a Rust model, no riscv toolchain needed.
+use proptest::prelude::*;
+
+use crate::cpu::{builder::CpuBuilder, cpu::Cpu};
+
+// rd = x3, rs1 = x1, rs2 = x2
+fn r_type(opcode: u32, funct3: u32, funct7: u32) -> u64 {
+    ((funct7 << 25) | (2 << 20) | (1 << 15) | (funct3 << 12) | (3 << 7) | opcode) as u64
+}
+
+fn exec(inst: u64, a: u64, b: u64) -> u64 {
+    let mut cpu: Cpu = CpuBuilder::new(vec![0], vec![0]).build();
+    cpu.regs[1] = a;
+    cpu.regs[2] = b;
+    cpu.execute(inst).unwrap();
+    cpu.regs[3]
+}
+
+fn sext32(value: u64) -> u64 {
+    value as i32 as i64 as u64
+}
+
+proptest! {
+    #[test]
+    fn prop_add_sub(a: u64, b: u64) {
+        prop_assert_eq!(exec(r_type(0x33, 0x0, 0x00), a, b), a.wrapping_add(b));
+        prop_assert_eq!(exec(r_type(0x33, 0x0, 0x20), a, b), a.wrapping_sub(b));
+        prop_assert_eq!(exec(r_type(0x3b, 0x0, 0x00), a, b), sext32(a.wrapping_add(b)));
+    }
+
+    #[test]
+    fn prop_shifts(a: u64, b: u64) {
+        prop_assert_eq!(exec(r_type(0x33, 0x1, 0x00), a, b), a << (b & 0x3f));
+        prop_assert_eq!(exec(r_type(0x33, 0x5, 0x00), a, b), a >> (b & 0x3f));
+        prop_assert_eq!(
+            exec(r_type(0x33, 0x5, 0x20), a, b),
+            ((a as i64) >> (b & 0x3f)) as u64
+        );
+    }
+
+    #[test]
+    fn prop_compare_logic(a: u64, b: u64) {
+        prop_assert_eq!(exec(r_type(0x33, 0x2, 0x00), a, b), ((a as i64) < (b as i64)) as u64);
+        prop_assert_eq!(exec(r_type(0x33, 0x3, 0x00), a, b), (a < b) as u64);
+        prop_assert_eq!(exec(r_type(0x33, 0x4, 0x00), a, b), a ^ b);
+        prop_assert_eq!(exec(r_type(0x33, 0x6, 0x00), a, b), a | b);
+        prop_assert_eq!(exec(r_type(0x33, 0x7, 0x00), a, b), a & b);
+    }
+
+    #[test]
+    fn prop_mul(a: u64, b: u64) {
+        prop_assert_eq!(exec(r_type(0x33, 0x0, 0x01), a, b), a.wrapping_mul(b));
+        let high = ((a as i64 as i128 * b as i64 as i128) >> 64) as u64;
+        prop_assert_eq!(exec(r_type(0x33, 0x1, 0x01), a, b), high);
+        let high = ((a as u128 * b as u128) >> 64) as u64;
+        prop_assert_eq!(exec(r_type(0x33, 0x3, 0x01), a, b), high);
+    }
+
+    #[test]
+    fn prop_div_rem(a: u64, b: u64) {
+        // division by zero and overflow results are defined by the spec, no traps
+        let (div, rem) = match (a as i64, b as i64) {
+            (x, 0) => (u64::MAX, x as u64),
+            (i64::MIN, -1) => (i64::MIN as u64, 0),
+            (x, y) => ((x / y) as u64, (x % y) as u64),
+        };
+        prop_assert_eq!(exec(r_type(0x33, 0x4, 0x01), a, b), div);
+        prop_assert_eq!(exec(r_type(0x33, 0x6, 0x01), a, b), rem);
+        let (divu, remu) = if b == 0 { (u64::MAX, a) } else { (a / b, a % b) };
+        prop_assert_eq!(exec(r_type(0x33, 0x5, 0x01), a, b), divu);
+        prop_assert_eq!(exec(r_type(0x33, 0x7, 0x01), a, b), remu);
+    }
+}
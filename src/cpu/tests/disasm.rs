@@ -0,0 +1,320 @@
+use crate::{
+    cpu::{
+        builder::CpuBuilder,
+        disasm::{
+            decode, decode_c_q0, decode_c_q1, decode_c_q2, format, x_registers, AmoWidth,
+            BranchCond, DecodedInst, LoadWidth, StoreWidth,
+        },
+    },
+    dram::AmoOp,
+    exept::Exception,
+};
+
+fn dis(inst: u32) -> String {
+    format(&decode(inst).unwrap_or_else(|e| panic!("{:#010x}: {}", inst, e)))
+}
+
+#[test]
+fn test_decode_r_type() {
+    assert_eq!(
+        decode(0x00c58533).unwrap(),
+        DecodedInst::Add {
+            rd: 10,
+            rs1: 11,
+            rs2: 12
+        }
+    );
+    assert_eq!(dis(0x00c58533), "add a0, a1, a2");
+    assert_eq!(dis(0x407302b3), "sub t0, t1, t2");
+    assert_eq!(dis(0x02c5a533), "mulhsu a0, a1, a2");
+    assert_eq!(dis(0x02c5f53b), "remuw a0, a1, a2");
+    assert_eq!(dis(0x20c5c53b), "sh2add.uw a0, a1, a2");
+}
+
+#[test]
+fn test_decode_i_type() {
+    assert_eq!(
+        decode(0xff010513).unwrap(),
+        DecodedInst::Addi {
+            rd: 10,
+            rs1: 2,
+            imm: -16
+        }
+    );
+    assert_eq!(dis(0xff010513), "addi a0, sp, -16");
+    // RV64 shift amounts are 6 bits, the W forms 5
+    assert_eq!(dis(0x02851513), "slli a0, a0, 40");
+    assert_eq!(dis(0x43f65593), "srai a1, a2, 63");
+    assert_eq!(dis(0x41f6559b), "sraiw a1, a2, 31");
+    assert_eq!(
+        decode(0x00813503).unwrap(),
+        DecodedInst::Load {
+            rd: 10,
+            rs1: 2,
+            offset: 8,
+            width: LoadWidth::D
+        }
+    );
+    assert_eq!(dis(0xfff5c283), "lbu t0, -1(a1)");
+    assert_eq!(dis(0x00008067), "jalr zero, 0(ra)");
+}
+
+#[test]
+fn test_decode_s_and_b_type() {
+    assert_eq!(
+        decode(0x00113c23).unwrap(),
+        DecodedInst::Store {
+            rs1: 2,
+            rs2: 1,
+            offset: 24,
+            width: StoreWidth::D
+        }
+    );
+    assert_eq!(dis(0x00113c23), "sd ra, 24(sp)");
+    assert_eq!(
+        decode(0xfeb50ce3).unwrap(),
+        DecodedInst::Branch {
+            cond: BranchCond::Eq,
+            rs1: 10,
+            rs2: 11,
+            offset: -8
+        }
+    );
+    // imm[11] comes from inst[7]
+    assert_eq!(dis(0x0062f0e3), "bgeu t0, t1, 2048");
+}
+
+#[test]
+fn test_decode_u_and_j_type() {
+    assert_eq!(
+        decode(0x12345537).unwrap(),
+        DecodedInst::Lui {
+            rd: 10,
+            imm: 0x12345000
+        }
+    );
+    assert_eq!(dis(0x12345537), "lui a0, 0x12345");
+    assert_eq!(
+        decode(0xfffff297).unwrap(),
+        DecodedInst::Auipc { rd: 5, imm: -4096 }
+    );
+    assert_eq!(dis(0xfffff297), "auipc t0, 0xfffff");
+    assert_eq!(dis(0x001000ef), "jal ra, 2048");
+    // jal zero, -4
+    assert_eq!(
+        decode(0xffdff06f).unwrap(),
+        DecodedInst::Jal { rd: 0, offset: -4 }
+    );
+}
+
+#[test]
+fn test_decode_system() {
+    assert_eq!(dis(0x30059573), "csrrw a0, mstatus, a1");
+    assert_eq!(dis(0x30446073), "csrrsi zero, mie, 8");
+    assert_eq!(dis(0x0310000f), "fence rw, w");
+    assert_eq!(dis(0x0100000f), "pause");
+    assert_eq!(dis(0x0000100f), "fence.i");
+    assert_eq!(dis(0x00000073), "ecall");
+    assert_eq!(dis(0x30200073), "mret");
+    assert_eq!(dis(0x12050073), "sfence.vma a0, zero");
+    assert_eq!(dis(0x16b50073), "sinval.vma a0, a1");
+    assert_eq!(dis(0x18000073), "sfence.w.inval");
+    assert_eq!(dis(0x18100073), "sfence.inval.ir");
+    assert_eq!(dis(0x0045200f), "cbo.zero (a0)");
+}
+
+#[test]
+fn test_decode_atomics() {
+    assert_eq!(
+        decode(0xe6c5b52f).unwrap(),
+        DecodedInst::Amo {
+            op: AmoOp::MaxU,
+            width: AmoWidth::D,
+            rd: 10,
+            rs1: 11,
+            rs2: 12,
+            aq: true,
+            rl: true
+        }
+    );
+    assert_eq!(dis(0xe6c5b52f), "amomaxu.d.aqrl a0, a2, (a1)");
+    assert_eq!(dis(0x08c5a52f), "amoswap.w a0, a2, (a1)");
+    assert_eq!(dis(0x1405b52f), "lr.d.aq a0, (a1)");
+    assert_eq!(dis(0x1ac5a52f), "sc.w.rl a0, a2, (a1)");
+}
+
+#[test]
+fn test_decode_float() {
+    assert_eq!(dis(0x04c5f553), "fadd.h fa0, fa1, fa2");
+    assert_eq!(dis(0x44049053), "fcvt.h.s ft0, fs1, rtz");
+    assert_eq!(dis(0xe4078553), "fmv.x.h a0, fa5");
+    assert_eq!(dis(0x00451507), "flh fa0, 4(a0)");
+    assert_eq!(dis(0xfeb11f27), "fsh fa1, -2(sp)");
+    // Zfinx works on the x registers
+    assert_eq!(dis(0x00c5f553), "fadd.s a0, a1, a2");
+    assert_eq!(dis(0x58058553), "fsqrt.s a0, a1, rne");
+}
+
+#[test]
+fn test_decode_vector() {
+    assert_eq!(dis(0x0d15f557), "vsetvli a0, a1, e32, m2, ta, ma");
+    assert_eq!(dis(0xc1f27057), "vsetivli zero, 4, e64, mf2, tu, mu");
+    assert_eq!(dis(0x00055207), "vle16.v v4, (a0), v0.t");
+    assert_eq!(dis(0x0205f427), "vse64.v v8, (a1)");
+    assert_eq!(dis(0x02454157), "vadd.vx v2, v4, a0");
+    assert_eq!(dis(0x02430157), "vadd.vv v2, v4, v6");
+    assert_eq!(dis(0xa6812277), "vaesem.vs v4, v8");
+    assert_eq!(dis(0xa683a277), "vaesz.vs v4, v8");
+    assert_eq!(dis(0x8a81a277), "vaeskf1.vi v4, v8, 3");
+    assert_eq!(dis(0xba862277), "vsha2ch.vv v4, v8, v12");
+}
+
+#[test]
+fn test_decode_crypto() {
+    assert_eq!(dis(0x31a59513), "aes64ks1i a0, a1, 10");
+    assert_eq!(dis(0x32c58533), "aes64es a0, a1, a2");
+    assert_eq!(dis(0x10559513), "sha512sum1 a0, a1");
+    assert_eq!(dis(0x6875d513), "brev8 a0, a1");
+}
+
+#[test]
+fn test_decode_compressed_q0() {
+    // shown as the instructions they expand to, like objdump does
+    assert_eq!(
+        decode_c_q0(0x0800).unwrap(),
+        DecodedInst::Addi {
+            rd: 8,
+            rs1: 2,
+            imm: 16
+        }
+    );
+    assert_eq!(dis(0x1ffc), "addi a5, sp, 1020");
+    assert_eq!(dis(0x5cf4), "lw a3, 124(s1)");
+    assert_eq!(dis(0x7cf8), "ld a4, 248(s1)");
+    assert_eq!(dis(0xc0b4), "sw a3, 64(s1)");
+    assert_eq!(dis(0xe0d8), "sd a4, 128(s1)");
+    // the upper half belongs to the next instruction
+    assert_eq!(dis(0x1234_404c), "lw a1, 4(s0)");
+    // c.illegal, c.addi4spn with nzuimm 0, c.fld
+    for inst in [0x0000, 0x0004, 0x2588] {
+        assert!(matches!(
+            decode_c_q0(inst),
+            Err(Exception::IllegalInstruction(i)) if i == inst as u64
+        ));
+    }
+}
+
+#[test]
+fn test_decode_compressed_q1() {
+    assert_eq!(
+        decode_c_q1(0x1575).unwrap(),
+        DecodedInst::Addi {
+            rd: 10,
+            rs1: 10,
+            imm: -3
+        }
+    );
+    assert_eq!(dis(0x0001), "addi zero, zero, 0");
+    assert_eq!(dis(0x2515), "addiw a0, a0, 5");
+    assert_eq!(dis(0x5581), "addi a1, zero, -32");
+    assert_eq!(dis(0x7605), "lui a2, 0xfffe1");
+    assert_eq!(dis(0x657d), "lui a0, 0x1f");
+    assert_eq!(dis(0x7139), "addi sp, sp, -64");
+    assert_eq!(dis(0x7101), "addi sp, sp, -512");
+    assert_eq!(dis(0x617d), "addi sp, sp, 496");
+    assert_eq!(dis(0x94fd), "srai s1, s1, 63");
+    assert_eq!(dis(0x9841), "andi s0, s0, -16");
+    assert_eq!(dis(0x8c05), "sub s0, s0, s1");
+    assert_eq!(dis(0x8f5d), "or a4, a4, a5");
+    assert_eq!(dis(0x9c25), "addw s0, s0, s1");
+    assert_eq!(
+        decode_c_q1(0xb001).unwrap(),
+        DecodedInst::Jal {
+            rd: 0,
+            offset: -2048
+        }
+    );
+    assert_eq!(dis(0xaffd), "jal zero, 2046");
+    assert_eq!(dis(0xbff5), "jal zero, -4");
+    assert_eq!(dis(0xeffd), "bne a5, zero, 254");
+    assert_eq!(dis(0xd081), "beq s1, zero, -256");
+    // c.addiw to x0, c.addi16sp and c.lui with 0, reserved subw / addw slot
+    for inst in [0x2001, 0x6101, 0x6501, 0x9c41] {
+        assert!(matches!(
+            decode_c_q1(inst),
+            Err(Exception::IllegalInstruction(i)) if i == inst as u64
+        ));
+    }
+}
+
+#[test]
+fn test_decode_compressed_q2() {
+    assert_eq!(
+        decode_c_q2(0x79fe).unwrap(),
+        DecodedInst::Load {
+            rd: 19,
+            rs1: 2,
+            offset: 504,
+            width: LoadWidth::D
+        }
+    );
+    assert_eq!(dis(0x14fe), "slli s1, s1, 63");
+    assert_eq!(dis(0x567e), "lw a2, 252(sp)");
+    assert_eq!(dis(0x66a2), "ld a3, 8(sp)");
+    assert_eq!(dis(0x8282), "jalr zero, 0(t0)");
+    assert_eq!(dis(0x873e), "add a4, zero, a5");
+    assert_eq!(dis(0x9002), "ebreak");
+    assert_eq!(dis(0x9082), "jalr ra, 0(ra)");
+    assert_eq!(dis(0x973e), "add a4, a4, a5");
+    assert_eq!(dis(0xdfb2), "sw a2, 252(sp)");
+    assert_eq!(dis(0xffca), "sd s2, 504(sp)");
+    // c.fldsp, c.lwsp / c.ldsp to x0, c.jr x0
+    for inst in [0x2522, 0x4012, 0x6002, 0x8002] {
+        assert!(matches!(
+            decode_c_q2(inst),
+            Err(Exception::IllegalInstruction(i)) if i == inst as u64
+        ));
+    }
+}
+
+#[test]
+fn test_decode_illegal() {
+    // rnum 0xb is reserved, funct3 7 has no load, opcode 0x7f isn't used
+    for inst in [0x31b59513, 0x0000f003, 0x0000007f, 0xffffffff] {
+        assert!(matches!(
+            decode(inst),
+            Err(Exception::IllegalInstruction(i)) if i == inst as u64
+        ));
+    }
+}
+
+#[test]
+fn test_x_registers() {
+    let regs = |inst| x_registers(&decode(inst).unwrap());
+    // add a0, a1, a2; sd ra, 24(sp); lui a0, 0x12345; csrrsi zero, mie, 8
+    assert_eq!(regs(0x00c58533), (Some(10), vec![11, 12]));
+    assert_eq!(regs(0x00113c23), (None, vec![2, 1]));
+    assert_eq!(regs(0x12345537), (Some(10), vec![]));
+    assert_eq!(regs(0x30446073), (Some(0), vec![]));
+    // fadd.h is on the f registers
+    assert_eq!(regs(0x04c5f553), (None, vec![]));
+}
+
+#[test]
+fn test_current_instruction() {
+    // addi a0, sp, -16; add a0, a1, a2
+    let code = [0xff010513u32, 0x00c58533]
+        .iter()
+        .flat_map(|inst| inst.to_le_bytes())
+        .collect();
+    let mut cpu = CpuBuilder::new(code, vec![0]).build();
+    assert_eq!(
+        format(&cpu.current_instruction().unwrap()),
+        "addi a0, sp, -16"
+    );
+    cpu.step();
+    assert!(matches!(
+        cpu.current_instruction().unwrap(),
+        DecodedInst::Add { .. }
+    ));
+}
@@ -0,0 +1,4 @@
+mod alu;
+mod disasm;
+mod paging;
+mod pmp;
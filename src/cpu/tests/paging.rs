@@ -0,0 +1,453 @@
+use crate::{
+    cpu::{
+        builder::CpuBuilder,
+        cpu::{AccessType, Cpu, PBMT_IO, PBMT_NC},
+    },
+    csr::{MASK_MPRV, MASK_PBMTE, MENVCFG, MSTATUS, SATP},
+    exept::Exception,
+    param::{DRAM_BASE, PAGE_SIZE},
+};
+
+// root table and the frames it maps, all inside DRAM
+const ROOT: u64 = DRAM_BASE + 0x10000;
+const L1: u64 = DRAM_BASE + 0x11000;
+const L0: u64 = DRAM_BASE + 0x12000;
+const FRAME: u64 = DRAM_BASE + 0x20000;
+
+const PTE_V: u64 = 1 << 0;
+const PTE_R: u64 = 1 << 1;
+const PTE_W: u64 = 1 << 2;
+const PTE_X: u64 = 1 << 3;
+
+fn pte(pa: u64, flags: u64) -> u64 {
+    ((pa >> 12) << 10) | flags
+}
+
+fn vpn(vaddr: u64, level: u64) -> u64 {
+    (vaddr >> (12 + 9 * level)) & 0x1ff
+}
+
+// Sv39 with root table at ROOT, PTEs have to be stored before this
+fn enable_sv39(cpu: &mut Cpu) {
+    let satp = (8 << 60) | (ROOT / PAGE_SIZE);
+    cpu.csr.store(SATP, satp);
+    cpu.page_table = ROOT;
+    cpu.enable_paging = true;
+}
+
+#[test]
+fn test_translate_4k_page() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).build();
+    let vaddr = 0x4020_1234;
+    cpu.bus
+        .store(ROOT + vpn(vaddr, 2) * 8, 64, pte(L1, PTE_V))
+        .unwrap();
+    cpu.bus
+        .store(L1 + vpn(vaddr, 1) * 8, 64, pte(L0, PTE_V))
+        .unwrap();
+    cpu.bus
+        .store(L0 + vpn(vaddr, 0) * 8, 64, pte(FRAME, PTE_V | PTE_R))
+        .unwrap();
+    enable_sv39(&mut cpu);
+
+    let (pa, _) = cpu.translate(vaddr, AccessType::Load).unwrap();
+    assert_eq!(pa, FRAME + 0x234);
+}
+
+#[test]
+fn test_translate_gigapage() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).build();
+    // leaf in the root table maps 1 GiB at DRAM_BASE
+    let vaddr = 0x4012_3456;
+    cpu.bus
+        .store(ROOT + vpn(vaddr, 2) * 8, 64, pte(DRAM_BASE, PTE_V | PTE_R))
+        .unwrap();
+    enable_sv39(&mut cpu);
+
+    let (pa, _) = cpu.translate(vaddr, AccessType::Load).unwrap();
+    assert_eq!(pa, DRAM_BASE + 0x12_3456);
+}
+
+#[test]
+fn test_translate_not_valid() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).build();
+    let vaddr = 0x4000_0000;
+    cpu.bus
+        .store(ROOT + vpn(vaddr, 2) * 8, 64, pte(DRAM_BASE, PTE_R))
+        .unwrap();
+    enable_sv39(&mut cpu);
+
+    assert!(matches!(
+        cpu.translate(vaddr, AccessType::Load),
+        Err(Exception::LoadPageFault(a)) if a == vaddr
+    ));
+}
+
+#[test]
+fn test_translate_misaligned_superpage() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).build();
+    // 2 MiB superpage whose ppn[0] isn't zero
+    let vaddr = 0x4020_0000;
+    cpu.bus
+        .store(ROOT + vpn(vaddr, 2) * 8, 64, pte(L1, PTE_V))
+        .unwrap();
+    cpu.bus
+        .store(
+            L1 + vpn(vaddr, 1) * 8,
+            64,
+            pte(DRAM_BASE + 0x1000, PTE_V | PTE_R),
+        )
+        .unwrap();
+    enable_sv39(&mut cpu);
+
+    assert!(matches!(
+        cpu.translate(vaddr, AccessType::Load),
+        Err(Exception::LoadPageFault(a)) if a == vaddr
+    ));
+}
+
+#[test]
+fn test_translate_write_only() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).build();
+    // R=0, W=1 is reserved
+    let vaddr = 0x4000_0000;
+    cpu.bus
+        .store(ROOT + vpn(vaddr, 2) * 8, 64, pte(DRAM_BASE, PTE_V | PTE_W))
+        .unwrap();
+    enable_sv39(&mut cpu);
+
+    assert!(matches!(
+        cpu.translate(vaddr, AccessType::Load),
+        Err(Exception::LoadPageFault(a)) if a == vaddr
+    ));
+}
+
+#[test]
+fn test_fetch_across_pages() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).build();
+    let vaddr = 0x4020_0000;
+    cpu.bus
+        .store(ROOT + vpn(vaddr, 2) * 8, 64, pte(L1, PTE_V))
+        .unwrap();
+    cpu.bus
+        .store(L1 + vpn(vaddr, 1) * 8, 64, pte(L0, PTE_V))
+        .unwrap();
+    cpu.bus
+        .store(L0 + vpn(vaddr, 0) * 8, 64, pte(FRAME, PTE_V | PTE_X))
+        .unwrap();
+    enable_sv39(&mut cpu);
+    cpu.mode = 0b01;
+    // addi a0, zero, 1 split over the end of the page, the second frame isn't contiguous
+    let next = FRAME + 4 * PAGE_SIZE;
+    cpu.bus.store(FRAME + 0xffe, 16, 0x0513).unwrap();
+    cpu.bus.store(next, 16, 0x0010).unwrap();
+    cpu.pc = vaddr + 0xffe;
+
+    // the fault is reported for the half on the unmapped page
+    assert!(matches!(
+        cpu.fetch(),
+        Err(Exception::InstructionPageFault(a)) if a == vaddr + PAGE_SIZE
+    ));
+    cpu.bus
+        .store(L0 + (vpn(vaddr, 0) + 1) * 8, 64, pte(next, PTE_V | PTE_X))
+        .unwrap();
+    assert_eq!(cpu.fetch().unwrap(), 0x00100513);
+}
+
+const PTE_N: u64 = 1 << 63;
+
+// 64 KiB NAPOT region at vaddr, all 16 ptes of the range hold the same value
+fn map_napot(cpu: &mut Cpu, vaddr: u64, napot_pte: u64) {
+    cpu.bus
+        .store(ROOT + vpn(vaddr, 2) * 8, 64, pte(L1, PTE_V))
+        .unwrap();
+    cpu.bus
+        .store(L1 + vpn(vaddr, 1) * 8, 64, pte(L0, PTE_V))
+        .unwrap();
+    for i in 0..16 {
+        cpu.bus
+            .store(L0 + (vpn(vaddr, 0) + i) * 8, 64, napot_pte)
+            .unwrap();
+    }
+    enable_sv39(cpu);
+}
+
+#[test]
+fn test_translate_napot_64k() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).build();
+    let vaddr = 0x4021_0000;
+    let region = DRAM_BASE + 0x30000;
+    // ppn[3:0] = 0b1000 encodes 64 KiB
+    let napot = pte(region | (0b1000 << 12), PTE_V | PTE_R) | PTE_N;
+    map_napot(&mut cpu, vaddr, napot);
+
+    for i in 0..16 {
+        let offset = i * PAGE_SIZE + i * 0x10;
+        let (pa, _) = cpu.translate(vaddr + offset, AccessType::Load).unwrap();
+        assert_eq!(pa, region + offset);
+    }
+}
+
+#[test]
+fn test_translate_napot_reserved() {
+    let vaddr = 0x4021_0000;
+    let region = DRAM_BASE + 0x30000;
+    let faults = |napot_pte: u64, svnapot: bool| {
+        let mut cpu = CpuBuilder::new(vec![0], vec![0]).build();
+        cpu.extensions.svnapot = svnapot;
+        map_napot(&mut cpu, vaddr, napot_pte);
+        matches!(
+            cpu.translate(vaddr, AccessType::Load),
+            Err(Exception::LoadPageFault(a)) if a == vaddr
+        )
+    };
+    let napot = pte(region | (0b1000 << 12), PTE_V | PTE_R) | PTE_N;
+    assert!(!faults(napot, true));
+    assert!(faults(napot, false));
+    // any other ppn[3:0] encoding is reserved
+    assert!(faults(
+        pte(region | (0b0100 << 12), PTE_V | PTE_R) | PTE_N,
+        true
+    ));
+}
+
+const PTE_PBMT_NC: u64 = 1 << 61;
+const PTE_PBMT_IO: u64 = 2 << 61;
+
+#[test]
+fn test_translate_svpbmt() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).build();
+    let (nc, io) = (0x4020_1000, 0x4020_2000);
+    cpu.bus
+        .store(ROOT + vpn(nc, 2) * 8, 64, pte(L1, PTE_V))
+        .unwrap();
+    cpu.bus
+        .store(L1 + vpn(nc, 1) * 8, 64, pte(L0, PTE_V))
+        .unwrap();
+    let leaf = PTE_V | PTE_R | PTE_W;
+    cpu.bus
+        .store(L0 + vpn(nc, 0) * 8, 64, pte(FRAME, leaf) | PTE_PBMT_NC)
+        .unwrap();
+    cpu.bus
+        .store(
+            L0 + vpn(io, 0) * 8,
+            64,
+            pte(FRAME + PAGE_SIZE, leaf) | PTE_PBMT_IO,
+        )
+        .unwrap();
+    enable_sv39(&mut cpu);
+
+    // the memory type bits are reserved until menvcfg.PBMTE is set
+    assert!(matches!(
+        cpu.translate(nc, AccessType::Load),
+        Err(Exception::LoadPageFault(a)) if a == nc
+    ));
+    cpu.csr.store(MENVCFG, MASK_PBMTE);
+    // a second lookup hits the tlb, which keeps the type
+    for _ in 0..2 {
+        assert_eq!(
+            cpu.translate(nc + 0x18, AccessType::Load).unwrap(),
+            (FRAME + 0x18, PBMT_NC)
+        );
+        assert_eq!(
+            cpu.translate(io, AccessType::Store).unwrap(),
+            (FRAME + PAGE_SIZE, PBMT_IO)
+        );
+    }
+    assert_eq!(cpu.tlb.len(), 2);
+
+    // plain accesses work on IO pages, AMOs don't
+    cpu.mode = 0b01;
+    cpu.store(io, 32, 5).unwrap();
+    assert_eq!(cpu.load(io, 32).unwrap(), 5);
+    cpu.regs[11] = io;
+    cpu.regs[12] = 1;
+    // amoadd.w a0, a2, (a1)
+    assert!(matches!(
+        cpu.execute(0x00c5a52f),
+        Err(Exception::StoreAMOAccessFault(a)) if a == io
+    ));
+    cpu.regs[11] = nc;
+    cpu.execute(0x00c5a52f).unwrap();
+    assert_eq!(cpu.load(nc, 32).unwrap(), 1);
+
+    // 0b11 is reserved, and PBMT in a non-leaf pte
+    cpu.tlb.clear();
+    cpu.bus
+        .store(
+            L0 + vpn(nc, 0) * 8,
+            64,
+            pte(FRAME, leaf) | PTE_PBMT_NC | PTE_PBMT_IO,
+        )
+        .unwrap();
+    assert!(cpu.translate(nc, AccessType::Load).is_err());
+    cpu.bus
+        .store(L1 + vpn(io, 1) * 8, 64, pte(L0, PTE_V) | PTE_PBMT_IO)
+        .unwrap();
+    assert!(cpu.translate(io, AccessType::Load).is_err());
+}
+
+#[test]
+fn test_tlb_hit_matches_walk() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).build();
+    // 16 pages at va 0x4020_0000 -> FRAME + i pages, in reverse so the mapping isn't linear
+    let base = 0x4020_0000;
+    cpu.bus
+        .store(ROOT + vpn(base, 2) * 8, 64, pte(L1, PTE_V))
+        .unwrap();
+    cpu.bus
+        .store(L1 + vpn(base, 1) * 8, 64, pte(L0, PTE_V))
+        .unwrap();
+    for i in 0..16 {
+        let frame = FRAME + (15 - i) * PAGE_SIZE;
+        cpu.bus
+            .store(L0 + (vpn(base, 0) + i) * 8, 64, pte(frame, PTE_V | PTE_R))
+            .unwrap();
+    }
+    enable_sv39(&mut cpu);
+
+    let misses: Vec<u64> = (0..16)
+        .map(|i| {
+            cpu.translate(base + i * PAGE_SIZE + 0x10, AccessType::Load)
+                .unwrap()
+                .0
+        })
+        .collect();
+    assert_eq!(cpu.tlb.len(), 16);
+    let hits: Vec<u64> = (0..16)
+        .map(|i| {
+            cpu.translate(base + i * PAGE_SIZE + 0x10, AccessType::Load)
+                .unwrap()
+                .0
+        })
+        .collect();
+    assert_eq!(hits, misses);
+    assert_eq!(misses[0], FRAME + 15 * PAGE_SIZE + 0x10);
+
+    // sfence.vma with a single page leaves the rest
+    cpu.flush_tlb(
+        None,
+        Some(vpn(base, 0) | (vpn(base, 1) << 9) | (vpn(base, 2) << 18)),
+    );
+    assert_eq!(cpu.tlb.len(), 15);
+    cpu.flush_tlb(None, None);
+    assert_eq!(cpu.tlb.len(), 0);
+}
+
+#[test]
+fn test_phys_access_bypasses_paging() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).build();
+    let vaddr = 0x4020_1234;
+    cpu.bus
+        .store(ROOT + vpn(vaddr, 2) * 8, 64, pte(L1, PTE_V))
+        .unwrap();
+    cpu.bus
+        .store(L1 + vpn(vaddr, 1) * 8, 64, pte(L0, PTE_V))
+        .unwrap();
+    cpu.bus
+        .store(
+            L0 + vpn(vaddr, 0) * 8,
+            64,
+            pte(FRAME, PTE_V | PTE_R | PTE_W),
+        )
+        .unwrap();
+    enable_sv39(&mut cpu);
+    cpu.mode = 0b01;
+
+    // the root table isn't mapped, only reachable physically
+    cpu.store_phys(FRAME + 0x234, 64, 0xabcd).unwrap();
+    assert_eq!(
+        cpu.load_phys(ROOT + vpn(vaddr, 2) * 8, 64).unwrap(),
+        pte(L1, PTE_V)
+    );
+    assert_eq!(cpu.tlb.len(), 0);
+
+    assert_eq!(cpu.load(vaddr, 64).unwrap(), 0xabcd);
+    assert_eq!(cpu.tlb.len(), 1);
+    cpu.tlb.clear();
+    assert_eq!(cpu.load_phys(FRAME + 0x234, 64).unwrap(), 0xabcd);
+    assert_eq!(cpu.tlb.len(), 0);
+}
+
+#[test]
+fn test_machine_mode_not_translated() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).build();
+    let vaddr = 0x4020_1234;
+    cpu.bus
+        .store(ROOT + vpn(vaddr, 2) * 8, 64, pte(L1, PTE_V))
+        .unwrap();
+    cpu.bus
+        .store(L1 + vpn(vaddr, 1) * 8, 64, pte(L0, PTE_V))
+        .unwrap();
+    cpu.bus
+        .store(L0 + vpn(vaddr, 0) * 8, 64, pte(FRAME, PTE_V | PTE_R))
+        .unwrap();
+    cpu.bus.store(FRAME + 0x234, 64, 0x55).unwrap();
+    enable_sv39(&mut cpu);
+
+    // M-mode uses physical addresses even with satp set
+    assert_eq!(cpu.load(FRAME + 0x234, 64).unwrap(), 0x55);
+    assert_eq!(cpu.tlb.len(), 0);
+    // MPRV with MPP = S translates loads and stores
+    cpu.csr.store(MSTATUS, MASK_MPRV | (0b01 << 11));
+    assert_eq!(cpu.load(vaddr, 64).unwrap(), 0x55);
+    assert_eq!(cpu.tlb.len(), 1);
+}
+
+#[test]
+fn test_mem_stats_tlb() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).build();
+    let vaddr = 0x4012_3456;
+    cpu.bus
+        .store(ROOT + vpn(vaddr, 2) * 8, 64, pte(DRAM_BASE, PTE_V | PTE_R))
+        .unwrap();
+    enable_sv39(&mut cpu);
+    cpu.reset_mem_stats();
+
+    cpu.translate(vaddr, AccessType::Load).unwrap();
+    cpu.translate(vaddr + 8, AccessType::Load).unwrap();
+    assert!(cpu.translate(0x8000_0000, AccessType::Load).is_err());
+    let stats = cpu.get_mem_stats();
+    assert_eq!(stats.tlb_misses, 2);
+    assert_eq!(stats.tlb_hits, 1);
+    assert_eq!(stats.page_faults, 1);
+    // the walks read one pte each
+    assert_eq!(stats.dram_reads, 2);
+}
+
+fn sv32_vpn(vaddr: u64, level: u64) -> u64 {
+    (vaddr >> (12 + 10 * level)) & 0x3ff
+}
+
+#[test]
+fn test_translate_sv32() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).rv32().build();
+    // 4 KiB page through both levels and a 4 MiB superpage in the root table
+    let vaddr = 0x4020_1234;
+    let super_vaddr = 0x00c1_2345;
+    cpu.bus
+        .store(ROOT + sv32_vpn(vaddr, 1) * 4, 32, pte(L0, PTE_V))
+        .unwrap();
+    cpu.bus
+        .store(L0 + sv32_vpn(vaddr, 0) * 4, 32, pte(FRAME, PTE_V | PTE_R))
+        .unwrap();
+    cpu.bus
+        .store(
+            ROOT + sv32_vpn(super_vaddr, 1) * 4,
+            32,
+            pte(DRAM_BASE + 0x40_0000, PTE_V | PTE_R),
+        )
+        .unwrap();
+    cpu.csr.store(SATP, (1 << 31) | (ROOT / PAGE_SIZE));
+    cpu.page_table = ROOT;
+    cpu.enable_paging = true;
+
+    let (pa, _) = cpu.translate(vaddr, AccessType::Load).unwrap();
+    assert_eq!(pa, FRAME + 0x234);
+    let (pa, _) = cpu.translate(super_vaddr, AccessType::Load).unwrap();
+    assert_eq!(pa, DRAM_BASE + 0x41_2345);
+    assert!(matches!(
+        cpu.translate(0x8000_0000, AccessType::Load),
+        Err(Exception::LoadPageFault(_))
+    ));
+}
@@ -0,0 +1,214 @@
+use crate::{
+    cpu::builder::CpuBuilder,
+    csr::{MSECCFG, PMPADDR0, PMPCFG0},
+    exept::Exception,
+    param::DRAM_BASE,
+    pmp::{
+        check_pmp, pmp_napot_match, MSECCFG_MML, MSECCFG_MMWP, MSECCFG_RLB, PMP_L, PMP_NAPOT,
+        PMP_R, PMP_TOR, PMP_W, PMP_X,
+    },
+};
+
+// 4 KiB region, base >> 2 with the low 9 bits set
+const REGION: u64 = DRAM_BASE + 0x10000;
+const NAPOT_4K: u64 = (REGION >> 2) | 0x1ff;
+
+#[test]
+fn test_napot_match() {
+    assert!(pmp_napot_match(NAPOT_4K, REGION, 8));
+    assert!(pmp_napot_match(NAPOT_4K, REGION + 0xff8, 8));
+    assert!(!pmp_napot_match(NAPOT_4K, REGION + 0xffc, 8));
+    assert!(!pmp_napot_match(NAPOT_4K, REGION - 1, 1));
+    assert!(!pmp_napot_match(NAPOT_4K, REGION + 0x1000, 1));
+    // no trailing ones is the smallest NAPOT region, 8 bytes
+    assert!(pmp_napot_match(REGION >> 2, REGION + 4, 4));
+    assert!(!pmp_napot_match(REGION >> 2, REGION + 8, 1));
+}
+
+#[test]
+fn test_pmp_napot_region() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).build();
+    cpu.csr.store(PMPADDR0, NAPOT_4K);
+    cpu.csr.store(PMPCFG0, (PMP_NAPOT << 3) | PMP_R);
+    cpu.bus.store(REGION + 0x10, 64, 0x1234).unwrap();
+
+    // S-mode: read-only inside the region, nothing outside it
+    cpu.mode = 0b01;
+    assert_eq!(cpu.load(REGION + 0x10, 64).unwrap(), 0x1234);
+    assert!(matches!(
+        cpu.load(REGION + 0x1000, 64),
+        Err(Exception::LoadAccessFault(_))
+    ));
+    assert!(matches!(
+        cpu.load(REGION - 8, 64),
+        Err(Exception::LoadAccessFault(_))
+    ));
+    // an access straddling the region's end matches it only partly
+    assert!(matches!(
+        cpu.load(REGION + 0xffc, 64),
+        Err(Exception::LoadAccessFault(_))
+    ));
+    assert!(matches!(
+        cpu.store(REGION + 0x10, 64, 0),
+        Err(Exception::StoreAMOAccessFault(_))
+    ));
+
+    // M-mode ignores entries that aren't locked
+    cpu.mode = 0b11;
+    assert!(cpu.store(REGION + 0x10, 64, 0).is_ok());
+    assert!(cpu.load(REGION + 0x1000, 64).is_ok());
+}
+
+#[test]
+fn test_pmp_priority_and_lock() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).build();
+    // entry 0: locked, no access to the 4 KiB region; entry 1: TOR over all memory below 2^56
+    cpu.csr.store(PMPADDR0, NAPOT_4K);
+    cpu.csr.store(PMPADDR0 + 1, u64::MAX);
+    let cfg = (PMP_NAPOT << 3) | PMP_L | ((PMP_TOR << 3 | PMP_R | PMP_W | PMP_X) << 8);
+    cpu.csr.store(PMPCFG0, cfg);
+
+    // the lower numbered entry wins, and locked entries hold M-mode too
+    assert!(!check_pmp(&cpu.csr, REGION, 8, PMP_R, true));
+    assert!(check_pmp(&cpu.csr, REGION + 0x1000, 8, PMP_R, true));
+    assert!(!check_pmp(&cpu.csr, REGION, 8, PMP_R, false));
+    assert!(check_pmp(&cpu.csr, REGION + 0x1000, 8, PMP_W, false));
+
+    // locked entries ignore writes until reset
+    cpu.csr.store(PMPADDR0, 0);
+    cpu.csr.store(PMPCFG0, 0);
+    assert_eq!(cpu.csr.load(PMPADDR0), NAPOT_4K);
+    assert_eq!(cpu.csr.load(PMPCFG0) & 0xff, (PMP_NAPOT << 3) | PMP_L);
+    assert_eq!(cpu.csr.load(PMPCFG0) >> 8, 0);
+}
+
+#[test]
+fn test_pmp_fetch_at_region_end() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).enable_c().build();
+    cpu.csr.store(PMPADDR0, NAPOT_4K);
+    cpu.csr.store(PMPCFG0, (PMP_NAPOT << 3) | PMP_X);
+    cpu.mode = 0b01;
+
+    // c.li a0, 5 in the last two bytes of the region, nothing executable after it
+    cpu.bus.store(REGION + 0xffe, 16, 0x4515).unwrap();
+    cpu.pc = REGION + 0xffe;
+    assert_eq!(cpu.fetch().unwrap(), 0x4515);
+    // the upper half of a 32-bit instruction is checked on its own
+    cpu.bus.store(REGION + 0xffe, 16, 0x0513).unwrap();
+    assert!(matches!(
+        cpu.fetch(),
+        Err(Exception::InstructionAccessFault(a)) if a == REGION + 0x1000
+    ));
+}
+
+// NAPOT pmpaddr of the 4 KiB region i pages above REGION
+fn napot_page(i: u64) -> u64 {
+    ((REGION + i * 0x1000) >> 2) | 0x1ff
+}
+
+#[test]
+fn test_smepmp_mml() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).build();
+    let napot = PMP_NAPOT << 3;
+    // page 0: M-mode only RW, page 1: S/U-mode only R, page 2: locked with no
+    // permissions, page 3: shared data, RW for M-mode and R for S/U-mode
+    for i in 0..4 {
+        cpu.csr.store(PMPADDR0 + i, napot_page(i as u64));
+    }
+    let cfg = [
+        napot | PMP_L | PMP_R | PMP_W,
+        napot | PMP_R,
+        napot | PMP_L,
+        napot | PMP_W,
+    ];
+    let cfg = cfg.iter().enumerate().fold(0, |c, (i, b)| c | b << (i * 8));
+    cpu.csr.store(PMPCFG0, cfg);
+    cpu.csr.store(MSECCFG, MSECCFG_MML);
+    let page = |i: u64| REGION + i * 0x1000;
+
+    assert!(check_pmp(&cpu.csr, page(0), 8, PMP_W, true));
+    assert!(!check_pmp(&cpu.csr, page(0), 8, PMP_X, true));
+    assert!(!check_pmp(&cpu.csr, page(0), 8, PMP_R, false));
+    // entries that aren't locked hold M-mode too
+    assert!(!check_pmp(&cpu.csr, page(1), 8, PMP_R, true));
+    assert!(check_pmp(&cpu.csr, page(1), 8, PMP_R, false));
+    assert!(!check_pmp(&cpu.csr, page(2), 8, PMP_R, true));
+    assert!(check_pmp(&cpu.csr, page(3), 8, PMP_W, true));
+    assert!(check_pmp(&cpu.csr, page(3), 8, PMP_R, false));
+    assert!(!check_pmp(&cpu.csr, page(3), 8, PMP_W, false));
+    // M-mode can read memory no entry covers, but not execute from it
+    assert!(check_pmp(&cpu.csr, page(4), 8, PMP_R, true));
+    assert!(!check_pmp(&cpu.csr, page(4), 4, PMP_X, true));
+
+    cpu.mode = 0b11;
+    assert!(matches!(
+        cpu.load(page(2), 64),
+        Err(Exception::LoadAccessFault(_))
+    ));
+    assert!(cpu.store(page(0), 64, 1).is_ok());
+
+    // MML is sticky, and locked executable entries can't be added under it
+    cpu.csr.store(MSECCFG, 0);
+    assert_eq!(cpu.csr.load(MSECCFG), MSECCFG_MML);
+    cpu.csr.store(PMPCFG0, cfg | (napot | PMP_L | PMP_X) << 32);
+    assert_eq!(cpu.csr.load(PMPCFG0), cfg);
+}
+
+#[test]
+fn test_smepmp_mmwp() {
+    let mut cpu = CpuBuilder::new(vec![0], vec![0]).build();
+    // RLB can be set while nothing is locked
+    cpu.csr.store(MSECCFG, MSECCFG_MMWP | MSECCFG_RLB);
+    assert_eq!(cpu.csr.load(MSECCFG), MSECCFG_MMWP | MSECCFG_RLB);
+
+    // one unlocked RWX page, M-mode keeps full access to it and loses the rest
+    cpu.csr.store(PMPADDR0, NAPOT_4K);
+    cpu.csr
+        .store(PMPCFG0, (PMP_NAPOT << 3) | PMP_R | PMP_W | PMP_X);
+    assert!(check_pmp(&cpu.csr, REGION, 8, PMP_W, true));
+    assert!(check_pmp(&cpu.csr, REGION, 4, PMP_X, true));
+    assert!(!check_pmp(&cpu.csr, REGION + 0x1000, 8, PMP_R, true));
+    cpu.mode = 0b11;
+    assert!(cpu.load(REGION, 64).is_ok());
+    assert!(matches!(
+        cpu.load(REGION + 0x1000, 64),
+        Err(Exception::LoadAccessFault(_))
+    ));
+
+    // with RLB set a locked entry can still be rewritten
+    cpu.csr.store(PMPCFG0, (PMP_NAPOT << 3) | PMP_L);
+    cpu.csr.store(PMPADDR0, 0);
+    cpu.csr.store(PMPCFG0, 0);
+    assert_eq!(cpu.csr.load(PMPCFG0), 0);
+    assert_eq!(cpu.csr.load(PMPADDR0), 0);
+
+    // with no entry on at all MMWP still denies M-mode
+    assert!(!check_pmp(&cpu.csr, REGION, 8, PMP_R, true));
+    // MMWP is sticky, RLB isn't
+    cpu.csr.store(MSECCFG, 0);
+    assert_eq!(cpu.csr.load(MSECCFG), MSECCFG_MMWP);
+}
+
+#[test]
+fn test_mseccfg_flushes_block_cache() {
+    // addi a0, a0, 1; jal zero, -4
+    let code = [0x00150513u32, 0xffdff06f]
+        .iter()
+        .flat_map(|inst| inst.to_le_bytes())
+        .collect();
+    let mut cpu = CpuBuilder::new(code, vec![0]).block_cache(true).build();
+    for _ in 0..10 {
+        cpu.step();
+    }
+    assert!(!cpu.block_cache.as_ref().unwrap().is_empty());
+
+    // csrrw zero, mseccfg, a1, MMWP takes M-mode's access to the cached code away
+    cpu.regs[11] = MSECCFG_MMWP;
+    cpu.execute(((MSECCFG as u64) << 20) | (11 << 15) | (1 << 12) | 0x73)
+        .unwrap();
+    assert!(cpu.block_cache.as_ref().unwrap().is_empty());
+    assert!(matches!(
+        cpu.fetch(),
+        Err(Exception::InstructionAccessFault(_))
+    ));
+}
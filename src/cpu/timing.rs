@@ -0,0 +1,61 @@
+use crate::cpu::disasm::DecodedInst::{self, *};
+
+// Cycles an instruction takes, step adds them to Cpu::cycles. The cycle csr,
+// budgets and with_timeout all count these weighted cycles.
+pub trait TimingModel: Send {
+    fn latency(&self, decoded: &DecodedInst) -> u64;
+
+    // the latency of every instruction if it doesn't depend on the instruction,
+    // step skips decoding then
+    fn fixed_latency(&self) -> Option<u64> {
+        None
+    }
+}
+
+// a cycle per instruction, the default
+pub struct SimpleTiming;
+
+impl TimingModel for SimpleTiming {
+    fn latency(&self, _decoded: &DecodedInst) -> u64 {
+        1
+    }
+
+    fn fixed_latency(&self) -> Option<u64> {
+        Some(1)
+    }
+}
+
+// An in-order core with a pipelined multiplier and an iterative divider, roughly
+// a SiFive U74. Whether a branch is taken isn't known from the instruction, so
+// conditional branches pay 2 and jalr, which jumps to a register, 3.
+pub struct SifiveTiming;
+
+impl TimingModel for SifiveTiming {
+    fn latency(&self, decoded: &DecodedInst) -> u64 {
+        match decoded {
+            Mul { .. } | Mulh { .. } | Mulhsu { .. } | Mulhu { .. } | Mulw { .. } => 3,
+            // the word forms have half the bits to go through
+            Divw { .. } | Divuw { .. } | Remw { .. } | Remuw { .. } => 10,
+            Div { .. } | Divu { .. } | Rem { .. } | Remu { .. } => 35,
+            Load { .. } | Flh { .. } | Lr { .. } | Amo { .. } => 3,
+            Store { .. } | Fsh { .. } | Sc { .. } => 1,
+            Jal { .. } => 1,
+            Branch { .. } => 2,
+            Jalr { .. } => 3,
+            _ => 1,
+        }
+    }
+}
+
+// every instruction in a cycle, a bound no real core reaches
+pub struct IdealTiming;
+
+impl TimingModel for IdealTiming {
+    fn latency(&self, _decoded: &DecodedInst) -> u64 {
+        1
+    }
+
+    fn fixed_latency(&self) -> Option<u64> {
+        Some(1)
+    }
+}
@@ -0,0 +1,84 @@
+// number of entries, a power of two
+pub const TLB_SIZE: usize = 64;
+
+// tag bit for a filled entry
+const VALID: u64 = 1 << 63;
+// pte global mapping bit
+const PTE_G: u64 = 1 << 5;
+
+// Direct-mapped translation cache indexed by the low bits of the vpn.
+// Tags hold the vpn and the satp.ASID, values are ppn << 10 | pte flags, with the
+// pte's Svpbmt bits 62:61 kept in place.
+pub struct Tlb {
+    entries: [(u64, u64); TLB_SIZE],
+}
+
+impl Default for Tlb {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Tlb {
+    pub fn new() -> Self {
+        Self {
+            entries: [(0, 0); TLB_SIZE],
+        }
+    }
+
+    fn index(vpn: u64) -> usize {
+        vpn as usize % TLB_SIZE
+    }
+
+    // Sv39 vpn is 27 bits, asid goes above it
+    fn tag(asid: u64, vpn: u64) -> u64 {
+        VALID | (asid << 27) | vpn
+    }
+
+    pub fn lookup(&self, asid: u64, vpn: u64) -> Option<u64> {
+        let (tag, entry) = self.entries[Self::index(vpn)];
+        if tag == Self::tag(asid, vpn) {
+            return Some(entry);
+        }
+        None
+    }
+
+    // replaces whatever was cached in the slot
+    pub fn insert(&mut self, asid: u64, vpn: u64, entry: u64) {
+        self.entries[Self::index(vpn)] = (Self::tag(asid, vpn), entry);
+    }
+
+    // sfence.vma, None matches every asid / page
+    pub fn flush(&mut self, asid: Option<u64>, vpn: Option<u64>) {
+        for (tag, entry) in self.entries.iter_mut() {
+            if *tag & VALID == 0 {
+                continue;
+            }
+            let asid_match = match asid {
+                // global mappings are shared by all address spaces
+                Some(a) => a == (*tag & !VALID) >> 27 && *entry & PTE_G == 0,
+                None => true,
+            };
+            let vpn_match = vpn.is_none_or(|v| v == *tag & ((1 << 27) - 1));
+            if asid_match && vpn_match {
+                *tag = 0;
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries = [(0, 0); TLB_SIZE];
+    }
+
+    // number of filled entries
+    pub fn len(&self) -> usize {
+        self.entries
+            .iter()
+            .filter(|(tag, _)| tag & VALID != 0)
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
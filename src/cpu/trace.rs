@@ -0,0 +1,133 @@
+// Execution traces for cross-checking against a reference (another emulator,
+// Spike, QEMU): one record per retired instruction with its pc, the instruction
+// and the integer registers after it, all little-endian u64.
+use std::{
+    fs::File,
+    io::{self, BufReader, ErrorKind, Read, Write},
+    path::Path,
+};
+
+use crate::cpu::cpu::{Cpu, StepResult, RVABI};
+
+pub const TRACE_RECORD_SIZE: usize = 8 + 8 + 32 * 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub pc: u64,
+    pub inst: u64,
+    pub regs: [u64; 32],
+}
+
+impl TraceRecord {
+    // x0 is recorded as 0 whatever the last write to it left in regs[0]
+    pub fn new(pc: u64, inst: u64, regs: &[u64; 32]) -> Self {
+        let mut regs = *regs;
+        regs[0] = 0;
+        Self { pc, inst, regs }
+    }
+
+    pub fn to_bytes(&self) -> [u8; TRACE_RECORD_SIZE] {
+        let mut bytes = [0; TRACE_RECORD_SIZE];
+        let words = [self.pc, self.inst].into_iter().chain(self.regs);
+        for (chunk, word) in bytes.chunks_exact_mut(8).zip(words) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; TRACE_RECORD_SIZE]) -> Self {
+        let mut words = bytes
+            .chunks_exact(8)
+            .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()));
+        let pc = words.next().unwrap();
+        let inst = words.next().unwrap();
+        let mut regs = [0; 32];
+        for (reg, word) in regs.iter_mut().zip(words) {
+            *reg = word;
+        }
+        Self { pc, inst, regs }
+    }
+}
+
+// Records read one at a time as the cpu retires instructions, see
+// CpuBuilder::reference_trace.
+pub struct ReferenceTrace {
+    reader: BufReader<File>,
+    // records checked so far
+    index: u64,
+}
+
+impl ReferenceTrace {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            reader: BufReader::new(File::open(path)?),
+            index: 0,
+        })
+    }
+
+    // None once the trace has ended
+    pub fn next_record(&mut self) -> io::Result<Option<TraceRecord>> {
+        let mut bytes = [0; TRACE_RECORD_SIZE];
+        match self.reader.read_exact(&mut bytes) {
+            Ok(()) => Ok(Some(TraceRecord::from_bytes(&bytes))),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Panics with the differing registers if actual isn't the next record.
+    // Past the end of the trace there is nothing left to compare.
+    pub fn check(&mut self, actual: &TraceRecord) {
+        let expected = match self.next_record() {
+            Ok(Some(expected)) => expected,
+            Ok(None) => return,
+            Err(e) => panic!("reference trace: {}", e),
+        };
+        self.index += 1;
+        if expected == *actual {
+            return;
+        }
+        let mut report = format!(
+            "diverged from the reference trace at instruction {}\n\
+             expected pc {:#x} inst {:#010x}\n\
+             actual   pc {:#x} inst {:#010x}\n",
+            self.index - 1,
+            expected.pc,
+            expected.inst,
+            actual.pc,
+            actual.inst
+        );
+        for (i, name) in RVABI.iter().enumerate() {
+            if expected.regs[i] != actual.regs[i] {
+                report += &format!(
+                    "{:>4}: expected {:#018x} actual {:#018x}\n",
+                    name, expected.regs[i], actual.regs[i]
+                );
+            }
+        }
+        panic!("{}", report);
+    }
+}
+
+// Steps cpu up to max_steps times, writing a record for every instruction that
+// retires, until the program halts or faults. Returns the number of records.
+pub fn record_trace(cpu: &mut Cpu, out: &mut impl Write, max_steps: u64) -> io::Result<u64> {
+    // the instruction of each record comes from history
+    cpu.history_size = cpu.history_size.max(1);
+    let mut records = 0;
+    for _ in 0..max_steps {
+        let (pc, instret) = (cpu.pc, cpu.instret);
+        let result = cpu.step();
+        if cpu.instret != instret {
+            let inst = cpu.history.front().map_or(0, |&(_, inst)| inst);
+            let record = TraceRecord::new(pc, inst, &cpu.regs);
+            out.write_all(&record.to_bytes())?;
+            records += 1;
+        }
+        if !matches!(result, StepResult::Ok) || cpu.is_shutdown() {
+            break;
+        }
+    }
+    out.flush()?;
+    Ok(records)
+}
@@ -0,0 +1,67 @@
+// RVV 1.0 baseline: register file layout and vtype decoding, VLEN = 128, ELEN = 64
+pub const VLEN: u64 = 128;
+pub const VLENB: u64 = VLEN / 8;
+pub const ELEN: u64 = 64;
+pub const NUM_VREGS: usize = 32;
+
+// set by vset{i}vl{i} for an unsupported vtype, vector instructions are illegal then
+pub const VTYPE_VILL: u64 = 1 << 63;
+
+// (SEW in bits, LMUL in eighths) of a vtype, None if vill is set or it's reserved
+pub fn decode_vtype(vtype: u64) -> Option<(u64, u64)> {
+    // vta and vma are bits 7:6, anything above is reserved
+    if vtype >> 8 != 0 {
+        return None;
+    }
+    let vsew = (vtype >> 3) & 0b111;
+    if vsew > 3 {
+        return None;
+    }
+    let sew = 8 << vsew;
+    let lmul8 = match vtype & 0b111 {
+        0 => 8,
+        1 => 16,
+        2 => 32,
+        3 => 64,
+        5 => 1,
+        6 => 2,
+        7 => 4,
+        _ => return None,
+    };
+    // fractional LMUL must still hold one ELEN-wide element
+    if sew * 8 > lmul8 * ELEN {
+        return None;
+    }
+    Some((sew, lmul8))
+}
+
+pub fn vlmax(sew: u64, lmul8: u64) -> u64 {
+    VLEN * lmul8 / 8 / sew
+}
+
+// registers taken by a group, fractional LMUL still uses a whole one
+pub fn group_regs(lmul8: u64) -> usize {
+    (lmul8 / 8).max(1) as usize
+}
+
+// element i of the group starting at base, elements are little-endian
+pub fn read_element(vregs: &[Vec<u8>], base: usize, i: u64, bytes: u64) -> u64 {
+    let offset = i * bytes;
+    let reg = &vregs[base + (offset / VLENB) as usize];
+    let start = (offset % VLENB) as usize;
+    let mut value = [0; 8];
+    value[..bytes as usize].copy_from_slice(&reg[start..start + bytes as usize]);
+    u64::from_le_bytes(value)
+}
+
+pub fn write_element(vregs: &mut [Vec<u8>], base: usize, i: u64, bytes: u64, value: u64) {
+    let offset = i * bytes;
+    let reg = &mut vregs[base + (offset / VLENB) as usize];
+    let start = (offset % VLENB) as usize;
+    reg[start..start + bytes as usize].copy_from_slice(&value.to_le_bytes()[..bytes as usize]);
+}
+
+// v0 holds one mask bit per element
+pub fn mask_bit(vregs: &[Vec<u8>], i: u64) -> bool {
+    (vregs[0][(i / 8) as usize] >> (i % 8)) & 1 != 0
+}
@@ -1,12 +1,41 @@
-const NUM_CSRS: usize = 4096;
+use crate::pmp;
+
+pub const NUM_CSRS: usize = 4096;
+
+// Zicntr counters, owned by the cpu and the clint
+pub struct CpuCounters {
+    pub cycles: u64,
+    pub instret: u64,
+    // clint mtime
+    pub time: u64,
+}
 
 pub struct Csr {
     csrs: [u64; NUM_CSRS],
 }
+impl Default for Csr {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Csr {
     pub fn new() -> Csr {
-        Self {
-            csrs: [0; NUM_CSRS],
+        let mut csrs = [0; NUM_CSRS];
+        // the ids of QEMU's virt machine, firmware picks its platform code by them
+        csrs[MVENDORID] = QEMU_MVENDORID;
+        csrs[MARCHID] = QEMU_MARCHID;
+        csrs[MIMPID] = QEMU_MIMPID;
+        Self { csrs }
+    }
+
+    // zero everything except read-only machine information registers
+    pub fn reset(&mut self) {
+        for (addr, csr) in self.csrs.iter_mut().enumerate() {
+            match addr {
+                MVENDORID | MARCHID | MIMPID | MHARTID | MISA => (),
+                _ => *csr = 0,
+            }
         }
     }
 
@@ -15,10 +44,28 @@ impl Csr {
             SIE => self.csrs[MIE] & self.csrs[MIDELEG],
             SIP => self.csrs[MIP] & self.csrs[MIDELEG],
             SSTATUS => self.csrs[MSTATUS] & MASK_SSTATUS,
+            FFLAGS => self.csrs[FCSR] & MASK_FFLAGS,
+            FRM => (self.csrs[FCSR] >> 5) & 0b111,
+            // sstateen bits are read-only zero where the matching mstateen bit is clear
+            SSTATEEN0..=SSTATEEN3 => self.csrs[addr] & self.csrs[addr - SSTATEEN0 + MSTATEEN0],
+            // only exists on RV32, mseccfg holds all of it here
+            MSECCFGH => 0,
             _ => self.csrs[addr],
         }
     }
 
+    // load that also sees the counters kept by the cpu
+    pub fn load_with_counters(&self, addr: usize, counters: &CpuCounters) -> u64 {
+        match addr {
+            CYCLE => counters.cycles,
+            TIME => counters.time,
+            INSTRET => counters.instret,
+            // high halves only exist on RV32
+            CYCLEH | TIMEH | INSTRETH => 0,
+            _ => self.load(addr),
+        }
+    }
+
     pub fn store(&mut self, addr: usize, value: u64) {
         match addr {
             SIE => {
@@ -27,15 +74,45 @@ impl Csr {
             }
             SIP => {
                 self.csrs[MIP] =
-                    (self.csrs[MIE] & !self.csrs[MIDELEG]) | (value & self.csrs[MIDELEG])
+                    (self.csrs[MIP] & !self.csrs[MIDELEG]) | (value & self.csrs[MIDELEG])
             }
+            // MEIP is read-only, only the PLIC sets it through set_external_interrupt
+            MIP => self.csrs[MIP] = (self.csrs[MIP] & MASK_MEIP) | (value & !MASK_MEIP),
             SSTATUS => {
                 self.csrs[MSTATUS] = (self.csrs[MSTATUS] & !MASK_SSTATUS) | (value & MASK_SSTATUS)
             }
+            FFLAGS => self.csrs[FCSR] = (self.csrs[FCSR] & !MASK_FFLAGS) | (value & MASK_FFLAGS),
+            FRM => self.csrs[FCSR] = (self.csrs[FCSR] & MASK_FFLAGS) | ((value & 0b111) << 5),
+            FCSR => self.csrs[FCSR] = value & 0xff,
+            SSTATEEN0..=SSTATEEN3 => {
+                self.csrs[addr] = value & self.csrs[addr - SSTATEEN0 + MSTATEEN0]
+            }
+            // ecall from M-mode can't be delegated, medeleg bit 11 is read-only zero
+            MEDELEG => self.csrs[MEDELEG] = value & !MASK_MEDELEG_ECALL_M,
+            // M-level interrupts can't be delegated
+            MIDELEG => self.csrs[MIDELEG] = value & !MASK_MIDELEG_M,
+            PMPCFG0 | PMPCFG2 => self.csrs[addr] = pmp::write_pmpcfg(self, addr, value),
+            PMPADDR0..=PMPADDR15 if pmp::pmpaddr_locked(self, addr - PMPADDR0) => (),
+            PMPADDR0..=PMPADDR15 => self.csrs[addr] = value & pmp::MASK_PMPADDR,
+            MSECCFG => self.csrs[MSECCFG] = pmp::write_mseccfg(self, value),
+            MSECCFGH => (),
+            // fixed ids, set once in new
+            MVENDORID | MARCHID | MIMPID => (),
+            // only Bare and Sv39x4 are supported, other modes leave hgatp as it was
+            HGATP if !matches!(value >> 60, 0 | 8) => (),
             _ => self.csrs[addr] = value,
         }
     }
 
+    // mip.MEIP, the external interrupt line from the PLIC
+    pub fn set_external_interrupt(&mut self, pending: bool) {
+        if pending {
+            self.csrs[MIP] |= MASK_MEIP;
+        } else {
+            self.csrs[MIP] &= !MASK_MEIP;
+        }
+    }
+
     #[inline]
     pub fn is_medelegated(&self, cause: u64) -> bool {
         (self.csrs[MEDELEG].wrapping_shr(cause as u32) & 1) == 1
@@ -47,9 +124,51 @@ impl Csr {
     }
 }
 
+// Unprivileged floating-point CSRs, fflags and frm are views of fcsr.
+/// Accrued floating-point exceptions.
+pub const FFLAGS: usize = 0x001;
+/// Floating-point dynamic rounding mode.
+pub const FRM: usize = 0x002;
+/// Floating-point control and status register.
+pub const FCSR: usize = 0x003;
+
+// Unprivileged counters (Zicntr), read-only.
+/// Cycle counter.
+pub const CYCLE: usize = 0xc00;
+/// Timer, mirrors the clint mtime.
+pub const TIME: usize = 0xc01;
+/// Instructions retired counter.
+pub const INSTRET: usize = 0xc02;
+/// Upper halves of the counters, RV32 only.
+pub const CYCLEH: usize = 0xc80;
+pub const TIMEH: usize = 0xc81;
+pub const INSTRETH: usize = 0xc82;
+
+// Unprivileged vector CSRs, read-only, the values live in the cpu.
+/// Vector length.
+pub const VL: usize = 0xc20;
+/// Vector data type.
+pub const VTYPE: usize = 0xc21;
+/// Vector register length in bytes.
+pub const VLENB: usize = 0xc22;
+
+/// mvendorid, marchid and mimpid of QEMU's virt machine.
+pub const QEMU_MVENDORID: u64 = 0;
+pub const QEMU_MARCHID: u64 = 0x70200;
+pub const QEMU_MIMPID: u64 = 0x70200;
+
+/// Vendor ID.
+pub const MVENDORID: usize = 0xf11;
+/// Architecture ID.
+pub const MARCHID: usize = 0xf12;
+/// Implementation ID.
+pub const MIMPID: usize = 0xf13;
+/// Hardware thread ID.
 pub const MHARTID: usize = 0xf14;
 /// Machine status register.
 pub const MSTATUS: usize = 0x300;
+/// ISA and extensions.
+pub const MISA: usize = 0x301;
 /// Machine exception delefation register.
 pub const MEDELEG: usize = 0x302;
 /// Machine interrupt delefation register.
@@ -60,6 +179,22 @@ pub const MIE: usize = 0x304;
 pub const MTVEC: usize = 0x305;
 /// Machine counter enable.
 pub const MCOUNTEREN: usize = 0x306;
+/// Machine environment configuration.
+pub const MENVCFG: usize = 0x30a;
+/// Machine state enable registers (Smstateen).
+pub const MSTATEEN0: usize = 0x30c;
+pub const MSTATEEN1: usize = 0x30d;
+pub const MSTATEEN2: usize = 0x30e;
+pub const MSTATEEN3: usize = 0x30f;
+/// Physical memory protection configuration, RV64 only has the even ones.
+pub const PMPCFG0: usize = 0x3a0;
+pub const PMPCFG2: usize = 0x3a2;
+/// Physical memory protection addresses.
+pub const PMPADDR0: usize = 0x3b0;
+pub const PMPADDR15: usize = 0x3bf;
+/// Machine security configuration (Smepmp), the high half is RV32 only.
+pub const MSECCFG: usize = 0x747;
+pub const MSECCFGH: usize = 0x748;
 /// Scratch register for machine trap handlers.
 pub const MSCRATCH: usize = 0x340;
 /// Machine exception program counter.
@@ -78,6 +213,13 @@ pub const SSTATUS: usize = 0x100;
 pub const SIE: usize = 0x104;
 /// Supervisor trap handler base address.
 pub const STVEC: usize = 0x105;
+/// Supervisor environment configuration.
+pub const SENVCFG: usize = 0x10a;
+/// Supervisor state enable registers (Smstateen).
+pub const SSTATEEN0: usize = 0x10c;
+pub const SSTATEEN1: usize = 0x10d;
+pub const SSTATEEN2: usize = 0x10e;
+pub const SSTATEEN3: usize = 0x10f;
 /// Scratch register for supervisor trap handlers.
 pub const SSCRATCH: usize = 0x140;
 /// Supervisor exception program counter.
@@ -88,11 +230,147 @@ pub const SCAUSE: usize = 0x142;
 pub const STVAL: usize = 0x143;
 /// Supervisor interrupt pending.
 pub const SIP: usize = 0x144;
+/// Supervisor timer compare (Sstc).
+pub const STIMECMP: usize = 0x14d;
 /// Supervisor address translation and protection.
 pub const SATP: usize = 0x180;
 
+// Hypervisor CSRs, HS-mode accesses them like supervisor CSRs. Guests are never
+// entered (V is always 0), so they only hold what software writes.
+/// Hypervisor status register.
+pub const HSTATUS: usize = 0x600;
+/// Hypervisor exception delegation.
+pub const HEDELEG: usize = 0x602;
+/// Hypervisor interrupt delegation.
+pub const HIDELEG: usize = 0x603;
+/// Hypervisor interrupt-enable register.
+pub const HIE: usize = 0x604;
+/// Time offset of the guest.
+pub const HTIMEDELTA: usize = 0x605;
+/// Hypervisor counter enable.
+pub const HCOUNTEREN: usize = 0x606;
+/// Hypervisor guest external interrupt-enable register.
+pub const HGEIE: usize = 0x607;
+/// Hypervisor bad guest physical address.
+pub const HTVAL: usize = 0x643;
+/// Hypervisor interrupt pending.
+pub const HIP: usize = 0x644;
+/// Hypervisor virtual interrupt pending.
+pub const HVIP: usize = 0x645;
+/// Hypervisor trap instruction.
+pub const HTINST: usize = 0x64a;
+/// Hypervisor guest address translation and protection.
+pub const HGATP: usize = 0x680;
+/// Hypervisor guest external interrupt pending, read-only.
+pub const HGEIP: usize = 0xe12;
+
+// symbolic names of known CSRs, "" for the rest
+pub static CSR_NAMES: [&str; NUM_CSRS] = {
+    let mut names = [""; NUM_CSRS];
+    names[FFLAGS] = "fflags";
+    names[FRM] = "frm";
+    names[FCSR] = "fcsr";
+    names[CYCLE] = "cycle";
+    names[TIME] = "time";
+    names[INSTRET] = "instret";
+    names[CYCLEH] = "cycleh";
+    names[TIMEH] = "timeh";
+    names[INSTRETH] = "instreth";
+    names[VL] = "vl";
+    names[VTYPE] = "vtype";
+    names[VLENB] = "vlenb";
+    names[MVENDORID] = "mvendorid";
+    names[MARCHID] = "marchid";
+    names[MIMPID] = "mimpid";
+    names[MHARTID] = "mhartid";
+    names[MSTATUS] = "mstatus";
+    names[MISA] = "misa";
+    names[MEDELEG] = "medeleg";
+    names[MIDELEG] = "mideleg";
+    names[MIE] = "mie";
+    names[MTVEC] = "mtvec";
+    names[MCOUNTEREN] = "mcounteren";
+    names[MENVCFG] = "menvcfg";
+    names[MSTATEEN0] = "mstateen0";
+    names[MSTATEEN1] = "mstateen1";
+    names[MSTATEEN2] = "mstateen2";
+    names[MSTATEEN3] = "mstateen3";
+    names[MSECCFG] = "mseccfg";
+    names[MSECCFGH] = "mseccfgh";
+    names[PMPCFG0] = "pmpcfg0";
+    names[PMPCFG2] = "pmpcfg2";
+    names[PMPADDR0] = "pmpaddr0";
+    names[PMPADDR0 + 1] = "pmpaddr1";
+    names[PMPADDR0 + 2] = "pmpaddr2";
+    names[PMPADDR0 + 3] = "pmpaddr3";
+    names[PMPADDR0 + 4] = "pmpaddr4";
+    names[PMPADDR0 + 5] = "pmpaddr5";
+    names[PMPADDR0 + 6] = "pmpaddr6";
+    names[PMPADDR0 + 7] = "pmpaddr7";
+    names[PMPADDR0 + 8] = "pmpaddr8";
+    names[PMPADDR0 + 9] = "pmpaddr9";
+    names[PMPADDR0 + 10] = "pmpaddr10";
+    names[PMPADDR0 + 11] = "pmpaddr11";
+    names[PMPADDR0 + 12] = "pmpaddr12";
+    names[PMPADDR0 + 13] = "pmpaddr13";
+    names[PMPADDR0 + 14] = "pmpaddr14";
+    names[PMPADDR15] = "pmpaddr15";
+    names[MSCRATCH] = "mscratch";
+    names[MEPC] = "mepc";
+    names[MCAUSE] = "mcause";
+    names[MTVAL] = "mtval";
+    names[MIP] = "mip";
+    names[SSTATUS] = "sstatus";
+    names[SIE] = "sie";
+    names[STVEC] = "stvec";
+    names[SENVCFG] = "senvcfg";
+    names[SSTATEEN0] = "sstateen0";
+    names[SSTATEEN1] = "sstateen1";
+    names[SSTATEEN2] = "sstateen2";
+    names[SSTATEEN3] = "sstateen3";
+    names[SSCRATCH] = "sscratch";
+    names[SEPC] = "sepc";
+    names[SCAUSE] = "scause";
+    names[STVAL] = "stval";
+    names[SIP] = "sip";
+    names[STIMECMP] = "stimecmp";
+    names[SATP] = "satp";
+    names[HSTATUS] = "hstatus";
+    names[HEDELEG] = "hedeleg";
+    names[HIDELEG] = "hideleg";
+    names[HIE] = "hie";
+    names[HTIMEDELTA] = "htimedelta";
+    names[HCOUNTEREN] = "hcounteren";
+    names[HGEIE] = "hgeie";
+    names[HTVAL] = "htval";
+    names[HIP] = "hip";
+    names[HVIP] = "hvip";
+    names[HTINST] = "htinst";
+    names[HGATP] = "hgatp";
+    names[HGEIP] = "hgeip";
+    names
+};
+
+pub const MASK_FFLAGS: u64 = 0x1f;
+
+// medeleg bit of mcause 11, environment call from M-mode
+pub const MASK_MEDELEG_ECALL_M: u64 = 1 << 11;
+
+// mideleg bits of the M-level interrupts, which always trap to M-mode
+pub const MASK_MIDELEG_M: u64 = MASK_MSIP | MASK_MTIP | MASK_MEIP;
+
 pub const MASK_PPN: u64 = (1 << 44) - 1;
 
+// menvcfg.STCE, enables stimecmp
+pub const MASK_STCE: u64 = 1 << 63;
+// menvcfg.PBMTE, enables Svpbmt memory types for S and U-mode
+pub const MASK_PBMTE: u64 = 1 << 62;
+
+// mstateen0.SE0 enables sstateen0 below M-mode (bit 63 of mstateenN for sstateenN),
+// mstateen0.ENVCFG enables senvcfg
+pub const MASK_STATEEN_SE0: u64 = 1 << 63;
+pub const MASK_STATEEN_ENVCFG: u64 = 1 << 62;
+
 pub const MASK_SIE: u64 = 1 << 1;
 pub const MASK_MIE: u64 = 1 << 3;
 pub const MASK_SPIE: u64 = 1 << 5;
@@ -132,3 +410,5 @@ pub const MASK_STIP: u64 = 1 << 5;
 pub const MASK_MTIP: u64 = 1 << 7;
 pub const MASK_SEIP: u64 = 1 << 9;
 pub const MASK_MEIP: u64 = 1 << 11;
+// MIE / SIE enable bits sit at the same positions
+pub const MASK_STIE: u64 = MASK_STIP;
@@ -0,0 +1,159 @@
+use crate::{exept::Exception, param::DM_BASE};
+
+// Debug module registers, word offsets from the debug spec (DMI addresses)
+pub const DM_DATA0: u64 = 0x04;
+pub const DM_DATA11: u64 = 0x0f;
+pub const DM_DMCONTROL: u64 = 0x10;
+pub const DM_DMSTATUS: u64 = 0x11;
+pub const DM_ABSTRACTCS: u64 = 0x16;
+pub const DM_COMMAND: u64 = 0x17;
+pub const DM_PROGBUF0: u64 = 0x20;
+pub const DM_PROGBUF15: u64 = 0x2f;
+
+// abstractcs.cmderr values
+pub const CMDERR_NONE: u32 = 0;
+pub const CMDERR_NOT_SUPPORTED: u32 = 2;
+pub const CMDERR_EXCEPTION: u32 = 3;
+
+// regno ranges of the access register command
+pub const REGNO_CSR_END: u32 = 0x0fff;
+pub const REGNO_GPR: u32 = 0x1000;
+pub const REGNO_FPR: u32 = 0x1020;
+
+// dmstatus: version 3 (debug spec 1.0), authenticated, the hart is running
+const DMSTATUS: u32 = 3 | (1 << 7) | (1 << 10) | (1 << 11);
+
+// An access register command, the fields of command with cmdtype 0
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccessRegister {
+    pub regno: u32,
+    pub write: bool,
+    pub transfer: bool,
+    pub postincrement: bool,
+    pub postexec: bool,
+    // 2 = 32 bits, 3 = 64 bits
+    pub aarsize: u32,
+}
+
+impl AccessRegister {
+    pub fn decode(command: u32) -> Option<Self> {
+        if command >> 24 != 0 {
+            return None;
+        }
+        Some(Self {
+            regno: command & 0xffff,
+            write: (command >> 16) & 1 != 0,
+            transfer: (command >> 17) & 1 != 0,
+            postexec: (command >> 18) & 1 != 0,
+            postincrement: (command >> 19) & 1 != 0,
+            aarsize: (command >> 20) & 0b111,
+        })
+    }
+}
+
+// Debug module with abstract commands, reachable on the bus at DM_BASE. The hart
+// is never halted, a written command is carried out by the cpu right after the store.
+pub struct DebugModule {
+    data: [u32; 12],
+    progbuf: [u32; 16],
+    dmcontrol: u32,
+    command: u32,
+    cmderr: u32,
+    // written command the cpu hasn't carried out yet
+    pending: Option<u32>,
+}
+
+impl Default for DebugModule {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DebugModule {
+    pub fn new() -> Self {
+        Self {
+            data: [0; 12],
+            progbuf: [0; 16],
+            dmcontrol: 0,
+            command: 0,
+            cmderr: CMDERR_NONE,
+            pending: None,
+        }
+    }
+
+    pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
+        let offset = match register(addr, size) {
+            Some(offset) => offset,
+            None => return Err(Exception::LoadAccessFault(addr)),
+        };
+        let value = match offset {
+            DM_DATA0..=DM_DATA11 => self.data[(offset - DM_DATA0) as usize],
+            DM_DMCONTROL => self.dmcontrol,
+            DM_DMSTATUS => DMSTATUS,
+            // datacount 12, progbufsize 16, never busy
+            DM_ABSTRACTCS => 12 | (self.cmderr << 8) | (16 << 24),
+            DM_COMMAND => self.command,
+            DM_PROGBUF0..=DM_PROGBUF15 => self.progbuf[(offset - DM_PROGBUF0) as usize],
+            _ => 0,
+        };
+        Ok(value as u64)
+    }
+
+    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        let offset = match register(addr, size) {
+            Some(offset) => offset,
+            None => return Err(Exception::StoreAMOAccessFault(addr)),
+        };
+        let value = value as u32;
+        match offset {
+            DM_DATA0..=DM_DATA11 => self.data[(offset - DM_DATA0) as usize] = value,
+            DM_DMCONTROL => self.dmcontrol = value,
+            // cmderr is write 1 to clear
+            DM_ABSTRACTCS => self.cmderr &= !((value >> 8) & 0b111),
+            // commands are ignored until cmderr is cleared
+            DM_COMMAND if self.cmderr == CMDERR_NONE => {
+                self.command = value;
+                self.pending = Some(value);
+            }
+            DM_PROGBUF0..=DM_PROGBUF15 => self.progbuf[(offset - DM_PROGBUF0) as usize] = value,
+            _ => (),
+        }
+        Ok(())
+    }
+
+    // command written since the last call
+    pub fn take_command(&mut self) -> Option<u32> {
+        self.pending.take()
+    }
+
+    pub fn set_cmderr(&mut self, cmderr: u32) {
+        self.cmderr = cmderr;
+    }
+
+    // aarpostincrement, regno of the command register moves to the next register
+    pub fn increment_regno(&mut self) {
+        let regno = (self.command & 0xffff).wrapping_add(1) & 0xffff;
+        self.command = (self.command & !0xffff) | regno;
+    }
+
+    // data0 and data1 as one 64-bit value
+    pub fn data(&self) -> u64 {
+        self.data[0] as u64 | ((self.data[1] as u64) << 32)
+    }
+
+    pub fn set_data(&mut self, value: u64, aarsize: u32) {
+        self.data[0] = value as u32;
+        if aarsize == 3 {
+            self.data[1] = (value >> 32) as u32;
+        }
+    }
+}
+
+// word offset of an aligned 32-bit access
+fn register(addr: u64, size: u64) -> Option<u64> {
+    let offset = addr.checked_sub(DM_BASE)?;
+    if size != 32 || !offset.is_multiple_of(4) {
+        return None;
+    }
+    Some(offset / 4)
+}
@@ -0,0 +1,154 @@
+use std::{
+    collections::BTreeSet,
+    io::{self, BufRead, Write},
+};
+
+use crate::cpu::{
+    cpu::{Cpu, StepResult},
+    disasm,
+};
+
+const HELP: &str = "s - step, c - continue, b <addr> - set breakpoint, d <addr> - delete breakpoint
+r - registers, csrs - non-zero CSRs, m <addr> <n> - dump n bytes
+w <addr> <value> <size> - write size bytes, dis <addr> <n> - n instructions, q - quit";
+
+// Interactive debugger, reads one command per line.
+// All addresses and values are hex, with or without 0x.
+pub struct Debugger {
+    pub cpu: Cpu,
+    breakpoints: BTreeSet<u64>,
+}
+
+impl Debugger {
+    pub fn new(cpu: Cpu) -> Self {
+        Self {
+            cpu,
+            breakpoints: BTreeSet::new(),
+        }
+    }
+
+    pub fn run<R: BufRead, W: Write>(&mut self, input: R, output: &mut W) -> io::Result<()> {
+        write!(output, "> ")?;
+        output.flush()?;
+        for line in input.lines() {
+            let line = line?;
+            let args: Vec<&str> = line.split_whitespace().collect();
+            if args.first() == Some(&"q") {
+                break;
+            }
+            if let Err(msg) = self.command(&args, output) {
+                writeln!(output, "{}", msg)?;
+            }
+            write!(output, "> ")?;
+            output.flush()?;
+        }
+        Ok(())
+    }
+
+    fn command<W: Write>(&mut self, args: &[&str], output: &mut W) -> Result<(), String> {
+        match args {
+            [] => Ok(()),
+            ["s"] => self.step(output).map(|_| ()),
+            ["c"] => {
+                // always move past a breakpoint we're currently sitting on
+                while self.step(output)? {
+                    if self.breakpoints.contains(&self.cpu.pc) {
+                        write_out(output, format!("breakpoint {:#x}", self.cpu.pc))?;
+                        break;
+                    }
+                }
+                Ok(())
+            }
+            ["b", addr] => {
+                self.breakpoints.insert(parse_hex(addr)?);
+                Ok(())
+            }
+            ["d", addr] => {
+                let addr = parse_hex(addr)?;
+                if !self.breakpoints.remove(&addr) {
+                    return Err(format!("no breakpoint at {:#x}", addr));
+                }
+                Ok(())
+            }
+            ["r"] => write_out(output, self.cpu.format_registers()),
+            ["csrs"] => write_out(output, self.cpu.format_csrs()),
+            ["m", addr, n] => {
+                let addr = parse_hex(addr)?;
+                let n = parse_hex(n)?;
+                let mut text = String::new();
+                for i in 0..n {
+                    if i % 16 == 0 {
+                        if i != 0 {
+                            text.push('\n');
+                        }
+                        text += &format!("{:#x}:", addr + i);
+                    }
+                    let byte = self.cpu.load(addr + i, 8).map_err(|e| e.to_string())?;
+                    text += &format!(" {:02x}", byte);
+                }
+                write_out(output, text)
+            }
+            ["w", addr, value, size] => {
+                let addr = parse_hex(addr)?;
+                let value = parse_hex(value)?;
+                let size = match parse_hex(size)? {
+                    size @ (1 | 2 | 4 | 8) => size * 8,
+                    size => return Err(format!("invalid size {}", size)),
+                };
+                self.cpu.store(addr, size, value).map_err(|e| e.to_string())
+            }
+            ["dis", addr, n] => {
+                // fetched like instructions are, so data endianness doesn't apply
+                let mut pc = parse_hex(addr)?;
+                for _ in 0..parse_hex(n)? {
+                    let (_, inst) = self.cpu.fetch_at(pc).map_err(|e| e.to_string())?;
+                    let text = match disasm::decode(inst as u32) {
+                        Ok(decoded) => disasm::format(&decoded),
+                        Err(_) => "unknown".to_string(),
+                    };
+                    write_out(output, format!("{:#x}: {:#010x} {}", pc, inst, text))?;
+                    pc += if inst & 0b11 == 0b11 { 4 } else { 2 };
+                }
+                Ok(())
+            }
+            ["h"] | ["help"] => write_out(output, HELP.to_string()),
+            _ => Err(format!("unknown command: {}", args.join(" "))),
+        }
+    }
+
+    // returns false if execution can't go on
+    fn step<W: Write>(&mut self, output: &mut W) -> Result<bool, String> {
+        let pc = self.cpu.pc;
+        match self.cpu.step() {
+            StepResult::Ok => {
+                if let Some(&(last_pc, inst)) = self.cpu.history.front() {
+                    if last_pc == pc {
+                        write_out(output, format!("{:#x}: {:#010x}", pc, inst))?;
+                    }
+                }
+                Ok(true)
+            }
+            StepResult::Halt => {
+                write_out(output, format!("halted at {:#x}", pc))?;
+                Ok(false)
+            }
+            StepResult::Fatal(e) => {
+                write_out(output, format!("{}", e))?;
+                Ok(false)
+            }
+            StepResult::BudgetExhausted => {
+                write_out(output, format!("budget exhausted at {:#x}", pc))?;
+                Ok(false)
+            }
+        }
+    }
+}
+
+fn write_out<W: Write>(output: &mut W, text: String) -> Result<(), String> {
+    writeln!(output, "{}", text).map_err(|e| e.to_string())
+}
+
+fn parse_hex(s: &str) -> Result<u64, String> {
+    let digits = s.trim_start_matches("0x");
+    u64::from_str_radix(digits, 16).map_err(|_| format!("invalid number: {}", s))
+}
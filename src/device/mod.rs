@@ -1,2 +1,3 @@
+pub mod rom;
 pub mod uart;
 pub mod virtio;
@@ -0,0 +1,60 @@
+use std::io::{self, ErrorKind};
+
+use crate::{
+    exept::Exception,
+    param::{ROM_BASE, ROM_DTB, ROM_SIZE},
+};
+
+// Read-only boot rom: a reset vector at ROM_BASE and room for a device tree at ROM_DTB.
+// The stub is the usual firmware handoff, a0 = hart id 0, a1 = dtb address, jump to entry.
+pub struct Rom {
+    rom: Vec<u8>,
+}
+
+// auipc t0, 0; li a0, 0; addi a1, t0, 0x100; ld t0, 24(t0); jr t0; then the entry address
+const RESET_VECTOR: [u32; 6] = [
+    0x00000297, 0x00000513, 0x10028593, 0x0182b283, 0x00028067, 0x00000000,
+];
+
+impl Rom {
+    // entry is where the reset vector jumps, normally the start of dram
+    pub fn new(entry: u64) -> Self {
+        let mut rom = vec![0; ROM_SIZE as usize];
+        for (i, inst) in RESET_VECTOR.iter().enumerate() {
+            rom[i * 4..i * 4 + 4].copy_from_slice(&inst.to_le_bytes());
+        }
+        rom[24..32].copy_from_slice(&entry.to_le_bytes());
+        Self { rom }
+    }
+
+    // device tree blob handed to the kernel in a1
+    pub fn set_dtb(&mut self, dtb: &[u8]) -> io::Result<()> {
+        let start = (ROM_DTB - ROM_BASE) as usize;
+        if dtb.len() > self.rom.len() - start {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("dtb of {} bytes doesn't fit in the rom", dtb.len()),
+            ));
+        }
+        self.rom[start..start + dtb.len()].copy_from_slice(dtb);
+        Ok(())
+    }
+
+    pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
+        let bytes = (size / 8) as usize;
+        if ![8, 16, 32, 64].contains(&size)
+            || addr < ROM_BASE
+            || addr - ROM_BASE + bytes as u64 > ROM_SIZE
+        {
+            return Err(Exception::LoadAccessFault(addr));
+        }
+        let index = (addr - ROM_BASE) as usize;
+        let mut value = [0; 8];
+        value[..bytes].copy_from_slice(&self.rom[index..index + bytes]);
+        Ok(u64::from_le_bytes(value))
+    }
+
+    pub fn store(&self, addr: u64, _size: u64, _value: u64) -> Result<(), Exception> {
+        Err(Exception::StoreAMOAccessFault(addr))
+    }
+}
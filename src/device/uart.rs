@@ -1,5 +1,6 @@
 use std::{
     array,
+    collections::VecDeque,
     io::{self, Read, Write},
     ops::Index,
     sync::{atomic::AtomicBool, Arc, Condvar, Mutex},
@@ -9,7 +10,8 @@ use std::{
 use crate::{
     exept::Exception,
     param::{
-        MASK_UART_LSR_RX, MASK_UART_LSR_TX, UART_BASE, UART_LSR, UART_RHR, UART_SIZE, UART_THR,
+        MASK_UART_IER_RX, MASK_UART_IER_TX, MASK_UART_LCR_DLAB, MASK_UART_LSR_RX, MASK_UART_LSR_TX,
+        UART_BASE, UART_IER, UART_LCR, UART_LSR, UART_RHR, UART_SIZE, UART_THR,
     },
 };
 
@@ -18,10 +20,17 @@ pub struct Uart {
     uart: Arc<(Mutex<[u8; UART_SIZE as usize]>, Condvar)>,
     // bit if interrupt happens
     interrupt: Arc<AtomicBool>,
+    // input fed from the cpu thread instead of stdin
+    script: VecDeque<u8>,
+    // THR written since the last transmit interrupt
+    thr_written: bool,
+    // baud rate divisor, low and high byte, behind LCR.DLAB
+    divisor: [u8; 2],
 }
 
 impl Uart {
-    pub fn new() -> Self {
+    // read_stdin - feed bytes from host stdin to the receiver
+    pub fn new(read_stdin: bool) -> Self {
         let mut array = [0; UART_SIZE as usize];
         // tell LSR that THR is empty, CPU will load next char
         array[UART_LSR as usize] |= MASK_UART_LSR_TX;
@@ -29,6 +38,16 @@ impl Uart {
         let uart = Arc::new(((Mutex::new(array)), Condvar::new()));
         let interrupt = Arc::new(AtomicBool::new(false));
 
+        if !read_stdin {
+            return Self {
+                uart,
+                interrupt,
+                script: VecDeque::new(),
+                thr_written: false,
+                divisor: [0; 2],
+            };
+        }
+
         // recieve part
         let read_uart = Arc::clone(&uart);
         let read_interrupt = Arc::clone(&interrupt);
@@ -53,7 +72,20 @@ impl Uart {
             }
         });
 
-        Self { uart, interrupt }
+        Self {
+            uart,
+            interrupt,
+            script: VecDeque::new(),
+            thr_written: false,
+            divisor: [0; 2],
+        }
+    }
+
+    // no stdin thread, the receiver gets bytes from script one at a time
+    pub fn with_script(script: Vec<u8>) -> Self {
+        let mut uart = Self::new(false);
+        uart.script = script.into();
+        uart
     }
 
     pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
@@ -64,8 +96,10 @@ impl Uart {
         let (uart, cvar) = &*self.uart;
         let mut array = uart.lock().unwrap();
         let index = addr - UART_BASE;
+        let dlab = array[UART_LCR as usize] & MASK_UART_LCR_DLAB != 0;
         // a read happens
         match index {
+            UART_RHR | UART_IER if dlab => Ok(self.divisor[index as usize] as u64),
             UART_RHR => {
                 // waking up cvar.wait
                 cvar.notify_one();
@@ -84,21 +118,49 @@ impl Uart {
         let (uart, cvar) = &*self.uart;
         let mut array = uart.lock().unwrap();
         let index = addr - UART_BASE;
+        let dlab = array[UART_LCR as usize] & MASK_UART_LCR_DLAB != 0;
         match index {
+            UART_THR | UART_IER if dlab => self.divisor[index as usize] = value as u8,
             UART_THR => {
                 print!("{}", value as u8 as char);
                 io::stdout().flush().unwrap();
-                return Ok(());
-            }
-            _ => {
-                array[index as usize] = value as u8;
-                return Ok(());
+                self.thr_written = true;
             }
+            // the upper four bits are reserved
+            UART_IER => array[UART_IER as usize] = value as u8 & 0x0f,
+            _ => array[index as usize] = value as u8,
         }
+        Ok(())
     }
 
-    pub fn is_interrupting(&self) -> bool {
-        self.interrupt
-            .swap(false, std::sync::atomic::Ordering::Acquire)
+    // is_interrupting without taking the interrupt, for wfi
+    pub fn interrupt_pending(&self) -> bool {
+        let (uart, _) = &*self.uart;
+        let ier = uart.lock().unwrap()[UART_IER as usize];
+        let rx =
+            !self.script.is_empty() || self.interrupt.load(std::sync::atomic::Ordering::Acquire);
+        (ier & MASK_UART_IER_RX != 0 && rx) || (ier & MASK_UART_IER_TX != 0 && self.thr_written)
+    }
+
+    // Received data only interrupts while IER enables it, until then the
+    // interrupt stays pending. A transmit interrupt follows each THR write.
+    pub fn is_interrupting(&mut self) -> bool {
+        let (uart, _) = &*self.uart;
+        let mut array = uart.lock().unwrap();
+        if !self.script.is_empty() && array[UART_LSR as usize] & MASK_UART_LSR_RX == 0 {
+            array[UART_RHR as usize] = self.script.pop_front().unwrap();
+            array[UART_LSR as usize] |= MASK_UART_LSR_RX;
+            self.interrupt
+                .store(true, std::sync::atomic::Ordering::Release);
+        }
+        let ier = array[UART_IER as usize];
+        if ier & MASK_UART_IER_RX != 0
+            && self
+                .interrupt
+                .swap(false, std::sync::atomic::Ordering::Acquire)
+        {
+            return true;
+        }
+        ier & MASK_UART_IER_TX != 0 && std::mem::take(&mut self.thr_written)
     }
 }
@@ -0,0 +1,12 @@
+use std::io;
+
+pub mod tap;
+
+// Where virtio-net frames go to and come from. A user-mode backend on libslirp,
+// which needs no privileges, is planned next to tap.
+pub trait NetworkBackend: Send {
+    // send one ethernet frame
+    fn send_packet(&mut self, data: &[u8]) -> io::Result<()>;
+    // replace buf with the next received frame, false if none is waiting
+    fn recv_packet(&mut self, buf: &mut Vec<u8>) -> io::Result<bool>;
+}
@@ -0,0 +1,106 @@
+use std::io;
+
+use super::NetworkBackend;
+
+#[cfg(target_os = "linux")]
+// largest frame read from the interface, an ethernet frame with room for a vlan tag
+const MAX_FRAME: usize = 1522;
+
+// Raw ethernet frames through a Linux TAP interface. Opening one needs
+// CAP_NET_ADMIN, or an interface created beforehand for this user.
+pub struct TapBackend {
+    #[cfg(target_os = "linux")]
+    file: std::fs::File,
+}
+
+#[cfg(target_os = "linux")]
+impl TapBackend {
+    // attach to the TAP interface name, it's created if it doesn't exist
+    pub fn open(name: &str) -> io::Result<Self> {
+        use std::{fs::OpenOptions, os::unix::fs::OpenOptionsExt, os::unix::io::AsRawFd};
+
+        // struct ifreq, only the name and flags are used
+        #[repr(C)]
+        struct IfReq {
+            name: [u8; libc::IFNAMSIZ],
+            flags: libc::c_short,
+            _pad: [u8; 22],
+        }
+
+        if name.is_empty() || name.len() >= libc::IFNAMSIZ {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("bad TAP device name {:?}", name),
+            ));
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open("/dev/net/tun")?;
+        let mut req = IfReq {
+            name: [0; libc::IFNAMSIZ],
+            flags: (libc::IFF_TAP | libc::IFF_NO_PI) as libc::c_short,
+            _pad: [0; 22],
+        };
+        req.name[..name.len()].copy_from_slice(name.as_bytes());
+        // SAFETY: req is a valid ifreq for TUNSETIFF and outlives the call
+        if unsafe { libc::ioctl(file.as_raw_fd(), libc::TUNSETIFF, &mut req) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { file })
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl NetworkBackend for TapBackend {
+    fn send_packet(&mut self, data: &[u8]) -> io::Result<()> {
+        use std::io::Write;
+
+        // nothing to put on the wire
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.file.write_all(data)
+    }
+
+    fn recv_packet(&mut self, buf: &mut Vec<u8>) -> io::Result<bool> {
+        use std::io::Read;
+
+        buf.resize(MAX_FRAME, 0);
+        match self.file.read(buf) {
+            Ok(n) => {
+                buf.truncate(n);
+                Ok(true)
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                buf.clear();
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unsupported() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "TAP not available")
+}
+
+#[cfg(not(target_os = "linux"))]
+impl TapBackend {
+    pub fn open(_name: &str) -> io::Result<Self> {
+        Err(unsupported())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl NetworkBackend for TapBackend {
+    fn send_packet(&mut self, _data: &[u8]) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    fn recv_packet(&mut self, _buf: &mut Vec<u8>) -> io::Result<bool> {
+        Err(unsupported())
+    }
+}
@@ -1,2 +1,6 @@
+pub mod backends;
 pub mod virtio;
+pub mod virtio_console;
+pub mod virtio_net;
+pub mod virtio_rng;
 pub mod virtqueue;
@@ -1,7 +1,35 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
 use crate::{exept::Exception, param::*};
 
+// Where the disk content lives.
+pub enum DiskBackend {
+    Memory(Vec<u8>),
+    // reader and writer share one file, writes go straight back to it
+    File {
+        reader: BufReader<File>,
+        writer: BufWriter<File>,
+        size: u64,
+        // where the next byte comes from without a seek, None after a write
+        read_pos: Option<u64>,
+        write_pos: Option<u64>,
+    },
+}
+
+impl DiskBackend {
+    fn size(&self) -> u64 {
+        match self {
+            DiskBackend::Memory(disk) => disk.len() as u64,
+            DiskBackend::File { size, .. } => *size,
+        }
+    }
+}
+
 pub struct VirtioBlock {
-    id: u64,
     driver_features: u32,
     page_size: u32,
     queue_sel: u32,
@@ -9,18 +37,33 @@ pub struct VirtioBlock {
     queue_pfn: u32,
     queue_notify: u32,
     status: u32,
-    disk: Vec<u8>,
+    // next available ring entry to be processed
+    last_avail_idx: u16,
+    disk: DiskBackend,
 }
 
 const MAX_BLOCK_QUEUE: u32 = 1;
 
 impl VirtioBlock {
     pub fn new(disk_image: Vec<u8>) -> Self {
-        let mut disk = Vec::new();
-        disk.extend(disk_image.into_iter());
+        Self::with_backend(DiskBackend::Memory(disk_image))
+    }
+
+    // disk backed by the file at path, guest writes change the file
+    pub fn new_from_file(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self::with_backend(DiskBackend::File {
+            reader: BufReader::new(file.try_clone()?),
+            writer: BufWriter::new(file),
+            size,
+            read_pos: None,
+            write_pos: None,
+        }))
+    }
 
+    fn with_backend(disk: DiskBackend) -> Self {
         Self {
-            id: 0,
             driver_features: 0,
             page_size: 0,
             queue_sel: 0,
@@ -28,7 +71,8 @@ impl VirtioBlock {
             queue_pfn: 0,
             queue_notify: MAX_BLOCK_QUEUE,
             status: 0,
-            disk: disk,
+            last_avail_idx: 0,
+            disk,
         }
     }
 
@@ -47,6 +91,7 @@ impl VirtioBlock {
 
         match addr {
             VIRTIO_MAGIC => Ok(0x74726976),
+            // legacy interface (QUEUE_PFN / GUEST_PAGE_SIZE), which is what xv6 drives
             VIRTIO_VERSION => Ok(0x1),
             VIRTIO_DEVICE_ID => Ok(0x2),
             VIRTIO_VENDOR_ID => Ok(0x554d4551),
@@ -55,6 +100,9 @@ impl VirtioBlock {
             VIRTIO_QUEUE_NUM_MAX => Ok(8),
             VIRTIO_QUEUE_PFN => Ok(self.queue_pfn as u64),
             VIRTIO_STATUS => Ok(self.status as u64),
+            // config space, capacity in 512-byte sectors
+            VIRTIO_CONFIG => Ok(self.capacity() & 0xffff_ffff),
+            a if a == VIRTIO_CONFIG + 4 => Ok(self.capacity() >> 32),
             _ => Ok(0),
         }
     }
@@ -67,31 +115,136 @@ impl VirtioBlock {
         let value = value as u32;
 
         match addr {
-            VIRTIO_DEVICE_FEATURES => Ok(self.driver_features = value),
+            VIRTIO_DRIVER_FEATURES => Ok(self.driver_features = value),
             VIRTIO_GUEST_PAGE_SIZE => Ok(self.page_size = value),
             VIRTIO_QUEUE_SEL => Ok(self.queue_sel = value),
             VIRTIO_QUEUE_NUM => Ok(self.queue_num = value),
             VIRTIO_QUEUE_PFN => Ok(self.queue_pfn = value),
-            VIRTIO_QUEUE_NOTIFY => Ok(self.queue_notify = value),
-            VIRTIO_STATUS => Ok(self.status = value),
+            VIRTIO_QUEUE_NOTIFY => {
+                // the device is not live until the driver sets DRIVER_OK
+                if self.status & VIRTIO_STATUS_DRIVER_OK != 0 {
+                    self.queue_notify = value;
+                }
+                Ok(())
+            }
+            VIRTIO_STATUS => {
+                // writing zero resets the device, so does a driver giving up with FAILED
+                if value == 0 || value & VIRTIO_STATUS_FAILED != 0 {
+                    self.reset();
+                } else {
+                    self.status = value;
+                }
+                Ok(())
+            }
             _ => Ok(()),
         }
     }
 
-    pub fn get_new_id(&mut self) -> u64 {
-        self.id = self.id.wrapping_add(1);
-        return self.id;
+    // back to the state right after power-on, disk content is kept
+    pub fn reset(&mut self) {
+        self.driver_features = 0;
+        self.page_size = 0;
+        self.queue_sel = 0;
+        self.queue_num = 0;
+        self.queue_pfn = 0;
+        self.queue_notify = MAX_BLOCK_QUEUE;
+        self.status = 0;
+        self.last_avail_idx = 0;
+    }
+
+    // returns the index of the next request, if the driver has added one
+    pub fn next_avail(&mut self, avail_idx: u16) -> Option<u16> {
+        if self.last_avail_idx == avail_idx {
+            return None;
+        }
+        let idx = self.last_avail_idx;
+        self.last_avail_idx = self.last_avail_idx.wrapping_add(1);
+        Some(idx)
     }
 
     pub fn desc_addr(&self) -> u64 {
         self.queue_pfn as u64 * self.page_size as u64
     }
 
-    pub fn read_disk(&self, addr: u64) -> u64 {
-        self.disk[addr as usize] as u64
+    pub fn capacity(&self) -> u64 {
+        self.disk.size() / SECTOR_SIZE
+    }
+
+    // true if len bytes from addr are all on the disk
+    pub fn in_range(&self, addr: u64, len: u64) -> bool {
+        addr.checked_add(len)
+            .is_some_and(|end| end <= self.disk.size())
+    }
+
+    pub fn read_disk(&mut self, addr: u64) -> io::Result<u64> {
+        if !self.in_range(addr, 1) {
+            return Err(past_end(addr));
+        }
+        match &mut self.disk {
+            DiskBackend::Memory(disk) => Ok(disk[addr as usize] as u64),
+            DiskBackend::File {
+                reader,
+                writer,
+                read_pos,
+                ..
+            } => {
+                if *read_pos != Some(addr) {
+                    // the reader has to see everything written so far
+                    writer.flush()?;
+                    reader.seek(SeekFrom::Start(addr))?;
+                }
+                let mut byte = [0];
+                reader.read_exact(&mut byte)?;
+                *read_pos = Some(addr + 1);
+                Ok(byte[0] as u64)
+            }
+        }
+    }
+
+    pub fn write_disk(&mut self, addr: u64, value: u64) -> io::Result<()> {
+        if !self.in_range(addr, 1) {
+            return Err(past_end(addr));
+        }
+        match &mut self.disk {
+            DiskBackend::Memory(disk) => disk[addr as usize] = value as u8,
+            DiskBackend::File {
+                writer,
+                read_pos,
+                write_pos,
+                ..
+            } => {
+                if *write_pos != Some(addr) {
+                    writer.seek(SeekFrom::Start(addr))?;
+                }
+                writer.write_all(&[value as u8])?;
+                *write_pos = Some(addr + 1);
+                // bytes the reader buffered may be stale now
+                *read_pos = None;
+            }
+        }
+        Ok(())
     }
 
-    pub fn write_disk(&mut self, addr: u64, value: u64) {
-        self.disk[addr as usize] = value as u8;
+    // VIRTIO_BLK_T_FLUSH, pending writes reach the file
+    pub fn flush_disk(&mut self) -> io::Result<()> {
+        match &mut self.disk {
+            DiskBackend::Memory(_) => Ok(()),
+            DiskBackend::File { writer, .. } => {
+                writer.flush()?;
+                writer.get_ref().sync_data()
+            }
+        }
     }
+
+    // device identifier, returned by VIRTIO_BLK_T_GET_ID
+    pub fn device_id(&self) -> &'static [u8] {
+        b"rustv-virtio-blk"
+    }
+}
+
+fn past_end(addr: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("byte {:#x} is past the end of the disk", addr),
+    )
 }
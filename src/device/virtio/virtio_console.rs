@@ -0,0 +1,196 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use crate::{exept::Exception, param::*};
+
+pub struct VirtioConsole {
+    driver_features: u32,
+    page_size: u32,
+    queue_sel: u32,
+    queue_num: [u32; CONSOLE_QUEUES],
+    queue_pfn: [u32; CONSOLE_QUEUES],
+    // bitmask of notified queues
+    notified: u32,
+    status: u32,
+    // next available ring entry to be processed, per queue
+    last_avail_idx: [u16; CONSOLE_QUEUES],
+    // bytes from the host waiting for receiveq buffers
+    input: Arc<Mutex<VecDeque<u8>>>,
+    input_ready: Arc<AtomicBool>,
+    // transmitq bytes go here, stdout by default
+    output: Box<dyn Write + Send>,
+}
+
+const CONSOLE_QUEUES: usize = 2;
+pub const CONSOLE_RECEIVEQ: usize = 0;
+pub const CONSOLE_TRANSMITQ: usize = 1;
+
+impl VirtioConsole {
+    // read_stdin - feed bytes from host stdin to the receiveq
+    pub fn new(read_stdin: bool) -> Self {
+        let input = Arc::new(Mutex::new(VecDeque::new()));
+        let input_ready = Arc::new(AtomicBool::new(false));
+
+        if read_stdin {
+            let read_input = Arc::clone(&input);
+            let read_ready = Arc::clone(&input_ready);
+            let mut byte = [0];
+            thread::spawn(move || loop {
+                match io::stdin().read(&mut byte) {
+                    Ok(0) => break,
+                    Ok(_) => {
+                        read_input.lock().unwrap().push_back(byte[0]);
+                        read_ready.store(true, Ordering::Release);
+                    }
+                    Err(e) => {
+                        println!("{}", e);
+                    }
+                }
+            });
+        }
+
+        Self {
+            driver_features: 0,
+            page_size: 0,
+            queue_sel: 0,
+            queue_num: [0; CONSOLE_QUEUES],
+            queue_pfn: [0; CONSOLE_QUEUES],
+            notified: 0,
+            status: 0,
+            last_avail_idx: [0; CONSOLE_QUEUES],
+            input,
+            input_ready,
+            output: Box::new(io::stdout()),
+        }
+    }
+
+    pub fn set_output(&mut self, output: Box<dyn Write + Send>) {
+        self.output = output;
+    }
+
+    // queue host input as if it was typed on stdin
+    pub fn push_input(&self, bytes: &[u8]) {
+        self.input.lock().unwrap().extend(bytes);
+        self.input_ready.store(true, Ordering::Release);
+    }
+
+    pub fn is_interrupting(&mut self) -> bool {
+        let input = self.input_ready.swap(false, Ordering::Acquire);
+        let notified = self.notified != 0;
+        self.notified = 0;
+        (input || notified) && self.status & VIRTIO_STATUS_DRIVER_OK != 0
+    }
+
+    // register layout is the same as virtio-blk, so its constants are reused
+    fn reg(addr: u64) -> u64 {
+        addr - VIRTIO_CONSOLE_BASE + VIRTIO_BASE
+    }
+
+    pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
+        let reg = Self::reg(addr);
+        // struct virtio_console_config, 16 bit fields
+        if reg >= VIRTIO_CONFIG {
+            return match (reg - VIRTIO_CONFIG, size) {
+                (0, 16) => Ok(CONSOLE_COLS),
+                (2, 16) => Ok(CONSOLE_ROWS),
+                (0, 32) => Ok(CONSOLE_COLS | (CONSOLE_ROWS << 16)),
+                _ => Ok(0),
+            };
+        }
+        if size != 32 {
+            return Err(Exception::LoadAccessFault(addr));
+        }
+
+        let sel = self.queue_sel as usize % CONSOLE_QUEUES;
+        match reg {
+            VIRTIO_MAGIC => Ok(0x74726976),
+            VIRTIO_VERSION => Ok(0x1),
+            VIRTIO_DEVICE_ID => Ok(0x3),
+            VIRTIO_VENDOR_ID => Ok(0x554d4551),
+            VIRTIO_DEVICE_FEATURES => Ok(1 << VIRTIO_CONSOLE_F_SIZE),
+            VIRTIO_DRIVER_FEATURES => Ok(self.driver_features as u64),
+            VIRTIO_QUEUE_NUM_MAX => Ok(DESC_NUM as u64),
+            VIRTIO_QUEUE_PFN => Ok(self.queue_pfn[sel] as u64),
+            VIRTIO_STATUS => Ok(self.status as u64),
+            _ => Ok(0),
+        }
+    }
+
+    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if size != 32 {
+            return Err(Exception::StoreAMOAccessFault(addr));
+        }
+
+        let value = value as u32;
+        let sel = self.queue_sel as usize % CONSOLE_QUEUES;
+
+        match Self::reg(addr) {
+            VIRTIO_DRIVER_FEATURES => Ok(self.driver_features = value),
+            VIRTIO_GUEST_PAGE_SIZE => Ok(self.page_size = value),
+            VIRTIO_QUEUE_SEL => Ok(self.queue_sel = value),
+            VIRTIO_QUEUE_NUM => Ok(self.queue_num[sel] = value),
+            VIRTIO_QUEUE_PFN => Ok(self.queue_pfn[sel] = value),
+            VIRTIO_QUEUE_NOTIFY => {
+                if self.status & VIRTIO_STATUS_DRIVER_OK != 0 && (value as usize) < CONSOLE_QUEUES {
+                    self.notified |= 1 << value;
+                }
+                Ok(())
+            }
+            VIRTIO_STATUS => {
+                if value == 0 || value & VIRTIO_STATUS_FAILED != 0 {
+                    self.reset();
+                } else {
+                    self.status = value;
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // pending input is kept, it wasn't delivered to anyone yet
+    pub fn reset(&mut self) {
+        self.driver_features = 0;
+        self.page_size = 0;
+        self.queue_sel = 0;
+        self.queue_num = [0; CONSOLE_QUEUES];
+        self.queue_pfn = [0; CONSOLE_QUEUES];
+        self.notified = 0;
+        self.status = 0;
+        self.last_avail_idx = [0; CONSOLE_QUEUES];
+    }
+
+    pub fn desc_addr(&self, queue: usize) -> u64 {
+        self.queue_pfn[queue] as u64 * self.page_size as u64
+    }
+
+    // returns the index of the next request, if the driver has added one
+    pub fn next_avail(&mut self, queue: usize, avail_idx: u16) -> Option<u16> {
+        if self.last_avail_idx[queue] == avail_idx {
+            return None;
+        }
+        let idx = self.last_avail_idx[queue];
+        self.last_avail_idx[queue] = idx.wrapping_add(1);
+        Some(idx)
+    }
+
+    pub fn has_input(&self) -> bool {
+        !self.input.lock().unwrap().is_empty()
+    }
+
+    pub fn read_input(&mut self) -> Option<u8> {
+        self.input.lock().unwrap().pop_front()
+    }
+
+    pub fn write_output(&mut self, bytes: &[u8]) {
+        self.output.write_all(bytes).unwrap();
+        self.output.flush().unwrap();
+    }
+}
@@ -0,0 +1,25 @@
+use std::io;
+
+use super::backends::NetworkBackend;
+
+// Network card frames go through. Its MMIO registers and queues aren't on the bus
+// yet, for now it only owns the backend.
+pub struct VirtioNet {
+    backend: Box<dyn NetworkBackend>,
+}
+
+impl VirtioNet {
+    pub fn new(backend: Box<dyn NetworkBackend>) -> Self {
+        Self { backend }
+    }
+
+    // frame the guest transmitted
+    pub fn transmit(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.backend.send_packet(frame)
+    }
+
+    // next frame for the guest into buf, false if none is waiting
+    pub fn receive(&mut self, buf: &mut Vec<u8>) -> io::Result<bool> {
+        self.backend.recv_packet(buf)
+    }
+}
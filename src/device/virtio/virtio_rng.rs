@@ -0,0 +1,147 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+};
+
+use crate::{exept::Exception, param::*};
+
+pub struct VirtioRng {
+    driver_features: u32,
+    page_size: u32,
+    queue_sel: u32,
+    queue_num: u32,
+    queue_pfn: u32,
+    queue_notify: u32,
+    status: u32,
+    // next available ring entry to be processed
+    last_avail_idx: u16,
+    // xorshift state
+    state: u64,
+}
+
+const MAX_RNG_QUEUE: u32 = 1;
+
+impl Default for VirtioRng {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VirtioRng {
+    pub fn new() -> Self {
+        // RandomState is seeded by the OS, good enough as an entropy source
+        let seed = RandomState::new().build_hasher().finish();
+        Self {
+            driver_features: 0,
+            page_size: 0,
+            queue_sel: 0,
+            queue_num: 0,
+            queue_pfn: 0,
+            queue_notify: MAX_RNG_QUEUE,
+            status: 0,
+            last_avail_idx: 0,
+            state: seed | 1,
+        }
+    }
+
+    // fixed seed, for reproducible runs
+    pub fn seed(&mut self, seed: u64) {
+        self.state = seed | 1;
+    }
+
+    pub fn is_interrupting(&mut self) -> bool {
+        if self.queue_notify < MAX_RNG_QUEUE {
+            self.queue_notify = MAX_RNG_QUEUE;
+            return true;
+        }
+        return false;
+    }
+
+    // register layout is the same as virtio-blk, so its constants are reused
+    fn reg(addr: u64) -> u64 {
+        addr - VIRTIO_RNG_BASE + VIRTIO_BASE
+    }
+
+    pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
+        if size != 32 {
+            return Err(Exception::LoadAccessFault(addr));
+        }
+
+        match Self::reg(addr) {
+            VIRTIO_MAGIC => Ok(0x74726976),
+            VIRTIO_VERSION => Ok(0x1),
+            VIRTIO_DEVICE_ID => Ok(0x4),
+            VIRTIO_VENDOR_ID => Ok(0x554d4551),
+            VIRTIO_DEVICE_FEATURES => Ok(0),
+            VIRTIO_DRIVER_FEATURES => Ok(self.driver_features as u64),
+            VIRTIO_QUEUE_NUM_MAX => Ok(DESC_NUM as u64),
+            VIRTIO_QUEUE_PFN => Ok(self.queue_pfn as u64),
+            VIRTIO_STATUS => Ok(self.status as u64),
+            _ => Ok(0),
+        }
+    }
+
+    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if size != 32 {
+            return Err(Exception::StoreAMOAccessFault(addr));
+        }
+
+        let value = value as u32;
+
+        match Self::reg(addr) {
+            VIRTIO_DRIVER_FEATURES => Ok(self.driver_features = value),
+            VIRTIO_GUEST_PAGE_SIZE => Ok(self.page_size = value),
+            VIRTIO_QUEUE_SEL => Ok(self.queue_sel = value),
+            VIRTIO_QUEUE_NUM => Ok(self.queue_num = value),
+            VIRTIO_QUEUE_PFN => Ok(self.queue_pfn = value),
+            VIRTIO_QUEUE_NOTIFY => {
+                if self.status & VIRTIO_STATUS_DRIVER_OK != 0 {
+                    self.queue_notify = value;
+                }
+                Ok(())
+            }
+            VIRTIO_STATUS => {
+                if value == 0 || value & VIRTIO_STATUS_FAILED != 0 {
+                    self.reset();
+                } else {
+                    self.status = value;
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.driver_features = 0;
+        self.page_size = 0;
+        self.queue_sel = 0;
+        self.queue_num = 0;
+        self.queue_pfn = 0;
+        self.queue_notify = MAX_RNG_QUEUE;
+        self.status = 0;
+        self.last_avail_idx = 0;
+    }
+
+    pub fn desc_addr(&self) -> u64 {
+        self.queue_pfn as u64 * self.page_size as u64
+    }
+
+    // returns the index of the next request, if the driver has added one
+    pub fn next_avail(&mut self, avail_idx: u16) -> Option<u16> {
+        if self.last_avail_idx == avail_idx {
+            return None;
+        }
+        let idx = self.last_avail_idx;
+        self.last_avail_idx = self.last_avail_idx.wrapping_add(1);
+        Some(idx)
+    }
+
+    // xorshift64*
+    pub fn random_byte(&mut self) -> u8 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        (self.state.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 56) as u8
+    }
+}
@@ -1,46 +1,239 @@
+use std::{
+    cmp::{max, min},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use crate::config::MemoryOrderingModel;
 use crate::exept::Exception;
-use crate::param::{DRAM_BASE, DRAM_SIZE};
+use crate::param::DRAM_BASE;
+
+// read-modify-write operations of the A extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AmoOp {
+    Swap,
+    Add,
+    Xor,
+    And,
+    Or,
+    Min,
+    Max,
+    MinU,
+    MaxU,
+}
+
+impl AmoOp {
+    // new memory value from the old one and the operand, both size bits wide
+    pub fn apply(self, old: u64, operand: u64, size: u64) -> u64 {
+        let mask = byte_mask((size / 8) as usize);
+        let (old, operand) = (old & mask, operand & mask);
+        let signed = |x: u64| ((x << (64 - size)) as i64) >> (64 - size);
+        let value = match self {
+            AmoOp::Swap => operand,
+            AmoOp::Add => old.wrapping_add(operand),
+            AmoOp::Xor => old ^ operand,
+            AmoOp::And => old & operand,
+            AmoOp::Or => old | operand,
+            AmoOp::Min => min(signed(old), signed(operand)) as u64,
+            AmoOp::Max => max(signed(old), signed(operand)) as u64,
+            AmoOp::MinU => min(old, operand),
+            AmoOp::MaxU => max(old, operand),
+        };
+        value & mask
+    }
+}
 
+// Memory is an array of 64-bit atomic words shared by every hart holding a
+// handle from `shared()`. An access inside one word is a single atomic operation,
+// a misaligned access spanning two words is two of them.
 pub struct Dram {
-    pub dram: Vec<u8>,
+    words: Arc<[AtomicU64]>,
+    size: u64,
+    model: MemoryOrderingModel,
 }
 
 impl Dram {
-    pub fn new(code: Vec<u8>) -> Self {
-        let mut dram = vec![0; DRAM_SIZE as usize];
-        dram.splice(..code.len(), code.into_iter());
-        Self { dram }
+    pub fn new(size: u64, code: Vec<u8>) -> Self {
+        // SAFETY: all zero bits is a valid AtomicU64. A zeroed allocation leaves
+        // untouched pages unmapped, so a large dram costs nothing until written.
+        let words: Arc<[AtomicU64]> =
+            unsafe { Arc::new_zeroed_slice(size.div_ceil(8) as usize).assume_init() };
+        for (word, chunk) in words.iter().zip(code.chunks(8)) {
+            let mut bytes = [0; 8];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            word.store(u64::from_le_bytes(bytes), Ordering::Relaxed);
+        }
+        Self {
+            words,
+            size,
+            model: MemoryOrderingModel::default(),
+        }
+    }
+
+    // another handle to the same memory, for a hart running on another thread
+    pub fn shared(&self) -> Self {
+        Self {
+            words: Arc::clone(&self.words),
+            size: self.size,
+            model: self.model,
+        }
+    }
+
+    pub fn is_shared(&self) -> bool {
+        Arc::strong_count(&self.words) > 1
+    }
+
+    pub fn set_memory_model(&mut self, model: MemoryOrderingModel) {
+        self.model = model;
     }
 
     pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
         if ![8, 16, 24, 32, 64].contains(&size) {
-            return Err(Exception::LoadAccessFault(size));
+            return Err(Exception::LoadAccessFault(addr));
         }
 
-        return Ok(self.load_little_endian((addr - DRAM_BASE) as usize, (size / 8) as usize));
+        let bytes = (size / 8) as usize;
+        match self.index(addr, bytes) {
+            Some(index) => Ok(self.load_little_endian(index, bytes)),
+            None => Err(Exception::LoadAccessFault(addr)),
+        }
+    }
+
+    // Index of addr in dram if all bytes of the access are inside it. An access
+    // ending on the last byte (DRAM_END) is fine, one crossing past it faults.
+    fn index(&self, addr: u64, bytes: usize) -> Option<usize> {
+        let index = usize::try_from(addr.checked_sub(DRAM_BASE)?).ok()?;
+        if index.checked_add(bytes)? as u64 > self.size {
+            return None;
+        }
+        Some(index)
     }
 
     fn load_little_endian(&self, index: usize, bytes: usize) -> u64 {
-        let mut code = self.dram[index] as u64;
-        for i in 1..bytes {
-            code |= (self.dram[index + i] as u64) << (i * 8);
+        let order = self.model.load_ordering();
+        let mut value = 0;
+        let mut done = 0;
+        while done < bytes {
+            let (word, offset, n) = split(index + done, bytes - done);
+            let w = self.words[word].load(order);
+            value |= ((w >> (offset * 8)) & byte_mask(n)) << (done * 8);
+            done += n;
         }
-        code
+        value
     }
 
-    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+    pub fn store(&self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
         if ![8, 16, 24, 32, 64].contains(&size) {
-            return Err(Exception::StoreAMOAccessFault(size));
+            return Err(Exception::StoreAMOAccessFault(addr));
         }
 
-        self.store_little_endian((addr - DRAM_BASE) as usize, (size / 8) as usize, value);
+        let bytes = (size / 8) as usize;
+        match self.index(addr, bytes) {
+            Some(index) => self.store_little_endian(index, bytes, value),
+            None => return Err(Exception::StoreAMOAccessFault(addr)),
+        }
 
         Ok(())
     }
 
-    fn store_little_endian(&mut self, index: usize, bytes: usize, value: u64) {
-        for i in 0..bytes {
-            self.dram[index + i] = (value >> i * 8) as u8;
+    fn store_little_endian(&self, index: usize, bytes: usize, value: u64) {
+        let order = self.model.store_ordering();
+        let mut done = 0;
+        while done < bytes {
+            let (word, offset, n) = split(index + done, bytes - done);
+            let part = (value >> (done * 8)) & byte_mask(n);
+            if n == 8 {
+                self.words[word].store(part, order);
+            } else {
+                // other harts may be storing to the rest of the word
+                let mask = byte_mask(n) << (offset * 8);
+                let _ = self.words[word].fetch_update(order, Ordering::Relaxed, |w| {
+                    Some(w & !mask | part << (offset * 8))
+                });
+            }
+            done += n;
+        }
+    }
+
+    // atomic read-modify-write of an aligned 32 or 64-bit value, returns the old value
+    pub fn amo(
+        &self,
+        addr: u64,
+        size: u64,
+        op: AmoOp,
+        operand: u64,
+        order: Ordering,
+    ) -> Result<u64, Exception> {
+        let (word, shift, mask) = self.amo_word(addr, size)?;
+        let old = self.words[word]
+            .fetch_update(order, failure_ordering(order), |w| {
+                Some(w & !(mask << shift) | op.apply(w >> shift, operand, size) << shift)
+            })
+            .unwrap();
+        Ok((old >> shift) & mask)
+    }
+
+    // store new only if memory still holds expected, for sc
+    pub fn compare_exchange(
+        &self,
+        addr: u64,
+        size: u64,
+        expected: u64,
+        new: u64,
+        order: Ordering,
+    ) -> Result<bool, Exception> {
+        let (word, shift, mask) = self.amo_word(addr, size)?;
+        let word = &self.words[word];
+        let mut current = word.load(failure_ordering(order));
+        loop {
+            if (current >> shift) & mask != expected & mask {
+                return Ok(false);
+            }
+            let next = current & !(mask << shift) | (new & mask) << shift;
+            match word.compare_exchange_weak(current, next, order, failure_ordering(order)) {
+                Ok(_) => return Ok(true),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    // (word index, bit shift, value mask) of a naturally aligned atomic access
+    fn amo_word(&self, addr: u64, size: u64) -> Result<(usize, u64, u64), Exception> {
+        if size != 32 && size != 64 {
+            return Err(Exception::StoreAMOAccessFault(addr));
         }
+        let bytes = (size / 8) as usize;
+        if !addr.is_multiple_of(bytes as u64) {
+            return Err(Exception::StoreAMOAddrMisaligned(addr));
+        }
+        let index = self
+            .index(addr, bytes)
+            .ok_or(Exception::StoreAMOAccessFault(addr))?;
+        Ok((index / 8, (index % 8 * 8) as u64, byte_mask(bytes)))
+    }
+}
+
+// (word, byte offset in it, bytes of the access in it) for an access at index
+fn split(index: usize, bytes: usize) -> (usize, usize, usize) {
+    let offset = index % 8;
+    (index / 8, offset, min(8 - offset, bytes))
+}
+
+fn byte_mask(bytes: usize) -> u64 {
+    if bytes >= 8 {
+        u64::MAX
+    } else {
+        (1 << (bytes * 8)) - 1
+    }
+}
+
+// a failed compare and swap only loads, it can't have release semantics
+fn failure_ordering(order: Ordering) -> Ordering {
+    match order {
+        Ordering::Release => Ordering::Relaxed,
+        Ordering::AcqRel => Ordering::Acquire,
+        order => order,
     }
 }
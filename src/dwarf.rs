@@ -0,0 +1,75 @@
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+};
+
+use gimli::{
+    AttributeValue, DebugLine, DebugLineOffset, DebugLineStr, DebugStr, EndianSlice, LittleEndian,
+};
+
+use crate::elf::Elf;
+
+// instruction address -> (file, line) from the ELF's .debug_line
+pub type DwarfLineTable = HashMap<u64, (String, u32)>;
+
+// rows further apart than this aren't filled in between, a gap this big is
+// data or padding rather than the code of one line
+const MAX_ROW_SPAN: u64 = 0x1000;
+
+// Line table from the .debug_line of an ELF built with -g, empty without it. base is added
+// to every address, for programs linked at 0 and loaded elsewhere. Every 2-byte
+// aligned address a row covers gets an entry, not just the row's first one.
+pub fn parse_line_table(data: &[u8], base: u64) -> io::Result<DwarfLineTable> {
+    let section = |name: &str| {
+        let bytes = Elf::section(data, name).ok().flatten().unwrap_or(&[]);
+        EndianSlice::new(bytes, LittleEndian)
+    };
+    let line_section = section(".debug_line");
+    let debug_line = DebugLine::from(line_section);
+    let debug_line_str = DebugLineStr::from(section(".debug_line_str"));
+    let debug_str = DebugStr::from(section(".debug_str"));
+
+    let mut table = DwarfLineTable::new();
+    // the programs are read back to back, without .debug_info to point at them
+    let mut offset = 0;
+    while offset < line_section.len() {
+        let program = debug_line
+            .program(DebugLineOffset(offset), 8, None, None)
+            .map_err(invalid)?;
+        let header = program.header();
+        offset += header.format().initial_length_size() as usize + header.unit_length();
+        let mut rows = program.rows();
+        // row waiting for the next one to know where it ends
+        let mut pending: Option<(u64, String, u32)> = None;
+        while let Some((header, row)) = rows.next_row().map_err(invalid)? {
+            let address = row.address();
+            if let Some((start, file, line)) = pending.take() {
+                if address >= start && address - start <= MAX_ROW_SPAN {
+                    for addr in (start..address).step_by(2) {
+                        table.insert(base + addr, (file.clone(), line));
+                    }
+                }
+            }
+            // line 0 is code the compiler made up
+            let line = row.line().map_or(0, |line| line.get() as u32);
+            if row.end_sequence() || line == 0 {
+                continue;
+            }
+            let Some(entry) = row.file(header) else {
+                continue;
+            };
+            let file = match entry.path_name() {
+                AttributeValue::String(name) => name,
+                AttributeValue::DebugLineStrRef(o) => debug_line_str.get_str(o).map_err(invalid)?,
+                AttributeValue::DebugStrRef(o) => debug_str.get_str(o).map_err(invalid)?,
+                _ => continue,
+            };
+            pending = Some((address, file.to_string_lossy().into_owned(), line));
+        }
+    }
+    Ok(table)
+}
+
+fn invalid(e: gimli::Error) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, e.to_string())
+}
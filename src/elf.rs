@@ -0,0 +1,174 @@
+use std::io::{self, ErrorKind};
+
+// ELF64 little-endian, only what loading a RISC-V program and naming its addresses needs
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_NOBITS: u32 = 8;
+const STT_SECTION: u8 = 3;
+const STT_FILE: u8 = 4;
+
+const PHDR_SIZE: usize = 56;
+const SHDR_SIZE: usize = 64;
+const SYM_SIZE: usize = 24;
+
+pub struct Elf {
+    pub entry: u64,
+    // (physical address, bytes) of each PT_LOAD segment, zero filled up to memsz
+    pub segments: Vec<(u64, Vec<u8>)>,
+    // (start, size, name) sorted by start, size is 0 for plain labels
+    pub symbols: Vec<(u64, u64, String)>,
+}
+
+impl Elf {
+    pub fn is_elf(data: &[u8]) -> bool {
+        data.starts_with(ELF_MAGIC)
+    }
+
+    pub fn parse(data: &[u8]) -> io::Result<Self> {
+        if !Self::is_elf(data) || data.len() < 64 {
+            return Err(invalid("not an ELF file"));
+        }
+        if data[4] != ELFCLASS64 || data[5] != ELFDATA2LSB {
+            return Err(invalid("not a 64-bit little-endian ELF file"));
+        }
+        let phoff = read_u64(data, 0x20)? as usize;
+        let shoff = read_u64(data, 0x28)? as usize;
+        let phnum = read_u16(data, 0x38)? as usize;
+        let shnum = read_u16(data, 0x3c)? as usize;
+
+        let mut segments = Vec::new();
+        for i in 0..phnum {
+            let ph = phoff + i * PHDR_SIZE;
+            if read_u32(data, ph)? != PT_LOAD {
+                continue;
+            }
+            let offset = read_u64(data, ph + 0x08)? as usize;
+            let paddr = read_u64(data, ph + 0x18)?;
+            let filesz = read_u64(data, ph + 0x20)? as usize;
+            let memsz = read_u64(data, ph + 0x28)? as usize;
+            let mut bytes = slice(data, offset, filesz)?.to_vec();
+            bytes.resize(memsz.max(filesz), 0);
+            segments.push((paddr, bytes));
+        }
+
+        let mut symbols = Vec::new();
+        for i in 0..shnum {
+            let sh = shoff + i * SHDR_SIZE;
+            if read_u32(data, sh + 0x04)? != SHT_SYMTAB {
+                continue;
+            }
+            let offset = read_u64(data, sh + 0x18)? as usize;
+            let size = read_u64(data, sh + 0x20)? as usize;
+            // sh_link is the string table the names are in
+            let strtab = shoff + read_u32(data, sh + 0x28)? as usize * SHDR_SIZE;
+            let str_offset = read_u64(data, strtab + 0x18)? as usize;
+            let str_size = read_u64(data, strtab + 0x20)? as usize;
+            let names = slice(data, str_offset, str_size)?;
+            for sym in slice(data, offset, size)?.chunks_exact(SYM_SIZE) {
+                let name = read_u32(sym, 0)? as usize;
+                let kind = sym[4] & 0xf;
+                let shndx = read_u16(sym, 6)?;
+                if shndx == 0 || kind == STT_SECTION || kind == STT_FILE {
+                    continue;
+                }
+                let name = names.get(name..).unwrap_or(&[]);
+                let name = name.split(|&b| b == 0).next().unwrap_or(&[]);
+                if name.is_empty() {
+                    continue;
+                }
+                let value = read_u64(sym, 8)?;
+                let size = read_u64(sym, 16)?;
+                symbols.push((value, size, String::from_utf8_lossy(name).into_owned()));
+            }
+        }
+        symbols.sort();
+
+        Ok(Self {
+            entry: read_u64(data, 0x18)?,
+            segments,
+            symbols,
+        })
+    }
+
+    // contents of the section called name, None if the file has no such section
+    pub fn section<'a>(data: &'a [u8], name: &str) -> io::Result<Option<&'a [u8]>> {
+        if !Self::is_elf(data) || data.len() < 64 {
+            return Err(invalid("not an ELF file"));
+        }
+        let shoff = read_u64(data, 0x28)? as usize;
+        let shnum = read_u16(data, 0x3c)? as usize;
+        let shstrndx = read_u16(data, 0x3e)? as usize;
+        if shnum == 0 {
+            return Ok(None);
+        }
+        let strtab = shoff + shstrndx * SHDR_SIZE;
+        let str_offset = read_u64(data, strtab + 0x18)? as usize;
+        let str_size = read_u64(data, strtab + 0x20)? as usize;
+        let names = slice(data, str_offset, str_size)?;
+        for i in 0..shnum {
+            let sh = shoff + i * SHDR_SIZE;
+            let section_name = names.get(read_u32(data, sh)? as usize..).unwrap_or(&[]);
+            let section_name = section_name.split(|&b| b == 0).next().unwrap_or(&[]);
+            if section_name != name.as_bytes() {
+                continue;
+            }
+            // SHT_NOBITS takes no space in the file
+            if read_u32(data, sh + 0x04)? == SHT_NOBITS {
+                return Ok(Some(&[]));
+            }
+            let offset = read_u64(data, sh + 0x18)? as usize;
+            let size = read_u64(data, sh + 0x20)? as usize;
+            return Ok(Some(slice(data, offset, size)?));
+        }
+        Ok(None)
+    }
+
+    // segments at or above base laid out from base, what goes into dram
+    pub fn image(&self, base: u64) -> Vec<u8> {
+        let mut image = Vec::new();
+        for (addr, bytes) in &self.segments {
+            let Some(start) = addr.checked_sub(base) else {
+                continue;
+            };
+            let start = start as usize;
+            if image.len() < start + bytes.len() {
+                image.resize(start + bytes.len(), 0);
+            }
+            image[start..start + bytes.len()].copy_from_slice(bytes);
+        }
+        image
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn slice(data: &[u8], offset: usize, len: usize) -> io::Result<&[u8]> {
+    offset
+        .checked_add(len)
+        .and_then(|end| data.get(offset..end))
+        .ok_or_else(|| invalid("truncated ELF file"))
+}
+
+fn read_u16(data: &[u8], offset: usize) -> io::Result<u16> {
+    Ok(u16::from_le_bytes(
+        slice(data, offset, 2)?.try_into().unwrap(),
+    ))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> io::Result<u32> {
+    Ok(u32::from_le_bytes(
+        slice(data, offset, 4)?.try_into().unwrap(),
+    ))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> io::Result<u64> {
+    Ok(u64::from_le_bytes(
+        slice(data, offset, 8)?.try_into().unwrap(),
+    ))
+}
@@ -0,0 +1,59 @@
+use std::{
+    env,
+    fs::File,
+    io::{self, BufWriter, Write},
+};
+
+use serde_json::json;
+
+// One JSON object per line for traps the cpu takes and hints it executes,
+// meant for external tooling.
+// RUSTV_LOG_JSON=1 turns it on, RUSTV_LOG_FILE=<path> writes to a file instead of stderr.
+pub struct EventLog {
+    out: Box<dyn Write + Send>,
+}
+
+impl EventLog {
+    pub fn new(out: Box<dyn Write + Send>) -> Self {
+        Self { out }
+    }
+
+    pub fn from_env() -> Option<Self> {
+        if env::var("RUSTV_LOG_JSON").ok()? != "1" {
+            return None;
+        }
+        let out: Box<dyn Write + Send> = match env::var("RUSTV_LOG_FILE") {
+            Ok(path) => match File::create(&path) {
+                Ok(file) => Box::new(BufWriter::new(file)),
+                Err(e) => {
+                    eprintln!("can't open {}: {}", path, e);
+                    Box::new(io::stderr())
+                }
+            },
+            Err(_) => Box::new(io::stderr()),
+        };
+        Some(Self::new(out))
+    }
+
+    // event is "exception", "fatal_exception", "interrupt" or "hint",
+    // kind is the variant or instruction name
+    pub fn record(&mut self, event: &str, kind: &str, pc: u64, value: u64, cycle: u64, mode: &str) {
+        let kind_key = match event {
+            "interrupt" | "hint" => event,
+            _ => "exception",
+        };
+        let mut record = json!({
+            "event": event,
+            "pc": format!("{:#x}", pc),
+            "value": format!("{:#x}", value),
+            "cycle": cycle,
+            "mode": mode,
+        });
+        record[kind_key] = kind.into();
+        // a broken log must not stop the emulator
+        let _ = writeln!(self.out, "{}", record);
+        if event == "fatal_exception" {
+            let _ = self.out.flush();
+        }
+    }
+}
@@ -18,6 +18,8 @@ pub enum Exception {
 
 use core::fmt;
 
+use crate::cpu::cpu::Cpu;
+
 use Exception::*;
 impl fmt::Display for Exception {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -41,6 +43,7 @@ impl fmt::Display for Exception {
 }
 
 impl Exception {
+    // written to mtval/stval
     pub fn value(self) -> u64 {
         match self {
             InstructionAddrMisaligned(addr) => addr,
@@ -51,15 +54,17 @@ impl Exception {
             LoadAccessFault(addr) => addr,
             StoreAMOAddrMisaligned(addr) => addr,
             StoreAMOAccessFault(addr) => addr,
-            EnvironmentCallFromUMode(pc) => pc,
-            EnvironmentCallFromSMode(pc) => pc,
-            EnvironmentCallFromMMode(pc) => pc,
+            // tval is zero for ecall, pc is only kept for printing
+            EnvironmentCallFromUMode(_) => 0,
+            EnvironmentCallFromSMode(_) => 0,
+            EnvironmentCallFromMMode(_) => 0,
             InstructionPageFault(addr) => addr,
             LoadPageFault(addr) => addr,
             StoreAMOPageFault(addr) => addr,
         }
     }
 
+    // mcause/scause exception code
     pub fn code(self) -> u64 {
         match self {
             InstructionAddrMisaligned(_) => 0,
@@ -75,7 +80,47 @@ impl Exception {
             EnvironmentCallFromMMode(_) => 11,
             InstructionPageFault(_) => 12,
             LoadPageFault(_) => 13,
-            StoreAMOPageFault(_) => 14,
+            StoreAMOPageFault(_) => 15,
+        }
+    }
+
+    // variant name, without the value
+    pub fn name(self) -> &'static str {
+        match self {
+            InstructionAddrMisaligned(_) => "InstructionAddrMisaligned",
+            InstructionAccessFault(_) => "InstructionAccessFault",
+            IllegalInstruction(_) => "IllegalInstruction",
+            Breakpoint(_) => "Breakpoint",
+            LoadAccessMisaligned(_) => "LoadAccessMisaligned",
+            LoadAccessFault(_) => "LoadAccessFault",
+            StoreAMOAddrMisaligned(_) => "StoreAMOAddrMisaligned",
+            StoreAMOAccessFault(_) => "StoreAMOAccessFault",
+            EnvironmentCallFromUMode(_) => "EnvironmentCallFromUMode",
+            EnvironmentCallFromSMode(_) => "EnvironmentCallFromSMode",
+            EnvironmentCallFromMMode(_) => "EnvironmentCallFromMMode",
+            InstructionPageFault(_) => "InstructionPageFault",
+            LoadPageFault(_) => "LoadPageFault",
+            StoreAMOPageFault(_) => "StoreAMOPageFault",
+        }
+    }
+
+    // like Display, addresses get the <symbol+offset> of the cpu's program
+    pub fn display_with_symbols(self, cpu: &Cpu) -> String {
+        match self {
+            IllegalInstruction(_) => self.to_string(),
+            InstructionAddrMisaligned(addr)
+            | InstructionAccessFault(addr)
+            | Breakpoint(addr)
+            | LoadAccessMisaligned(addr)
+            | LoadAccessFault(addr)
+            | StoreAMOAddrMisaligned(addr)
+            | StoreAMOAccessFault(addr)
+            | EnvironmentCallFromUMode(addr)
+            | EnvironmentCallFromSMode(addr)
+            | EnvironmentCallFromMMode(addr)
+            | InstructionPageFault(addr)
+            | LoadPageFault(addr)
+            | StoreAMOPageFault(addr) => format!("{} {}", self.name(), cpu.format_addr(addr)),
         }
     }
 
@@ -0,0 +1 @@
+pub mod opensbi_stub;
@@ -0,0 +1,444 @@
+// M-mode firmware stub standing in for OpenSBI. It runs from the start of dram,
+// answers SBI v1.0 ecalls from S-mode in guest code and starts the kernel at
+// KERNEL_OFFSET in S-mode with a0 = hart id and a1 = the dtb the rom handed over.
+//
+// base, legacy set_timer / console_putchar / shutdown, TIME, HSM hart_start and
+// hart_get_status, SRST. Shutting down jumps to a zero word, which stops the run.
+use std::collections::HashMap;
+
+use crate::{
+    csr::{
+        MASK_MPP, MASK_MTIP, MASK_SEIP, MASK_SSIP, MASK_STIP, MCAUSE, MCOUNTEREN, MEDELEG, MEPC,
+        MHARTID, MIDELEG, MIE, MIP, MSCRATCH, MSTATUS, MTVEC,
+    },
+    param::{CLINT_MTIMECMP_BASE, UART_BASE, UART_THR},
+    sbi::*,
+};
+
+// the kernel is loaded and entered here, like OpenSBI's fw_jump
+pub const KERNEL_OFFSET: u64 = 0x20_0000;
+// harts past this one wait forever
+pub const MAX_HARTS: u64 = 32;
+
+// code is below DATA, the kernel entry is the first word of it
+const DATA: u64 = 0x1000;
+const KERNEL_ENTRY: u64 = DATA;
+// 64 bytes per hart: saved t0, t1, hart_start address and opaque
+const HART_AREAS: u64 = DATA + 0x100;
+const AREA_T0: i32 = 0;
+const AREA_T1: i32 = 8;
+const AREA_START: i32 = 16;
+const AREA_OPAQUE: i32 = 24;
+
+// misaligned fetch, breakpoint, ecall from U, page faults
+const MEDELEG_S: i32 = 1 << 0 | 1 << 3 | 1 << 8 | 1 << 12 | 1 << 13 | 1 << 15;
+// mstatus.MPP of S-mode
+const MPP_S: i32 = 1 << 11;
+const MCAUSE_ECALL_S: i32 = 9;
+const MCAUSE_TIMER_M: i32 = 7;
+
+const ZERO: u32 = 0;
+const T0: u32 = 5;
+const T1: u32 = 6;
+const A0: u32 = 10;
+const A1: u32 = 11;
+const A2: u32 = 12;
+const A6: u32 = 16;
+const A7: u32 = 17;
+const T6: u32 = 31;
+
+// firmware and kernel as one dram image, with the firmware at dram_base
+pub fn image(dram_base: u64, kernel: &[u8]) -> Vec<u8> {
+    let mut image: Vec<u8> = firmware().iter().flat_map(|w| w.to_le_bytes()).collect();
+    assert!(
+        image.len() as u64 <= DATA,
+        "firmware code runs into its data"
+    );
+    image.resize(KERNEL_OFFSET as usize, 0);
+    let entry = KERNEL_ENTRY as usize;
+    image[entry..entry + 8].copy_from_slice(&(dram_base + KERNEL_OFFSET).to_le_bytes());
+    image.extend_from_slice(kernel);
+    image
+}
+
+fn firmware() -> Vec<u32> {
+    let mut a = Asm::default();
+
+    // every hart: trap vector, its area in mscratch, delegation to S-mode
+    a.csrr(A0, MHARTID);
+    a.li(T0, MAX_HARTS as i32);
+    a.branch(BGEU, A0, T0, "park");
+    a.la(T0, "trap");
+    a.csrw(MTVEC, T0);
+    a.la(T1, HART_AREAS);
+    a.i_type(OP_IMM, 1, T0, A0, 6); // slli t0, a0, 6
+    a.r_type(0, T1, T1, T0); // add t1, t1, t0
+    a.csrw(MSCRATCH, T1);
+    a.li(T0, MEDELEG_S);
+    a.csrw(MEDELEG, T0);
+    a.li(T0, (MASK_SSIP | MASK_STIP | MASK_SEIP) as i32);
+    a.csrw(MIDELEG, T0);
+    a.li(T0, -1);
+    a.csrw(MCOUNTEREN, T0);
+    a.branch(BNE, A0, ZERO, "secondary");
+
+    // hart 0 enters the kernel, a1 is still the dtb
+    a.la(T0, KERNEL_ENTRY);
+    a.ld(T0, T0, 0);
+    a.sd(T0, T1, AREA_START);
+    a.j("enter");
+
+    // the others wait for hart_start to give them an address
+    a.label("secondary");
+    a.emit(WFI);
+    a.ld(T0, T1, AREA_START);
+    a.branch(BEQ, T0, ZERO, "secondary");
+    a.emit(FENCE);
+    a.ld(A1, T1, AREA_OPAQUE);
+
+    // S-mode at t0
+    a.label("enter");
+    a.csrw(MEPC, T0);
+    a.li(T0, MASK_MPP as i32);
+    a.csr(CSRRC, ZERO, MSTATUS, T0);
+    a.li(T0, MPP_S);
+    a.csr(CSRRS, ZERO, MSTATUS, T0);
+    a.emit(MRET);
+
+    a.label("park");
+    a.emit(WFI);
+    a.j("park");
+
+    a.label("halt");
+    a.emit(0);
+
+    // t0, t1 are saved in the hart's area, t6 holds its address
+    a.label("trap");
+    a.csr(CSRRW, T6, MSCRATCH, T6);
+    a.sd(T0, T6, AREA_T0);
+    a.sd(T1, T6, AREA_T1);
+    a.csrr(T0, MCAUSE);
+    a.branch(BLT, T0, ZERO, "interrupt");
+    // anything but an ecall from S-mode is a firmware bug or a kernel gone wrong
+    a.li(T1, MCAUSE_ECALL_S);
+    a.branch(BNE, T0, T1, "halt");
+    a.csrr(T0, MEPC);
+    a.addi(T0, T0, 4);
+    a.csrw(MEPC, T0);
+
+    a.branch(BEQ, A7, ZERO, "set_timer");
+    a.li(T1, SBI_CONSOLE_PUTCHAR as i32);
+    a.branch(BEQ, A7, T1, "putchar");
+    a.li(T1, SBI_SHUTDOWN as i32);
+    a.branch(BEQ, A7, T1, "halt");
+    a.li(T1, SBI_EXT_BASE as i32);
+    a.branch(BEQ, A7, T1, "base");
+    a.li(T1, SBI_EXT_TIME as i32);
+    a.branch(BEQ, A7, T1, "set_timer");
+    a.li(T1, SBI_EXT_HSM as i32);
+    a.branch(BEQ, A7, T1, "hsm");
+    a.li(T1, SBI_EXT_SRST as i32);
+    a.branch(BEQ, A7, T1, "halt");
+    a.j("not_supported");
+
+    // mtimecmp of this hart, the timer interrupt is passed on to S-mode as STIP
+    a.label("set_timer");
+    a.csrr(T0, MHARTID);
+    a.i_type(OP_IMM, 1, T0, T0, 3); // slli t0, t0, 3
+    a.li(T1, CLINT_MTIMECMP_BASE as i32);
+    a.r_type(0, T0, T0, T1);
+    a.sd(A0, T0, 0);
+    a.li(T1, MASK_STIP as i32);
+    a.csr(CSRRC, ZERO, MIP, T1);
+    a.li(T1, MASK_MTIP as i32);
+    a.csr(CSRRS, ZERO, MIE, T1);
+    // legacy calls only return a0
+    a.li(A0, SBI_SUCCESS as i32);
+    a.branch(BEQ, A7, ZERO, "ret");
+    a.li(A1, 0);
+    a.j("ret");
+
+    a.label("putchar");
+    a.li(T1, (UART_BASE + UART_THR) as i32);
+    a.s_type(0, T1, A0, 0); // sb a0, 0(t1)
+    a.li(A0, SBI_SUCCESS as i32);
+    a.j("ret");
+
+    a.label("base");
+    a.li(A1, 0);
+    a.branch(BEQ, A6, ZERO, "spec_version");
+    a.li(T1, SBI_BASE_GET_IMPL_ID as i32);
+    a.branch(BEQ, A6, T1, "impl_id");
+    a.li(T1, SBI_BASE_PROBE_EXTENSION as i32);
+    a.branch(BEQ, A6, T1, "probe");
+    // impl version, mvendorid, marchid, mimpid are all 0
+    a.li(T1, SBI_BASE_GET_MIMPID as i32);
+    a.branch(BGEU, T1, A6, "success");
+    a.j("not_supported");
+    a.label("spec_version");
+    a.li(A1, SBI_SPEC_VERSION as i32);
+    a.j("success");
+    a.label("impl_id");
+    a.li(A1, SBI_IMPL_ID as i32);
+    a.j("success");
+    a.label("probe");
+    a.li(A1, 1);
+    for eid in [
+        SBI_SET_TIMER,
+        SBI_CONSOLE_PUTCHAR,
+        SBI_SHUTDOWN,
+        SBI_EXT_BASE,
+        SBI_EXT_TIME,
+        SBI_EXT_HSM,
+        SBI_EXT_SRST,
+    ] {
+        a.li(T1, eid as i32);
+        a.branch(BEQ, A0, T1, "success");
+    }
+    a.li(A1, 0);
+    a.j("success");
+
+    // a0 = hart id, its area in t0
+    a.label("hsm");
+    a.li(T1, MAX_HARTS as i32);
+    a.branch(BGEU, A0, T1, "invalid_param");
+    a.la(T0, HART_AREAS);
+    a.i_type(OP_IMM, 1, T1, A0, 6);
+    a.r_type(0, T0, T0, T1);
+    a.li(T1, SBI_HSM_HART_GET_STATUS as i32);
+    a.branch(BEQ, A6, T1, "hart_status");
+    a.branch(BNE, A6, ZERO, "not_supported");
+    // hart_start(hartid, start_addr, opaque), a hart only starts once
+    a.ld(T1, T0, AREA_START);
+    a.branch(BNE, T1, ZERO, "already_available");
+    a.sd(A2, T0, AREA_OPAQUE);
+    a.emit(FENCE);
+    a.sd(A1, T0, AREA_START);
+    a.j("success");
+    // 0 started, 1 stopped
+    a.label("hart_status");
+    a.ld(T1, T0, AREA_START);
+    a.i_type(OP_IMM, 3, A1, T1, 1); // seqz a1, t1
+    a.j("success");
+
+    a.label("already_available");
+    a.li(A0, SBI_ERR_ALREADY_AVAILABLE as i32);
+    a.j("ret");
+    a.label("invalid_param");
+    a.li(A0, SBI_ERR_INVALID_PARAM as i32);
+    a.j("ret");
+    a.label("not_supported");
+    a.li(A0, SBI_ERR_NOT_SUPPORTED as i32);
+    a.j("ret");
+    a.label("success");
+    a.li(A0, SBI_SUCCESS as i32);
+    a.j("ret");
+
+    // only the timer is expected, it's handed to S-mode until the next set_timer
+    a.label("interrupt");
+    a.i_type(OP_IMM, 1, T0, T0, 1); // slli t0, t0, 1
+    a.i_type(OP_IMM, 5, T0, T0, 1); // srli t0, t0, 1
+    a.li(T1, MCAUSE_TIMER_M);
+    a.branch(BNE, T0, T1, "halt");
+    a.li(T1, MASK_MTIP as i32);
+    a.csr(CSRRC, ZERO, MIE, T1);
+    a.li(T1, MASK_STIP as i32);
+    a.csr(CSRRS, ZERO, MIP, T1);
+
+    a.label("ret");
+    a.ld(T0, T6, AREA_T0);
+    a.ld(T1, T6, AREA_T1);
+    a.csr(CSRRW, T6, MSCRATCH, T6);
+    a.emit(MRET);
+
+    a.finish()
+}
+
+const OP_IMM: u32 = 0x13;
+const OP: u32 = 0x33;
+const LOAD: u32 = 0x03;
+const STORE: u32 = 0x23;
+const BRANCH: u32 = 0x63;
+const JAL: u32 = 0x6f;
+const AUIPC: u32 = 0x17;
+const LUI: u32 = 0x37;
+const SYSTEM: u32 = 0x73;
+
+// branch funct3
+const BEQ: u32 = 0;
+const BNE: u32 = 1;
+const BLT: u32 = 4;
+const BGEU: u32 = 7;
+// csr funct3
+const CSRRW: u32 = 1;
+const CSRRS: u32 = 2;
+const CSRRC: u32 = 3;
+
+const MRET: u32 = 0x30200073;
+const WFI: u32 = 0x10500073;
+// fence rw, rw
+const FENCE: u32 = 0x0330000f;
+
+// Just enough of an assembler for the stub: branches and jumps name a label,
+// la takes a label or an offset from the start of the firmware.
+#[derive(Default)]
+struct Asm {
+    words: Vec<u32>,
+    labels: HashMap<&'static str, usize>,
+    // (word index, label) of branches and jumps waiting for their target
+    fixups: Vec<(usize, &'static str)>,
+    // (word index, label) of auipc + addi pairs waiting for their target
+    addresses: Vec<(usize, &'static str)>,
+}
+
+enum Target {
+    Label(&'static str),
+    Offset(u64),
+}
+
+impl From<&'static str> for Target {
+    fn from(label: &'static str) -> Self {
+        Target::Label(label)
+    }
+}
+
+impl From<u64> for Target {
+    fn from(offset: u64) -> Self {
+        Target::Offset(offset)
+    }
+}
+
+impl Asm {
+    fn emit(&mut self, inst: u32) {
+        self.words.push(inst);
+    }
+
+    fn label(&mut self, name: &'static str) {
+        self.labels.insert(name, self.words.len());
+    }
+
+    fn r_type(&mut self, funct3: u32, rd: u32, rs1: u32, rs2: u32) {
+        self.emit(rs2 << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | OP);
+    }
+
+    fn i_type(&mut self, opcode: u32, funct3: u32, rd: u32, rs1: u32, imm: i32) {
+        assert!((-2048..2048).contains(&imm));
+        self.emit((imm as u32) << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode);
+    }
+
+    fn s_type(&mut self, funct3: u32, rs1: u32, rs2: u32, imm: i32) {
+        assert!((-2048..2048).contains(&imm));
+        let imm = imm as u32;
+        self.emit(
+            (imm >> 5 & 0x7f) << 25
+                | rs2 << 20
+                | rs1 << 15
+                | funct3 << 12
+                | (imm & 0x1f) << 7
+                | STORE,
+        );
+    }
+
+    fn addi(&mut self, rd: u32, rs1: u32, imm: i32) {
+        self.i_type(OP_IMM, 0, rd, rs1, imm);
+    }
+
+    fn ld(&mut self, rd: u32, rs1: u32, imm: i32) {
+        self.i_type(LOAD, 3, rd, rs1, imm);
+    }
+
+    fn sd(&mut self, rs2: u32, rs1: u32, imm: i32) {
+        self.s_type(3, rs1, rs2, imm);
+    }
+
+    fn li(&mut self, rd: u32, imm: i32) {
+        if (-2048..2048).contains(&imm) {
+            return self.addi(rd, ZERO, imm);
+        }
+        let (hi, lo) = split_imm(imm as i64);
+        self.emit((hi as u32) << 12 | rd << 7 | LUI);
+        if lo != 0 {
+            self.addi(rd, rd, lo);
+        }
+    }
+
+    fn la(&mut self, rd: u32, target: impl Into<Target>) {
+        let label = match target.into() {
+            Target::Label(label) => label,
+            Target::Offset(offset) => {
+                let (hi, lo) = split_imm(offset as i64 - 4 * self.words.len() as i64);
+                self.emit((hi as u32) << 12 | rd << 7 | AUIPC);
+                return self.addi(rd, rd, lo);
+            }
+        };
+        self.addresses.push((self.words.len(), label));
+        self.emit(rd << 7 | AUIPC);
+        self.addi(rd, rd, 0);
+    }
+
+    fn csr(&mut self, funct3: u32, rd: u32, csr: usize, rs1: u32) {
+        self.emit((csr as u32) << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | SYSTEM);
+    }
+
+    fn csrr(&mut self, rd: u32, csr: usize) {
+        self.csr(CSRRS, rd, csr, ZERO);
+    }
+
+    fn csrw(&mut self, csr: usize, rs1: u32) {
+        self.csr(CSRRW, ZERO, csr, rs1);
+    }
+
+    fn branch(&mut self, funct3: u32, rs1: u32, rs2: u32, label: &'static str) {
+        self.fixups.push((self.words.len(), label));
+        self.emit(rs2 << 20 | rs1 << 15 | funct3 << 12 | BRANCH);
+    }
+
+    fn j(&mut self, label: &'static str) {
+        self.fixups.push((self.words.len(), label));
+        self.emit(JAL);
+    }
+
+    fn target(&self, at: usize, label: &str) -> i32 {
+        let Some(&to) = self.labels.get(label) else {
+            panic!("no label {label}");
+        };
+        4 * (to as i32 - at as i32)
+    }
+
+    // fill in the offsets of everything that named a label
+    fn finish(mut self) -> Vec<u32> {
+        for &(at, label) in &self.fixups {
+            let imm = self.target(at, label) as u32;
+            self.words[at] |= match self.words[at] & 0x7f {
+                BRANCH => {
+                    (imm >> 12 & 1) << 31
+                        | (imm >> 5 & 0x3f) << 25
+                        | (imm >> 1 & 0xf) << 8
+                        | (imm >> 11 & 1) << 7
+                }
+                _ => {
+                    (imm >> 20 & 1) << 31
+                        | (imm >> 1 & 0x3ff) << 21
+                        | (imm >> 11 & 1) << 20
+                        | (imm >> 12 & 0xff) << 12
+                }
+            };
+        }
+        for &(at, label) in &self.addresses {
+            let (hi, lo) = split_imm(self.target(at, label) as i64);
+            self.words[at] |= (hi as u32) << 12;
+            self.words[at + 1] |= (lo as u32) << 20;
+        }
+        self.words
+    }
+}
+
+// upper 20 and sign-extended lower 12 bits, for lui / auipc + addi
+fn split_imm(imm: i64) -> (i64, i32) {
+    let hi = (imm + 0x800) >> 12;
+    assert!(
+        (-(1 << 19)..1 << 19).contains(&hi),
+        "{imm:#x} needs more than 32 bits"
+    );
+    (hi & 0xfffff, (imm - (hi << 12)) as i32)
+}
@@ -1,40 +1,197 @@
+use std::{
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
 use crate::{
     exept::Exception,
-    param::{CLINT_MTIME, CLINT_MTIMECMP, PLIC_PENDING, PLIC_SCLAIM, PLIC_SENABLE, PLIC_SPRIORITY},
+    param::{CLINT_MSIP_BASE, CLINT_MTIME, CLINT_MTIMECMP_BASE},
 };
 
 pub struct Clint {
     mtime: u64,
-    mtimecmp: u64,
+    // one msip per hart id, bit 0 is the hart's MSIP, shared like mtimecmp
+    // so one hart can interrupt another. MSIP_UNWRITTEN until the first store.
+    msip: Arc<[AtomicU32]>,
+    // one mtimecmp per hart id, shared with the other harts' handles like dram
+    mtimecmp: Arc<[AtomicU64]>,
+    // mtime follows the wall clock from this point, None while driven by tick()
+    start: Option<Instant>,
+    // mtime as of the last SAMPLE_TICKS ticks, timers compare against it
+    // so the host clock isn't read on every step
+    sampled_mtime: u64,
+    ticks: u64,
 }
 
+// mtime frequency for wall-clock mode, same as qemu virt
+const MTIME_HZ: u128 = 10_000_000;
+// ticks between wall-clock samples for timer_pending
+const SAMPLE_TICKS: u64 = 256;
+// msip that was never stored to, reads as 0
+const MSIP_UNWRITTEN: u32 = u32::MAX;
+
 impl Clint {
-    pub fn new() -> Self {
+    // mtimecmp starts all ones, the timer never fires until it's programmed.
+    // mtime counts steps until use_wall_clock.
+    pub fn new(num_harts: u64) -> Self {
         Self {
             mtime: 0,
-            mtimecmp: 0,
+            msip: (0..num_harts)
+                .map(|_| AtomicU32::new(MSIP_UNWRITTEN))
+                .collect(),
+            mtimecmp: (0..num_harts).map(|_| AtomicU64::new(u64::MAX)).collect(),
+            start: None,
+            sampled_mtime: 0,
+            ticks: 0,
+        }
+    }
+
+    // Another handle for a hart on another thread, mtimecmp is the same registers.
+    // mtime is copied, in wall-clock mode both keep following the same clock.
+    pub fn shared(&self) -> Self {
+        Self {
+            mtime: self.mtime,
+            msip: Arc::clone(&self.msip),
+            mtimecmp: Arc::clone(&self.mtimecmp),
+            start: self.start,
+            sampled_mtime: self.sampled_mtime,
+            ticks: 0,
+        }
+    }
+
+    // mtime restarts from zero and advances by one on every tick()
+    // instead of with the wall clock
+    pub fn use_counter(&mut self) {
+        self.mtime = 0;
+        self.start = None;
+        self.sampled_mtime = 0;
+    }
+
+    // mtime restarts from zero and follows the wall clock at MTIME_HZ
+    pub fn use_wall_clock(&mut self) {
+        self.mtime = 0;
+        self.start = Some(Instant::now());
+        self.sampled_mtime = 0;
+    }
+
+    // true once use_wall_clock made mtime follow the host clock
+    pub fn follows_wall_clock(&self) -> bool {
+        self.start.is_some()
+    }
+
+    pub fn tick(&mut self) {
+        self.ticks = self.ticks.wrapping_add(1);
+        if self.start.is_none() {
+            self.mtime = self.mtime.wrapping_add(1);
+            self.sampled_mtime = self.mtime;
+        } else if self.ticks.is_multiple_of(SAMPLE_TICKS) {
+            self.sampled_mtime = self.mtime();
         }
     }
 
+    pub fn mtime(&self) -> u64 {
+        match self.start {
+            Some(start) => {
+                let ticks = start.elapsed().as_nanos() * MTIME_HZ / 1_000_000_000;
+                self.mtime.wrapping_add(ticks as u64)
+            }
+            None => self.mtime,
+        }
+    }
+
+    // all ones for a hart id past num_harts
+    pub fn mtimecmp(&self, hartid: u64) -> u64 {
+        self.mtimecmp
+            .get(hartid as usize)
+            .map_or(u64::MAX, |cmp| cmp.load(Ordering::Relaxed))
+    }
+
+    // Some(mtime >= mtimecmp) for the hart with mtime as last sampled, None while
+    // mtimecmp still holds all ones, MTIP is then left to whoever else sets it
+    pub fn timer_pending(&self, hartid: u64) -> Option<bool> {
+        match self.mtimecmp(hartid) {
+            u64::MAX => None,
+            mtimecmp => Some(self.sampled_mtime >= mtimecmp),
+        }
+    }
+
+    // Some(msip bit 0) for the hart, None until its msip is first written, MSIP
+    // is then left to whoever else sets it, like timer_pending
+    pub fn software_pending(&self, hartid: u64) -> Option<bool> {
+        match self.msip.get(hartid as usize)?.load(Ordering::Relaxed) {
+            MSIP_UNWRITTEN => None,
+            msip => Some(msip & 1 != 0),
+        }
+    }
+
+    // msip of hart (addr - CLINT_MSIP_BASE) / 4, None past the last hart
+    fn msip_reg(&self, addr: u64) -> Option<&AtomicU32> {
+        let offset = addr.checked_sub(CLINT_MSIP_BASE)?;
+        if !offset.is_multiple_of(4) {
+            return None;
+        }
+        self.msip.get((offset / 4) as usize)
+    }
+
+    // mtimecmp of hart (addr - CLINT_MTIMECMP_BASE) / 8
+    fn mtimecmp_reg(&self, addr: u64) -> Option<&AtomicU64> {
+        let offset = addr.checked_sub(CLINT_MTIMECMP_BASE)?;
+        if !offset.is_multiple_of(8) {
+            return None;
+        }
+        self.mtimecmp.get((offset / 8) as usize)
+    }
+
     pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
+        if size == 32 {
+            return match self.msip_reg(addr) {
+                Some(msip) => Ok((msip.load(Ordering::Relaxed) & 1) as u64),
+                None => Err(Exception::LoadAccessFault(addr)),
+            };
+        }
         if size != 64 {
             return Err(Exception::LoadAccessFault(addr));
         }
         match addr {
-            CLINT_MTIME => Ok(self.mtime),
-            CLINT_MTIMECMP => Ok(self.mtimecmp),
-            _ => Ok(0),
+            CLINT_MTIME => Ok(self.mtime()),
+            _ => Ok(self
+                .mtimecmp_reg(addr)
+                .map_or(0, |cmp| cmp.load(Ordering::Relaxed))),
         }
     }
 
     pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if size == 32 {
+            // only bit 0 is writable
+            return match self.msip_reg(addr) {
+                Some(msip) => {
+                    msip.store(value as u32 & 1, Ordering::Relaxed);
+                    Ok(())
+                }
+                None => Err(Exception::StoreAMOAccessFault(addr)),
+            };
+        }
         if size != 64 {
             return Err(Exception::StoreAMOAccessFault(addr));
         }
         match addr {
-            CLINT_MTIME => Ok(self.mtime = value),
-            CLINT_MTIMECMP => Ok(self.mtimecmp = value),
-            _ => Ok(()),
+            CLINT_MTIME => {
+                self.mtime = value;
+                self.sampled_mtime = value;
+                if self.start.is_some() {
+                    self.start = Some(Instant::now());
+                }
+                Ok(())
+            }
+            _ => {
+                if let Some(cmp) = self.mtimecmp_reg(addr) {
+                    cmp.store(value, Ordering::Relaxed);
+                }
+                Ok(())
+            }
         }
     }
 }
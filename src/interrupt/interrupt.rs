@@ -22,4 +22,9 @@ impl Interrupt {
             MachineExternalInterrupt => 11 | MASK_INTERRUPT_BIT,
         }
     }
+
+    // bit in mip/mie
+    pub fn mask(self) -> u64 {
+        1 << (self.code() & !MASK_INTERRUPT_BIT)
+    }
 }
@@ -1,48 +1,167 @@
-use crate::{
-    exept::Exception,
-    param::{PLIC_PENDING, PLIC_SCLAIM, PLIC_SENABLE, PLIC_SPRIORITY},
-};
+use crate::{exept::Exception, param::*};
+
+// 32-bit words in the pending and enable bitmaps
+const WORDS: usize = NUM_PLIC_SOURCES / 32;
 
 pub struct Plic {
-    pending: u64,
-    senable: u64,
-    spriority: u64,
-    sclaim: u64,
+    priority: [u32; NUM_PLIC_SOURCES],
+    pending: [u32; WORDS],
+    // per context, see PLIC_MCONTEXT / PLIC_SCONTEXT
+    enable: [[u32; WORDS]; PLIC_CONTEXTS],
+    threshold: [u32; PLIC_CONTEXTS],
+    claim: [u32; PLIC_CONTEXTS],
+    // claimed, but not yet completed sources
+    claimed: [u32; WORDS],
+}
+
+// register, decoded from the address
+enum Reg {
+    Priority(usize),
+    Pending(usize),
+    Enable(usize, usize),
+    Threshold(usize),
+    Claim(usize),
+    Other,
+}
+
+impl Default for Plic {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Plic {
     pub fn new() -> Self {
         Self {
-            pending: 0,
-            senable: 0,
-            spriority: 0,
-            sclaim: 0,
+            priority: [0; NUM_PLIC_SOURCES],
+            pending: [0; WORDS],
+            enable: [[0; WORDS]; PLIC_CONTEXTS],
+            threshold: [0; PLIC_CONTEXTS],
+            claim: [0; PLIC_CONTEXTS],
+            claimed: [0; WORDS],
         }
     }
 
-    pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
+    // device asserts its interrupt line
+    pub fn raise(&mut self, source: u64) {
+        let source = source as usize;
+        self.pending[source / 32] |= 1 << (source % 32);
+    }
+
+    // a source enabled for context is waiting to be claimed
+    pub fn is_pending(&self, context: usize) -> bool {
+        self.best_source(context) != 0
+    }
+
+    // highest priority source that is pending, not claimed, enabled for context and
+    // above its threshold, ties go to the lowest id. 0 if none
+    fn best_source(&self, context: usize) -> usize {
+        let mut best = 0;
+        let mut best_priority = self.threshold[context];
+        for word in 0..WORDS {
+            let mut available =
+                self.pending[word] & !self.claimed[word] & self.enable[context][word];
+            while available != 0 {
+                let source = word * 32 + available.trailing_zeros() as usize;
+                available &= available - 1;
+                if source != 0 && self.priority[source] > best_priority {
+                    best = source;
+                    best_priority = self.priority[source];
+                }
+            }
+        }
+        best
+    }
+
+    // reading claim register returns the best pending source for the context (0 if none)
+    // and clears its pending bit until the driver completes it
+    fn claim(&mut self, context: usize) -> u64 {
+        let source = self.best_source(context);
+        if source != 0 {
+            self.pending[source / 32] &= !(1 << (source % 32));
+            self.claimed[source / 32] |= 1 << (source % 32);
+        }
+        self.claim[context] = source as u32;
+        source as u64
+    }
+
+    // writing claimed source back to claim register
+    pub fn interrupt_complete(&mut self, context: usize, source: u64) {
+        let source = source as usize;
+        self.pending[source / 32] &= !(1 << (source % 32));
+        self.claimed[source / 32] &= !(1 << (source % 32));
+        self.claim[context] = 0;
+    }
+
+    fn decode(addr: u64) -> Reg {
+        let pending_end = PLIC_PENDING + 4 * WORDS as u64;
+        let enable_end = PLIC_ENABLE + PLIC_ENABLE_STRIDE * PLIC_CONTEXTS as u64;
+        let context_end = PLIC_CONTEXT + PLIC_CONTEXT_STRIDE * PLIC_CONTEXTS as u64;
+        if !addr.is_multiple_of(4) {
+            return Reg::Other;
+        }
+        if addr < PLIC_PENDING {
+            return Reg::Priority(((addr - PLIC_PRIORITY) / 4) as usize);
+        } else if addr < pending_end {
+            return Reg::Pending(((addr - PLIC_PENDING) / 4) as usize);
+        } else if (PLIC_ENABLE..enable_end).contains(&addr) {
+            let offset = addr - PLIC_ENABLE;
+            let word = (offset % PLIC_ENABLE_STRIDE / 4) as usize;
+            if word < WORDS {
+                return Reg::Enable((offset / PLIC_ENABLE_STRIDE) as usize, word);
+            }
+        } else if (PLIC_CONTEXT..context_end).contains(&addr) {
+            let offset = addr - PLIC_CONTEXT;
+            let context = (offset / PLIC_CONTEXT_STRIDE) as usize;
+            match offset % PLIC_CONTEXT_STRIDE {
+                0 => return Reg::Threshold(context),
+                4 => return Reg::Claim(context),
+                _ => (),
+            }
+        }
+        Reg::Other
+    }
+
+    pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
         if size != 32 {
             return Err(Exception::LoadAccessFault(addr));
         }
-        match addr {
-            PLIC_PENDING => Ok(self.pending),
-            PLIC_SENABLE => Ok(self.senable),
-            PLIC_SPRIORITY => Ok(self.spriority),
-            PLIC_SCLAIM => Ok(self.sclaim),
-            _ => Ok(0),
-        }
+        let value = match Self::decode(addr) {
+            Reg::Priority(source) => self.priority[source],
+            Reg::Pending(word) => self.pending[word],
+            Reg::Enable(context, word) => self.enable[context][word],
+            Reg::Threshold(context) => self.threshold[context],
+            Reg::Claim(context) => return Ok(self.claim(context)),
+            Reg::Other => 0,
+        };
+        Ok(value as u64)
     }
 
     pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
         if size != 32 {
             return Err(Exception::StoreAMOAccessFault(addr));
         }
-        match addr {
-            PLIC_PENDING => Ok(self.pending = value),
-            PLIC_SENABLE => Ok(self.senable = value),
-            PLIC_SPRIORITY => Ok(self.spriority = value),
-            PLIC_SCLAIM => Ok(self.sclaim = value),
-            _ => Ok(()),
+        let value = value as u32;
+        match Self::decode(addr) {
+            // source 0 has no priority
+            Reg::Priority(source) if source != 0 => {
+                self.priority[source] = value.min(PLIC_MAX_PRIORITY)
+            }
+            Reg::Pending(word) => self.pending[word] = value,
+            Reg::Enable(context, word) => self.enable[context][word] = value,
+            Reg::Threshold(context) => self.threshold[context] = value.min(PLIC_MAX_PRIORITY),
+            Reg::Claim(context) => {
+                // complete, ignored for a source that wasn't claimed
+                let source = value as usize;
+                if source != 0
+                    && source < NUM_PLIC_SOURCES
+                    && self.claimed[source / 32] & (1 << (source % 32)) != 0
+                {
+                    self.interrupt_complete(context, source as u64);
+                }
+            }
+            _ => (),
         }
+        Ok(())
     }
 }
@@ -0,0 +1,17 @@
+pub mod bus;
+pub mod config;
+pub mod cpu;
+pub mod csr;
+pub mod debug_module;
+pub mod debugger;
+pub mod device;
+pub mod dram;
+pub mod dwarf;
+pub mod elf;
+pub mod event_log;
+pub mod exept;
+pub mod firmware;
+pub mod interrupt;
+pub mod param;
+pub mod pmp;
+pub mod sbi;
@@ -2,21 +2,73 @@ use std::{
     env,
     fs::File,
     io::{self, Read},
+    process,
 };
 
-use cpu::{cpu::Cpu, test_framework::run_cpu};
-
-mod bus;
-mod cpu;
-mod csr;
-mod device;
-mod dram;
-mod exept;
-mod interrupt;
-mod param;
+use rustV::{
+    config::MachineConfig,
+    cpu::{builder::CpuBuilder, cpu::ExitReason, test_framework::run},
+    debugger::Debugger,
+    device::virtio::{backends::tap::TapBackend, virtio_net::VirtioNet},
+    dwarf::{parse_line_table, DwarfLineTable},
+    elf::Elf,
+    firmware::opensbi_stub::KERNEL_OFFSET,
+};
 
 fn main() -> io::Result<()> {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+
+    // --config <file> - machine configuration in TOML
+    let mut config = MachineConfig::default();
+    if let Some(i) = args.iter().position(|a| a == "--config") {
+        if i + 1 >= args.len() {
+            println!("pass the config filename");
+            return Ok(());
+        }
+        config = MachineConfig::load(&args[i + 1])?;
+        args.drain(i..i + 2);
+    }
+
+    // --debug - interactive debugger on stdin instead of free running
+    let debug = match args.iter().position(|a| a == "--debug") {
+        Some(i) => {
+            args.remove(i);
+            // stdin belongs to the debugger now
+            config.uart_stdin = false;
+            true
+        }
+        None => false,
+    };
+
+    // --trace - print every retired instruction to stderr, with its source line
+    // when the program has debug info
+    let trace = match args.iter().position(|a| a == "--trace") {
+        Some(i) => {
+            args.remove(i);
+            true
+        }
+        None => false,
+    };
+
+    // --opensbi - boot the firmware stub, the program is loaded as its kernel
+    let opensbi = match args.iter().position(|a| a == "--opensbi") {
+        Some(i) => {
+            args.remove(i);
+            true
+        }
+        None => false,
+    };
+
+    // --tap-device <name> - network card on the host's TAP interface name
+    let mut tap_device = None;
+    if let Some(i) = args.iter().position(|a| a == "--tap-device") {
+        if i + 1 >= args.len() {
+            println!("pass the TAP device name");
+            return Ok(());
+        }
+        tap_device = Some(args[i + 1].clone());
+        args.drain(i..i + 2);
+    }
 
     if args.len() < 2 {
         println!("pass the filename");
@@ -27,6 +79,20 @@ fn main() -> io::Result<()> {
     let mut file = File::open(&args[1])?;
     let mut code = Vec::new();
     file.read_to_end(&mut code)?;
+    // an ELF program is laid out in dram from its segments and starts at its entry
+    let mut symbols = Vec::new();
+    let mut source_lines = DwarfLineTable::new();
+    if Elf::is_elf(&code) {
+        let elf = Elf::parse(&code)?;
+        source_lines = parse_line_table(&code, 0)?;
+        if opensbi {
+            code = elf.image(config.dram_base + KERNEL_OFFSET);
+        } else {
+            code = elf.image(config.dram_base);
+            config.boot_pc = elf.entry;
+        }
+        symbols = elf.symbols;
+    }
 
     let mut disk_image = Vec::new();
     if args.len() == 3 {
@@ -34,6 +100,34 @@ fn main() -> io::Result<()> {
         file.read_to_end(&mut disk_image)?;
     }
 
-    run_cpu(code, disk_image, -1);
+    let mut builder = CpuBuilder::new(code, disk_image)
+        .config(config)
+        .symbols(symbols)
+        .source_lines(source_lines)
+        .with_wall_clock()
+        .block_cache(true);
+    if trace {
+        builder = builder.instruction_trace(Box::new(io::stderr()));
+    }
+    if opensbi {
+        builder = builder.with_opensbi();
+    }
+    if let Some(name) = tap_device {
+        let tap = TapBackend::open(&name)?;
+        builder = builder.virtio_net(VirtioNet::new(Box::new(tap)));
+    }
+    let cpu = builder.build();
+    if debug {
+        let mut debugger = Debugger::new(cpu);
+        return debugger.run(io::stdin().lock(), &mut io::stdout());
+    }
+    let (cpu, reason) = run(cpu, -1)?;
+    // RUSTV_MEM_STATS=1 - guest memory traffic on exit
+    if env::var("RUSTV_MEM_STATS").as_deref() == Ok("1") {
+        eprintln!("{}", cpu.get_mem_stats());
+    }
+    if let ExitReason::FatalException(_) = reason {
+        process::exit(1);
+    }
     Ok(())
 }
@@ -3,6 +3,19 @@ pub const DRAM_SIZE: u64 = 1024 * 1024 * 128 * 2;
 pub const DRAM_BASE: u64 = 0x8000_0000;
 pub const DRAM_END: u64 = DRAM_SIZE + DRAM_BASE - 1;
 
+// Debug module
+// abstract command interface for external debuggers, below the rom
+pub const DM_BASE: u64 = 0x0;
+pub const DM_SIZE: u64 = 0x100;
+
+// ROM
+// boot rom with the reset vector, read-only
+pub const ROM_BASE: u64 = 0x1000;
+pub const ROM_SIZE: u64 = 0x1000;
+pub const ROM_END: u64 = ROM_BASE + ROM_SIZE - 1;
+// device tree blob, its address is passed in a1
+pub const ROM_DTB: u64 = ROM_BASE + 0x100;
+
 // UART
 pub const UART_BASE: u64 = 0x1000_0000;
 pub const UART_SIZE: u64 = 0x100;
@@ -13,8 +26,16 @@ pub const UART_IRQ: u64 = 10;
 pub const UART_RHR: u64 = 0;
 // Transmit holding register (for output bytes).
 pub const UART_THR: u64 = 0;
+// Interrupt enable register.
+// IER BIT 0: received data available interrupt.
+// IER BIT 1: transmit holding register empty interrupt.
+pub const UART_IER: u64 = 1;
+pub const MASK_UART_IER_RX: u8 = 1;
+pub const MASK_UART_IER_TX: u8 = 1 << 1;
 // Line control register.
 pub const UART_LCR: u64 = 3;
+// LCR BIT 7: divisor latch access, offsets 0 and 1 are the baud rate divisor while it's set.
+pub const MASK_UART_LCR_DLAB: u8 = 1 << 7;
 // Line status register.
 // LSR BIT 0:
 //     0 = no data in receive holding register or FIFO.
@@ -33,7 +54,14 @@ pub const CLINT_BASE: u64 = 0x200_0000;
 pub const CLINT_SIZE: u64 = 0x10000;
 pub const CLINT_END: u64 = CLINT_BASE + CLINT_SIZE - 1;
 
-pub const CLINT_MTIMECMP: u64 = CLINT_BASE + 0x4000;
+// msip of hart i is the 32-bit word at CLINT_MSIP_BASE + 4 * i
+pub const CLINT_MSIP_BASE: u64 = CLINT_BASE;
+// hart 0's msip
+pub const CLINT_MSIP: u64 = CLINT_MSIP_BASE;
+// mtimecmp of hart i is at CLINT_MTIMECMP_BASE + 8 * i, mtime is shared
+pub const CLINT_MTIMECMP_BASE: u64 = CLINT_BASE + 0x4000;
+// hart 0's mtimecmp
+pub const CLINT_MTIMECMP: u64 = CLINT_MTIMECMP_BASE;
 pub const CLINT_MTIME: u64 = CLINT_BASE + 0xbff8;
 
 //PLIC
@@ -41,10 +69,29 @@ pub const PLIC_BASE: u64 = 0xc00_0000;
 pub const PLIC_SIZE: u64 = 0x4000000;
 pub const PLIC_END: u64 = PLIC_BASE + PLIC_SIZE - 1;
 
+// source 0 is reserved, sources 1..NUM_PLIC_SOURCES
+pub const NUM_PLIC_SOURCES: usize = 1024;
+// one 32-bit word per source, priorities 0 (never interrupts) to PLIC_MAX_PRIORITY
+pub const PLIC_PRIORITY: u64 = PLIC_BASE;
+pub const PLIC_MAX_PRIORITY: u32 = 7;
+// pending bitmap, 32 sources per word
 pub const PLIC_PENDING: u64 = PLIC_BASE + 0x1000;
-pub const PLIC_SENABLE: u64 = PLIC_BASE + 0x2000;
-pub const PLIC_SPRIORITY: u64 = PLIC_BASE + 0x201000;
-pub const PLIC_SCLAIM: u64 = PLIC_BASE + 0x201004;
+// context 0 is hart 0 M-mode, context 1 is hart 0 S-mode
+pub const PLIC_CONTEXTS: usize = 2;
+pub const PLIC_MCONTEXT: usize = 0;
+pub const PLIC_SCONTEXT: usize = 1;
+// enable bitmap at ENABLE + 0x80 * context
+pub const PLIC_ENABLE: u64 = PLIC_BASE + 0x2000;
+pub const PLIC_ENABLE_STRIDE: u64 = 0x80;
+// threshold and claim at CONTEXT + 0x1000 * context
+pub const PLIC_CONTEXT: u64 = PLIC_BASE + 0x200000;
+pub const PLIC_CONTEXT_STRIDE: u64 = 0x1000;
+pub const PLIC_MENABLE: u64 = PLIC_ENABLE;
+pub const PLIC_MPRIORITY: u64 = PLIC_CONTEXT;
+pub const PLIC_MCLAIM: u64 = PLIC_CONTEXT + 4;
+pub const PLIC_SENABLE: u64 = PLIC_ENABLE + PLIC_ENABLE_STRIDE;
+pub const PLIC_SPRIORITY: u64 = PLIC_CONTEXT + PLIC_CONTEXT_STRIDE;
+pub const PLIC_SCLAIM: u64 = PLIC_CONTEXT + PLIC_CONTEXT_STRIDE + 4;
 
 // VIRTIO
 // The address which virtio starts.
@@ -86,6 +133,34 @@ pub const VIRTIO_QUEUE_NOTIFY: u64 = VIRTIO_BASE + 0x050;
 // Writing non-zero values to this register sets the status flags, indicating the OS/driver
 // progress. Writing zero (0x0) to this register triggers a device reset.
 pub const VIRTIO_STATUS: u64 = VIRTIO_BASE + 0x070;
+// Device specific configuration space starts here.
+pub const VIRTIO_CONFIG: u64 = VIRTIO_BASE + 0x100;
+
+// device status bits, set by the driver during initialization
+pub const VIRTIO_STATUS_ACKNOWLEDGE: u32 = 1;
+pub const VIRTIO_STATUS_DRIVER: u32 = 2;
+pub const VIRTIO_STATUS_DRIVER_OK: u32 = 4;
+pub const VIRTIO_STATUS_FEATURES_OK: u32 = 8;
+pub const VIRTIO_STATUS_DEVICE_NEEDS_RESET: u32 = 64;
+pub const VIRTIO_STATUS_FAILED: u32 = 128;
+
+// VIRTIO RNG
+// virtio entropy device, same register layout as the block device
+pub const VIRTIO_RNG_BASE: u64 = 0x1000_2000;
+pub const VIRTIO_RNG_SIZE: u64 = 0x1000;
+pub const VIRTIO_RNG_END: u64 = VIRTIO_RNG_BASE + VIRTIO_RNG_SIZE - 1;
+pub const VIRTIO_RNG_IRQ: u64 = 2;
+
+// VIRTIO CONSOLE
+// virtio serial console, same register layout as the block device
+pub const VIRTIO_CONSOLE_BASE: u64 = 0x1000_3000;
+pub const VIRTIO_CONSOLE_SIZE: u64 = 0x1000;
+pub const VIRTIO_CONSOLE_END: u64 = VIRTIO_CONSOLE_BASE + VIRTIO_CONSOLE_SIZE - 1;
+pub const VIRTIO_CONSOLE_IRQ: u64 = 3;
+// console size is reported in the config space
+pub const VIRTIO_CONSOLE_F_SIZE: u64 = 0;
+pub const CONSOLE_COLS: u64 = 80;
+pub const CONSOLE_ROWS: u64 = 25;
 
 pub const PAGE_SIZE: u64 = 4096;
 pub const SECTOR_SIZE: u64 = 512;
@@ -93,6 +168,14 @@ pub const SECTOR_SIZE: u64 = 512;
 // virtio block request type
 pub const VIRTIO_BLK_T_IN: u32 = 0;
 pub const VIRTIO_BLK_T_OUT: u32 = 1;
+pub const VIRTIO_BLK_T_FLUSH: u32 = 4;
+pub const VIRTIO_BLK_T_GET_ID: u32 = 8;
+// virtio block request status, written to the last descriptor
+pub const VIRTIO_BLK_S_OK: u8 = 0;
+pub const VIRTIO_BLK_S_IOERR: u8 = 1;
+pub const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+// length of device id string returned by VIRTIO_BLK_T_GET_ID
+pub const VIRTIO_BLK_ID_BYTES: u64 = 20;
 
 // virtqueue descriptor flags
 pub const VIRTQ_DESC_F_NEXT: u16 = 1;
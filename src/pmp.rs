@@ -0,0 +1,172 @@
+// Physical memory protection, 16 entries configured by pmpcfg0/2 and pmpaddr0-15.
+use crate::csr::{Csr, MSECCFG, PMPADDR0, PMPCFG0};
+
+pub const PMP_ENTRIES: usize = 16;
+
+// pmpcfg fields, one byte per entry
+pub const PMP_R: u64 = 1 << 0;
+pub const PMP_W: u64 = 1 << 1;
+pub const PMP_X: u64 = 1 << 2;
+pub const PMP_A: u64 = 0b11 << 3;
+pub const PMP_L: u64 = 1 << 7;
+
+// mseccfg fields (Smepmp). MML and MMWP are sticky, once set they stay set until reset.
+// MML: entries apply to M-mode too, locked ones are M-mode only and the rest S/U-mode only
+pub const MSECCFG_MML: u64 = 1 << 0;
+// MMWP: M-mode accesses no entry matches fail
+pub const MSECCFG_MMWP: u64 = 1 << 1;
+// RLB: locked entries can be edited
+pub const MSECCFG_RLB: u64 = 1 << 2;
+
+// address matching modes in pmpcfg.A
+pub const PMP_OFF: u64 = 0;
+pub const PMP_TOR: u64 = 1;
+pub const PMP_NA4: u64 = 2;
+pub const PMP_NAPOT: u64 = 3;
+
+// pmpaddr holds bits 55:2 of the address
+pub const MASK_PMPADDR: u64 = (1 << 54) - 1;
+
+// A over all eight entries of a pmpcfg register
+const MASK_PMPCFG_A: u64 = 0x1818_1818_1818_1818;
+
+// cfg byte of entry i, on RV64 pmpcfg0 has entries 0-7 and pmpcfg2 entries 8-15
+pub fn pmp_cfg(csr: &Csr, i: usize) -> u64 {
+    (csr.load(PMPCFG0 + i / 8 * 2) >> (i % 8 * 8)) & 0xff
+}
+
+// A NAPOT pmpaddr is the base >> 2 with its low k-3 bits set, for a 2^k byte region
+fn napot_range(pmpaddr: u64) -> (u64, u64) {
+    let k = (!pmpaddr).trailing_zeros() + 3;
+    let region_size = 1 << k;
+    let region_base = (pmpaddr & !((region_size >> 2) - 1)) << 2;
+    (region_base, region_base + region_size)
+}
+
+// size is in bytes
+pub fn pmp_napot_match(pmpaddr: u64, addr: u64, size: u64) -> bool {
+    let (base, end) = napot_range(pmpaddr);
+    addr >= base && addr.checked_add(size).is_some_and(|a| a <= end)
+}
+
+// [start, end) covered by entry i, None while it's off
+fn entry_range(csr: &Csr, i: usize) -> Option<(u64, u64)> {
+    let pmpaddr = csr.load(PMPADDR0 + i);
+    match (pmp_cfg(csr, i) & PMP_A) >> 3 {
+        PMP_TOR => {
+            let start = match i {
+                0 => 0,
+                _ => csr.load(PMPADDR0 + i - 1) << 2,
+            };
+            Some((start, pmpaddr << 2))
+        }
+        PMP_NA4 => Some((pmpaddr << 2, (pmpaddr << 2) + 4)),
+        PMP_NAPOT => Some(napot_range(pmpaddr)),
+        _ => None,
+    }
+}
+
+// Permissions an entry grants under mseccfg.MML. R=0 W=1, reserved otherwise,
+// encodes the regions shared between M-mode and S/U-mode, as does L=1 RWX.
+fn mml_perms(cfg: u64, machine: bool) -> u64 {
+    const WX: u64 = PMP_W | PMP_X;
+    const RWX: u64 = PMP_R | PMP_W | PMP_X;
+    let rwx = cfg & RWX;
+    match (cfg & PMP_L != 0, rwx) {
+        (false, PMP_W) if machine => PMP_R | PMP_W,
+        (false, PMP_W) => PMP_R,
+        (false, WX) => PMP_R | PMP_W,
+        (true, PMP_W) => PMP_X,
+        (true, WX) if machine => PMP_R | PMP_X,
+        (true, WX) => PMP_X,
+        (true, RWX) => PMP_R,
+        (true, _) if machine => rwx,
+        (false, _) if !machine => rwx,
+        _ => 0,
+    }
+}
+
+// True if an access of size bytes needing permission (PMP_R / PMP_W / PMP_X) is
+// allowed. The lowest numbered entry touching any byte of the access decides, and
+// it must cover all of it. M-mode is only held to locked entries, unless
+// mseccfg.MML is set. S and U-mode accesses no entry matches fail, unless no entry
+// is on at all; M-mode ones succeed, unless mseccfg.MMWP is set or they're
+// instruction fetches under mseccfg.MML.
+pub fn check_pmp(csr: &Csr, addr: u64, size: u64, perm: u64, machine: bool) -> bool {
+    let mseccfg = csr.load(MSECCFG);
+    let any_on = (csr.load(PMPCFG0) | csr.load(PMPCFG0 + 2)) & MASK_PMPCFG_A != 0;
+    if !any_on && mseccfg == 0 {
+        return true;
+    }
+    let mml = mseccfg & MSECCFG_MML != 0;
+    let end = addr.saturating_add(size);
+    for i in 0..PMP_ENTRIES {
+        let Some((start, stop)) = entry_range(csr, i) else {
+            continue;
+        };
+        if end <= start || addr >= stop {
+            continue;
+        }
+        if addr < start || end > stop {
+            return false;
+        }
+        let cfg = pmp_cfg(csr, i);
+        if mml {
+            return mml_perms(cfg, machine) & perm != 0;
+        }
+        if machine && cfg & PMP_L == 0 {
+            return true;
+        }
+        return cfg & perm != 0;
+    }
+    if !machine {
+        return !any_on;
+    }
+    mseccfg & MSECCFG_MMWP == 0 && !(mml && perm == PMP_X)
+}
+
+// MML and MMWP can only be set. RLB can't be set once an entry is locked
+// while it's clear.
+pub fn write_mseccfg(csr: &Csr, value: u64) -> u64 {
+    let old = csr.load(MSECCFG);
+    let locked = (0..PMP_ENTRIES).any(|i| pmp_cfg(csr, i) & PMP_L != 0);
+    let rlb = match old & MSECCFG_RLB == 0 && locked {
+        true => 0,
+        false => value & MSECCFG_RLB,
+    };
+    (old | value) & (MSECCFG_MML | MSECCFG_MMWP) | rlb
+}
+
+fn rlb(csr: &Csr) -> bool {
+    csr.load(MSECCFG) & MSECCFG_RLB != 0
+}
+
+// pmpcfg with the bytes of locked entries kept as they were, unless mseccfg.RLB is
+// set. Under mseccfg.MML without RLB, bytes that would add an M-mode executable or
+// a locked shared entry are kept too.
+pub fn write_pmpcfg(csr: &Csr, addr: usize, value: u64) -> u64 {
+    let old = csr.load(addr);
+    let first = (addr - PMPCFG0) / 2 * 8;
+    let rlb = rlb(csr);
+    let mml = csr.load(MSECCFG) & MSECCFG_MML != 0;
+    (0..8).fold(0, |cfg, byte| {
+        let new = (value >> (byte * 8)) & 0xff;
+        let locked = pmp_cfg(csr, first + byte) & PMP_L != 0;
+        let adds_exec = new & PMP_L != 0 && mml_perms(new, true) & PMP_X != 0;
+        let from = match !rlb && (locked || (mml && adds_exec)) {
+            true => old,
+            false => value,
+        };
+        cfg | (from & (0xff << (byte * 8)))
+    })
+}
+
+// pmpaddr writes are ignored for a locked entry, a locked TOR entry also locks the
+// one below it. mseccfg.RLB lifts both.
+pub fn pmpaddr_locked(csr: &Csr, i: usize) -> bool {
+    let next_tor = i + 1 < PMP_ENTRIES && {
+        let next = pmp_cfg(csr, i + 1);
+        next & PMP_L != 0 && (next & PMP_A) >> 3 == PMP_TOR
+    };
+    !rlb(csr) && (pmp_cfg(csr, i) & PMP_L != 0 || next_tor)
+}
@@ -0,0 +1,134 @@
+use crate::{
+    bus::Bus,
+    csr::{Csr, MARCHID, MASK_MTIP, MASK_STIE, MASK_STIP, MIE, MIMPID, MIP, MVENDORID, STIMECMP},
+    param::{MASK_UART_LSR_RX, UART_BASE, UART_LSR, UART_RHR, UART_THR},
+};
+
+// legacy extensions (SBI v0.1), result is returned in a0 only
+pub const SBI_SET_TIMER: u64 = 0x00;
+pub const SBI_CONSOLE_PUTCHAR: u64 = 0x01;
+pub const SBI_CONSOLE_GETCHAR: u64 = 0x02;
+pub const SBI_SHUTDOWN: u64 = 0x08;
+// base extension, result is returned in a0 (error) and a1 (value)
+pub const SBI_EXT_BASE: u64 = 0x10;
+// timer extension, "TIME"
+pub const SBI_EXT_TIME: u64 = 0x54494d45;
+// hart state management, "HSM", only the firmware stub implements it
+pub const SBI_EXT_HSM: u64 = 0x48534d;
+// system reset, "SRST", only the firmware stub implements it
+pub const SBI_EXT_SRST: u64 = 0x53525354;
+
+pub const SBI_TIME_SET_TIMER: u64 = 0;
+pub const SBI_HSM_HART_START: u64 = 0;
+pub const SBI_HSM_HART_GET_STATUS: u64 = 2;
+
+// base extension functions
+pub const SBI_BASE_GET_SPEC_VERSION: u64 = 0;
+pub const SBI_BASE_GET_IMPL_ID: u64 = 1;
+pub const SBI_BASE_GET_IMPL_VERSION: u64 = 2;
+pub const SBI_BASE_PROBE_EXTENSION: u64 = 3;
+pub const SBI_BASE_GET_MVENDORID: u64 = 4;
+pub const SBI_BASE_GET_MARCHID: u64 = 5;
+pub const SBI_BASE_GET_MIMPID: u64 = 6;
+
+pub const SBI_SUCCESS: i64 = 0;
+pub const SBI_ERR_NOT_SUPPORTED: i64 = -2;
+pub const SBI_ERR_INVALID_PARAM: i64 = -3;
+pub const SBI_ERR_ALREADY_AVAILABLE: i64 = -6;
+
+// v1.0: major in bits [30:24], minor in [23:0]
+pub const SBI_SPEC_VERSION: u64 = 1 << 24;
+// not a registered implementation id, anything past the known ones
+pub const SBI_IMPL_ID: u64 = 0xff;
+
+// Minimal M-mode firmware: ecalls from S-mode are answered by the emulator
+// directly instead of trapping into M-mode code.
+pub struct SbiHandler {
+    // set by sbi_shutdown, run loop stops after it
+    pub shutdown: bool,
+    // set by the first sbi_set_timer, from then on mip.STIP follows mtime >= stimecmp
+    pub timer_armed: bool,
+}
+
+impl Default for SbiHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SbiHandler {
+    pub fn new() -> Self {
+        Self {
+            shutdown: false,
+            timer_armed: false,
+        }
+    }
+
+    // The next timer event goes to stimecmp and is delivered to S-mode as STIP,
+    // programming it clears whatever timer interrupt is pending.
+    fn set_timer(&mut self, csr: &mut Csr, time: u64) {
+        csr.store(STIMECMP, time);
+        csr.store(MIP, csr.load(MIP) & !(MASK_MTIP | MASK_STIP));
+        csr.store(MIE, csr.load(MIE) | MASK_STIE);
+        self.timer_armed = true;
+    }
+
+    // returns new values of (a0, a1)
+    pub fn handle(
+        &mut self,
+        bus: &mut Bus,
+        csr: &mut Csr,
+        eid: u64,
+        fid: u64,
+        args: [u64; 6],
+    ) -> (u64, u64) {
+        match eid {
+            SBI_SET_TIMER => {
+                self.set_timer(csr, args[0]);
+                (0, args[1])
+            }
+            SBI_CONSOLE_PUTCHAR => {
+                bus.store(UART_BASE + UART_THR, 8, args[0] & 0xff).unwrap();
+                (0, args[1])
+            }
+            SBI_CONSOLE_GETCHAR => {
+                let lsr = bus.load(UART_BASE + UART_LSR, 8).unwrap() as u8;
+                if lsr & MASK_UART_LSR_RX == 0 {
+                    return (-1_i64 as u64, args[1]);
+                }
+                (bus.load(UART_BASE + UART_RHR, 8).unwrap(), args[1])
+            }
+            SBI_SHUTDOWN => {
+                self.shutdown = true;
+                (0, args[1])
+            }
+            SBI_EXT_BASE => self.handle_base(csr, fid, args),
+            SBI_EXT_TIME => match fid {
+                SBI_TIME_SET_TIMER => {
+                    self.set_timer(csr, args[0]);
+                    (SBI_SUCCESS as u64, 0)
+                }
+                _ => (SBI_ERR_NOT_SUPPORTED as u64, 0),
+            },
+            _ => (SBI_ERR_NOT_SUPPORTED as u64, 0),
+        }
+    }
+
+    fn handle_base(&self, csr: &Csr, fid: u64, args: [u64; 6]) -> (u64, u64) {
+        let value = match fid {
+            SBI_BASE_GET_SPEC_VERSION => SBI_SPEC_VERSION,
+            SBI_BASE_GET_IMPL_ID => SBI_IMPL_ID,
+            SBI_BASE_GET_IMPL_VERSION => 0,
+            SBI_BASE_PROBE_EXTENSION => match args[0] {
+                SBI_SET_TIMER | SBI_CONSOLE_PUTCHAR | SBI_CONSOLE_GETCHAR | SBI_SHUTDOWN
+                | SBI_EXT_BASE | SBI_EXT_TIME => 1,
+                _ => 0,
+            },
+            SBI_BASE_GET_MVENDORID => csr.load(MVENDORID),
+            SBI_BASE_GET_MARCHID => csr.load(MARCHID),
+            SBI_BASE_GET_MIMPID => csr.load(MIMPID),
+            _ => return (SBI_ERR_NOT_SUPPORTED as u64, 0),
+        };
+        (SBI_SUCCESS as u64, value)
+    }
+}
@@ -0,0 +1,53 @@
+use proptest::prelude::*;
+
+use rustV::{
+    config::MachineConfig,
+    cpu::cpu::Cpu,
+    csr::{MCAUSE, MEPC, MSCRATCH, MTVAL, MTVEC, SCAUSE, SEPC, SSCRATCH, STVAL, STVEC},
+};
+
+// CSRs that keep every bit written to them, WARL ones would mask the results
+const WRITABLE: [usize; 10] = [
+    MSCRATCH, MEPC, MCAUSE, MTVAL, MTVEC, SSCRATCH, SEPC, SCAUSE, STVAL, STVEC,
+];
+
+// csr instructions read a1 or a 5-bit immediate and write the old value to a0
+const RD: u64 = 10;
+const RS1: u64 = 11;
+
+fn csr_inst(funct3: u64, csr: usize, rs1: u64) -> u64 {
+    ((csr as u64) << 20) | (rs1 << 15) | (funct3 << 12) | (RD << 7) | 0x73
+}
+
+// runs a single csr instruction on a fresh cpu with csr = old and a1 = rs1,
+// returns (a0, csr) after it
+fn run_csr(funct3: u64, csr: usize, old: u64, rs1: u64, operand: u64) -> (u64, u64) {
+    let mut config = MachineConfig::default();
+    config.uart_stdin = false;
+    let mut cpu = Cpu::new(&config, vec![0], vec![0]);
+    cpu.csr.store(csr, old);
+    cpu.regs[RS1 as usize] = rs1;
+    cpu.execute(csr_inst(funct3, csr, operand)).unwrap();
+    (cpu.reg("a0"), cpu.csr.load(csr))
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(1000))]
+
+    #[test]
+    fn csr_read_modify_write(
+        csr in prop::sample::select(&WRITABLE[..]),
+        old in any::<u64>(),
+        rs1 in any::<u64>(),
+        uimm in 0u64..32,
+    ) {
+        // csrrw, csrrs and csrrc with a1
+        prop_assert_eq!(run_csr(0b001, csr, old, rs1, RS1), (old, rs1));
+        prop_assert_eq!(run_csr(0b010, csr, old, rs1, RS1), (old, old | rs1));
+        prop_assert_eq!(run_csr(0b011, csr, old, rs1, RS1), (old, old & !rs1));
+        // csrrwi, csrrsi and csrrci with the immediate zero-extended
+        prop_assert_eq!(run_csr(0b101, csr, old, rs1, uimm), (old, uimm));
+        prop_assert_eq!(run_csr(0b110, csr, old, rs1, uimm), (old, old | uimm));
+        prop_assert_eq!(run_csr(0b111, csr, old, rs1, uimm), (old, old & !uimm));
+    }
+}